@@ -0,0 +1,72 @@
+//! An in-process EIS server for the benchmarks, so they measure eitype
+//! against a server that reads as fast as it can rather than a compositor.
+
+use eitype::{EiType, EiTypeConfig};
+use reis::eis;
+use reis::event::DeviceCapability;
+use reis::request::{EisRequest, EisRequestConverter};
+use reis::PendingRequestResult;
+use std::os::unix::net::UnixStream;
+
+/// Serve a keyboard on `stream` until the client goes away, discarding
+/// everything it sends
+fn serve(stream: UnixStream) {
+    let poll_stream = stream.try_clone().unwrap();
+    let context = eis::Context::new(stream).unwrap();
+    let mut handshaker = reis::handshake::EisHandshaker::new(&context, 1);
+    let mut converter: Option<EisRequestConverter> = None;
+    loop {
+        let mut pollfd = [rustix::event::PollFd::new(
+            &poll_stream,
+            rustix::event::PollFlags::IN,
+        )];
+        let _ = rustix::event::poll(&mut pollfd, None);
+        if context.read().is_err() {
+            return;
+        }
+        while let Some(result) = context.pending_request() {
+            let PendingRequestResult::Request(request) = result else {
+                continue;
+            };
+            let Some(converter) = converter.as_mut() else {
+                if let Some(resp) = handshaker.handle_request(request).unwrap() {
+                    let new = EisRequestConverter::new(&context, resp, 1);
+                    new.handle()
+                        .add_seat(Some("default"), &[DeviceCapability::Keyboard]);
+                    converter = Some(new);
+                }
+                continue;
+            };
+            if converter.handle_request(request).is_err() {
+                return;
+            }
+            while let Some(request) = converter.next_request() {
+                if let EisRequest::Bind(bind) = request {
+                    let device = bind.seat.add_device(
+                        Some("keyboard"),
+                        eis::device::DeviceType::Virtual,
+                        &[DeviceCapability::Keyboard],
+                        |_| {},
+                    );
+                    device.resumed();
+                }
+            }
+        }
+        let _ = context.flush();
+    }
+}
+
+/// An `EiType` (on a US keymap) connected to a fresh server thread
+pub fn connect() -> EiType {
+    let (client, server) = UnixStream::pair().unwrap();
+    client.set_nonblocking(true).unwrap();
+    server.set_nonblocking(true).unwrap();
+    std::thread::spawn(move || serve(server));
+    let config = EiTypeConfig {
+        layout: Some("us".to_string()),
+        layout_index: Some(0),
+        ignore_layout_cache: true,
+        ..Default::default()
+    };
+    EiType::from_stream(client, config).unwrap()
+}
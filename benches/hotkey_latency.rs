@@ -0,0 +1,24 @@
+//! Latency of a single key press, as when pressing one hotkey on the user's
+//! behalf, against an in-process EIS server that reads as fast as it can.
+//!
+//! ```sh
+//! cargo bench --bench hotkey_latency
+//! ```
+
+mod common;
+
+use common::connect;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn hotkey_latency(c: &mut Criterion) {
+    let typer = connect();
+    c.bench_function("press_key escape", |b| {
+        b.iter(|| typer.press_key("escape").unwrap())
+    });
+    c.bench_function("type_text single char", |b| {
+        b.iter(|| typer.type_text("x").unwrap())
+    });
+}
+
+criterion_group!(benches, hotkey_latency);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+//! CPU-side cost of turning text into key events: planning strings of
+//! several lengths on several layouts, the per-character keymap search, the
+//! cost of compiling the keymap again for every string instead of reusing a
+//! planner, and typing ASCII text end to end against an in-process EIS
+//! server. Nothing here needs a compositor.
+//!
+//! ```sh
+//! cargo bench --bench typing_throughput
+//! ```
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use eitype::{EiTypeConfig, Planner};
+
+/// Layouts to plan on, each with text its keys can type
+const LAYOUTS: &[(&str, &str)] = &[
+    (
+        "us",
+        "The quick brown fox jumps over the lazy dog, 42 times! ",
+    ),
+    ("de", "Größe über Äpfel, Öl und Straße; 3 € für 4 Bücher. "),
+    ("fr", "Où est la gare ? Voilà, c'est déjà l'été à 12 h. "),
+];
+
+/// Lengths of the planned strings, in characters
+const SIZES: &[usize] = &[16, 256, 4096];
+
+fn config(layout: &str) -> EiTypeConfig {
+    EiTypeConfig {
+        layout: Some(layout.to_string()),
+        ..Default::default()
+    }
+}
+
+/// `sample` repeated and cut to `chars` characters
+fn text_of(sample: &str, chars: usize) -> String {
+    sample.chars().cycle().take(chars).collect()
+}
+
+fn plan_text(c: &mut Criterion) {
+    let mut group = c.benchmark_group("plan_text");
+    for &(layout, sample) in LAYOUTS {
+        let config = config(layout);
+        let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+        for &size in SIZES {
+            let text = text_of(sample, size);
+            group.throughput(Throughput::Elements(size as u64));
+            group.bench_with_input(BenchmarkId::new(layout, size), &text, |b, text| {
+                b.iter(|| planner.plan_text(black_box(text)).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn plan_char(c: &mut Criterion) {
+    let config = config("de");
+    let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+    let mut group = c.benchmark_group("plan_char");
+    // Unshifted near the start of the keymap, shifted, AltGr, and a
+    // character no key has, which searches every key before failing
+    for ch in ['1', 'A', '@', '☃'] {
+        group.bench_with_input(BenchmarkId::from_parameter(ch), &ch, |b, &ch| {
+            b.iter(|| planner.plan_char(black_box(ch)))
+        });
+    }
+    group.finish();
+}
+
+fn planner_reuse(c: &mut Criterion) {
+    let config = config("de");
+    let text = text_of(LAYOUTS[1].1, 16);
+    let mut group = c.benchmark_group("planner_reuse");
+    let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+    group.bench_function("reused", |b| {
+        b.iter(|| planner.plan_text(black_box(&text)).unwrap())
+    });
+    group.bench_function("compiled_per_string", |b| {
+        b.iter(|| {
+            let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+            planner.plan_text(black_box(&text)).unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn type_text(c: &mut Criterion) {
+    let typer = common::connect();
+    let mut group = c.benchmark_group("type_text");
+    for &size in SIZES {
+        let text = text_of(LAYOUTS[0].1, size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("us", size), &text, |b, text| {
+            b.iter(|| typer.type_text(black_box(text)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, plan_text, plan_char, planner_reuse, type_text);
+criterion_main!(benches);
@@ -0,0 +1,99 @@
+//! Type text from a calloop event loop without blocking it.
+//!
+//! The EI socket is registered twice: for readability, to drain server events
+//! with `dispatch_pending`, and (only while typing is stalled) for writability,
+//! to resume `try_type_text` once the socket has room again.
+//!
+//! ```sh
+//! cargo run --example calloop_typing -- "Hello from calloop"
+//! ```
+
+use calloop::generic::Generic;
+use calloop::{EventLoop, Interest, LoopHandle, LoopSignal, Mode, PostAction};
+use eitype::{EiType, EiTypeConfig, TryTypeStatus, TypeCursor};
+use std::os::fd::AsFd;
+use std::time::Duration;
+
+struct State {
+    typer: EiType,
+    cursor: TypeCursor,
+    signal: LoopSignal,
+}
+
+impl State {
+    /// Type as much as possible; returns true once all text is out.
+    fn type_some(&mut self) -> bool {
+        match self.typer.try_type_text(&mut self.cursor) {
+            Ok(TryTypeStatus::Completed) => {
+                println!("Typed {} characters", self.cursor.typed());
+                self.signal.stop();
+                true
+            }
+            Ok(TryTypeStatus::WouldBlock) => false,
+            Err(e) => {
+                eprintln!("Typing failed: {}", e);
+                self.signal.stop();
+                true
+            }
+        }
+    }
+}
+
+/// Resume typing when the socket becomes writable
+fn watch_writable(handle: &LoopHandle<'static, State>, state: &State) -> std::io::Result<()> {
+    let fd = state.typer.as_fd().try_clone_to_owned()?;
+    handle
+        .insert_source(
+            Generic::new(fd, Interest::WRITE, Mode::OneShot),
+            |_, _, state| {
+                if state.type_some() {
+                    Ok(PostAction::Remove)
+                } else {
+                    Ok(PostAction::Reregister)
+                }
+            },
+        )
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let text = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "Hello from calloop".to_string());
+
+    let typer = EiType::connect_portal(EiTypeConfig::default())?;
+
+    let mut event_loop: EventLoop<'static, State> = EventLoop::try_new()?;
+    let handle = event_loop.handle();
+
+    // Drain server events whenever the socket is readable
+    let fd = typer.as_fd().try_clone_to_owned()?;
+    handle
+        .insert_source(
+            Generic::new(fd, Interest::READ, Mode::Level),
+            |_, _, state| match state.typer.dispatch_pending() {
+                Ok(()) => Ok(PostAction::Continue),
+                Err(e) => {
+                    eprintln!("Connection lost: {}", e);
+                    state.signal.stop();
+                    Ok(PostAction::Remove)
+                }
+            },
+        )
+        .map_err(|e| e.error)?;
+
+    let mut state = State {
+        typer,
+        cursor: TypeCursor::new(text),
+        signal: event_loop.get_signal(),
+    };
+
+    if !state.type_some() {
+        watch_writable(&handle, &state)?;
+    }
+
+    event_loop.run(Duration::from_millis(100), &mut state, |_| {})?;
+    Ok(())
+}
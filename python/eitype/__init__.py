@@ -29,7 +29,32 @@ from __future__ import annotations
 from typing import Optional, Tuple
 
 # Import from the Rust extension module
-from eitype.eitype import EiType as _RustEiType, EiTypeConfig
+from eitype.eitype import (
+    EiType as _RustEiType,
+    EiTypeConfig,
+    AltGrMode,
+    ModifierOrder,
+    BenchReport,
+    Calibration,
+    CalibrationStep,
+    CharTiming,
+    ChunkPolicy,
+    ConnectionInfo,
+    DeviceCapability,
+    KeyTableEntry,
+    KeymapSource,
+    KeystrokeInfo,
+    LatencyPercentiles,
+    PasteTrigger,
+    Selection,
+    SkippedChar,
+    Substitution,
+    TimestampSource,
+    TypeOutcome,
+    TypeSession,
+    TypingError,
+    TypingStats,
+)
 
 
 def connect_portal(config: Optional[EiTypeConfig] = None) -> _RustEiType:
@@ -82,6 +107,28 @@ def connect_portal_with_token(
     return _RustEiType.py_connect_portal_with_token(restore_token, config)
 
 
+def request_portal_permission(config: Optional[EiTypeConfig] = None) -> Optional[str]:
+    """Ask for remote control permission ahead of time.
+
+    Shows the portal's authorization dialog now, e.g. from a settings toggle,
+    rather than on the first connection. The connection is closed again at
+    once. A token saved under config.token_namespace renews a grant that
+    still holds without asking again.
+
+    Args:
+        config: Optional configuration; its devices (keyboard, pointer, ...)
+            are the ones authorized.
+
+    Returns:
+        A restore token to save and pass to connect_portal_with_token, or
+        None if the portal issued none.
+
+    Raises:
+        RuntimeError: If the user denies the request or the portal fails.
+    """
+    return _RustEiType.request_portal_permission(config)
+
+
 def connect_socket(path: str, config: Optional[EiTypeConfig] = None) -> _RustEiType:
     """Connect via a Unix socket.
 
@@ -101,6 +148,17 @@ def connect_socket(path: str, config: Optional[EiTypeConfig] = None) -> _RustEiT
     return _RustEiType.py_connect_socket(path, config)
 
 
+def clear_detected_layout_cache() -> None:
+    """Forget the layout index detected by earlier connections.
+
+    A layout index detected from the desktop (gsettings, qdbus or swaymsg) is
+    reused by later connections in this process for
+    EiTypeConfig.layout_detection_ttl_ms (0 turns the reuse off). Call this
+    after the user switches layouts so the next connection asks again.
+    """
+    _RustEiType.clear_detected_layout_cache()
+
+
 # Create a wrapper class that has nice static methods
 class EiType:
     """Main interface for typing text via the EI protocol.
@@ -161,11 +219,49 @@ class EiType:
         """
         return connect_socket(path, config)
 
+    @staticmethod
+    def null(config: Optional[EiTypeConfig] = None) -> "_RustEiType":
+        """An EiType connected to nothing: typing is planned and paced as
+        usual, but the events are dropped. Useful with bench() to measure
+        eitype's own overhead apart from the compositor's.
+
+        Args:
+            config: Optional keyboard configuration.
+
+        Returns:
+            An EiType instance that types into nothing.
+        """
+        return _RustEiType.py_null(config)
+
 
 __all__ = [
+    "AltGrMode",
+    "BenchReport",
+    "CharTiming",
+    "Calibration",
+    "CalibrationStep",
+    "ChunkPolicy",
+    "ConnectionInfo",
+    "DeviceCapability",
     "EiType",
     "EiTypeConfig",
+    "KeyTableEntry",
+    "KeymapSource",
+    "KeystrokeInfo",
+    "LatencyPercentiles",
+    "ModifierOrder",
+    "PasteTrigger",
+    "Selection",
+    "SkippedChar",
+    "Substitution",
+    "TimestampSource",
+    "TypeOutcome",
+    "TypeSession",
+    "TypingError",
+    "TypingStats",
+    "clear_detected_layout_cache",
     "connect_portal",
     "connect_portal_with_token",
     "connect_socket",
+    "request_portal_permission",
 ]
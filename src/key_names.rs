@@ -0,0 +1,180 @@
+//! Localized key name aliases and "did you mean" suggestions for key names.
+//!
+//! `press_key` and friends look names up in the canonical (English) key map.
+//! Users often type the name printed on their own keyboard instead, so the
+//! table below maps common localized names onto the canonical ones.
+//!
+//! To add a language, add a block of `(alias, canonical name)` pairs to the
+//! table. Aliases are matched case-insensitively and must be written in
+//! lowercase; include an ASCII spelling alongside any accented one (e.g.
+//! "entrée" and "entree").
+
+/// Localized alias -> canonical key name, grouped by language
+const KEY_NAME_ALIASES: &[(&str, &str)] = &[
+    // German
+    ("eingabe", "return"),
+    ("eingabetaste", "return"),
+    ("leertaste", "space"),
+    ("leerzeichen", "space"),
+    ("rücktaste", "backspace"),
+    ("ruecktaste", "backspace"),
+    ("tabulator", "tab"),
+    ("entf", "delete"),
+    ("entfernen", "delete"),
+    // French
+    ("entrée", "return"),
+    ("entree", "return"),
+    ("espace", "space"),
+    ("retour arrière", "backspace"),
+    ("retour arriere", "backspace"),
+    ("échap", "escape"),
+    ("echap", "escape"),
+    ("échappement", "escape"),
+    ("echappement", "escape"),
+    ("tabulation", "tab"),
+    ("suppr", "delete"),
+    ("supprimer", "delete"),
+    // Spanish
+    ("intro", "return"),
+    ("espacio", "space"),
+    ("retroceso", "backspace"),
+    ("tabulador", "tab"),
+    ("supr", "delete"),
+    ("suprimir", "delete"),
+    // Italian
+    ("invio", "return"),
+    ("spazio", "space"),
+    ("tabulazione", "tab"),
+    ("canc", "delete"),
+    ("cancella", "delete"),
+    // Portuguese
+    ("espaço", "space"),
+    ("espaco", "space"),
+    ("retrocesso", "backspace"),
+    ("excluir", "delete"),
+    // Dutch
+    ("invoer", "return"),
+    ("spatie", "space"),
+    ("verwijderen", "delete"),
+    // Polish
+    ("spacja", "space"),
+    ("usuń", "delete"),
+    ("usun", "delete"),
+    // Russian, in Cyrillic and transliterated
+    ("ввод", "return"),
+    ("vvod", "return"),
+    ("пробел", "space"),
+    ("probel", "space"),
+    ("забой", "backspace"),
+    ("zaboj", "backspace"),
+    ("эскейп", "escape"),
+    ("eskejp", "escape"),
+    ("табуляция", "tab"),
+    ("tabulyaciya", "tab"),
+    ("удалить", "delete"),
+    ("udalit", "delete"),
+];
+
+/// The canonical name for `name` if it is a localized alias (`name` must
+/// already be lowercase)
+pub(crate) fn canonical_key_name(name: &str) -> Option<&'static str> {
+    KEY_NAME_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, canonical)| *canonical)
+}
+
+/// Localized aliases of the canonical key name `name`
+pub(crate) fn aliases_of(name: &str) -> impl Iterator<Item = &'static str> + '_ {
+    KEY_NAME_ALIASES
+        .iter()
+        .filter(move |(_, canonical)| *canonical == name)
+        .map(|(alias, _)| *alias)
+}
+
+/// Up to three known key names (canonical ones from `known`, or localized
+/// aliases) within a small edit distance of `name`, closest first
+pub(crate) fn suggestions<'a>(name: &str, known: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let name = name.to_lowercase();
+    // Allow roughly one typo per four characters, and at least one
+    let max_distance = (name.chars().count() / 4).max(1);
+
+    let aliases = KEY_NAME_ALIASES.iter().map(|(alias, _)| *alias);
+    let mut candidates: Vec<(usize, &str)> = known
+        .chain(aliases)
+        .map(|candidate| (edit_distance(&name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect();
+    candidates.sort();
+    candidates.dedup_by(|a, b| a.1 == b.1);
+    candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+/// Edit distance between `a` and `b` in characters, counting a swap of two
+/// adjacent characters ("retrun") as a single edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // Rows i-2, i-1 and i of the distance table
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (previous[j - 1] + cost)
+                .min(previous[j] + 1)
+                .min(current[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(before[j - 2] + 1);
+            }
+            current.push(distance);
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_key_name() {
+        assert_eq!(canonical_key_name("leertaste"), Some("space"));
+        assert_eq!(canonical_key_name("ввод"), Some("return"));
+        assert_eq!(canonical_key_name("suppr"), Some("delete"));
+        assert_eq!(canonical_key_name("return"), None);
+    }
+
+    #[test]
+    fn test_aliases_are_lowercase_unique_and_resolvable() {
+        let known = crate::build_key_to_keycode_map();
+        let mut seen = std::collections::HashSet::new();
+        for (alias, canonical) in KEY_NAME_ALIASES {
+            assert_eq!(*alias, alias.to_lowercase());
+            assert!(seen.insert(alias), "duplicate alias {}", alias);
+            assert!(known.contains_key(*canonical), "unknown key {}", canonical);
+        }
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let known = ["return", "space", "tab"];
+        assert_eq!(suggestions("Spcae", known.into_iter()), vec!["space"]);
+        assert_eq!(suggestions("probek", known.into_iter()), vec!["probel"]);
+        assert!(suggestions("xyzzy", known.into_iter()).is_empty());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("пробел", "пробл"), 1);
+        assert_eq!(edit_distance("", "tab"), 3);
+        assert_eq!(edit_distance("retrun", "return"), 1);
+    }
+}
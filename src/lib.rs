@@ -19,13 +19,20 @@
 //! typer.press_key("Return").unwrap();
 //! ```
 
+mod key_names;
+
 use log::{debug, error, info, trace, warn};
 use reis::ei::{self, handshake::ContextType, keyboard::KeyState};
-use reis::event::{DeviceCapability, EiEvent};
-use std::collections::HashMap;
+use reis::event::{DeviceCapability as EiCapability, EiEvent, EiEventConverter};
+use reis::PendingRequestResult;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
-use std::sync::OnceLock;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use xkbcommon::xkb;
@@ -33,6 +40,14 @@ use xkbcommon::xkb;
 #[cfg(feature = "python")]
 use pyo3::prelude::*;
 
+#[cfg(feature = "python")]
+pyo3::create_exception!(
+    eitype,
+    TypingError,
+    pyo3::exceptions::PyRuntimeError,
+    "Typing failed partway through the input; see .index, .byte_offset and .context."
+);
+
 /// Global tokio runtime for portal connections.
 /// Using a single runtime avoids issues with zbus/DBus connection state
 /// being left in a bad state when a runtime is dropped.
@@ -59,25 +74,291 @@ pub enum EiTypeError {
     #[error("Connection error: {0}")]
     Connection(String),
 
+    /// The EI server ended the connection. `reason` is the protocol's
+    /// disconnect reason: `disconnected` when the session was closed on
+    /// purpose (e.g. the user stopped sharing), or `error`, `mode`,
+    /// `protocol`, `value` or `transport` when something went wrong.
+    /// `explanation` is the server's own message, possibly empty.
+    #[error("Disconnected by the EI server ({reason}){}", explanation_suffix(.explanation))]
+    Disconnected { reason: String, explanation: String },
+
+    /// An `EiTypeConfig` value that can never work (see `EiTypeConfig::validate`)
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    /// The user denied (or dismissed) the portal's remote control dialog
+    #[error("Remote control was not authorized")]
+    AuthorizationDenied,
+
+    /// The portal turned the request down for another reason, e.g. its
+    /// backend failed or rejected an argument. Unlike a `Connection` error,
+    /// trying again won't help, so `connect_retries` doesn't.
+    #[error("The portal refused the request: {0}")]
+    PortalRefused(String),
+
     /// Failed to load or parse keymap
     #[error("Keymap error: {0}")]
     Keymap(String),
 
     /// Unknown key name
-    #[error("Unknown key: {0}")]
+    #[error("Unknown key: {0}{hint}", hint = unknown_key_hint(.0))]
     UnknownKey(String),
 
     /// Error during typing operation
     #[error("Typing error: {0}")]
     Typing(String),
 
-    /// No keyboard device found
-    #[error("No keyboard device found")]
-    NoKeyboard,
+    /// Sending queued events to the server failed, e.g. because the socket
+    /// closed or stayed full
+    #[error("Failed to send events: {0}")]
+    Flush(String),
+
+    /// No device with this capability: the server offered no keyboard, or
+    /// the pointer or touch capability wasn't requested (see
+    /// `EiTypeConfig::pointer` and `EiTypeConfig::capabilities`)
+    #[error("No device with the {0:?} capability found")]
+    NoDevice(DeviceCapability),
+
+    /// A touch event that doesn't fit the touches in progress or the
+    /// device's regions
+    #[error("Touch error: {0}")]
+    Touch(String),
+
+    /// Held modifiers couldn't be pressed again after the server resumed the
+    /// device, so what follows would be typed without them
+    #[error("Could not re-press held modifiers after the device resumed: {0}")]
+    HeldKeysLost(String),
+
+    /// The server removed the keyboard device and offered no replacement
+    /// within `EiTypeConfig::device_failover_ms`
+    #[error("The keyboard device was removed and not replaced ({chars_typed} characters typed)")]
+    DeviceLost {
+        /// Characters of the current text typed before the device went away
+        chars_typed: usize,
+    },
+
+    /// No window matched the target of `EiType::focus_window`
+    #[error("No window matches the focus target {0}")]
+    FocusTargetNotFound(String),
+
+    /// `EiTypeConfig::refuse_if_focused_app` has keyboard focus
+    #[error("Refusing to type into the focused app {focused}")]
+    FocusGuardTriggered {
+        /// App id of the focused window
+        focused: String,
+    },
+
+    /// A capability was requested but the server did not grant it
+    #[error("The {0:?} capability was requested but not granted")]
+    CapabilityUnavailable(DeviceCapability),
 
     /// Character not found in keymap
-    #[error("Character not found in keymap: {0}")]
+    #[error("Character not found in keymap: {}", describe_char(*.0))]
     CharNotFound(char),
+
+    /// Keysym (by name) not found in keymap
+    #[error("Keysym not found in keymap: {0}")]
+    KeysymNotFound(String),
+
+    /// Failed to set the clipboard contents
+    #[error("Clipboard error: {0}")]
+    Clipboard(String),
+
+    /// Operation was cancelled via a `CancelHandle`
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    /// The server didn't confirm processing a key press made with
+    /// `press_key_confirmed` in time
+    #[error("The server did not confirm processing {key} within {timeout:?}")]
+    ConfirmationTimeout {
+        /// The key name as passed to `press_key_confirmed`
+        key: String,
+        timeout: Duration,
+    },
+
+    /// Another eitype instance held the exclusive lock for longer than
+    /// `EiTypeConfig::exclusive_timeout_ms`
+    #[error("Another eitype instance is typing")]
+    WouldConflict,
+
+    /// `ThreadedEiType::type_text_if_idle` found another call running or
+    /// queued
+    #[error("The connection is busy with another call")]
+    Busy,
+
+    /// Text for `parse_inline` with a malformed `{...}` or `\` sequence
+    #[error("Invalid inline input at byte {offset}: {message}")]
+    InlineSyntax {
+        /// Byte offset of the sequence in the input
+        offset: usize,
+        message: String,
+    },
+
+    /// Input read as bytes (a file, stdin) isn't UTF-8 text
+    #[error("Input is not valid UTF-8 at byte {offset}{hint}", hint = invalid_utf8_hint(*.truncated))]
+    InvalidUtf8 {
+        /// Byte offset of the first byte that isn't part of valid UTF-8
+        offset: usize,
+        /// Whether the input ends in the middle of a multi-byte character
+        truncated: bool,
+    },
+
+    /// An error raised while typing text, with the position where it happened.
+    ///
+    /// `type_text` and the other calls that type a string wrap their errors
+    /// in this, so match on `root()` for the underlying error: a
+    /// `CharNotFound` from `type_text` no longer matches
+    /// `Err(EiTypeError::CharNotFound(_))` directly (a breaking change; see
+    /// CHANGELOG.md).
+    #[error("{source} at character {index} (byte {byte_offset}) near {context:?}")]
+    InText {
+        /// Character index of the failing character in the input
+        index: usize,
+        /// Byte offset of the failing character in the input
+        byte_offset: usize,
+        /// Up to 15 characters either side of the failing character
+        context: String,
+        /// The underlying error
+        source: Box<EiTypeError>,
+    },
+}
+
+impl EiTypeError {
+    /// The underlying error, with any positional `InText` wrapper removed
+    pub fn root(&self) -> &EiTypeError {
+        match self {
+            EiTypeError::InText { source, .. } => source.root(),
+            other => other,
+        }
+    }
+
+    /// The capability no device had, for `NoDevice` (also inside `InText`)
+    pub fn missing_capability(&self) -> Option<DeviceCapability> {
+        match self.root() {
+            EiTypeError::NoDevice(capability) => Some(*capability),
+            _ => None,
+        }
+    }
+}
+
+/// `: explanation` for `EiTypeError::Disconnected`, or nothing if it's empty
+fn explanation_suffix(explanation: &str) -> String {
+    if explanation.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", explanation)
+    }
+}
+
+/// " (did you mean ...?)" with key names close to `name`, or nothing
+fn unknown_key_hint(name: &str) -> String {
+    // Built on first use rather than each time an error is displayed
+    static KNOWN: OnceLock<Vec<String>> = OnceLock::new();
+    let known = KNOWN.get_or_init(|| {
+        let combos = NAMED_COMBOS.iter().map(|(combo, _)| combo.to_string());
+        build_key_to_keycode_map()
+            .into_keys()
+            .chain(combos)
+            .collect()
+    });
+    let suggestions = key_names::suggestions(name, known.iter().map(String::as_str));
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!(" (did you mean {}?)", suggestions.join(", "))
+    }
+}
+
+/// `bytes` as text, for input that arrives as bytes (files, stdin), or
+/// `EiTypeError::InvalidUtf8` with the offset of the first invalid byte
+pub fn validate_input(bytes: &[u8]) -> Result<&str, EiTypeError> {
+    std::str::from_utf8(bytes).map_err(|e| EiTypeError::InvalidUtf8 {
+        offset: e.valid_up_to(),
+        truncated: e.error_len().is_none(),
+    })
+}
+
+/// Why input might not be UTF-8, for `EiTypeError::InvalidUtf8`
+fn invalid_utf8_hint(truncated: bool) -> &'static str {
+    if truncated {
+        " (it ends partway through a character)"
+    } else {
+        " (is it in another encoding, e.g. Latin-1? iconv can convert it)"
+    }
+}
+
+/// Whether `ch` ends a line: LF, CR, vertical tab, form feed, NEL (U+0085)
+/// and the Unicode line and paragraph separators
+fn is_line_break(ch: char) -> bool {
+    matches!(
+        ch,
+        '\n' | '\r' | '\u{0b}' | '\u{0c}' | '\u{85}' | '\u{2028}' | '\u{2029}'
+    )
+}
+
+/// `text` as typed with `EiTypeConfig::terminal_safe`, so that typing it into
+/// a shell can't run anything:
+///
+/// - Line breaks at the end (LF, CR, vertical tab, form feed, NEL, U+2028
+///   and U+2029, in any number) are dropped, so the last line isn't submitted.
+/// - Line breaks inside the text become one space each (CR LF counts as
+///   one), so lines can't run one by one.
+/// - Tab is kept: it can complete a command, but not run it.
+/// - Every other control character (U+0000 to U+001F, DEL and U+0080 to
+///   U+009F) is left out, so escape sequences and Ctrl-key characters never
+///   reach the terminal.
+///
+/// Text with none of these comes back borrowed.
+pub fn terminal_safe_text(text: &str) -> std::borrow::Cow<'_, str> {
+    let body = text.trim_end_matches(is_line_break);
+    let unsafe_char = |ch: char| ch != '\t' && (ch.is_control() || is_line_break(ch));
+    if body.len() == text.len() && !body.contains(unsafe_char) {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    std::borrow::Cow::Owned(terminal_safe_chars(text).flatten().collect())
+}
+
+/// What `terminal_safe_text` makes of each character of `text`: itself, a
+/// space for a line break, or nothing
+fn terminal_safe_chars(text: &str) -> impl Iterator<Item = Option<char>> + '_ {
+    let body_end = text.trim_end_matches(is_line_break).len();
+    let mut chars = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        let (offset, ch) = chars.next()?;
+        let crlf = ch == '\r' && matches!(chars.peek(), Some((_, '\n')));
+        Some(if offset >= body_end || crlf {
+            None
+        } else if is_line_break(ch) {
+            Some(' ')
+        } else if ch != '\t' && ch.is_control() {
+            None
+        } else {
+            Some(ch)
+        })
+    })
+}
+
+/// Number of characters of input shown either side of a typing error
+const ERROR_CONTEXT_CHARS: usize = 15;
+
+/// Wrap `error` with the position of character `index` (at `byte_offset`) in `text`.
+///
+/// Only called on the error path, so the snippet is built lazily. The snippet
+/// is cut on character boundaries, so multi-byte characters are never split.
+fn error_in_text(error: EiTypeError, text: &str, index: usize, byte_offset: usize) -> EiTypeError {
+    let start = index.saturating_sub(ERROR_CONTEXT_CHARS);
+    let context = text
+        .chars()
+        .skip(start)
+        .take(index - start + 1 + ERROR_CONTEXT_CHARS)
+        .collect();
+    EiTypeError::InText {
+        index,
+        byte_offset,
+        context,
+        source: Box::new(error),
+    }
 }
 
 // ============================================================================
@@ -85,8 +366,8 @@ pub enum EiTypeError {
 // ============================================================================
 
 /// Configuration for keyboard layout and typing behavior
-#[derive(Debug, Clone, Default)]
-#[cfg_attr(feature = "python", pyclass)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "python", pyclass(get_all, eq))]
 pub struct EiTypeConfig {
     /// XKB keyboard layout (e.g., "us", "de", "fr")
     pub layout: Option<String>,
@@ -99,15 +380,318 @@ pub struct EiTypeConfig {
     /// Layout index to use when multiple layouts are available.
     /// `None` = auto-detect from EI protocol, `Some(n)` = explicit override.
     pub layout_index: Option<u32>,
-    /// Delay between key events in milliseconds (default: 0)
+    /// Delay after every key press and release, modifiers included, in
+    /// milliseconds (default: 0)
     pub delay_ms: u64,
+    /// Full XKB keymap in text format. Takes precedence over both the
+    /// RMLVO names above and the keymap provided by the EI server.
+    pub keymap_string: Option<String>,
+    /// Path to an XKB keymap file in text format. Takes precedence over the
+    /// RMLVO names and the server keymap, but not over `keymap_string`.
+    pub keymap_file: Option<PathBuf>,
+    /// Split `type_text` into chunks with a pause between them (default: no chunking)
+    pub chunk: Option<ChunkPolicy>,
+    /// `insert_text` pastes text longer than this many characters via the
+    /// clipboard instead of typing it (default: always type)
+    pub paste_threshold: Option<usize>,
+    /// Stop emulating after this many milliseconds without input, and start
+    /// again on the next input (default: emulate for the whole session).
+    /// There is no timer: the timeout is only checked when
+    /// `EiType::check_idle` is called, or by the next input, which then
+    /// starts a new session at once. Call `check_idle` periodically for the
+    /// device to stop while nothing is being typed.
+    pub idle_stop_emulating_ms: Option<u64>,
+    /// Stop emulating at the end of every `type_text` and start again on the
+    /// next input, so a long-lived session doesn't look like it holds an
+    /// input grab between calls, at the cost of a little latency
+    /// (default: emulate for the whole session)
+    pub stop_emulating_after_each: bool,
+    /// Also request a pointer device, needed for middle-click paste (default: false)
+    pub pointer: bool,
+    /// Neither read nor update the layout index cache (default: false).
+    /// See `EiType::set_layout_index`.
+    pub ignore_layout_cache: bool,
+    /// Hold a lock shared by all eitype processes while sending input, so
+    /// two instances never interleave keystrokes (default: false)
+    pub exclusive: bool,
+    /// How long to wait for the exclusive lock before failing with
+    /// `WouldConflict`; `Some(0)` = don't wait (default: wait indefinitely)
+    pub exclusive_timeout_ms: Option<u64>,
+    /// Clock used for frame timestamps (default: `ProcessRelative`)
+    pub timestamp_source: TimestampSource,
+    /// Slow down when the server falls behind: pause briefly between key
+    /// events while flushes keep hitting a full socket (or take over 5 ms),
+    /// and speed back up once they go through again (default: false). Never
+    /// pauses otherwise. Meant for compositors known to drop input they
+    /// can't keep up with; elsewhere a full socket only delays typing.
+    pub adaptive_throttle: bool,
+    /// Refuse to type text longer than this many characters, before sending
+    /// anything (default: unlimited)
+    pub max_text_len: Option<usize>,
+    /// Use the keymap's own keycode for special key names ("escape",
+    /// "return", ...) whose built-in keycode doesn't produce that key in the
+    /// keymap, e.g. with `caps:swapescape` (default: true). When no key
+    /// produces it, Return, Tab and space fall back to a stand-in such as
+    /// KP_Enter. Mismatches are logged either way; see
+    /// `EiType::key_table_report`.
+    pub prefer_keymap_keys: bool,
+    /// Device capabilities to bind on the seat (default: keyboard, plus
+    /// pointer, button and scroll when `pointer` is set). The keyboard is
+    /// always bound, since typing needs it.
+    pub capabilities: Option<Vec<DeviceCapability>>,
+    /// Record how long each typed character took (default: false). See
+    /// `EiType::timings`.
+    pub collect_timing: bool,
+    /// Without `layout_index`, ask the desktop for the active layout
+    /// (`gsettings` on GNOME, `qdbus` on KDE, `swaymsg` on Sway) when neither
+    /// the layout cache nor the EI server has it (default: true). Disable to
+    /// avoid spawning those tools, e.g. in sandboxes; index 0 is used instead.
+    pub auto_detect_layout: bool,
+    /// Reuse a layout index detected that way by an earlier connection in
+    /// this process for this many milliseconds, so frequent reconnects don't
+    /// spawn the tools each time; 0 = always ask (default: 5000). See
+    /// `clear_detected_layout_cache`.
+    pub layout_detection_ttl_ms: u64,
+    /// Before typing or pasting text, fail with `FocusGuardTriggered` if the
+    /// window with keyboard focus belongs to this app id (compared
+    /// case-insensitively), e.g. the dictation app's own. Only enforceable
+    /// where the compositor can be asked (`swaymsg` on Sway, `hyprctl` on
+    /// Hyprland); elsewhere text is typed anyway (default: no check).
+    pub refuse_if_focused_app: Option<String>,
+    /// Pause this many milliseconds after `EiType::focus_window` focuses a
+    /// window, for the compositor to move keyboard focus before typing
+    /// starts (default: 200).
+    pub focus_settle_ms: u64,
+    /// Fail with a `Typing` error on a press of a key that is already down,
+    /// or a release of one that isn't, instead of skipping it with a debug
+    /// log (default: false). Meant for catching bugs in calling code, e.g.
+    /// typing an uppercase letter while `hold_modifier("shift")` is active.
+    pub strict_key_state: bool,
+    /// Pause this many milliseconds after typing whitespace, so apps whose
+    /// autocorrect acts on each finished word have settled before the next
+    /// word arrives (default: no pause). Adds to `delay_ms`, which applies
+    /// to every key.
+    pub settle_after_word_ms: Option<u64>,
+    /// Which key to press for AltGr (level 3) characters and the "altgr"
+    /// key name (default: `RightAlt`). Try `Level3Keysym` where right Alt
+    /// doesn't register as AltGr, e.g. in some virtual machines and remote
+    /// sessions.
+    pub altgr_mode: AltGrMode,
+    /// Whether Shift or AltGr goes down first (and comes up last) for
+    /// characters that need both (default: `ShiftFirst`). Try
+    /// `Level3First` for the rare app that mistypes them.
+    pub modifier_order: ModifierOrder,
+    /// Pause this many milliseconds between a modifier pressed to reach a
+    /// character's level (Shift, AltGr, level 5) and the character's key,
+    /// and again between that key's release and the modifier's, for
+    /// receivers (e.g. VNC bridges) that otherwise see the key unmodified
+    /// (default: 0). Adds to `delay_ms` there and nowhere else.
+    pub modifier_settle_ms: u64,
+    /// Type a character no key produces as an ASCII approximation instead
+    /// (é as e, “ as ", – as -), as a last resort for fields that only take
+    /// ASCII (default: false). Needs the `transliterate` feature.
+    pub ascii_transliterate: bool,
+    /// Leave invisible format characters no keymap types out of typed text
+    /// (zero width spaces, word joiners, soft hyphens, byte order marks and
+    /// direction marks), instead of failing on them; the count is in
+    /// `TypingStats::format_chars_stripped` (default: true)
+    pub strip_format_chars: bool,
+    /// Type `\n` as a linefeed character (the key with the Linefeed keysym)
+    /// instead of pressing Return, e.g. for a terminal or raw buffer that
+    /// tells them apart. Tab always sends the Tab keysym, which is the tab
+    /// character (default: false).
+    pub literal_whitespace: bool,
+    /// How long to wait for a replacement when the server removes the
+    /// keyboard device mid-text (some compositors re-create it, e.g. on
+    /// monitor hotplug), before failing with `EiTypeError::DeviceLost`
+    /// (default: 2000; 0 fails at once)
+    pub device_failover_ms: u64,
+    /// How many more times to run the whole portal connection when it fails
+    /// in a way that may be transient (D-Bus timing, xdg-desktop-portal
+    /// restarting), e.g. at boot (default: 0). The user or the portal
+    /// refusing is final.
+    pub connect_retries: u32,
+    /// How long to wait before each retry of `connect_retries` (default: 1000)
+    pub connect_retry_delay_ms: u64,
+    /// Release and press again modifiers held with `hold_modifier` (or a
+    /// `with_modifier` scope) once they have been down this long, at the
+    /// next character boundary, for receivers that synthesize key repeat
+    /// for them (default: never)
+    pub max_modifier_hold_ms: Option<u64>,
+    /// Filter text for typing into a terminal, so it can't run a command by
+    /// accident (default: false): control characters are left out, line
+    /// breaks inside the text become spaces, and line breaks at its end
+    /// aren't typed. Press Return explicitly (`type_line`, or
+    /// `press_key("return")`) to submit. See `terminal_safe_text`.
+    pub terminal_safe: bool,
+    /// Keep the portal restore token in the `TokenStore` for this namespace:
+    /// connecting through the portal without a token (and without
+    /// `EITYPE_RESTORE_TOKEN`) uses the saved one, and each token the portal
+    /// issues is saved there. Letters, digits, `.`, `_` and `-` only
+    /// (default: unset, the caller keeps the token)
+    pub token_namespace: Option<String>,
+}
+
+impl Default for EiTypeConfig {
+    fn default() -> Self {
+        Self {
+            layout: None,
+            variant: None,
+            model: None,
+            options: None,
+            layout_index: None,
+            delay_ms: 0,
+            keymap_string: None,
+            keymap_file: None,
+            chunk: None,
+            paste_threshold: None,
+            idle_stop_emulating_ms: None,
+            stop_emulating_after_each: false,
+            pointer: false,
+            ignore_layout_cache: false,
+            exclusive: false,
+            exclusive_timeout_ms: None,
+            timestamp_source: TimestampSource::ProcessRelative,
+            adaptive_throttle: false,
+            max_text_len: None,
+            prefer_keymap_keys: true,
+            capabilities: None,
+            collect_timing: false,
+            auto_detect_layout: true,
+            layout_detection_ttl_ms: 5000,
+            refuse_if_focused_app: None,
+            focus_settle_ms: 200,
+            strict_key_state: false,
+            settle_after_word_ms: None,
+            altgr_mode: AltGrMode::RightAlt,
+            modifier_order: ModifierOrder::ShiftFirst,
+            modifier_settle_ms: 0,
+            ascii_transliterate: false,
+            strip_format_chars: true,
+            literal_whitespace: false,
+            device_failover_ms: 2000,
+            connect_retries: 0,
+            connect_retry_delay_ms: 1000,
+            max_modifier_hold_ms: None,
+            terminal_safe: false,
+            token_namespace: None,
+        }
+    }
+}
+
+/// `EiTypeConfig` fields as Python sees them, in declaration order
+#[cfg(feature = "python")]
+const PY_CONFIG_FIELDS: &[&str] = &[
+    "layout",
+    "variant",
+    "model",
+    "options",
+    "layout_index",
+    "delay_ms",
+    "keymap_string",
+    "keymap_file",
+    "chunk",
+    "paste_threshold",
+    "idle_stop_emulating_ms",
+    "stop_emulating_after_each",
+    "pointer",
+    "ignore_layout_cache",
+    "exclusive",
+    "exclusive_timeout_ms",
+    "timestamp_source",
+    "adaptive_throttle",
+    "max_text_len",
+    "prefer_keymap_keys",
+    "capabilities",
+    "collect_timing",
+    "auto_detect_layout",
+    "layout_detection_ttl_ms",
+    "refuse_if_focused_app",
+    "focus_settle_ms",
+    "strict_key_state",
+    "settle_after_word_ms",
+    "altgr_mode",
+    "modifier_order",
+    "modifier_settle_ms",
+    "ascii_transliterate",
+    "strip_format_chars",
+    "literal_whitespace",
+    "device_failover_ms",
+    "connect_retries",
+    "connect_retry_delay_ms",
+    "max_modifier_hold_ms",
+    "terminal_safe",
+    "token_namespace",
+];
+
+#[cfg(feature = "python")]
+impl EiTypeConfig {
+    /// Set field `name` from a Python value, without validating
+    fn set_py_field(&mut self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        match name {
+            "layout" => self.layout = value.extract()?,
+            "variant" => self.variant = value.extract()?,
+            "model" => self.model = value.extract()?,
+            "options" => self.options = value.extract()?,
+            "layout_index" => self.layout_index = value.extract()?,
+            "delay_ms" => self.delay_ms = value.extract()?,
+            "keymap_string" => self.keymap_string = value.extract()?,
+            "keymap_file" => self.keymap_file = value.extract()?,
+            "chunk" => self.chunk = value.extract()?,
+            "paste_threshold" => self.paste_threshold = value.extract()?,
+            "idle_stop_emulating_ms" => self.idle_stop_emulating_ms = value.extract()?,
+            "stop_emulating_after_each" => self.stop_emulating_after_each = value.extract()?,
+            "pointer" => self.pointer = value.extract()?,
+            "ignore_layout_cache" => self.ignore_layout_cache = value.extract()?,
+            "exclusive" => self.exclusive = value.extract()?,
+            "exclusive_timeout_ms" => self.exclusive_timeout_ms = value.extract()?,
+            "timestamp_source" => self.timestamp_source = value.extract()?,
+            "adaptive_throttle" => self.adaptive_throttle = value.extract()?,
+            "max_text_len" => self.max_text_len = value.extract()?,
+            "prefer_keymap_keys" => self.prefer_keymap_keys = value.extract()?,
+            "capabilities" => self.capabilities = value.extract()?,
+            "collect_timing" => self.collect_timing = value.extract()?,
+            "auto_detect_layout" => self.auto_detect_layout = value.extract()?,
+            "layout_detection_ttl_ms" => self.layout_detection_ttl_ms = value.extract()?,
+            "refuse_if_focused_app" => self.refuse_if_focused_app = value.extract()?,
+            "focus_settle_ms" => self.focus_settle_ms = value.extract()?,
+            "strict_key_state" => self.strict_key_state = value.extract()?,
+            "settle_after_word_ms" => self.settle_after_word_ms = value.extract()?,
+            "altgr_mode" => self.altgr_mode = value.extract()?,
+            "modifier_order" => self.modifier_order = value.extract()?,
+            "modifier_settle_ms" => self.modifier_settle_ms = value.extract()?,
+            "ascii_transliterate" => self.ascii_transliterate = value.extract()?,
+            "strip_format_chars" => self.strip_format_chars = value.extract()?,
+            "literal_whitespace" => self.literal_whitespace = value.extract()?,
+            "device_failover_ms" => self.device_failover_ms = value.extract()?,
+            "connect_retries" => self.connect_retries = value.extract()?,
+            "connect_retry_delay_ms" => self.connect_retry_delay_ms = value.extract()?,
+            "max_modifier_hold_ms" => self.max_modifier_hold_ms = value.extract()?,
+            "terminal_safe" => self.terminal_safe = value.extract()?,
+            "token_namespace" => self.token_namespace = value.extract()?,
+            _ => {
+                return Err(pyo3::exceptions::PyAttributeError::new_err(format!(
+                    "EiTypeConfig has no field {:?}",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// `validate`, with errors as Python `ValueError`s
+    fn py_validate(&self) -> PyResult<()> {
+        self.validate()
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+    }
 }
 
 #[cfg(feature = "python")]
 #[pymethods]
 impl EiTypeConfig {
     #[new]
-    #[pyo3(signature = (layout=None, variant=None, model=None, options=None, layout_index=None, delay_ms=0))]
+    #[pyo3(signature = (layout=None, variant=None, model=None, options=None, layout_index=None, delay_ms=0, keymap_string=None, keymap_file=None, paste_threshold=None, idle_stop_emulating_ms=None, pointer=false, ignore_layout_cache=false, exclusive=false, exclusive_timeout_ms=None, timestamp_source=TimestampSource::ProcessRelative, adaptive_throttle=false, max_text_len=None, prefer_keymap_keys=true, capabilities=None, collect_timing=false, auto_detect_layout=true, layout_detection_ttl_ms=5000, refuse_if_focused_app=None, strict_key_state=false, chunk=None, settle_after_word_ms=None, altgr_mode=AltGrMode::RightAlt, modifier_order=ModifierOrder::ShiftFirst, modifier_settle_ms=0, ascii_transliterate=false, focus_settle_ms=200, strip_format_chars=true, literal_whitespace=false, device_failover_ms=2000, connect_retries=0, connect_retry_delay_ms=1000, max_modifier_hold_ms=None, terminal_safe=false, token_namespace=None, stop_emulating_after_each=false))]
+    #[allow(clippy::too_many_arguments)]
     fn py_new(
         layout: Option<String>,
         variant: Option<String>,
@@ -115,31 +699,268 @@ impl EiTypeConfig {
         options: Option<String>,
         layout_index: Option<u32>,
         delay_ms: u64,
-    ) -> Self {
-        Self {
+        keymap_string: Option<String>,
+        keymap_file: Option<PathBuf>,
+        paste_threshold: Option<usize>,
+        idle_stop_emulating_ms: Option<u64>,
+        pointer: bool,
+        ignore_layout_cache: bool,
+        exclusive: bool,
+        exclusive_timeout_ms: Option<u64>,
+        timestamp_source: TimestampSource,
+        adaptive_throttle: bool,
+        max_text_len: Option<usize>,
+        prefer_keymap_keys: bool,
+        capabilities: Option<Vec<DeviceCapability>>,
+        collect_timing: bool,
+        auto_detect_layout: bool,
+        layout_detection_ttl_ms: u64,
+        refuse_if_focused_app: Option<String>,
+        strict_key_state: bool,
+        chunk: Option<ChunkPolicy>,
+        settle_after_word_ms: Option<u64>,
+        altgr_mode: AltGrMode,
+        modifier_order: ModifierOrder,
+        modifier_settle_ms: u64,
+        ascii_transliterate: bool,
+        focus_settle_ms: u64,
+        strip_format_chars: bool,
+        literal_whitespace: bool,
+        device_failover_ms: u64,
+        connect_retries: u32,
+        connect_retry_delay_ms: u64,
+        max_modifier_hold_ms: Option<u64>,
+        terminal_safe: bool,
+        token_namespace: Option<String>,
+        stop_emulating_after_each: bool,
+    ) -> PyResult<Self> {
+        let config = Self {
             layout,
             variant,
             model,
             options,
             layout_index,
             delay_ms,
+            keymap_string,
+            keymap_file,
+            chunk,
+            paste_threshold,
+            idle_stop_emulating_ms,
+            stop_emulating_after_each,
+            pointer,
+            ignore_layout_cache,
+            exclusive,
+            exclusive_timeout_ms,
+            timestamp_source,
+            adaptive_throttle,
+            max_text_len,
+            prefer_keymap_keys,
+            capabilities,
+            collect_timing,
+            auto_detect_layout,
+            layout_detection_ttl_ms,
+            refuse_if_focused_app,
+            focus_settle_ms,
+            strict_key_state,
+            settle_after_word_ms,
+            altgr_mode,
+            modifier_order,
+            modifier_settle_ms,
+            ascii_transliterate,
+            strip_format_chars,
+            literal_whitespace,
+            device_failover_ms,
+            connect_retries,
+            connect_retry_delay_ms,
+            max_modifier_hold_ms,
+            terminal_safe,
+            token_namespace,
+        };
+        config.py_validate()?;
+        Ok(config)
+    }
+
+    /// Set a field, refusing values `validate` rejects (with ValueError)
+    fn __setattr__(&mut self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let mut config = self.clone();
+        config.set_py_field(name, value)?;
+        config.py_validate()?;
+        *self = config;
+        Ok(())
+    }
+
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let fields = PY_CONFIG_FIELDS
+            .iter()
+            .map(|name| Ok(format!("{}={}", name, slf.getattr(*name)?.repr()?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(format!("EiTypeConfig({})", fields.join(", ")))
+    }
+
+    /// Every field as a dict, which from_dict turns back into an equal config
+    fn to_dict<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, pyo3::types::PyDict>> {
+        let dict = pyo3::types::PyDict::new(slf.py());
+        for name in PY_CONFIG_FIELDS {
+            dict.set_item(*name, slf.getattr(*name)?)?;
+        }
+        Ok(dict)
+    }
+
+    /// A config with the fields in the dict, and defaults for the rest
+    #[classmethod]
+    fn from_dict(
+        _cls: &Bound<'_, pyo3::types::PyType>,
+        fields: &Bound<'_, pyo3::types::PyDict>,
+    ) -> PyResult<Self> {
+        let mut config = Self::default();
+        for (name, value) in fields.iter() {
+            config.set_py_field(&name.extract::<String>()?, &value)?;
         }
+        config.py_validate()?;
+        Ok(config)
     }
 }
 
 impl EiTypeConfig {
-    /// Create config from environment variables
+    /// Create config from environment variables (unset or empty ones are
+    /// left unset)
     pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
         Self {
-            layout: std::env::var("XKB_DEFAULT_LAYOUT").ok(),
-            variant: std::env::var("XKB_DEFAULT_VARIANT").ok(),
-            model: std::env::var("XKB_DEFAULT_MODEL").ok(),
-            options: std::env::var("XKB_DEFAULT_OPTIONS").ok(),
+            layout: var("XKB_DEFAULT_LAYOUT"),
+            variant: var("XKB_DEFAULT_VARIANT"),
+            model: var("XKB_DEFAULT_MODEL"),
+            options: var("XKB_DEFAULT_OPTIONS"),
             layout_index: None,
             delay_ms: 0,
+            keymap_string: None,
+            keymap_file: None,
+            chunk: None,
+            paste_threshold: None,
+            idle_stop_emulating_ms: None,
+            stop_emulating_after_each: false,
+            pointer: false,
+            ignore_layout_cache: false,
+            exclusive: false,
+            exclusive_timeout_ms: None,
+            timestamp_source: TimestampSource::ProcessRelative,
+            adaptive_throttle: false,
+            max_text_len: None,
+            prefer_keymap_keys: true,
+            capabilities: None,
+            collect_timing: false,
+            auto_detect_layout: true,
+            layout_detection_ttl_ms: 5000,
+            refuse_if_focused_app: None,
+            focus_settle_ms: 200,
+            strict_key_state: false,
+            settle_after_word_ms: None,
+            altgr_mode: AltGrMode::RightAlt,
+            modifier_order: ModifierOrder::ShiftFirst,
+            modifier_settle_ms: 0,
+            ascii_transliterate: false,
+            strip_format_chars: true,
+            literal_whitespace: false,
+            device_failover_ms: 2000,
+            connect_retries: 0,
+            connect_retry_delay_ms: 1000,
+            max_modifier_hold_ms: None,
+            terminal_safe: false,
+            token_namespace: None,
         }
     }
 
+    /// Check for values that can never work: empty strings and paths (leave
+    /// them unset for the default instead), a `layout_index` past the
+    /// layouts listed in `layout`, and a `token_namespace` that can't be part
+    /// of a file name.
+    ///
+    /// The `EiType` constructors check this before connecting, and the
+    /// Python bindings on every change.
+    pub fn validate(&self) -> Result<(), EiTypeError> {
+        let strings = [
+            ("layout", &self.layout),
+            ("variant", &self.variant),
+            ("model", &self.model),
+            ("options", &self.options),
+            ("keymap_string", &self.keymap_string),
+            ("refuse_if_focused_app", &self.refuse_if_focused_app),
+            ("token_namespace", &self.token_namespace),
+        ];
+        for (name, value) in strings {
+            if value.as_deref().is_some_and(|v| v.trim().is_empty()) {
+                return Err(EiTypeError::InvalidConfig(format!(
+                    "{} is empty; leave it unset for the default",
+                    name
+                )));
+            }
+        }
+        if self
+            .keymap_file
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            return Err(EiTypeError::InvalidConfig(
+                "keymap_file is empty; leave it unset for the default".to_string(),
+            ));
+        }
+        if let (Some(layout), Some(index)) = (&self.layout, self.layout_index) {
+            let layouts = layout.split(',').count();
+            if index as usize >= layouts {
+                return Err(EiTypeError::InvalidConfig(format!(
+                    "layout_index {} is out of range for the {} layouts in {:?}",
+                    index, layouts, layout
+                )));
+            }
+        }
+        if let Some(namespace) = &self.token_namespace {
+            validate_token_namespace(namespace)?;
+        }
+        if self.ascii_transliterate && !cfg!(feature = "transliterate") {
+            return Err(EiTypeError::InvalidConfig(
+                "ascii_transliterate needs eitype built with the transliterate feature".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Capabilities to bind: `capabilities`, or the default it describes,
+    /// always starting with the keyboard
+    pub fn requested_capabilities(&self) -> Vec<DeviceCapability> {
+        let extra = match &self.capabilities {
+            Some(capabilities) => capabilities.clone(),
+            None if self.pointer => vec![
+                DeviceCapability::Pointer,
+                DeviceCapability::Button,
+                DeviceCapability::Scroll,
+            ],
+            None => Vec::new(),
+        };
+        let mut requested = vec![DeviceCapability::Keyboard];
+        for capability in extra {
+            if !requested.contains(&capability) {
+                requested.push(capability);
+            }
+        }
+        requested
+    }
+
+    /// The `TokenStore` for `token_namespace`, if it is set
+    pub fn token_store(&self) -> Result<Option<TokenStore>, EiTypeError> {
+        self.token_namespace
+            .as_deref()
+            .map(TokenStore::open)
+            .transpose()
+    }
+
+    /// Compile the keymap this configuration describes, without connecting.
+    ///
+    /// This is the keymap eitype falls back to when the server sends none,
+    /// e.g. for use with `convert_layout`.
+    pub fn compile_keymap(&self) -> Result<xkb::Keymap, EiTypeError> {
+        load_keymap(self, None).map(|(keymap, _)| keymap)
+    }
+
     /// Check if any XKB configuration is specified
     fn is_specified(&self) -> bool {
         self.layout.is_some()
@@ -149,1765 +970,15742 @@ impl EiTypeConfig {
     }
 }
 
-/// Actions that can be performed
-#[derive(Debug, Clone)]
-pub enum Action {
-    /// Type a string of text
-    Type(String),
-    /// Press and release a special key (e.g., "Return", "Tab")
-    Key(String),
-    /// Hold a modifier key (will be released at the end)
-    ModifierHold(String),
-    /// Press and release a modifier key
-    ModifierPress(String),
+/// How `type_text` splits long input into chunks.
+///
+/// Some compositors (and some receiving applications) drop or reorder input when
+/// thousands of events arrive in one burst, even though the socket accepts them.
+/// Between chunks eitype flushes fully, drains incoming events, and pauses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all, eq))]
+pub struct ChunkPolicy {
+    /// Number of characters per chunk (0 disables chunking)
+    pub chars_per_chunk: usize,
+    /// Pause between consecutive chunks
+    pub pause_between: Duration,
 }
 
-// ============================================================================
-// Internal Utilities
-// ============================================================================
-
-/// Build a map of key names to keycodes (evdev codes)
-fn build_key_to_keycode_map() -> HashMap<String, u32> {
-    let mut map = HashMap::new();
-
-    // Modifiers
-    map.insert("shift".to_string(), 42);
-    map.insert("lshift".to_string(), 42);
-    map.insert("rshift".to_string(), 54);
-    map.insert("ctrl".to_string(), 29);
-    map.insert("control".to_string(), 29);
-    map.insert("lctrl".to_string(), 29);
-    map.insert("rctrl".to_string(), 97);
-    map.insert("alt".to_string(), 56);
-    map.insert("lalt".to_string(), 56);
-    map.insert("ralt".to_string(), 100);
-    map.insert("altgr".to_string(), 100);
-    map.insert("super".to_string(), 125);
-    map.insert("meta".to_string(), 125);
-    map.insert("win".to_string(), 125);
-    map.insert("lsuper".to_string(), 125);
-    map.insert("rsuper".to_string(), 126);
+#[cfg(feature = "python")]
+#[pymethods]
+impl ChunkPolicy {
+    #[new]
+    fn py_new(chars_per_chunk: usize, pause_between: Duration) -> Self {
+        Self {
+            chars_per_chunk,
+            pause_between,
+        }
+    }
 
-    // Special keys
-    map.insert("escape".to_string(), 1);
-    map.insert("esc".to_string(), 1);
-    map.insert("return".to_string(), 28);
-    map.insert("enter".to_string(), 28);
-    map.insert("tab".to_string(), 15);
-    map.insert("backspace".to_string(), 14);
-    map.insert("delete".to_string(), 111);
-    map.insert("insert".to_string(), 110);
-    map.insert("home".to_string(), 102);
-    map.insert("end".to_string(), 107);
-    map.insert("pageup".to_string(), 104);
-    map.insert("pagedown".to_string(), 109);
-    map.insert("space".to_string(), 57);
-    map.insert("capslock".to_string(), 58);
-    map.insert("numlock".to_string(), 69);
-    map.insert("scrolllock".to_string(), 70);
-    map.insert("print".to_string(), 99);
-    map.insert("printscreen".to_string(), 99);
-    map.insert("pause".to_string(), 119);
-    map.insert("menu".to_string(), 127);
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        Ok(format!(
+            "ChunkPolicy(chars_per_chunk={}, pause_between={})",
+            slf.borrow().chars_per_chunk,
+            slf.getattr("pause_between")?.repr()?
+        ))
+    }
+}
 
-    // Arrow keys
-    map.insert("up".to_string(), 103);
-    map.insert("down".to_string(), 108);
-    map.insert("left".to_string(), 105);
-    map.insert("right".to_string(), 106);
+/// Clock that frame timestamps are taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+pub enum TimestampSource {
+    /// Microseconds since eitype first sent an event in this process
+    #[default]
+    ProcessRelative,
+    /// Microseconds of `CLOCK_MONOTONIC`, the clock EI servers use for their
+    /// own timestamps. Strict compositors may drop events with timestamps
+    /// that don't match their clock.
+    ClockMonotonic,
+}
 
-    // Function keys
-    for i in 1..=12 {
-        map.insert(format!("f{}", i), 58 + i);
+impl TimestampSource {
+    /// Current time from this clock, in microseconds
+    fn now(self) -> u64 {
+        match self {
+            TimestampSource::ProcessRelative => get_timestamp(),
+            TimestampSource::ClockMonotonic => monotonic_timestamp(),
+        }
     }
+}
 
-    // Number keys (top row)
-    map.insert("1".to_string(), 2);
-    map.insert("2".to_string(), 3);
-    map.insert("3".to_string(), 4);
-    map.insert("4".to_string(), 5);
-    map.insert("5".to_string(), 6);
-    map.insert("6".to_string(), 7);
-    map.insert("7".to_string(), 8);
-    map.insert("8".to_string(), 9);
-    map.insert("9".to_string(), 10);
-    map.insert("0".to_string(), 11);
+/// Where `EiType` takes frame timestamps from
+enum Clock {
+    /// The configured `TimestampSource`, with real sleeps
+    Real(TimestampSource),
+    /// A clock (in microseconds) that only moves when `cancellable_sleep`
+    /// sleeps on it, which then returns at once
+    #[cfg(test)]
+    Fake(Cell<u64>),
+}
 
-    // Letter keys
-    let letters = "abcdefghijklmnopqrstuvwxyz";
-    let letter_codes = [
-        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
-        45, 21, 44,
-    ];
-    for (ch, code) in letters.chars().zip(letter_codes.iter()) {
-        map.insert(ch.to_string(), *code);
+impl Clock {
+    /// Current time, in microseconds
+    fn now(&self) -> u64 {
+        match self {
+            Clock::Real(source) => source.now(),
+            #[cfg(test)]
+            Clock::Fake(now) => now.get(),
+        }
     }
 
-    map
+    /// Sleep for `duration`; a fake clock moves forward by it instead
+    fn sleep(&self, duration: Duration) {
+        match self {
+            Clock::Real(_) => std::thread::sleep(duration),
+            #[cfg(test)]
+            Clock::Fake(now) => now.set(now.get() + duration.as_micros() as u64),
+        }
+    }
 }
 
-/// Result of locating a character in the keymap.
-///
-/// Encodes everything `type_char` needs: which physical key to tap (`evdev_keycode`),
-/// and where in the keymap the keysym lives (`layout` + `level`) so we can ask
-/// libxkbcommon which modifier keys to hold.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct KeyMatch {
-    evdev_keycode: u32,
-    layout: u32,
-    level: u32,
+impl std::str::FromStr for TimestampSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "process-relative" => Ok(TimestampSource::ProcessRelative),
+            "monotonic" => Ok(TimestampSource::ClockMonotonic),
+            _ => Err(format!(
+                "unknown timestamp source {:?} (expected process-relative or monotonic)",
+                s
+            )),
+        }
+    }
 }
 
-/// Find the keycode for a character, plus the layout/level it was found at.
-///
-/// Uses a two-pass approach:
-/// 1. First pass: search only keys that explicitly define the requested layout (exact match)
-/// 2. Second pass: fall back to layout 0 for keys that don't define the requested layout
-///    (layout-independent keys like space, enter, tab)
-///
-/// This ensures keys at the requested layout take priority, while layout-independent
-/// keys are still found via fallback.
-fn find_keycode_for_char(
-    ch: char,
-    keymap: &xkb::Keymap,
-    layout_index: u32,
-) -> Result<KeyMatch, EiTypeError> {
-    let min_keycode: u32 = keymap.min_keycode().into();
-    let max_keycode: u32 = keymap.max_keycode().into();
+/// Which key `EiTypeConfig::altgr_mode` presses for AltGr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+pub enum AltGrMode {
+    /// The key the keymap's modifier map binds to level 3 (Mod5): right Alt
+    /// on most layouts
+    #[default]
+    RightAlt,
+    /// The first key whose base keysym is ISO_Level3_Shift, for sessions
+    /// where injected right Alt presses don't act as AltGr. On evdev keymaps
+    /// this is usually the LVL3 key (evdev 84), which has no physical
+    /// counterpart to intercept.
+    Level3Keysym,
+}
 
-    // Pass 1: search keys that have the requested layout
-    for keycode_raw in min_keycode..=max_keycode {
-        let keycode = xkb::Keycode::new(keycode_raw);
-        let num_layouts = keymap.num_layouts_for_key(keycode);
+impl std::str::FromStr for AltGrMode {
+    type Err = String;
 
-        if layout_index < num_layouts {
-            if let Some(result) =
-                search_key_for_char(ch, keymap, keycode, keycode_raw, layout_index)
-            {
-                return Ok(result);
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "right-alt" => Ok(AltGrMode::RightAlt),
+            "level3-keysym" => Ok(AltGrMode::Level3Keysym),
+            _ => Err(format!(
+                "unknown AltGr mode {:?} (expected right-alt or level3-keysym)",
+                s
+            )),
         }
     }
+}
 
-    // Pass 2: fall back to layout 0 for keys that don't define the requested layout
-    if layout_index != 0 {
-        for keycode_raw in min_keycode..=max_keycode {
-            let keycode = xkb::Keycode::new(keycode_raw);
-            let num_layouts = keymap.num_layouts_for_key(keycode);
+/// Which modifier `EiTypeConfig::modifier_order` presses first for
+/// characters that need both Shift and AltGr (level 4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+pub enum ModifierOrder {
+    /// Shift down first and up last, around AltGr
+    #[default]
+    ShiftFirst,
+    /// AltGr down first and up last, around Shift
+    Level3First,
+}
 
-            if layout_index >= num_layouts && num_layouts > 0 {
-                if let Some(result) = search_key_for_char(ch, keymap, keycode, keycode_raw, 0) {
-                    return Ok(result);
-                }
-            }
+impl std::str::FromStr for ModifierOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "shift-first" => Ok(ModifierOrder::ShiftFirst),
+            "level3-first" => Ok(ModifierOrder::Level3First),
+            _ => Err(format!(
+                "unknown modifier order {:?} (expected shift-first or level3-first)",
+                s
+            )),
         }
     }
-
-    Err(EiTypeError::CharNotFound(ch))
 }
 
-/// Search a single key at a given layout for a character match.
-fn search_key_for_char(
-    ch: char,
-    keymap: &xkb::Keymap,
-    keycode: xkb::Keycode,
-    keycode_raw: u32,
-    layout: u32,
-) -> Option<KeyMatch> {
-    let num_levels = keymap.num_levels_for_key(keycode, layout);
+/// Settings a named typing profile overrides, e.g. a shorter delay for a
+/// terminal or a paste threshold for a browser. Unset settings are inherited
+/// from the `EiTypeConfig` the connection was made with.
+///
+/// Profiles only hold settings `EiType::apply_profile` can change on a live
+/// connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TypingProfile {
+    /// `EiTypeConfig::delay_ms`
+    pub delay_ms: Option<u64>,
+    /// `EiTypeConfig::settle_after_word_ms` (0 turns the pause off)
+    pub settle_after_word_ms: Option<u64>,
+    /// `EiTypeConfig::modifier_settle_ms`
+    pub modifier_settle_ms: Option<u64>,
+    /// `EiTypeConfig::paste_threshold`; `Some(None)` turns pasting off
+    /// (`paste_threshold = false` in a profiles file)
+    #[serde(deserialize_with = "paste_threshold_setting")]
+    pub paste_threshold: Option<Option<usize>>,
+    /// `EiTypeConfig::ascii_transliterate`
+    pub ascii_transliterate: Option<bool>,
+    /// `EiTypeConfig::strip_format_chars`
+    pub strip_format_chars: Option<bool>,
+}
 
-    for level in 0..num_levels {
-        let syms = keymap.key_get_syms_by_level(keycode, layout, level);
+/// A profile's `paste_threshold`: a number of characters, or `false` for
+/// no pasting
+fn paste_threshold_setting<'de, D>(deserializer: D) -> Result<Option<Option<usize>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Setting {
+        Chars(usize),
+        Paste(bool),
+    }
+    match serde::Deserialize::deserialize(deserializer) {
+        Ok(Setting::Chars(chars)) => Ok(Some(Some(chars))),
+        Ok(Setting::Paste(false)) => Ok(Some(None)),
+        _ => Err(serde::de::Error::custom(
+            "expected a number of characters, or false to turn pasting off",
+        )),
+    }
+}
 
-        for sym in syms {
-            let sym_raw: u32 = (*sym).into();
-            if let Some(sym_char) = keysym_to_char(sym_raw) {
-                if sym_char == ch {
-                    return Some(KeyMatch {
-                        evdev_keycode: keycode_raw - 8,
-                        layout,
-                        level,
-                    });
-                }
-            }
+impl TypingProfile {
+    /// Every setting a profile can hold, as `config` has it
+    fn of_config(config: &EiTypeConfig) -> Self {
+        Self {
+            delay_ms: Some(config.delay_ms),
+            settle_after_word_ms: Some(config.settle_after_word_ms.unwrap_or(0)),
+            modifier_settle_ms: Some(config.modifier_settle_ms),
+            paste_threshold: Some(config.paste_threshold),
+            ascii_transliterate: Some(config.ascii_transliterate),
+            strip_format_chars: Some(config.strip_format_chars),
         }
     }
 
-    None
+    /// This profile with the settings `over` sets replaced by its values
+    pub fn merged(&self, over: &TypingProfile) -> Self {
+        Self {
+            delay_ms: over.delay_ms.or(self.delay_ms),
+            settle_after_word_ms: over.settle_after_word_ms.or(self.settle_after_word_ms),
+            modifier_settle_ms: over.modifier_settle_ms.or(self.modifier_settle_ms),
+            paste_threshold: over.paste_threshold.or(self.paste_threshold),
+            ascii_transliterate: over.ascii_transliterate.or(self.ascii_transliterate),
+            strip_format_chars: over.strip_format_chars.or(self.strip_format_chars),
+        }
+    }
 }
 
-/// Translate a `KeyMatch` into the modifier keycodes that must be held to reach
-/// its level.
+/// Named typing profiles, selected with `EiType::apply_profile` or
+/// `--profile NAME`.
 ///
-/// Asks libxkbcommon (`xkb_keymap_key_get_mods_for_level`) for the modifier-mask
-/// candidates that produce the requested level on this specific key, then picks
-/// the simplest mask that resolves entirely to modifier keys we know how to press
-/// (Shift / Control / Mod1=Alt / Mod4=Super / Mod5=AltGr).
+/// Read from `[profile.NAME]` tables of a TOML file, by default
+/// `$XDG_CONFIG_HOME/eitype/config.toml` (`~/.config/eitype/config.toml`):
 ///
-/// Masks containing Lock (caps lock), Mod2 (typically NumLock), or Mod3 are
-/// rejected so we never toggle locking state to type a single character.
+/// ```toml
+/// [profile.terminal]
+/// delay_ms = 3
 ///
-/// `keymap_mod_keycodes` maps an xkb real-modifier name (e.g. "Mod5") to the
-/// evdev keycode of a key that produces that modifier *in this specific keymap* —
-/// see `build_keymap_modifier_map`. This is what makes the function correct under
-/// xkb-options like `lv3:ralt_alt` that move ISO_Level3_Shift off Right Alt.
-fn modifier_keycodes_for_match(
-    keymap: &xkb::Keymap,
-    m: &KeyMatch,
-    keymap_mod_keycodes: &HashMap<String, u32>,
-) -> Vec<u32> {
-    if m.level == 0 {
-        return Vec::new();
+/// [profile.browser]
+/// paste_threshold = 1
+///
+/// [profile.editor]
+/// paste_threshold = false  # never paste, whatever the configured threshold
+/// ```
+///
+/// Other tables are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct Profiles {
+    #[serde(default, rename = "profile")]
+    profiles: std::collections::BTreeMap<String, TypingProfile>,
+}
+
+impl Profiles {
+    /// `$XDG_CONFIG_HOME/eitype/config.toml` (default
+    /// `~/.config/eitype/config.toml`)
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("eitype").join("config.toml"))
     }
 
-    let xkb_keycode = xkb::Keycode::new(m.evdev_keycode + 8);
-    let mut masks = [xkb::ModMask::default(); 8];
-    let n = keymap.key_get_mods_for_level(xkb_keycode, m.layout, m.level, &mut masks);
-    if n == 0 {
-        return Vec::new();
+    /// Profiles from the file at `path`
+    pub fn load(path: &Path) -> Result<Self, EiTypeError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            EiTypeError::InvalidConfig(format!("Failed to read profiles from {:?}: {}", path, e))
+        })?;
+        Self::parse(&contents)
+            .map_err(|e| EiTypeError::InvalidConfig(format!("{}: {}", path.display(), e)))
     }
 
-    // Among acceptable masks, prefer the one with the fewest modifier keys.
-    let mut best: Option<Vec<u32>> = None;
-    for &mask in &masks[..n] {
-        if let Some(keycodes) = mask_to_modifier_keycodes(mask, keymap, keymap_mod_keycodes) {
-            match &best {
-                Some(b) if b.len() <= keycodes.len() => {}
-                _ => best = Some(keycodes),
-            }
+    /// Profiles from the text of a TOML file
+    pub fn parse(contents: &str) -> Result<Self, EiTypeError> {
+        let profiles: Self = toml::from_str(contents)
+            .map_err(|e| EiTypeError::InvalidConfig(e.to_string().trim_end().to_string()))?;
+        if profiles.profiles.contains_key("") {
+            return Err(EiTypeError::InvalidConfig("empty profile name".to_string()));
         }
+        Ok(profiles)
     }
-    best.unwrap_or_default()
-}
 
-/// Convert one xkb modifier mask into the list of evdev keycodes we'd press to
-/// produce it. Returns `None` if the mask contains a modifier we don't support
-/// (Lock, Mod2, Mod3, or any unmapped name) so the caller can try a different mask.
-///
-/// Looks up keycodes via `keymap_mod_keycodes`, which was built from this exact
-/// keymap, so e.g. with `lv3:ralt_alt` set we'll resolve Mod5 to the Menu key
-/// rather than the hardcoded Right Alt.
-fn mask_to_modifier_keycodes(
-    mask: xkb::ModMask,
-    keymap: &xkb::Keymap,
-    keymap_mod_keycodes: &HashMap<String, u32>,
-) -> Option<Vec<u32>> {
-    let num_mods = keymap.num_mods();
-    let mut keycodes = Vec::new();
-    for idx in 0..num_mods {
-        if mask & (1 << idx) == 0 {
-            continue;
+    /// Set `delay_ms` in the profile called `name` in the file at `path`
+    /// (as `eitype calibrate --save NAME` does), creating the file and the
+    /// profile if need be. The rest of the file, comments included, is kept.
+    pub fn save_delay(path: &Path, name: &str, delay_ms: u64) -> Result<(), EiTypeError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(EiTypeError::InvalidConfig(format!(
+                    "Failed to read profiles from {:?}: {}",
+                    path, e
+                )))
+            }
+        };
+        let updated = Self::with_delay(&contents, name, delay_ms)
+            .map_err(|e| EiTypeError::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+        write_file_atomically(path, updated.as_bytes(), 0o666).map_err(|e| {
+            EiTypeError::InvalidConfig(format!("Failed to write profiles to {:?}: {}", path, e))
+        })
+    }
+
+    /// The text of a TOML file with `delay_ms` set in the profile called
+    /// `name`
+    fn with_delay(contents: &str, name: &str, delay_ms: u64) -> Result<String, EiTypeError> {
+        if name.is_empty() {
+            return Err(EiTypeError::InvalidConfig("empty profile name".to_string()));
         }
-        let mod_name = keymap.mod_get_name(idx);
-        match mod_name {
-            "Shift" | "Control" | "Mod1" | "Mod4" | "Mod5" => {}
-            _ => return None,
+        let not_a_table =
+            |what: String| EiTypeError::InvalidConfig(format!("{} is not a table", what));
+        let mut document: toml_edit::DocumentMut =
+            contents.parse().map_err(|e: toml_edit::TomlError| {
+                EiTypeError::InvalidConfig(e.to_string().trim_end().to_string())
+            })?;
+        let profiles = document
+            .entry("profile")
+            .or_insert_with(|| {
+                let mut profiles = toml_edit::Table::new();
+                profiles.set_implicit(true);
+                profiles.into()
+            })
+            .as_table_like_mut()
+            .ok_or_else(|| not_a_table("profile".to_string()))?;
+        let profile = profiles
+            .entry(name)
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .ok_or_else(|| not_a_table(format!("profile.{}", name)))?;
+        let delay_ms = i64::try_from(delay_ms).map_err(|_| {
+            EiTypeError::InvalidConfig(format!("delay {} ms is too long", delay_ms))
+        })?;
+        match profile
+            .get_mut("delay_ms")
+            .and_then(toml_edit::Item::as_value_mut)
+        {
+            // Keeping a comment after the old value
+            Some(value) => {
+                let decor = value.decor().clone();
+                *value = delay_ms.into();
+                *value.decor_mut() = decor;
+            }
+            None => {
+                profile.insert("delay_ms", toml_edit::value(delay_ms));
+            }
         }
-        let kc = keymap_mod_keycodes.get(mod_name).copied()?;
-        keycodes.push(kc);
+        let updated = document.to_string();
+        // Anything the profiles can't hold was in the file already
+        Self::parse(&updated)?;
+        Ok(updated)
     }
-    Some(keycodes)
-}
 
-/// Find the evdev keycode of a key that, when pressed, produces the given
-/// real-modifier index in this keymap.
-///
-/// Walks the keycode range and uses `xkb_state` to simulate a press, then checks
-/// whether the requested modifier bit became active. This honors the keymap's
-/// modmap (including remappings from xkb-options), so the result is always valid
-/// for the actual keymap in use — not a hardcoded assumption like "AltGr is RAlt".
-///
-/// Two heuristics are needed to pick the *right* keycode when several produce
-/// the same modifier:
-///
-/// 1. xkb's standard `evdev` keycodes file aliases low-numbered phantom keycodes
-///    (e.g. evdev 84 for ISO_Level3_Shift) alongside the real physical keys
-///    (e.g. RAlt=100, Menu=127). The phantoms share the modifier-keysym name but
-///    aren't reachable on physical hardware, so we group hits by their level-0
-///    keysym name and keep only the highest keycode per group — that filters
-///    phantoms.
-/// 2. Among distinct physical modifier keys (e.g. `Shift_L`=42 vs `Shift_R`=54),
-///    we pick the lowest keycode — Left variants are conventionally lower-numbered
-///    and are the more common choice for synthesized input.
-fn find_keycode_for_modifier(keymap: &xkb::Keymap, mod_idx: xkb::ModIndex) -> Option<u32> {
-    if mod_idx == xkb::MOD_INVALID {
-        return None;
+    /// Add or replace the profile called `name`
+    pub fn insert(&mut self, name: impl Into<String>, profile: TypingProfile) {
+        self.profiles.insert(name.into(), profile);
     }
-    let target_bit = 1u32 << mod_idx;
-    let min: u32 = keymap.min_keycode().into();
-    let max: u32 = keymap.max_keycode().into();
 
-    // Per keysym name, track the highest keycode that produces this modifier.
-    // Highest wins because xkb's phantom aliases are low-numbered.
-    let mut by_keysym: HashMap<String, u32> = HashMap::new();
-    for kc_raw in min..=max {
-        let kc = xkb::Keycode::new(kc_raw);
-        let mut state = xkb::State::new(keymap);
-        state.update_key(kc, xkb::KeyDirection::Down);
-        let mods = state.serialize_mods(xkb::STATE_MODS_DEPRESSED);
-        if mods & target_bit == 0 {
-            continue;
-        }
-        let level0_syms = keymap.key_get_syms_by_level(kc, 0, 0);
-        let sym_name = level0_syms
-            .first()
-            .map(|s| xkb::keysym_get_name(*s))
-            .unwrap_or_default();
-        let evdev = kc_raw - 8;
-        by_keysym
-            .entry(sym_name)
-            .and_modify(|e| {
-                if evdev > *e {
-                    *e = evdev;
+    /// The profile called `name`; an error lists the available ones
+    pub fn get(&self, name: &str) -> Result<&TypingProfile, EiTypeError> {
+        self.profiles.get(name).ok_or_else(|| {
+            let available: Vec<&str> = self.names().collect();
+            EiTypeError::InvalidConfig(format!(
+                "unknown profile {:?} (available: {})",
+                name,
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
                 }
-            })
-            .or_insert(evdev);
+            ))
+        })
     }
 
-    // Lowest among distinct physical keys → typically the Left variant.
-    by_keysym.values().copied().min()
+    /// Names of the profiles, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
 }
 
-/// Resolve the set of real modifiers we care about (Shift, Control, Mod1, Mod4,
-/// Mod5) to evdev keycodes for *this specific keymap*.
+/// Progress report passed to the callback set with `EiType::set_progress_callback`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypingProgress {
+    /// Characters typed so far in the current `type_text` call
+    pub typed: usize,
+    /// Total characters in the current `type_text` call
+    pub total: usize,
+    /// True when this character completed a chunk and a pause follows
+    pub chunk_boundary: bool,
+}
+
+/// Callback invoked with typing progress
+type ProgressCallback = Box<dyn Fn(&TypingProgress)>;
+
+/// A single key event, passed to the hook set with `EiType::set_keystroke_hook`
+/// once its frame has been flushed to the server.
+#[cfg_attr(feature = "python", pyclass(get_all))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeystrokeInfo {
+    /// Evdev keycode of the key
+    pub keycode: u32,
+    /// True for a press, false for a release
+    pub pressed: bool,
+    /// Character being typed when the key was sent on its behalf (including
+    /// any modifier or group switch keys), or `None` for named keys and while
+    /// keystrokes are redacted
+    pub ch: Option<char>,
+    /// Timestamp of the frame, in microseconds (see `TimestampSource`)
+    pub timestamp: u64,
+}
+
+/// Hook invoked after every key frame
+type KeystrokeHook = Box<dyn FnMut(KeystrokeInfo)>;
+
+/// Callback invoked with the new fingerprint when the keymap changes
+type KeymapChangedCallback = Box<dyn Fn(u64)>;
+
+/// Opens a fresh connection the way the current one was made (see
+/// `EiType::reconnect`)
+type Reconnector = Box<dyn FnMut() -> Result<EiType, EiTypeError>>;
+
+/// Handle for cancelling an in-progress operation from another thread.
 ///
-/// Modifiers that aren't bound to any key in the keymap are simply omitted —
-/// `mask_to_modifier_keycodes` will then reject any mask requiring them.
-fn build_keymap_modifier_map(keymap: &xkb::Keymap) -> HashMap<String, u32> {
-    let mut map = HashMap::new();
-    for name in ["Shift", "Control", "Mod1", "Mod4", "Mod5"] {
-        let idx = keymap.mod_get_index(name);
-        if let Some(kc) = find_keycode_for_modifier(keymap, idx) {
-            map.insert(name.to_string(), kc);
-        }
+/// Cancellation is checked between characters and during chunk pauses, never
+/// between a key press and its release. The pending cancellation is consumed by
+/// the operation that reports `EiTypeError::Cancelled`.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Request cancellation of the current (or next) typing operation
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
     }
-    map
 }
 
-/// Convert an XKB keysym to a character
-fn keysym_to_char(keysym: u32) -> Option<char> {
-    // Map XK_Return to '\n' so callers can pass "\n" to press Enter.
-    // xkb_keysym_to_utf32 returns '\r' (U+000D) for this keysym.
-    if keysym == 0xff0d {
-        return Some('\n');
+/// Text being typed by `EiType::try_type_text`, plus how far typing has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeCursor {
+    text: String,
+    /// Byte offset of the next character to type
+    byte_offset: usize,
+    /// Number of characters typed so far
+    typed: usize,
+}
+
+impl TypeCursor {
+    /// Start typing `text` from the beginning
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            byte_offset: 0,
+            typed: 0,
+        }
     }
-    let codepoint = xkb::keysym_to_utf32(xkb::Keysym::new(keysym));
-    if codepoint == 0 {
-        None
-    } else {
-        char::from_u32(codepoint)
+
+    /// The full text
+    pub fn text(&self) -> &str {
+        &self.text
     }
-}
 
-/// Poll the EI connection for a KeyboardModifiers event to detect the active layout group.
-/// Uses a short timeout to avoid blocking if no modifiers event is pending.
-fn poll_for_layout_group(
-    poll_stream: &UnixStream,
-    event_iter: &mut impl Iterator<Item = Result<EiEvent, reis::Error>>,
-) -> Option<u32> {
-    use rustix::event::{poll, PollFd, PollFlags};
-    use rustix::time::Timespec;
+    /// Number of characters typed so far
+    pub fn typed(&self) -> usize {
+        self.typed
+    }
 
-    let mut pollfd = [PollFd::new(poll_stream, PollFlags::IN)];
+    /// The part of the text not yet typed
+    pub fn remaining(&self) -> &str {
+        &self.text[self.byte_offset..]
+    }
 
-    // Wait up to 100ms for modifiers event from compositor.
-    // The event arrives asynchronously from Mutter's main loop after the device is resumed.
-    // 100ms is generous — in practice it arrives within a few milliseconds.
-    // If it doesn't arrive, the active group is likely 0 (default layout).
-    let timeout = Timespec {
-        tv_sec: 0,
-        tv_nsec: 100_000_000, // 100ms
-    };
-    match poll(&mut pollfd, Some(&timeout)) {
-        Ok(n) if n > 0 => match event_iter.next() {
-            Some(Ok(EiEvent::KeyboardModifiers(mods))) => {
-                info!("Auto-detected active layout group: {}", mods.group);
-                Some(mods.group)
-            }
-            Some(Ok(other)) => {
-                debug!(
-                    "Got {:?} instead of KeyboardModifiers, using default",
-                    other
-                );
-                None
-            }
-            _ => None,
-        },
-        _ => {
-            debug!("No modifiers event received within timeout, using default layout 0");
-            None
-        }
+    /// Whether every character has been typed
+    pub fn is_finished(&self) -> bool {
+        self.byte_offset == self.text.len()
     }
 }
 
-/// Detect the active keyboard layout index using compositor-specific methods.
-/// Dispatches based on `$XDG_CURRENT_DESKTOP` and `$SWAYSOCK` environment variables.
-fn detect_active_layout_index() -> Option<u32> {
-    let desktop = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+/// Text typed a piece at a time that may be revised after it was typed, e.g.
+/// the running transcript of streaming speech recognition.
+///
+/// The session remembers the text committed so far. `revise` replaces it from
+/// a character index on, deleting and retyping only what differs from the
+/// text already typed. Typing goes through `EiType::type_text_report`, so
+/// delays, chunking and cancellation apply; characters no key produces are
+/// skipped and reported by `finish`. After an error the session no longer
+/// knows what is on screen; start a new one.
+#[derive(Debug, Clone)]
+pub struct TypeSession {
+    /// Text committed so far, including skipped characters
+    committed: String,
+    /// Characters of `committed` that were skipped, so are not on screen
+    skipped: Vec<SkippedChar>,
+    /// Characters of `committed` that were typed as something else
+    substitutions: Vec<Substitution>,
+    /// Fallbacks used at any point in the session
+    fallbacks: Vec<String>,
+    /// `EiType::stats` when the session started
+    start: TypingStats,
+}
 
-    if desktop.contains("GNOME") || desktop.contains("Unity") {
-        let result = detect_gnome_layout_index();
-        if result.is_some() {
-            return result;
-        }
-    } else if desktop.contains("KDE") {
-        let result = detect_kde_layout_index();
-        if result.is_some() {
-            return result;
+impl TypeSession {
+    /// Start a session typing with `eitype`, with nothing committed
+    pub fn new(eitype: &EiType) -> Self {
+        Self {
+            committed: String::new(),
+            skipped: Vec::new(),
+            substitutions: Vec::new(),
+            fallbacks: Vec::new(),
+            start: eitype.stats(),
         }
     }
 
-    if std::env::var("SWAYSOCK").is_ok() {
-        let result = detect_sway_layout_index();
-        if result.is_some() {
-            return result;
-        }
+    /// The text committed so far
+    pub fn text(&self) -> &str {
+        &self.committed
     }
 
-    // If desktop wasn't recognized, try GNOME then KDE as generic fallbacks
-    if !desktop.contains("GNOME") && !desktop.contains("Unity") && !desktop.contains("KDE") {
-        if let Some(idx) = detect_gnome_layout_index() {
-            return Some(idx);
+    /// Type `text` after the committed text
+    pub fn feed(&mut self, eitype: &EiType, text: &str) -> Result<(), EiTypeError> {
+        let end = self.committed.chars().count();
+        self.revise(eitype, end, text)
+    }
+
+    /// Replace the committed text from character `from_index` on with
+    /// `new_text`. Characters shared by the old and new text are kept; the
+    /// rest of the old text is deleted and the rest of the new text typed.
+    pub fn revise(
+        &mut self,
+        eitype: &EiType,
+        from_index: usize,
+        new_text: &str,
+    ) -> Result<(), EiTypeError> {
+        let Some(tail_offset) = char_offset(&self.committed, from_index) else {
+            return Err(EiTypeError::Typing(format!(
+                "revision index {} is past the end of the committed text ({} characters)",
+                from_index,
+                self.committed.chars().count()
+            )));
+        };
+        let (kept, insert) = common_prefix_edit(&self.committed[tail_offset..], new_text);
+        let keep_chars = from_index + kept;
+        // The shared prefix is the same bytes in both texts
+        let keep_offset = tail_offset + (new_text.len() - insert.len());
+
+        let removed = self.committed[keep_offset..].chars().count();
+        let removed_skipped = self
+            .skipped
+            .iter()
+            .filter(|s| s.index >= keep_chars)
+            .count();
+        debug!(
+            "Revising from {}: keeping {} characters, deleting {}, typing {:?}",
+            from_index,
+            kept,
+            removed - removed_skipped,
+            insert
+        );
+        eitype.delete_chars(removed - removed_skipped)?;
+        self.committed.truncate(keep_offset);
+        self.skipped.retain(|s| s.index < keep_chars);
+        self.substitutions.retain(|s| s.index < keep_chars);
+
+        if insert.is_empty() {
+            return Ok(());
         }
-        if let Some(idx) = detect_kde_layout_index() {
-            return Some(idx);
+        let outcome = eitype.type_text_report(insert)?;
+        self.committed.push_str(insert);
+        self.skipped
+            .extend(outcome.skipped.into_iter().map(|s| SkippedChar {
+                index: keep_chars + s.index,
+                ..s
+            }));
+        self.substitutions
+            .extend(outcome.substitutions.into_iter().map(|s| Substitution {
+                index: keep_chars + s.index,
+                ..s
+            }));
+        for fallback in outcome.fallbacks {
+            if !self.fallbacks.contains(&fallback) {
+                self.fallbacks.push(fallback);
+            }
         }
+        Ok(())
     }
 
-    None
+    /// End the session: what the final text was, which of its characters
+    /// were skipped or substituted, and what the whole session sent (including deleted
+    /// text and the Backspaces deleting it)
+    pub fn finish(self, eitype: &EiType) -> TypeOutcome {
+        let chars_requested = self.committed.chars().count();
+        let stats = eitype.stats().since(&self.start);
+        TypeOutcome {
+            chars_requested,
+            chars_delivered: chars_requested - self.skipped.len() - stats.format_chars_stripped,
+            skipped: self.skipped,
+            substitutions: self.substitutions,
+            format_chars_stripped: stats.format_chars_stripped,
+            fallbacks: self.fallbacks,
+            stats,
+        }
+    }
 }
 
-/// Detect active layout index on GNOME by comparing `mru-sources` against `sources`.
+/// Byte offset of character `index` of `text` (`text.len()` for the index
+/// just past the end), or None if `text` is shorter
+fn char_offset(text: &str, index: usize) -> Option<usize> {
+    text.char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(text.len()))
+        .nth(index)
+}
+
+/// How to turn `old` into `new` keeping their common prefix: the number of
+/// characters kept, and the part of `new` to type after them. The rest of
+/// `old` is deleted.
+fn common_prefix_edit<'a>(old: &str, new: &'a str) -> (usize, &'a str) {
+    let (kept, offset) = old
+        .chars()
+        .zip(new.char_indices())
+        .take_while(|(old_ch, (_, new_ch))| old_ch == new_ch)
+        .fold((0, 0), |(kept, _), (_, (offset, ch))| {
+            (kept + 1, offset + ch.len_utf8())
+        });
+    (kept, &new[offset..])
+}
+
+/// Outcome of a successful `EiType::try_type_text` call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryTypeStatus {
+    /// All text has been typed and flushed to the server
+    Completed,
+    /// The socket is full; wait until it is writable, then call again
+    WouldBlock,
+}
+
+/// Where the active keymap came from.
 ///
-/// The first entry in `mru-sources` is the currently active input source. We find its
-/// position in the `sources` list, counting only `xkb` type sources (IBus sources don't
-/// get their own XKB group).
-fn detect_gnome_layout_index() -> Option<u32> {
-    use std::process::Command;
+/// Recorded while connecting so callers (and bug reports) can tell whether the
+/// compositor supplied a keymap or eitype had to fall back to one of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+pub enum KeymapSource {
+    /// Keymap provided by the EI server for the keyboard device
+    Server,
+    /// Compiled from the layout/variant/model/options names in `EiTypeConfig`
+    ConfigNames,
+    /// Compiled from `EiTypeConfig::keymap_string`
+    CustomString,
+    /// Loaded from `EiTypeConfig::keymap_file`
+    File,
+    /// System default XKB keymap (no configuration and no server keymap)
+    SystemDefault,
+}
 
-    let mru_output = Command::new("gsettings")
-        .args(["get", "org.gnome.desktop.input-sources", "mru-sources"])
-        .output();
+/// Connection or device state change, delivered by `EiType::status_receiver`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatusEvent {
+    /// Connected, with a keyboard ready (the first event on every receiver)
+    Connected,
+    /// The server paused a device; its input is dropped until it resumes
+    DevicePaused { device: String },
+    /// A paused device may be used again
+    DeviceResumed { device: String },
+    /// The server removed a device
+    DeviceRemoved { device: String },
+    /// The connection ended, either from the server or through `close`
+    Disconnected { reason: String },
+}
 
-    let mru_output = match mru_output {
-        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).trim().to_string(),
-        Ok(o) => {
-            warn!(
-                "gsettings mru-sources failed: {}",
-                String::from_utf8_lossy(&o.stderr).trim()
-            );
-            return None;
-        }
-        Err(e) => {
-            warn!("Failed to run gsettings: {}", e);
-            return None;
-        }
-    };
+/// Input capability of the devices bound on a connection.
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceCapability {
+    /// Key events (always bound, or connecting fails)
+    Keyboard,
+    /// Pointer buttons, e.g. middle-click paste (`EiTypeConfig::pointer`)
+    Button,
+    /// Scroll wheel (`EiTypeConfig::pointer`)
+    Scroll,
+    /// Relative pointer motion (`EiTypeConfig::pointer`)
+    Pointer,
+    /// Absolute pointer motion
+    PointerAbsolute,
+    /// Touchscreen
+    Touch,
+}
 
-    // mru-sources can be empty (@a(ss) []) if never switched
-    if mru_output.starts_with("@") || mru_output == "[]" {
-        debug!("mru-sources is empty, assuming layout index 0");
-        return Some(0);
+impl DeviceCapability {
+    /// Every capability, in the order `EiType::capabilities` reports them
+    const ALL: [DeviceCapability; 6] = [
+        DeviceCapability::Keyboard,
+        DeviceCapability::Button,
+        DeviceCapability::Scroll,
+        DeviceCapability::Pointer,
+        DeviceCapability::PointerAbsolute,
+        DeviceCapability::Touch,
+    ];
+
+    fn to_ei(self) -> EiCapability {
+        match self {
+            DeviceCapability::Keyboard => EiCapability::Keyboard,
+            DeviceCapability::Button => EiCapability::Button,
+            DeviceCapability::Scroll => EiCapability::Scroll,
+            DeviceCapability::Pointer => EiCapability::Pointer,
+            DeviceCapability::PointerAbsolute => EiCapability::PointerAbsolute,
+            DeviceCapability::Touch => EiCapability::Touch,
+        }
     }
+}
 
-    let sources_output = Command::new("gsettings")
-        .args(["get", "org.gnome.desktop.input-sources", "sources"])
-        .output();
+/// Summary of an established connection, for diagnostics and caching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct ConnectionInfo {
+    /// Where the active keymap came from
+    pub keymap_source: KeymapSource,
+    /// Stable hash of the active keymap (see `EiType::keymap_fingerprint`)
+    pub keymap_fingerprint: u64,
+    /// Number of layouts (groups) in the active keymap
+    pub num_layouts: u32,
+    /// Layout index used for typing
+    pub layout_index: u32,
+    /// Name of the layout typed in, e.g. "English (US)"; empty without a keymap
+    pub layout_name: String,
+    /// The configured or detected layout index, when it was out of range for
+    /// the keymap and typing fell back to layout 0
+    pub clamped_layout_index: Option<u32>,
+}
 
-    let sources_output = match sources_output {
-        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).trim().to_string(),
-        Ok(o) => {
-            warn!(
-                "gsettings sources failed: {}",
-                String::from_utf8_lossy(&o.stderr).trim()
-            );
-            return None;
-        }
-        Err(e) => {
-            warn!("Failed to run gsettings for sources: {}", e);
-            return None;
-        }
-    };
+/// How a special key name in the built-in table fits the active keymap.
+///
+/// The table assumes a standard evdev keyboard; on remapped keymaps (e.g.
+/// `caps:swapescape`) the keycode may produce some other key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct KeyTableEntry {
+    /// Key name, as passed to `press_key`
+    pub name: String,
+    /// Keysym the key should produce, e.g. "Escape"
+    pub keysym: String,
+    /// Keycode in the built-in table
+    pub table_keycode: u32,
+    /// Keycode producing `keysym` at level 0 in the keymap, if any
+    pub keymap_keycode: Option<u32>,
+    /// Keysym `keymap_keycode` produces instead of `keysym` when no key
+    /// produces `keysym` itself, e.g. "KP_Enter" for Return
+    pub substitute: Option<String>,
+    /// Keycode `press_key` sends for `name`
+    pub keycode: u32,
+}
 
-    let mru_entries = parse_gnome_input_sources(&mru_output);
-    let source_entries = parse_gnome_input_sources(&sources_output);
+impl KeyTableEntry {
+    /// Whether the built-in keycode produces the expected keysym
+    pub fn matches_keymap(&self) -> bool {
+        self.keymap_keycode == Some(self.table_keycode)
+    }
+}
 
-    if mru_entries.is_empty() || source_entries.is_empty() {
-        warn!(
-            "Failed to parse GNOME input sources: mru={:?}, sources={:?}",
-            mru_output, sources_output
-        );
-        return None;
+impl std::fmt::Display for KeyTableEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.keymap_keycode {
+            Some(keycode) if keycode == self.table_keycode => {
+                write!(f, "{}: keycode {} ({})", self.name, keycode, self.keysym)
+            }
+            Some(keycode) if self.substitute.is_some() => write!(
+                f,
+                "{}: no key produces {} in this keymap, keycode {} produces {} (using {})",
+                self.name,
+                self.keysym,
+                keycode,
+                self.substitute.as_deref().unwrap_or_default(),
+                self.keycode
+            ),
+            Some(keycode) => write!(
+                f,
+                "{}: keycode {} doesn't produce {} in this keymap, keycode {} does (using {})",
+                self.name, self.table_keycode, self.keysym, keycode, self.keycode
+            ),
+            None => write!(
+                f,
+                "{}: no key produces {} in this keymap (using keycode {})",
+                self.name, self.keysym, self.keycode
+            ),
+        }
     }
+}
 
-    let active = &mru_entries[0];
+/// Actions that can be performed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Type a string of text
+    Type(String),
+    /// Press and release a special key (e.g., "Return", "Tab")
+    Key(String),
+    /// Hold a modifier key (will be released at the end)
+    ModifierHold(String),
+    /// Press and release a modifier key
+    ModifierPress(String),
+    /// Press a key and keep it down until a matching `KeyUp` (or the end)
+    KeyDown(String),
+    /// Release a key pressed with `KeyDown`
+    KeyUp(String),
+    /// Type a key by its XKB keysym name (e.g., "eacute")
+    Keysym(String),
+    /// Paste a string of text via the clipboard or primary selection
+    Paste(String, PasteOptions),
+    /// Scroll by whole wheel notches (horizontal, vertical)
+    ScrollDiscrete(i32, i32),
+    /// Scroll by pixel deltas (horizontal, vertical)
+    ScrollSmooth(f64, f64),
+    /// Touch and lift at a position, in logical pixels (x, y)
+    Tap(f64, f64),
+    /// Hold modifiers (e.g. "ctrl", "shift") only around the inner actions:
+    /// they are pressed in order, and released in reverse once the inner
+    /// actions are done or one fails. Scopes nest; a modifier that is
+    /// already held isn't pressed again, nor released by the inner scope.
+    WithModifiers {
+        mods: Vec<String>,
+        inner: Vec<Action>,
+    },
+    /// Pause before the next action (a `CancelHandle` cuts it short)
+    Sleep(Duration),
+    /// Focus a window (see `EiType::focus_window`)
+    Focus(FocusTarget),
+}
 
-    // Find the position of the active source in the sources list,
-    // counting only xkb sources (IBus engines don't get their own XKB group).
-    let mut xkb_index = 0u32;
-    for entry in &source_entries {
-        if entry.0 == "xkb" {
-            if entry == active {
-                info!(
-                    "GNOME: detected active layout '{}' at XKB group index {}",
-                    active.1, xkb_index
-                );
-                return Some(xkb_index);
-            }
-            xkb_index += 1;
-        } else if entry == active {
-            // Active source is an IBus engine, not an XKB layout — use group 0
-            info!(
-                "GNOME: active source '{}' is IBus, using XKB group index 0",
-                active.1
-            );
-            return Some(0);
+impl Action {
+    /// Short snake_case name of the action's kind, e.g. "type" or "modifier_hold"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::Type(_) => "type",
+            Action::Key(_) => "key",
+            Action::ModifierHold(_) => "modifier_hold",
+            Action::ModifierPress(_) => "modifier_press",
+            Action::KeyDown(_) => "key_down",
+            Action::KeyUp(_) => "key_up",
+            Action::Keysym(_) => "keysym",
+            Action::Paste(..) => "paste",
+            Action::ScrollDiscrete(..) => "scroll_discrete",
+            Action::ScrollSmooth(..) => "scroll_smooth",
+            Action::Tap(..) => "tap",
+            Action::WithModifiers { .. } => "with_modifiers",
+            Action::Sleep(_) => "sleep",
+            Action::Focus(_) => "focus",
         }
     }
+}
 
-    warn!(
-        "GNOME: active source {:?} not found in sources list {:?}",
-        active, source_entries
-    );
-    None
+/// Window for `EiType::focus_window` to focus, matched against the
+/// compositor's window list
+#[derive(Debug, Clone)]
+pub enum FocusTarget {
+    /// The window of this app id (or X11 class), compared case-insensitively
+    AppId(String),
+    /// The window whose title matches this regex
+    Title(regex::Regex),
 }
 
-/// Parse GNOME GVariant input source list format.
-/// Input: `[('xkb', 'us+dvp'), ('xkb', 'us')]`
-/// Output: `vec![("xkb", "us+dvp"), ("xkb", "us")]`
-fn parse_gnome_input_sources(input: &str) -> Vec<(String, String)> {
-    let trimmed = input.trim().trim_start_matches('[').trim_end_matches(']');
-    if trimmed.is_empty() {
-        return Vec::new();
+impl FocusTarget {
+    /// Target the window whose title matches `pattern`
+    pub fn title(pattern: &str) -> Result<Self, EiTypeError> {
+        regex::Regex::new(pattern)
+            .map(FocusTarget::Title)
+            .map_err(|e| EiTypeError::InvalidConfig(format!("invalid title pattern: {}", e)))
     }
 
-    let mut results = Vec::new();
-    // Split on "), (" to separate entries, handling the surrounding parens/quotes
-    for entry in trimmed.split("), (") {
-        let entry = entry.trim().trim_start_matches('(').trim_end_matches(')');
-        // entry is now: 'type', 'id'
-        let parts: Vec<&str> = entry.splitn(2, ", ").collect();
-        if parts.len() == 2 {
-            let source_type = parts[0].trim().trim_matches('\'');
-            let source_id = parts[1].trim().trim_matches('\'');
-            results.push((source_type.to_string(), source_id.to_string()));
+    /// Whether a window with this app id and title is the target
+    fn matches(&self, app_id: Option<&str>, title: Option<&str>) -> bool {
+        match self {
+            FocusTarget::AppId(target) => app_id.is_some_and(|id| id.eq_ignore_ascii_case(target)),
+            FocusTarget::Title(pattern) => title.is_some_and(|title| pattern.is_match(title)),
         }
     }
-    results
 }
 
-/// Detect active layout index on KDE Plasma via D-Bus.
-fn detect_kde_layout_index() -> Option<u32> {
-    use std::process::Command;
+impl PartialEq for FocusTarget {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FocusTarget::AppId(a), FocusTarget::AppId(b)) => a == b,
+            (FocusTarget::Title(a), FocusTarget::Title(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
 
-    // Try qdbus6 first (Plasma 6 / Qt6), then qdbus (Plasma 5 / Qt5)
-    for cmd in &["qdbus6", "qdbus"] {
-        let output = Command::new(cmd)
-            .args([
-                "org.kde.keyboard",
-                "/Layouts",
-                "org.kde.KeyboardLayouts.getLayout",
-            ])
-            .output();
+impl std::fmt::Display for FocusTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FocusTarget::AppId(app_id) => write!(f, "app id {}", app_id),
+            FocusTarget::Title(pattern) => write!(f, "title /{}/", pattern),
+        }
+    }
+}
 
-        if let Ok(o) = output {
-            if o.status.success() {
-                let text = String::from_utf8_lossy(&o.stdout).trim().to_string();
-                if let Ok(idx) = text.parse::<u32>() {
-                    info!("KDE: detected active layout index {} via {}", idx, cmd);
-                    return Some(idx);
+/// Parse a string that mixes text and keys into actions, e.g.
+/// `user@example.com\tsecret{key:return}` (`--inline`):
+///
+/// - `{key:NAME}` presses a key, e.g. `{key:escape}`
+/// - `{combo:SPEC}` presses a combo, e.g. `{combo:ctrl+a}` or `{combo:backtab}`
+/// - `{sleep:MS}` pauses for MS milliseconds
+/// - `\t` and `\n` press Tab and Return, and `\\` types a backslash
+/// - `{{` types a `{`
+///
+/// Everything else is typed as is. Key names are checked against the names
+/// `press_key` knows, not a keymap. A malformed sequence is an
+/// `EiTypeError::InlineSyntax` with its byte offset.
+pub fn parse_inline(input: &str) -> Result<Vec<Action>, EiTypeError> {
+    let names = Planner::without_keymap(&EiTypeConfig::default());
+    let error = |offset, message| EiTypeError::InlineSyntax { offset, message };
+    let mut actions = Vec::new();
+    let mut text = String::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((offset, ch)) = chars.next() {
+        let action = match ch {
+            '{' if chars.next_if(|&(_, next)| next == '{').is_some() => {
+                text.push('{');
+                continue;
+            }
+            '{' => {
+                let body_start = offset + 1;
+                let Some(len) = input[body_start..].find('}') else {
+                    return Err(error(offset, "no closing }".to_string()));
+                };
+                while chars.next_if(|&(i, _)| i <= body_start + len).is_some() {}
+                parse_inline_sequence(&input[body_start..body_start + len], &names)
+                    .map_err(|message| error(offset, message))?
+            }
+            '\\' => match chars.next() {
+                Some((_, 't')) => Action::Key("tab".to_string()),
+                Some((_, 'n')) => Action::Key("return".to_string()),
+                Some((_, '\\')) => {
+                    text.push('\\');
+                    continue;
                 }
+                Some((_, other)) => {
+                    let message = format!("unknown escape \\{} (use \\\\ for a backslash)", other);
+                    return Err(error(offset, message));
+                }
+                None => return Err(error(offset, "\\ at the end of the input".to_string())),
+            },
+            _ => {
+                text.push(ch);
+                continue;
             }
+        };
+        if !text.is_empty() {
+            actions.push(Action::Type(std::mem::take(&mut text)));
         }
+        actions.push(action);
     }
+    if !text.is_empty() {
+        actions.push(Action::Type(text));
+    }
+    Ok(actions)
+}
 
-    // Fall back to dbus-send (no Qt dependency)
-    let output = Command::new("dbus-send")
-        .args([
-            "--session",
-            "--print-reply",
-            "--dest=org.kde.keyboard",
-            "/Layouts",
-            "org.kde.KeyboardLayouts.getLayout",
-        ])
-        .output();
-
-    match output {
-        Ok(o) if o.status.success() => {
-            let text = String::from_utf8_lossy(&o.stdout);
-            // dbus-send output format: "   int32 1\n"
-            for line in text.lines() {
-                let line = line.trim();
-                if let Some(val) = line
-                    .strip_prefix("int32 ")
-                    .or_else(|| line.strip_prefix("uint32 "))
-                {
-                    if let Ok(idx) = val.trim().parse::<u32>() {
-                        info!("KDE: detected active layout index {} via dbus-send", idx);
-                        return Some(idx);
-                    }
-                }
+/// The action for the inside of a `{...}` sequence of `parse_inline`, or
+/// what is wrong with it
+fn parse_inline_sequence(body: &str, names: &Planner) -> Result<Action, String> {
+    let expected = "expected {key:NAME}, {combo:SPEC} or {sleep:MS} (or {{ for a {)";
+    let Some((kind, arg)) = body.split_once(':') else {
+        return Err(format!("{{{}}}: {}", body, expected));
+    };
+    match kind {
+        "key" | "combo" => {
+            let (held, _) = names.combo_for_name(arg).map_err(|e| e.to_string())?;
+            if kind == "key" && !held.is_empty() {
+                return Err(format!("{} is a combo, write {{combo:{}}}", arg, arg));
             }
-            warn!("KDE: could not parse dbus-send output: {}", text.trim());
-            None
-        }
-        Ok(_) => {
-            debug!("KDE keyboard D-Bus service not available");
-            None
-        }
-        Err(e) => {
-            warn!("Failed to run dbus-send: {}", e);
-            None
+            Ok(Action::Key(arg.to_string()))
         }
+        "sleep" => arg
+            .parse()
+            .map(|ms| Action::Sleep(Duration::from_millis(ms)))
+            .map_err(|_| format!("{{sleep:{}}}: expected a number of milliseconds", arg)),
+        _ => Err(format!("{{{}}}: {}", body, expected)),
     }
 }
 
-/// Detect active layout index on Sway via `swaymsg -t get_inputs`.
-fn detect_sway_layout_index() -> Option<u32> {
-    use std::process::Command;
+/// Whether an action run by `execute_actions_reported` succeeded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionStatus {
+    Ok,
+    /// The action failed with this error message
+    Failed(String),
+    /// Not run because an earlier action failed
+    Skipped,
+}
 
-    let output = Command::new("swaymsg")
-        .args(["-t", "get_inputs", "--raw"])
-        .output();
+/// Outcome of one action run by `execute_actions_reported`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionReport {
+    /// Position of the action in the input
+    pub index: usize,
+    /// The action's kind (see `Action::kind`)
+    pub kind: &'static str,
+    pub status: ActionStatus,
+    /// Characters this action typed
+    pub chars_typed: usize,
+}
 
-    let output = match output {
-        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).to_string(),
-        Ok(o) => {
-            warn!(
-                "swaymsg failed: {}",
-                String::from_utf8_lossy(&o.stderr).trim()
-            );
-            return None;
-        }
-        Err(e) => {
-            warn!("Failed to run swaymsg: {}", e);
-            return None;
+/// Running totals of what an `EiType` has sent since it connected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct TypingStats {
+    /// Characters typed (not pasted)
+    pub chars_typed: usize,
+    /// Key presses sent, including modifiers and group switch keys
+    pub keys_pressed: usize,
+    /// Times a group switch key was used to reach a character in another layout
+    pub group_switches: usize,
+    /// Characters typed with the built-in US QWERTY table because no keymap
+    /// was available
+    pub qwerty_fallback_chars: usize,
+    /// Pauses inserted by adaptive throttling (`EiTypeConfig::adaptive_throttle`)
+    pub throttle_events: usize,
+    /// Total time spent in those pauses
+    pub throttle_time: Duration,
+    /// Held modifier presses sent again because the server paused and
+    /// resumed the device, which drops every key held in it
+    pub held_key_replays: usize,
+    /// Frames of key events sent
+    pub frames_sent: usize,
+    /// Flushes retried because the socket was full
+    pub flush_retries: usize,
+    /// Invisible format characters (e.g. zero width spaces) left out of
+    /// typed text (`EiTypeConfig::strip_format_chars`)
+    pub format_chars_stripped: usize,
+}
+
+impl TypingStats {
+    /// What was added to the totals since they were `earlier`
+    fn since(&self, earlier: &TypingStats) -> TypingStats {
+        TypingStats {
+            chars_typed: self.chars_typed - earlier.chars_typed,
+            keys_pressed: self.keys_pressed - earlier.keys_pressed,
+            group_switches: self.group_switches - earlier.group_switches,
+            qwerty_fallback_chars: self.qwerty_fallback_chars - earlier.qwerty_fallback_chars,
+            throttle_events: self.throttle_events - earlier.throttle_events,
+            throttle_time: self.throttle_time - earlier.throttle_time,
+            held_key_replays: self.held_key_replays - earlier.held_key_replays,
+            frames_sent: self.frames_sent - earlier.frames_sent,
+            flush_retries: self.flush_retries - earlier.flush_retries,
+            format_chars_stripped: self.format_chars_stripped - earlier.format_chars_stripped,
         }
-    };
+    }
+}
 
-    // Parse JSON without adding serde_json dependency.
-    // Look for keyboard devices and extract xkb_active_layout_index.
-    // We find the first keyboard device's layout index.
-    parse_sway_layout_index(&output)
+/// Where the time went for one typed character, recorded when
+/// `EiTypeConfig::collect_timing` is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct CharTiming {
+    pub ch: char,
+    /// Looking up the keys that type it
+    pub plan: Duration,
+    /// Flushing its events to the server, including waits on a full socket
+    pub flush: Duration,
+    /// Time since the previous character started (zero for the first)
+    pub gap: Duration,
 }
 
-/// Extract `xkb_active_layout_index` from swaymsg JSON output.
-/// Uses simple string matching to avoid adding serde_json as a dependency.
-fn parse_sway_layout_index(json: &str) -> Option<u32> {
-    // Strategy: find blocks containing `"type": "keyboard"` and extract
-    // `xkb_active_layout_index` from the same object.
-    //
-    // We look for xkb_active_layout_index near a keyboard type indicator.
-    // Since swaymsg returns a flat array of input devices, we scan for
-    // the pattern in order.
-    let mut in_keyboard = false;
+/// Timings of the most recent characters (`EiTypeConfig::collect_timing`)
+#[derive(Debug, Default)]
+struct TimingTrace {
+    chars: VecDeque<CharTiming>,
+    /// Whether older entries were dropped to stay within `CAPACITY`
+    truncated: bool,
+    /// When the previous character started
+    last_start: Option<Instant>,
+    /// Flush time spent on the character being typed
+    flush: Duration,
+}
 
-    for line in json.lines() {
-        let trimmed = line.trim();
+impl TimingTrace {
+    /// Characters kept; older ones are dropped first
+    const CAPACITY: usize = 100_000;
 
-        if trimmed.contains("\"type\"") && trimmed.contains("\"keyboard\"") {
-            in_keyboard = true;
-        } else if in_keyboard && trimmed.contains("\"type\"") {
-            // Moved past the keyboard device into another device type
-            in_keyboard = false;
-        }
+    /// Start timing a character, returning its start time
+    fn start(&mut self) -> Instant {
+        self.flush = Duration::ZERO;
+        Instant::now()
+    }
 
-        if in_keyboard {
-            if let Some(idx_str) = trimmed.strip_prefix("\"xkb_active_layout_index\":") {
-                let idx_str = idx_str.trim().trim_end_matches(',');
-                if let Ok(idx) = idx_str.trim().parse::<u32>() {
-                    info!("Sway: detected active layout index {}", idx);
-                    return Some(idx);
-                }
-            }
+    /// Record a character started at `started` that took `plan` to look up
+    fn record(&mut self, ch: char, started: Instant, plan: Duration) {
+        let gap = self
+            .last_start
+            .map_or(Duration::ZERO, |last| started.duration_since(last));
+        self.last_start = Some(started);
+        if self.chars.len() == Self::CAPACITY {
+            self.chars.pop_front();
+            self.truncated = true;
         }
+        self.chars.push_back(CharTiming {
+            ch,
+            plan,
+            flush: self.flush,
+            gap,
+        });
     }
-
-    warn!("Sway: no keyboard device with xkb_active_layout_index found");
-    None
 }
 
-/// Get current timestamp in microseconds
-fn get_timestamp() -> u64 {
-    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
-    let start = START.get_or_init(Instant::now);
-    start.elapsed().as_micros() as u64
+/// A character `EiType::type_text_report` left out because no key produces it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct SkippedChar {
+    /// Position of the character in the text, in characters
+    pub index: usize,
+    pub ch: char,
+    /// Why it couldn't be typed
+    pub reason: String,
 }
 
-// ============================================================================
-// Connection Functions
-// ============================================================================
+/// A character `EiType::type_text_report` typed as something else
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct Substitution {
+    /// Position of the character (the first of a grapheme cluster) in the
+    /// text, in characters
+    pub index: usize,
+    /// The character, or grapheme cluster, in the text
+    pub original: String,
+    /// What was typed instead (see `EiTypeConfig::ascii_transliterate`)
+    pub replacement: String,
+}
 
-/// Connect to EI via the XDG RemoteDesktop portal.
-/// Returns the stream and optionally a new restore token for future sessions.
-fn connect_via_portal(
-    restore_token: Option<&str>,
-) -> Result<(UnixStream, Option<String>), EiTypeError> {
-    use ashpd::desktop::remote_desktop::{DeviceType, RemoteDesktop};
-    use ashpd::desktop::PersistMode;
+/// What a best-effort `EiType::type_text_report` call typed and left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct TypeOutcome {
+    /// Characters in the text, after `EiTypeConfig::terminal_safe` has
+    /// left out control characters (`skipped` indices count the same way)
+    pub chars_requested: usize,
+    /// Characters of the text typed, as themselves or substituted
+    pub chars_delivered: usize,
+    /// Characters left out, in order
+    pub skipped: Vec<SkippedChar>,
+    /// Characters typed as something else, in order; they count as delivered
+    pub substitutions: Vec<Substitution>,
+    /// Invisible format characters left out (`EiTypeConfig::strip_format_chars`),
+    /// which are neither delivered nor listed in `skipped`
+    pub format_chars_stripped: usize,
+    /// Ways characters were reached other than the configured layout:
+    /// "group_switch" (another layout of the keymap) and "qwerty_fallback" (the
+    /// built-in US QWERTY table, without a keymap)
+    pub fallbacks: Vec<String>,
+    /// What the call added to `EiType::stats`
+    pub stats: TypingStats,
+}
 
-    info!("Connecting via XDG RemoteDesktop portal...");
-    if restore_token.is_some() {
-        info!("Using saved restore token for session persistence");
+/// The attributes of a `get_all` pyclass instance as a dict
+#[cfg(feature = "python")]
+fn py_attributes_dict<'py>(
+    object: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, pyo3::types::PyDict>> {
+    let dict = pyo3::types::PyDict::new(object.py());
+    for name in object.dir()? {
+        let name: String = name.extract()?;
+        if name.starts_with('_') {
+            continue;
+        }
+        let value = object.getattr(name.as_str())?;
+        if !value.is_callable() {
+            dict.set_item(name, value)?;
+        }
     }
+    Ok(dict)
+}
 
-    // Use global runtime to avoid DBus connection issues when runtime is dropped.
-    // Creating a new runtime per connection causes zbus to leave stale state
-    // that blocks subsequent connections.
-    let rt = get_tokio_runtime();
+#[cfg(feature = "python")]
+#[pymethods]
+impl TypeOutcome {
+    /// The report as a dict, with the skipped characters, substitutions and
+    /// stats as dicts too (e.g. to turn into JSON)
+    fn to_dict<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, pyo3::types::PyDict>> {
+        let dict = py_attributes_dict(slf.as_any())?;
+        for name in ["skipped", "substitutions"] {
+            let items = slf
+                .getattr(name)?
+                .try_iter()?
+                .map(|item| py_attributes_dict(&item?))
+                .collect::<PyResult<Vec<_>>>()?;
+            dict.set_item(name, items)?;
+        }
+        dict.set_item("stats", py_attributes_dict(&slf.getattr("stats")?)?)?;
+        Ok(dict)
+    }
+}
 
-    rt.block_on(async {
-        let proxy = RemoteDesktop::new().await.map_err(|e| {
-            EiTypeError::Connection(format!("Failed to create RemoteDesktop proxy: {}", e))
-        })?;
+/// What `EiType::calibrate` types, and at which rates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalibrateOptions {
+    /// Text typed at each rate (default: 20 lowercase letters)
+    pub probe: String,
+    /// Delete the probe with one Backspace per character after each rate,
+    /// so nothing is left behind (default: true)
+    pub erase: bool,
+    /// Key delays to try, slowest first (default: 8, 4, 2, 1 and 0 ms)
+    pub delays_ms: Vec<u64>,
+}
 
-        let session = proxy
-            .create_session()
-            .await
-            .map_err(|e| EiTypeError::Connection(format!("Failed to create session: {}", e)))?;
+impl Default for CalibrateOptions {
+    fn default() -> Self {
+        Self {
+            probe: "abcdefghijklmnopqrst".to_string(),
+            erase: true,
+            delays_ms: vec![8, 4, 2, 1, 0],
+        }
+    }
+}
 
-        proxy
-            .select_devices(
-                &session,
-                DeviceType::Keyboard.into(),
-                restore_token,
-                PersistMode::ExplicitlyRevoked,
-            )
-            .await
-            .map_err(|e| EiTypeError::Connection(format!("Failed to select devices: {}", e)))?;
+/// How one rate `EiType::calibrate` tried went
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct CalibrationStep {
+    /// Key delay typed with (`EiTypeConfig::delay_ms`)
+    pub delay_ms: u64,
+    /// Characters (Backspaces included) per second, until the server had
+    /// processed them all
+    pub chars_per_sec: f64,
+    /// Flushes retried because the socket was full
+    pub flush_retries: usize,
+    /// Pauses adaptive throttling inserted
+    pub throttle_events: usize,
+    /// Whether the server kept up: no retries and no throttling
+    pub kept_up: bool,
+}
 
-        let response = proxy
-            .start(&session, None)
-            .await
-            .map_err(|e| EiTypeError::Connection(format!("Failed to start session: {}", e)))?
-            .response()
-            .map_err(|e| {
-                EiTypeError::Connection(format!("Failed to get session response: {}", e))
-            })?;
+/// What `EiType::calibrate` found
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct Calibration {
+    /// The rates tried, slowest first; the last one didn't keep up unless
+    /// they all did
+    pub steps: Vec<CalibrationStep>,
+    /// Fastest key delay the server kept up with, if any
+    pub recommended_delay_ms: Option<u64>,
+    /// Characters per second at that delay
+    pub recommended_chars_per_sec: Option<f64>,
+    /// Highest rate reached, whether the server kept up or not
+    pub ceiling_chars_per_sec: f64,
+}
 
-        let new_token = response.restore_token().map(|s| s.to_string());
-        if new_token.is_some() {
-            debug!("Received new restore token from portal");
+impl Calibration {
+    fn from_steps(steps: Vec<CalibrationStep>) -> Self {
+        let recommended = steps.iter().rfind(|step| step.kept_up);
+        Self {
+            recommended_delay_ms: recommended.map(|step| step.delay_ms),
+            recommended_chars_per_sec: recommended.map(|step| step.chars_per_sec),
+            ceiling_chars_per_sec: steps
+                .iter()
+                .map(|step| step.chars_per_sec)
+                .fold(0.0, f64::max),
+            steps,
         }
+    }
+}
 
-        let fd = proxy
-            .connect_to_eis(&session)
-            .await
-            .map_err(|e| EiTypeError::Connection(format!("Failed to connect to EIS: {}", e)))?;
+/// What `EiType::bench` types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchOptions {
+    /// Characters of pangram text to type (default: 2000)
+    pub chars: usize,
+    /// Delete the text with one Backspace per character afterwards, so
+    /// nothing is left behind (default: true)
+    pub erase: bool,
+}
 
-        let stream = UnixStream::from(fd);
-        stream
-            .set_nonblocking(true)
-            .map_err(|e| EiTypeError::Connection(format!("Failed to set non-blocking: {}", e)))?;
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            chars: 2000,
+            erase: true,
+        }
+    }
+}
 
-        Ok((stream, new_token))
-    })
+/// Percentiles of one part of the per-character times `EiType::bench`
+/// recorded (nearest rank)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
 }
 
-/// Connect to EI via socket
-fn connect_via_socket(path: &Path) -> Result<UnixStream, EiTypeError> {
-    info!("Connecting to socket: {:?}", path);
+impl LatencyPercentiles {
+    fn of(mut durations: Vec<Duration>) -> Self {
+        durations.sort_unstable();
+        let rank = |percent: usize| {
+            let index = (durations.len() * percent).div_ceil(100).saturating_sub(1);
+            durations.get(index).copied().unwrap_or_default()
+        };
+        Self {
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: durations.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// What `EiType::bench` measured. The Backspaces that erase the text are
+/// left out of every figure.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct BenchReport {
+    /// Characters typed
+    pub chars: usize,
+    /// From the first character until the server had processed the last
+    pub elapsed: Duration,
+    pub chars_per_sec: f64,
+    /// Flushes retried because the socket was full
+    pub flush_retries: usize,
+    /// Pauses adaptive throttling inserted
+    pub throttle_events: usize,
+    /// Characters the percentiles below are taken from: the last
+    /// `TimingTrace::CAPACITY` (100000) when more were typed
+    pub sampled: usize,
+    /// Looking up each character's keys (`CharTiming::plan`)
+    pub plan: LatencyPercentiles,
+    /// Flushing each character's events (`CharTiming::flush`)
+    pub flush: LatencyPercentiles,
+    /// From one character to the next (`CharTiming::gap`)
+    pub gap: LatencyPercentiles,
+}
+
+/// Pangrams `EiType::bench` types, one after another
+const BENCH_PANGRAMS: &[&str] = &[
+    "The quick brown fox jumps over the lazy dog. ",
+    "Pack my box with five dozen liquor jugs! ",
+    "How vexingly quick daft zebras jump? ",
+    "Sphinx of black quartz, judge my vow. ",
+    "The five boxing wizards jump quickly. ",
+];
+
+/// `chars` characters of pangrams, repeated as often as needed
+fn bench_corpus(chars: usize) -> String {
+    BENCH_PANGRAMS
+        .iter()
+        .flat_map(|pangram| pangram.chars())
+        .cycle()
+        .take(chars)
+        .collect()
+}
+
+/// Key names that press a combination of keys, e.g. `press_key("backtab")`
+/// presses Shift+Tab. Each expands to a "+"-joined combo, which `press_key`
+/// also accepts directly.
+pub const NAMED_COMBOS: &[(&str, &str)] = &[
+    ("backtab", "shift+tab"),
+    ("shifttab", "shift+tab"),
+    ("redo", "ctrl+shift+z"),
+    ("paste", "ctrl+v"),
+    ("copy", "ctrl+c"),
+    ("cut", "ctrl+x"),
+    ("selectall", "ctrl+a"),
+];
+
+/// Every key name `press_key` accepts, sorted, with the combo each named
+/// combo expands to (localized aliases and "kc:N" are not listed)
+pub fn list_key_names() -> Vec<(String, Option<&'static str>)> {
+    let mut names: Vec<(String, Option<&'static str>)> = build_key_to_keycode_map()
+        .into_keys()
+        .map(|name| (name, None))
+        .chain(
+            NAMED_COMBOS
+                .iter()
+                .map(|(name, combo)| (name.to_string(), Some(*combo))),
+        )
+        .collect();
+    names.sort();
+    names
+}
+
+/// Version of the `Capabilities` layout; bumped when a field is renamed or
+/// removed, or changes meaning (adding fields or entries doesn't bump it)
+pub const CAPABILITIES_SCHEMA_VERSION: u32 = 1;
+
+/// Machine-readable catalogue of what this build of eitype supports, for
+/// tools that build pickers from it (see `capabilities`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `CAPABILITIES_SCHEMA_VERSION`
+    pub schema_version: u32,
+    /// The eitype version
+    pub version: &'static str,
+    /// Key names `press_key` accepts, sorted by name
+    pub keys: Vec<KeyCapability>,
+    /// Named combos (see `NAMED_COMBOS`)
+    pub combos: Vec<ComboCapability>,
+    /// Action kinds (see `Action::kind`) and their parameters
+    pub actions: Vec<ActionCapability>,
+    /// Ways text gets in when the configured layout can't type it:
+    /// "group_switch", "qwerty_fallback" (as in `TypeOutcome::fallbacks`) and
+    /// "paste" (`EiTypeConfig::paste_threshold`)
+    pub fallbacks: Vec<&'static str>,
+    /// Ways to connect: "portal" and "socket"
+    pub backends: Vec<&'static str>,
+    /// Optional cargo features compiled in, e.g. "graphemes"
+    pub features: Vec<&'static str>,
+}
+
+/// A key name `press_key` accepts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCapability {
+    pub name: String,
+    /// Linux evdev code of the key
+    pub code: u32,
+    /// Localized names that press the same key, e.g. "leertaste" for "space"
+    pub aliases: Vec<&'static str>,
+}
+
+/// A named combo and the keys it presses, e.g. "backtab" and "shift+tab"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComboCapability {
+    pub name: &'static str,
+    pub keys: &'static str,
+}
+
+/// An action kind and its parameters, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActionCapability {
+    /// As returned by `Action::kind`
+    pub kind: &'static str,
+    pub params: &'static [ActionParam],
+}
+
+/// One parameter of an action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActionParam {
+    pub name: &'static str,
+    /// "string", "integer", "number", "string_list", or "actions" (a list
+    /// of nested actions)
+    pub kind: &'static str,
+    /// The values a string accepts, or empty for any
+    pub choices: &'static [&'static str],
+    /// Whether the parameter can be left out
+    pub optional: bool,
+}
+
+impl ActionParam {
+    const fn required(name: &'static str, kind: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            choices: &[],
+            optional: false,
+        }
+    }
+}
+
+/// Parameters of every `Action` kind
+const ACTION_CAPABILITIES: &[ActionCapability] = &[
+    ActionCapability {
+        kind: "type",
+        params: &[ActionParam::required("text", "string")],
+    },
+    ActionCapability {
+        kind: "key",
+        params: &[ActionParam::required("key", "string")],
+    },
+    ActionCapability {
+        kind: "modifier_hold",
+        params: &[ActionParam::required("modifier", "string")],
+    },
+    ActionCapability {
+        kind: "modifier_press",
+        params: &[ActionParam::required("modifier", "string")],
+    },
+    ActionCapability {
+        kind: "key_down",
+        params: &[ActionParam::required("key", "string")],
+    },
+    ActionCapability {
+        kind: "key_up",
+        params: &[ActionParam::required("key", "string")],
+    },
+    ActionCapability {
+        kind: "keysym",
+        params: &[ActionParam::required("keysym", "string")],
+    },
+    ActionCapability {
+        kind: "paste",
+        params: &[
+            ActionParam::required("text", "string"),
+            ActionParam {
+                name: "selection",
+                kind: "string",
+                choices: &["clipboard", "primary"],
+                optional: true,
+            },
+            ActionParam {
+                name: "trigger",
+                kind: "string",
+                choices: &["ctrl-v", "ctrl-shift-v", "shift-insert", "middle-click"],
+                optional: true,
+            },
+            ActionParam {
+                name: "restore_after_ms",
+                kind: "integer",
+                choices: &[],
+                optional: true,
+            },
+        ],
+    },
+    ActionCapability {
+        kind: "scroll_discrete",
+        params: &[
+            ActionParam::required("dx", "integer"),
+            ActionParam::required("dy", "integer"),
+        ],
+    },
+    ActionCapability {
+        kind: "scroll_smooth",
+        params: &[
+            ActionParam::required("dx", "number"),
+            ActionParam::required("dy", "number"),
+        ],
+    },
+    ActionCapability {
+        kind: "tap",
+        params: &[
+            ActionParam::required("x", "number"),
+            ActionParam::required("y", "number"),
+        ],
+    },
+    ActionCapability {
+        kind: "with_modifiers",
+        params: &[
+            ActionParam::required("mods", "string_list"),
+            ActionParam::required("inner", "actions"),
+        ],
+    },
+    ActionCapability {
+        kind: "sleep",
+        params: &[ActionParam::required("ms", "integer")],
+    },
+    ActionCapability {
+        kind: "focus",
+        params: &[
+            ActionParam {
+                name: "app_id",
+                kind: "string",
+                choices: &[],
+                optional: true,
+            },
+            ActionParam {
+                name: "title",
+                kind: "string",
+                choices: &[],
+                optional: true,
+            },
+        ],
+    },
+];
+
+/// What this build of eitype supports: key names, combos, actions and their
+/// parameters, fallbacks, backends and features
+pub fn capabilities() -> Capabilities {
+    let mut keys: Vec<KeyCapability> = build_key_to_keycode_map()
+        .into_iter()
+        .map(|(name, code)| KeyCapability {
+            aliases: key_names::aliases_of(&name).collect(),
+            name,
+            code,
+        })
+        .collect();
+    keys.sort_by(|a, b| a.name.cmp(&b.name));
+    let features = [
+        ("graphemes", cfg!(feature = "graphemes")),
+        ("python", cfg!(feature = "python")),
+    ];
+    Capabilities {
+        schema_version: CAPABILITIES_SCHEMA_VERSION,
+        version: env!("CARGO_PKG_VERSION"),
+        keys,
+        combos: NAMED_COMBOS
+            .iter()
+            .map(|&(name, keys)| ComboCapability { name, keys })
+            .collect(),
+        actions: ACTION_CAPABILITIES.to_vec(),
+        fallbacks: vec!["group_switch", "qwerty_fallback", "paste"],
+        backends: vec!["portal", "socket"],
+        features: features
+            .into_iter()
+            .filter(|&(_, enabled)| enabled)
+            .map(|(name, _)| name)
+            .collect(),
+    }
+}
+
+// ============================================================================
+// Internal Utilities
+// ============================================================================
+
+/// Build a map of key names to keycodes (evdev codes)
+fn build_key_to_keycode_map() -> HashMap<String, u32> {
+    let mut map = HashMap::new();
+
+    // Modifiers
+    map.insert("shift".to_string(), 42);
+    map.insert("lshift".to_string(), 42);
+    map.insert("rshift".to_string(), 54);
+    map.insert("ctrl".to_string(), 29);
+    map.insert("control".to_string(), 29);
+    map.insert("lctrl".to_string(), 29);
+    map.insert("rctrl".to_string(), 97);
+    map.insert("alt".to_string(), 56);
+    map.insert("lalt".to_string(), 56);
+    map.insert("ralt".to_string(), 100);
+    map.insert("altgr".to_string(), 100);
+    map.insert("level3".to_string(), 100);
+    map.insert("super".to_string(), 125);
+    map.insert("meta".to_string(), 125);
+    map.insert("win".to_string(), 125);
+    map.insert("lsuper".to_string(), 125);
+    map.insert("rsuper".to_string(), 126);
+
+    // Special keys
+    map.insert("escape".to_string(), 1);
+    map.insert("esc".to_string(), 1);
+    map.insert("return".to_string(), 28);
+    map.insert("enter".to_string(), 28);
+    map.insert("tab".to_string(), 15);
+    map.insert("backspace".to_string(), 14);
+    map.insert("delete".to_string(), 111);
+    map.insert("insert".to_string(), 110);
+    map.insert("home".to_string(), 102);
+    map.insert("end".to_string(), 107);
+    map.insert("pageup".to_string(), 104);
+    map.insert("pagedown".to_string(), 109);
+    map.insert("space".to_string(), 57);
+    map.insert("capslock".to_string(), 58);
+    map.insert("numlock".to_string(), 69);
+    map.insert("scrolllock".to_string(), 70);
+    map.insert("print".to_string(), 99);
+    map.insert("printscreen".to_string(), 99);
+    map.insert("pause".to_string(), 119);
+    map.insert("menu".to_string(), 127);
+
+    // Arrow keys
+    map.insert("up".to_string(), 103);
+    map.insert("down".to_string(), 108);
+    map.insert("left".to_string(), 105);
+    map.insert("right".to_string(), 106);
+
+    // Function keys (F11 and F12 come after the keypad)
+    for i in 1..=10 {
+        map.insert(format!("f{}", i), 58 + i);
+    }
+    map.insert("f11".to_string(), 87);
+    map.insert("f12".to_string(), 88);
+
+    // Number keys (top row)
+    map.insert("1".to_string(), 2);
+    map.insert("2".to_string(), 3);
+    map.insert("3".to_string(), 4);
+    map.insert("4".to_string(), 5);
+    map.insert("5".to_string(), 6);
+    map.insert("6".to_string(), 7);
+    map.insert("7".to_string(), 8);
+    map.insert("8".to_string(), 9);
+    map.insert("9".to_string(), 10);
+    map.insert("0".to_string(), 11);
+
+    // Letter keys
+    let letters = "abcdefghijklmnopqrstuvwxyz";
+    let letter_codes = [
+        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
+        45, 21, 44,
+    ];
+    for (ch, code) in letters.chars().zip(letter_codes.iter()) {
+        map.insert(ch.to_string(), *code);
+    }
+
+    map
+}
+
+/// Punctuation on a US QWERTY keyboard: (character, evdev keycode, needs Shift).
+/// Used only when no keymap could be loaded at all.
+const QWERTY_SYMBOLS: &[(char, u32, bool)] = &[
+    (' ', 57, false),
+    ('\n', 28, false),
+    ('\t', 15, false),
+    ('-', 12, false),
+    ('=', 13, false),
+    ('[', 26, false),
+    (']', 27, false),
+    (';', 39, false),
+    ('\'', 40, false),
+    ('`', 41, false),
+    ('\\', 43, false),
+    (',', 51, false),
+    ('.', 52, false),
+    ('/', 53, false),
+    ('!', 2, true),
+    ('@', 3, true),
+    ('#', 4, true),
+    ('$', 5, true),
+    ('%', 6, true),
+    ('^', 7, true),
+    ('&', 8, true),
+    ('*', 9, true),
+    ('(', 10, true),
+    (')', 11, true),
+    ('_', 12, true),
+    ('+', 13, true),
+    ('{', 26, true),
+    ('}', 27, true),
+    (':', 39, true),
+    ('"', 40, true),
+    ('~', 41, true),
+    ('|', 43, true),
+    ('<', 51, true),
+    ('>', 52, true),
+    ('?', 53, true),
+];
+
+/// Special key names in the built-in table, with the keysym their key should
+/// produce at level 0. Checked against each keymap by `audit_key_table`.
+const SPECIAL_KEY_KEYSYMS: &[(&str, &str)] = &[
+    ("escape", "Escape"),
+    ("esc", "Escape"),
+    ("return", "Return"),
+    ("enter", "Return"),
+    ("tab", "Tab"),
+    ("backspace", "BackSpace"),
+    ("delete", "Delete"),
+    ("insert", "Insert"),
+    ("home", "Home"),
+    ("end", "End"),
+    ("pageup", "Prior"),
+    ("pagedown", "Next"),
+    ("space", "space"),
+    ("capslock", "Caps_Lock"),
+    ("numlock", "Num_Lock"),
+    ("scrolllock", "Scroll_Lock"),
+    ("print", "Print"),
+    ("printscreen", "Print"),
+    ("pause", "Pause"),
+    ("menu", "Menu"),
+    ("up", "Up"),
+    ("down", "Down"),
+    ("left", "Left"),
+    ("right", "Right"),
+    ("f1", "F1"),
+    ("f2", "F2"),
+    ("f3", "F3"),
+    ("f4", "F4"),
+    ("f5", "F5"),
+    ("f6", "F6"),
+    ("f7", "F7"),
+    ("f8", "F8"),
+    ("f9", "F9"),
+    ("f10", "F10"),
+    ("f11", "F11"),
+    ("f12", "F12"),
+];
+
+/// Keysyms that do the job of a special key's keysym, for keymaps where no
+/// key produces the keysym itself (e.g. Return remapped to a modifier).
+/// BackSpace and Escape have no such stand-ins.
+const SPECIAL_KEY_STAND_INS: &[(&str, &[&str])] = &[
+    ("Return", &["KP_Enter", "ISO_Enter"]),
+    ("Tab", &["KP_Tab", "ISO_Left_Tab"]),
+    ("space", &["KP_Space"]),
+];
+
+/// Check the special keys of `key_to_keycode` against `keymap`: does each
+/// keycode produce the expected keysym at level 0 of `layout_index`? For keys
+/// that don't, look for the key that does, or failing that for one that
+/// produces a stand-in (`SPECIAL_KEY_STAND_INS`).
+fn audit_key_table(
+    keymap: &xkb::Keymap,
+    layout_index: u32,
+    key_to_keycode: &HashMap<String, u32>,
+) -> Vec<KeyTableEntry> {
+    let produces = |evdev_keycode: u32, keysym: xkb::Keysym| {
+        let keycode = xkb::Keycode::new(evdev_keycode + 8);
+        let num_layouts = keymap.num_layouts_for_key(keycode);
+        let layout = if layout_index < num_layouts {
+            layout_index
+        } else {
+            0
+        };
+        num_layouts > 0
+            && keymap
+                .key_get_syms_by_level(keycode, layout, 0)
+                .contains(&keysym)
+    };
+    let min_keycode = u32::from(keymap.min_keycode()).max(8) - 8;
+    let max_keycode = u32::from(keymap.max_keycode()).saturating_sub(8);
+    let find = |keysym| (min_keycode..=max_keycode).find(|&keycode| produces(keycode, keysym));
+
+    SPECIAL_KEY_KEYSYMS
+        .iter()
+        .filter_map(|&(name, keysym_name)| {
+            let table_keycode = *key_to_keycode.get(name)?;
+            let keysym = keysym_from_name(keysym_name)?;
+            let mut substitute = None;
+            let keymap_keycode = if produces(table_keycode, keysym) {
+                Some(table_keycode)
+            } else {
+                find(keysym).or_else(|| {
+                    let (_, stand_ins) = SPECIAL_KEY_STAND_INS
+                        .iter()
+                        .find(|(keysym, _)| *keysym == keysym_name)?;
+                    stand_ins.iter().find_map(|&stand_in| {
+                        let keycode = find(keysym_from_name(stand_in)?)?;
+                        substitute = Some(stand_in.to_string());
+                        Some(keycode)
+                    })
+                })
+            };
+            Some(KeyTableEntry {
+                name: name.to_string(),
+                keysym: keysym_name.to_string(),
+                table_keycode,
+                keymap_keycode,
+                substitute,
+                keycode: table_keycode,
+            })
+        })
+        .collect()
+}
+
+/// Pacing for servers that can't keep up (`EiTypeConfig::adaptive_throttle`).
+///
+/// Keeps a small window of recent flushes, marking those that hit EAGAIN or
+/// were slow. Each such flush doubles the pause taken before every frame;
+/// once the last few flushes went through first time the pause is halved,
+/// until it disappears again.
+#[derive(Debug, Clone, Copy, Default)]
+struct AdaptiveThrottle {
+    /// One bit per recent flush, newest lowest; set = under pressure
+    window: u32,
+    /// Current pause before each frame
+    pause: Duration,
+}
+
+impl AdaptiveThrottle {
+    const MIN_PAUSE: Duration = Duration::from_micros(100);
+    const MAX_PAUSE: Duration = Duration::from_millis(5);
+    /// Clean flushes in a row needed before the pause is relaxed
+    const CALM_FLUSHES: u32 = 8;
+    /// A flush slower than this counts as pressure even without EAGAIN: a
+    /// flush that doesn't fill the socket returns in microseconds, so
+    /// milliseconds mean the kernel or the process was held up
+    const SLOW_FLUSH: Duration = Duration::from_millis(5);
+
+    /// Record the outcome of a flush and adjust the pause
+    fn record(&mut self, pressured: bool) {
+        self.window = (self.window << 1) | u32::from(pressured);
+        if pressured {
+            self.pause = (self.pause * 2).clamp(Self::MIN_PAUSE, Self::MAX_PAUSE);
+        } else if self.window.trailing_zeros() >= Self::CALM_FLUSHES {
+            self.pause /= 2;
+            if self.pause < Self::MIN_PAUSE {
+                self.pause = Duration::ZERO;
+            }
+        }
+    }
+}
+
+/// Keycode and Shift state for `ch` on US QWERTY, for typing without a keymap
+fn qwerty_fallback(ch: char, key_to_keycode: &HashMap<String, u32>) -> Option<(u32, bool)> {
+    if ch.is_ascii_alphanumeric() {
+        let mut name = [0; 4];
+        let name = ch.to_ascii_lowercase().encode_utf8(&mut name);
+        let keycode = key_to_keycode.get(&*name)?;
+        return Some((*keycode, ch.is_ascii_uppercase()));
+    }
+    QWERTY_SYMBOLS
+        .iter()
+        .find(|(symbol, _, _)| *symbol == ch)
+        .map(|&(_, keycode, shift)| (keycode, shift))
+}
+
+/// Keycode of the "shift" key name, or of left Shift if there is none
+fn qwerty_shift_keycode(key_to_keycode: &HashMap<String, u32>) -> u32 {
+    key_to_keycode.get("shift").copied().unwrap_or(42)
+}
+
+/// Result of locating a character in the keymap.
+///
+/// Encodes everything `type_char` needs: which physical key to tap (`evdev_keycode`),
+/// and where in the keymap the keysym lives (`layout` + `level`) so we can ask
+/// libxkbcommon which modifier keys to hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeyMatch {
+    evdev_keycode: u32,
+    layout: u32,
+    level: u32,
+}
+
+/// Find the keycode for a character, plus the layout/level it was found at.
+fn find_keycode_for_char(
+    ch: char,
+    keymap: &xkb::Keymap,
+    layout_index: u32,
+) -> Result<KeyMatch, EiTypeError> {
+    find_key_match(keymap, layout_index, |sym| keysym_to_char(sym) == Some(ch))
+        .ok_or(EiTypeError::CharNotFound(ch))
+}
+
+/// Find the keycode for a keysym, plus the layout/level it was found at.
+fn find_keycode_for_keysym(
+    keysym: xkb::Keysym,
+    keymap: &xkb::Keymap,
+    layout_index: u32,
+) -> Option<KeyMatch> {
+    let target: u32 = keysym.into();
+    find_key_match(keymap, layout_index, |sym| sym == target)
+}
+
+/// Find the first key/level whose keysym satisfies `matches`.
+///
+/// Uses a two-pass approach:
+/// 1. First pass: search only keys that explicitly define the requested layout (exact match)
+/// 2. Second pass: fall back to layout 0 for keys that don't define the requested layout
+///    (layout-independent keys like space, enter, tab)
+///
+/// This ensures keys at the requested layout take priority, while layout-independent
+/// keys are still found via fallback.
+fn find_key_match(
+    keymap: &xkb::Keymap,
+    layout_index: u32,
+    matches: impl Fn(u32) -> bool,
+) -> Option<KeyMatch> {
+    let min_keycode: u32 = keymap.min_keycode().into();
+    let max_keycode: u32 = keymap.max_keycode().into();
+
+    // Pass 1: search keys that have the requested layout
+    for keycode_raw in min_keycode..=max_keycode {
+        let keycode = xkb::Keycode::new(keycode_raw);
+        let num_layouts = keymap.num_layouts_for_key(keycode);
+
+        if layout_index < num_layouts {
+            if let Some(result) =
+                search_key_for_keysym(&matches, keymap, keycode, keycode_raw, layout_index)
+            {
+                return Some(result);
+            }
+        }
+    }
+
+    // Pass 2: fall back to layout 0 for keys that don't define the requested layout
+    if layout_index != 0 {
+        for keycode_raw in min_keycode..=max_keycode {
+            let keycode = xkb::Keycode::new(keycode_raw);
+            let num_layouts = keymap.num_layouts_for_key(keycode);
+
+            if layout_index >= num_layouts && num_layouts > 0 {
+                if let Some(result) =
+                    search_key_for_keysym(&matches, keymap, keycode, keycode_raw, 0)
+                {
+                    return Some(result);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Characters of every keysym `find_key_match` can find in `layout_index`:
+/// on keys that have that layout, and in layout 0 of keys that don't
+fn layout_chars(keymap: &xkb::Keymap, layout_index: u32) -> Vec<char> {
+    let min_keycode: u32 = keymap.min_keycode().into();
+    let max_keycode: u32 = keymap.max_keycode().into();
+    let mut chars = Vec::new();
+    for keycode_raw in min_keycode..=max_keycode {
+        let keycode = xkb::Keycode::new(keycode_raw);
+        let layout = match keymap.num_layouts_for_key(keycode) {
+            0 => continue,
+            n if layout_index < n => layout_index,
+            _ => 0,
+        };
+        for level in 0..keymap.num_levels_for_key(keycode, layout) {
+            let syms = keymap.key_get_syms_by_level(keycode, layout, level);
+            chars.extend(syms.iter().filter_map(|&sym| keysym_to_char(sym.into())));
+        }
+    }
+    chars
+}
+
+/// Search a single key at a given layout for a keysym satisfying `matches`.
+fn search_key_for_keysym(
+    matches: &impl Fn(u32) -> bool,
+    keymap: &xkb::Keymap,
+    keycode: xkb::Keycode,
+    keycode_raw: u32,
+    layout: u32,
+) -> Option<KeyMatch> {
+    let num_levels = keymap.num_levels_for_key(keycode, layout);
+
+    for level in 0..num_levels {
+        let syms = keymap.key_get_syms_by_level(keycode, layout, level);
+
+        for sym in syms {
+            if matches((*sym).into()) {
+                return Some(KeyMatch {
+                    evdev_keycode: keycode_raw - 8,
+                    layout,
+                    level,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Resolve an XKB keysym name (e.g. "eacute", "Cyrillic_de") to a keysym.
+///
+/// Exact (case-sensitive) names are preferred; a case-insensitive match is
+/// accepted as a fallback so `"Return"` and `"return"` both work.
+fn keysym_from_name(name: &str) -> Option<xkb::Keysym> {
+    let sym = xkb::keysym_from_name(name, xkb::KEYSYM_NO_FLAGS);
+    if sym.raw() != xkb::keysyms::KEY_NoSymbol {
+        return Some(sym);
+    }
+    let sym = xkb::keysym_from_name(name, xkb::KEYSYM_CASE_INSENSITIVE);
+    (sym.raw() != xkb::keysyms::KEY_NoSymbol).then_some(sym)
+}
+
+/// Keycodes of the modifiers held for one character; kept inline, as no
+/// level takes more than a few
+type ModifierKeycodes = smallvec::SmallVec<[u32; 4]>;
+
+/// Translate a `KeyMatch` into the modifier keycodes that must be held to reach
+/// its level.
+///
+/// Asks libxkbcommon (`xkb_keymap_key_get_mods_for_level`) for the modifier-mask
+/// candidates that produce the requested level on this specific key, then picks
+/// the simplest mask that resolves entirely to modifier keys we know how to press
+/// (Shift / Control / Mod1=Alt / Mod4=Super / Mod5=AltGr).
+///
+/// Masks containing Lock (caps lock), Mod2 (typically NumLock), or Mod3 are
+/// rejected so we never toggle locking state to type a single character.
+///
+/// `keymap_mod_keycodes` maps an xkb real-modifier name (e.g. "Mod5") to the
+/// evdev keycode of a key that produces that modifier *in this specific keymap* —
+/// see `build_keymap_modifier_map`. This is what makes the function correct under
+/// xkb-options like `lv3:ralt_alt` that move ISO_Level3_Shift off Right Alt.
+fn modifier_keycodes_for_match(
+    keymap: &xkb::Keymap,
+    m: &KeyMatch,
+    keymap_mod_keycodes: &HashMap<String, u32>,
+) -> ModifierKeycodes {
+    if m.level == 0 {
+        return ModifierKeycodes::new();
+    }
+
+    let xkb_keycode = xkb::Keycode::new(m.evdev_keycode + 8);
+    let mut masks = [xkb::ModMask::default(); 8];
+    let n = keymap.key_get_mods_for_level(xkb_keycode, m.layout, m.level, &mut masks);
+    if n == 0 {
+        return ModifierKeycodes::new();
+    }
+
+    // Among acceptable masks, prefer the one with the fewest modifier keys.
+    let mut best: Option<ModifierKeycodes> = None;
+    for &mask in &masks[..n] {
+        if let Some(keycodes) = mask_to_modifier_keycodes(mask, keymap, keymap_mod_keycodes) {
+            match &best {
+                Some(b) if b.len() <= keycodes.len() => {}
+                _ => best = Some(keycodes),
+            }
+        }
+    }
+    best.unwrap_or_default()
+}
+
+/// Convert one xkb modifier mask into the list of evdev keycodes we'd press to
+/// produce it. Returns `None` if the mask contains a modifier we don't support
+/// (Lock, Mod2, Mod3, or any unmapped name) so the caller can try a different mask.
+///
+/// Looks up keycodes via `keymap_mod_keycodes`, which was built from this exact
+/// keymap, so e.g. with `lv3:ralt_alt` set we'll resolve Mod5 to the Menu key
+/// rather than the hardcoded Right Alt.
+fn mask_to_modifier_keycodes(
+    mask: xkb::ModMask,
+    keymap: &xkb::Keymap,
+    keymap_mod_keycodes: &HashMap<String, u32>,
+) -> Option<ModifierKeycodes> {
+    let num_mods = keymap.num_mods();
+    let mut keycodes = ModifierKeycodes::new();
+    for idx in 0..num_mods {
+        if mask & (1 << idx) == 0 {
+            continue;
+        }
+        let mod_name = keymap.mod_get_name(idx);
+        match mod_name {
+            "Shift" | "Control" | "Mod1" | "Mod4" | "Mod5" => {}
+            _ => return None,
+        }
+        let kc = keymap_mod_keycodes.get(mod_name).copied()?;
+        keycodes.push(kc);
+    }
+    Some(keycodes)
+}
+
+/// Find the evdev keycode of a key that, when pressed, produces the given
+/// real-modifier index in this keymap.
+///
+/// Walks the keycode range and uses `xkb_state` to simulate a press, then checks
+/// whether the requested modifier bit became active. This honors the keymap's
+/// modmap (including remappings from xkb-options), so the result is always valid
+/// for the actual keymap in use — not a hardcoded assumption like "AltGr is RAlt".
+///
+/// Two heuristics are needed to pick the *right* keycode when several produce
+/// the same modifier:
+///
+/// 1. xkb's standard `evdev` keycodes file aliases low-numbered phantom keycodes
+///    (e.g. evdev 84 for ISO_Level3_Shift) alongside the real physical keys
+///    (e.g. RAlt=100, Menu=127). The phantoms share the modifier-keysym name but
+///    aren't reachable on physical hardware, so we group hits by their level-0
+///    keysym name and keep only the highest keycode per group — that filters
+///    phantoms.
+/// 2. Among distinct physical modifier keys (e.g. `Shift_L`=42 vs `Shift_R`=54),
+///    we pick the lowest keycode — Left variants are conventionally lower-numbered
+///    and are the more common choice for synthesized input.
+fn find_keycode_for_modifier(keymap: &xkb::Keymap, mod_idx: xkb::ModIndex) -> Option<u32> {
+    if mod_idx == xkb::MOD_INVALID {
+        return None;
+    }
+    let target_bit = 1u32 << mod_idx;
+    let min: u32 = keymap.min_keycode().into();
+    let max: u32 = keymap.max_keycode().into();
+
+    // Per keysym name, track the highest keycode that produces this modifier.
+    // Highest wins because xkb's phantom aliases are low-numbered.
+    let mut by_keysym: HashMap<String, u32> = HashMap::new();
+    for kc_raw in min..=max {
+        let kc = xkb::Keycode::new(kc_raw);
+        let mut state = xkb::State::new(keymap);
+        state.update_key(kc, xkb::KeyDirection::Down);
+        let mods = state.serialize_mods(xkb::STATE_MODS_DEPRESSED);
+        if mods & target_bit == 0 {
+            continue;
+        }
+        let level0_syms = keymap.key_get_syms_by_level(kc, 0, 0);
+        let sym_name = level0_syms
+            .first()
+            .map(|s| xkb::keysym_get_name(*s))
+            .unwrap_or_default();
+        let evdev = kc_raw - 8;
+        by_keysym
+            .entry(sym_name)
+            .and_modify(|e| {
+                if evdev > *e {
+                    *e = evdev;
+                }
+            })
+            .or_insert(evdev);
+    }
+
+    // Lowest among distinct physical keys → typically the Left variant.
+    by_keysym.values().copied().min()
+}
+
+/// The first key whose level 0 keysym is ISO_Level3_Shift
+/// (`AltGrMode::Level3Keysym`)
+fn find_level3_shift_keycode(keymap: &xkb::Keymap, layout_index: u32) -> Option<u32> {
+    let keysym = xkb::Keysym::new(xkb::keysyms::KEY_ISO_Level3_Shift);
+    find_keycode_for_keysym(keysym, keymap, layout_index)
+        .filter(|key_match| key_match.level == 0)
+        .map(|key_match| key_match.evdev_keycode)
+}
+
+/// Resolve the set of real modifiers we care about (Shift, Control, Mod1, Mod4,
+/// Mod5) to evdev keycodes for *this specific keymap*.
+///
+/// Modifiers that aren't bound to any key in the keymap are simply omitted —
+/// `mask_to_modifier_keycodes` will then reject any mask requiring them.
+fn build_keymap_modifier_map(keymap: &xkb::Keymap) -> HashMap<String, u32> {
+    let mut map = HashMap::new();
+    for name in ["Shift", "Control", "Mod1", "Mod4", "Mod5"] {
+        let idx = keymap.mod_get_index(name);
+        if let Some(kc) = find_keycode_for_modifier(keymap, idx) {
+            map.insert(name.to_string(), kc);
+        }
+    }
+    map
+}
+
+/// How a key switches the keyboard group (layout) in a given keymap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GroupSwitchKind {
+    /// Group changes only while the key is held (e.g. `grp:switch`, Mode_switch)
+    Momentary,
+    /// Group change applies to the next key only (ISO_Group_Latch)
+    Latch,
+    /// Group changes on press and stays (e.g. `grp:sclk_toggle`, ISO_Next_Group)
+    Lock,
+}
+
+/// A key that switches the keyboard group on its own (no chord required).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GroupSwitchKey {
+    evdev_keycode: u32,
+    kind: GroupSwitchKind,
+}
+
+/// Key events that switch into a group, and the events that switch back.
+///
+/// Events are `(evdev_keycode, pressed)` pairs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GroupSwitchPlan {
+    target: u32,
+    kind: GroupSwitchKind,
+    enter: Vec<(u32, bool)>,
+    exit: Vec<(u32, bool)>,
+}
+
+/// Replay key events on a fresh `xkb_state` locked to group `from` and return
+/// the resulting (effective, locked) group.
+fn simulate_group(keymap: &xkb::Keymap, from: u32, events: &[(u32, bool)]) -> (u32, u32) {
+    let mut state = xkb::State::new(keymap);
+    state.update_mask(0, 0, 0, 0, 0, from);
+    for &(keycode, pressed) in events {
+        let direction = if pressed {
+            xkb::KeyDirection::Down
+        } else {
+            xkb::KeyDirection::Up
+        };
+        state.update_key(xkb::Keycode::new(keycode + 8), direction);
+    }
+    (
+        state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+        state.serialize_layout(xkb::STATE_LAYOUT_LOCKED),
+    )
+}
+
+/// Find the keys that switch groups on their own in this keymap, and whether
+/// each one is momentary, latching, or locking.
+///
+/// Discovered by simulating a tap of every key in `xkb_state`, so it honors
+/// whatever `grp:` option the user configured. Results are ordered by keycode:
+/// xkb's built-in Mode_switch and ISO_Next_Group aliases live on high phantom
+/// keycodes, so the user's real switch key (Scroll Lock, Caps Lock, Right
+/// Alt, ...) is tried first.
+fn find_group_switch_keys(keymap: &xkb::Keymap) -> Vec<GroupSwitchKey> {
+    if keymap.num_layouts() < 2 {
+        return Vec::new();
+    }
+    let min: u32 = keymap.min_keycode().into();
+    let max: u32 = keymap.max_keycode().into();
+
+    let mut keys = Vec::new();
+    for kc_raw in min..=max {
+        let evdev = kc_raw - 8;
+        let (held, _) = simulate_group(keymap, 0, &[(evdev, true)]);
+        if held == 0 {
+            continue;
+        }
+        let mut state = xkb::State::new(keymap);
+        state.update_key(xkb::Keycode::new(kc_raw), xkb::KeyDirection::Down);
+        state.update_key(xkb::Keycode::new(kc_raw), xkb::KeyDirection::Up);
+        let kind = if state.serialize_layout(xkb::STATE_LAYOUT_LOCKED) != 0 {
+            GroupSwitchKind::Lock
+        } else if state.serialize_layout(xkb::STATE_LAYOUT_LATCHED) != 0 {
+            GroupSwitchKind::Latch
+        } else {
+            GroupSwitchKind::Momentary
+        };
+        keys.push(GroupSwitchKey {
+            evdev_keycode: evdev,
+            kind,
+        });
+    }
+    keys
+}
+
+/// Plan how to type a key (`char_keycode`) that lives in group `target` while
+/// the keyboard is in group `from`.
+///
+/// Momentary keys are held for the duration of the foreign run; lock toggles
+/// are tapped until `target` is active and tapped again afterwards until `from`
+/// is restored; latches are tapped once before each key.
+///
+/// Every candidate plan is checked by simulating it in `xkb_state`: entering
+/// must make `target` effective and exiting (or, for latches, typing the key)
+/// must return both the effective and locked group to `from`. Leaving the
+/// user's layout switched after we exit would be worse than failing, so plans
+/// that don't pass are discarded.
+fn plan_group_switch(
+    keymap: &xkb::Keymap,
+    switch_keys: &[GroupSwitchKey],
+    from: u32,
+    target: u32,
+    char_keycode: u32,
+) -> Option<GroupSwitchPlan> {
+    let num_layouts = keymap.num_layouts();
+    for key in switch_keys {
+        let kc = key.evdev_keycode;
+        let tap = [(kc, true), (kc, false)];
+        let (enter, exit) = match key.kind {
+            GroupSwitchKind::Momentary => (vec![(kc, true)], vec![(kc, false)]),
+            GroupSwitchKind::Latch => (tap.to_vec(), Vec::new()),
+            GroupSwitchKind::Lock => {
+                let mut enter = Vec::new();
+                while enter.len() < 2 * num_layouts as usize
+                    && simulate_group(keymap, from, &enter).0 != target
+                {
+                    enter.extend(tap);
+                }
+                let mut exit = Vec::new();
+                while exit.len() < 2 * num_layouts as usize
+                    && simulate_group(keymap, target, &exit) != (from, from)
+                {
+                    exit.extend(tap);
+                }
+                (enter, exit)
+            }
+        };
+
+        if simulate_group(keymap, from, &enter).0 != target {
+            continue;
+        }
+        // Post-condition: after the whole sequence the group must be back where it started
+        let mut sequence = enter.clone();
+        if key.kind == GroupSwitchKind::Latch {
+            sequence.extend([(char_keycode, true), (char_keycode, false)]);
+        }
+        sequence.extend(&exit);
+        if simulate_group(keymap, from, &sequence) != (from, from) {
+            debug!(
+                "Group switch via keycode {} doesn't restore group {}, skipping",
+                kc, from
+            );
+            continue;
+        }
+
+        return Some(GroupSwitchPlan {
+            target,
+            kind: key.kind,
+            enter,
+            exit,
+        });
+    }
+    None
+}
+
+/// Find a character in a group other than `layout_index`.
+fn find_char_in_other_group(ch: char, keymap: &xkb::Keymap, layout_index: u32) -> Option<KeyMatch> {
+    let matches = |sym: u32| keysym_to_char(sym) == Some(ch);
+    let min_keycode: u32 = keymap.min_keycode().into();
+    let max_keycode: u32 = keymap.max_keycode().into();
+    for group in (0..keymap.num_layouts()).filter(|&g| g != layout_index) {
+        for keycode_raw in min_keycode..=max_keycode {
+            let keycode = xkb::Keycode::new(keycode_raw);
+            if group < keymap.num_layouts_for_key(keycode) {
+                if let Some(result) =
+                    search_key_for_keysym(&matches, keymap, keycode, keycode_raw, group)
+                {
+                    return Some(result);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// `text`'s grapheme clusters with their byte offsets, so that a cluster
+/// (an emoji ZWJ sequence, a flag, a letter and its combining accents) is
+/// typed whole or skipped whole
+#[cfg(feature = "graphemes")]
+fn grapheme_indices(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    unicode_segmentation::UnicodeSegmentation::grapheme_indices(text, true)
+}
+
+/// `text`'s characters with their byte offsets, each a cluster of its own
+/// without the `graphemes` feature
+#[cfg(not(feature = "graphemes"))]
+fn grapheme_indices(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.char_indices()
+        .map(|(offset, ch)| (offset, &text[offset..offset + ch.len_utf8()]))
+}
+
+/// Invisible format characters that turn up in copied and transcribed
+/// text, which no keymap types (`EiTypeConfig::strip_format_chars`), with
+/// their Unicode names. Joiners that change how visible characters render
+/// (ZWJ, ZWNJ) aren't among them.
+const FORMAT_CHARS: &[(char, &str)] = &[
+    ('\u{00AD}', "SOFT HYPHEN"),
+    ('\u{180E}', "MONGOLIAN VOWEL SEPARATOR"),
+    ('\u{200B}', "ZERO WIDTH SPACE"),
+    ('\u{200E}', "LEFT-TO-RIGHT MARK"),
+    ('\u{200F}', "RIGHT-TO-LEFT MARK"),
+    ('\u{2060}', "WORD JOINER"),
+    ('\u{2061}', "FUNCTION APPLICATION"),
+    ('\u{2062}', "INVISIBLE TIMES"),
+    ('\u{2063}', "INVISIBLE SEPARATOR"),
+    ('\u{2064}', "INVISIBLE PLUS"),
+    ('\u{FEFF}', "ZERO WIDTH NO-BREAK SPACE"),
+];
+
+/// The Unicode name of `ch` if it is one of `FORMAT_CHARS`
+fn format_char_name(ch: char) -> Option<&'static str> {
+    FORMAT_CHARS
+        .iter()
+        .find(|&&(format_char, _)| format_char == ch)
+        .map(|&(_, name)| name)
+}
+
+/// Whether `cluster` is a single one of `FORMAT_CHARS` (which are grapheme
+/// clusters of their own)
+fn is_format_char_cluster(cluster: &str) -> bool {
+    let mut chars = cluster.chars();
+    matches!((chars.next(), chars.next()), (Some(ch), None) if format_char_name(ch).is_some())
+}
+
+/// `ch` for error messages: as is if it is visible, by code point (and
+/// name, for `FORMAT_CHARS`) otherwise, e.g. "U+200B ZERO WIDTH SPACE"
+fn describe_char(ch: char) -> String {
+    match format_char_name(ch) {
+        Some(name) => format!("U+{:04X} {}", ch as u32, name),
+        None if ch.is_control() => format!("U+{:04X}", ch as u32),
+        None => ch.to_string(),
+    }
+}
+
+/// An ASCII approximation of `text` (é as e, … as ...), or `None` if a
+/// character has none. Trailing spaces some scripts get (北 as "Bei ") are
+/// dropped.
+#[cfg(feature = "transliterate")]
+fn ascii_approximation(text: &str) -> Option<String> {
+    let ascii: String = text
+        .chars()
+        .map(deunicode::deunicode_char)
+        .collect::<Option<_>>()?;
+    let ascii = ascii.trim_end();
+    (!ascii.is_empty()).then(|| ascii.to_string())
+}
+
+/// Without the `transliterate` feature nothing has an approximation
+#[cfg(not(feature = "transliterate"))]
+fn ascii_approximation(_text: &str) -> Option<String> {
+    None
+}
+
+/// Convert text typed on the wrong layout into what the same keystrokes
+/// produce on another layout of `keymap`, e.g. "ghbdtn" (us) to "привет" (ru).
+///
+/// Each character is located (keycode and level) in layout `from_layout_index`
+/// and replaced by the character at the same key and level in
+/// `to_layout_index`. Characters that aren't on the source layout, or whose
+/// key has nothing at that level in the target layout, are kept as they are.
+pub fn convert_layout(
+    text: &str,
+    from_layout_index: u32,
+    to_layout_index: u32,
+    keymap: &xkb::Keymap,
+) -> String {
+    text.chars()
+        .map(|ch| convert_char(ch, from_layout_index, to_layout_index, keymap).unwrap_or(ch))
+        .collect()
+}
+
+/// `ch` moved from layout `from` to layout `to`, if it can be mapped
+fn convert_char(ch: char, from: u32, to: u32, keymap: &xkb::Keymap) -> Option<char> {
+    let key_match = find_keycode_for_char(ch, keymap, from).ok()?;
+    if key_match.layout != from {
+        // A layout-independent key (space, Return, ...)
+        return None;
+    }
+    let keycode = xkb::Keycode::new(key_match.evdev_keycode + 8);
+    if to >= keymap.num_layouts_for_key(keycode) {
+        return None;
+    }
+    keymap
+        .key_get_syms_by_level(keycode, to, key_match.level)
+        .iter()
+        .find_map(|sym| keysym_to_char((*sym).into()))
+}
+
+/// Where a keymap puts a keysym, as found when typing it (see `KeymapDiff`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPosition {
+    /// Evdev keycode of the key
+    pub keycode: u32,
+    /// Shift level on that key, 0 for the unmodified keysym
+    pub level: u32,
+    /// Modifiers that reach `level`, e.g. `["Shift", "AltGr"]`
+    pub modifiers: Vec<String>,
+}
+
+impl std::fmt::Display for KeyPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "keycode {} level {}", self.keycode, self.level)?;
+        if !self.modifiers.is_empty() {
+            write!(f, " ({})", self.modifiers.join("+"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A keysym in a `KeymapDiff`, with where each keymap puts it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysymDiff {
+    /// Keysym name, e.g. "adiaeresis"
+    pub keysym: String,
+    /// The character the keysym types, if any
+    pub ch: Option<char>,
+    /// Position in the first keymap (`None` if it has no such keysym)
+    pub a: Option<KeyPosition>,
+    /// Position in the second keymap (`None` if it has no such keysym)
+    pub b: Option<KeyPosition>,
+}
+
+/// How two keymaps differ in what they can type, from `diff_keymaps`.
+///
+/// Each list is sorted by keysym name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeymapDiff {
+    /// Keysyms only the first keymap has
+    pub only_in_a: Vec<KeysymDiff>,
+    /// Keysyms only the second keymap has
+    pub only_in_b: Vec<KeysymDiff>,
+    /// Keysyms both have, but on a different key, level or modifiers
+    pub moved: Vec<KeysymDiff>,
+}
+
+impl KeymapDiff {
+    /// Whether the keymaps type the same keysyms the same way
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.moved.is_empty()
+    }
+}
+
+/// Compare what the first layouts of two keymaps can type, to explain why
+/// text comes out right on one machine and wrong on another.
+///
+/// Each keysym is looked up the way typing looks it up (the first key and
+/// level producing it), so a keysym that is on both keymaps but reached
+/// differently (e.g. the apostrophe behind AltGr on `us(intl)`) shows up in
+/// `moved`.
+pub fn diff_keymaps(a: &xkb::Keymap, b: &xkb::Keymap) -> KeymapDiff {
+    let positions_a = keysym_positions(a);
+    let positions_b = keysym_positions(b);
+    let mut keysyms: Vec<u32> = positions_a
+        .keys()
+        .chain(positions_b.keys())
+        .copied()
+        .collect();
+    keysyms.sort_by_cached_key(|&keysym| xkb::keysym_get_name(xkb::Keysym::new(keysym)));
+    keysyms.dedup();
+
+    let mut diff = KeymapDiff::default();
+    for keysym in keysyms {
+        let entry = KeysymDiff {
+            keysym: xkb::keysym_get_name(xkb::Keysym::new(keysym)),
+            ch: keysym_to_char(keysym).filter(|ch| !ch.is_control()),
+            a: positions_a.get(&keysym).cloned(),
+            b: positions_b.get(&keysym).cloned(),
+        };
+        match (&entry.a, &entry.b) {
+            (Some(_), None) => diff.only_in_a.push(entry),
+            (None, Some(_)) => diff.only_in_b.push(entry),
+            (Some(a), Some(b)) if a != b => diff.moved.push(entry),
+            _ => {}
+        }
+    }
+    diff
+}
+
+/// Where layout 0 of `keymap` puts each keysym it has
+fn keysym_positions(keymap: &xkb::Keymap) -> HashMap<u32, KeyPosition> {
+    let min_keycode: u32 = keymap.min_keycode().into();
+    let max_keycode: u32 = keymap.max_keycode().into();
+    let mut positions = HashMap::new();
+    for keycode_raw in min_keycode..=max_keycode {
+        let keycode = xkb::Keycode::new(keycode_raw);
+        if keymap.num_layouts_for_key(keycode) == 0 {
+            continue;
+        }
+        for level in 0..keymap.num_levels_for_key(keycode, 0) {
+            for &sym in keymap.key_get_syms_by_level(keycode, 0, level) {
+                let sym = u32::from(sym);
+                if positions.contains_key(&sym) {
+                    continue;
+                }
+                if let Some(key_match) = find_keycode_for_keysym(xkb::Keysym::new(sym), keymap, 0) {
+                    positions.insert(
+                        sym,
+                        KeyPosition {
+                            keycode: key_match.evdev_keycode,
+                            level: key_match.level,
+                            modifiers: modifier_names_for_match(keymap, &key_match),
+                        },
+                    );
+                }
+            }
+        }
+    }
+    positions
+}
+
+/// Names of the modifiers that reach `m`'s level, from the simplest mask
+/// that does (preferring masks without locking modifiers, like
+/// `modifier_keycodes_for_match`)
+fn modifier_names_for_match(keymap: &xkb::Keymap, m: &KeyMatch) -> Vec<String> {
+    if m.level == 0 {
+        return Vec::new();
+    }
+    let xkb_keycode = xkb::Keycode::new(m.evdev_keycode + 8);
+    let mut masks = [xkb::ModMask::default(); 8];
+    let n = keymap.key_get_mods_for_level(xkb_keycode, m.layout, m.level, &mut masks);
+    let names = |mask: xkb::ModMask| -> Vec<&str> {
+        (0..keymap.num_mods())
+            .filter(|idx| mask & (1 << idx) != 0)
+            .map(|idx| keymap.mod_get_name(idx))
+            .collect()
+    };
+    let locking = |names: &[&str]| {
+        names
+            .iter()
+            .any(|name| matches!(*name, "Lock" | "Mod2" | "Mod3"))
+    };
+    let Some(best) = masks[..n]
+        .iter()
+        .map(|&mask| names(mask))
+        .min_by_key(|names| (locking(names), names.len()))
+    else {
+        return Vec::new();
+    };
+    best.into_iter()
+        .map(|name| match name {
+            "Mod1" => "Alt",
+            "Mod4" => "Super",
+            "Mod5" => "AltGr",
+            name => name,
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// XKB Linefeed keysym, whose character is '\n' (as is Return's, in
+/// `keysym_to_char`)
+const XK_LINEFEED: u32 = 0xff0a;
+
+/// Evdev keycode of the Linefeed key, for `EiTypeConfig::literal_whitespace`
+/// without a keymap
+const KEY_LINEFEED: u32 = 101;
+
+/// Convert an XKB keysym to a character
+fn keysym_to_char(keysym: u32) -> Option<char> {
+    // Map XK_Return to '\n' so callers can pass "\n" to press Enter.
+    // xkb_keysym_to_utf32 returns '\r' (U+000D) for this keysym.
+    if keysym == 0xff0d {
+        return Some('\n');
+    }
+    let codepoint = xkb::keysym_to_utf32(xkb::Keysym::new(keysym));
+    if codepoint == 0 {
+        None
+    } else {
+        char::from_u32(codepoint)
+    }
+}
+
+/// Poll the EI connection for a KeyboardModifiers event to detect the active layout group.
+/// Uses a short timeout to avoid blocking if no modifiers event is pending.
+fn poll_for_layout_group(events: &mut EventReader) -> Option<u32> {
+    // Wait up to 100ms for modifiers event from compositor.
+    // The event arrives asynchronously from Mutter's main loop after the device is resumed.
+    // 100ms is generous — in practice it arrives within a few milliseconds.
+    // If it doesn't arrive, the active group is likely 0 (default layout).
+    if !events.wait_readable(Some(Duration::from_millis(100))) {
+        debug!("No modifiers event received within timeout, using default layout 0");
+        return None;
+    }
+    match events.try_next() {
+        Ok(Some(EiEvent::KeyboardModifiers(mods))) => {
+            info!("Auto-detected active layout group: {}", mods.group);
+            Some(mods.group)
+        }
+        Ok(Some(other)) => {
+            debug!(
+                "Got {:?} instead of KeyboardModifiers, using default",
+                other
+            );
+            None
+        }
+        _ => None,
+    }
+}
+
+/// A compositor-detected layout index (or `None` if detection found
+/// nothing) and when it was detected
+type DetectedLayout = std::sync::Mutex<Option<(Instant, Option<u32>)>>;
+
+/// The last compositor-detected layout index, shared by every connection in
+/// the process (`EiTypeConfig::layout_detection_ttl_ms`)
+static DETECTED_LAYOUT: DetectedLayout = std::sync::Mutex::new(None);
+
+/// Forget the layout index detected by earlier connections, so the next one
+/// asks the desktop again
+pub fn clear_detected_layout_cache() {
+    *DETECTED_LAYOUT
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = None;
+}
+
+/// Layout index from `detect` (compositor-specific detection), unless
+/// `EiTypeConfig::auto_detect_layout` is off. A result in `cache` younger
+/// than the configured TTL is used instead of detecting again. The cache
+/// isn't locked while `detect` runs its subprocesses, so other connections
+/// never wait on them.
+fn auto_detected_layout_index(
+    config: &EiTypeConfig,
+    cache: &DetectedLayout,
+    detect: impl FnOnce() -> Option<u32>,
+) -> Option<u32> {
+    if !config.auto_detect_layout {
+        debug!("Layout auto-detection disabled");
+        return None;
+    }
+    let ttl = Duration::from_millis(config.layout_detection_ttl_ms);
+    let lock = || {
+        cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    };
+    let cached = *lock();
+    if let Some((detected_at, index)) = cached {
+        if detected_at.elapsed() < ttl {
+            debug!(
+                "Using layout index {:?} detected {:?} ago",
+                index,
+                detected_at.elapsed()
+            );
+            return index;
+        }
+    }
+    info!("No layout group from EI protocol, trying compositor-specific detection");
+    let index = detect();
+    *lock() = Some((Instant::now(), index));
+    index
+}
+
+/// Detect the active keyboard layout index of `keymap` using compositor-specific methods.
+/// Dispatches based on `$XDG_CURRENT_DESKTOP` and `$SWAYSOCK` environment variables.
+fn detect_active_layout_index(keymap: &xkb::Keymap) -> Option<u32> {
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+
+    if desktop.contains("GNOME") || desktop.contains("Unity") {
+        let result = detect_gnome_layout_index(keymap);
+        if result.is_some() {
+            return result;
+        }
+    } else if desktop.contains("KDE") {
+        let result = detect_kde_layout_index();
+        if result.is_some() {
+            return result;
+        }
+    }
+
+    if std::env::var("SWAYSOCK").is_ok() {
+        let result = detect_sway_layout_index();
+        if result.is_some() {
+            return result;
+        }
+    }
+
+    // If desktop wasn't recognized, try GNOME then KDE as generic fallbacks
+    if !desktop.contains("GNOME") && !desktop.contains("Unity") && !desktop.contains("KDE") {
+        if let Some(idx) = detect_gnome_layout_index(keymap) {
+            return Some(idx);
+        }
+        if let Some(idx) = detect_kde_layout_index() {
+            return Some(idx);
+        }
+    }
+
+    None
+}
+
+/// Detect active layout index on GNOME by comparing `mru-sources` against `sources`.
+fn detect_gnome_layout_index(keymap: &xkb::Keymap) -> Option<u32> {
+    let mru_output = command_stdout(
+        "gsettings",
+        &["get", "org.gnome.desktop.input-sources", "mru-sources"],
+    )?;
+    let sources_output = command_stdout(
+        "gsettings",
+        &["get", "org.gnome.desktop.input-sources", "sources"],
+    )?;
+    gnome_layout_index(mru_output.trim(), sources_output.trim(), keymap)
+}
+
+/// Layout index of GNOME's active input source, given the `mru-sources` and
+/// `sources` settings as printed by `gsettings get`.
+///
+/// The first entry in `mru-sources` is the currently active input source. We find its
+/// position in the `sources` list, counting only `xkb` type sources (IBus sources don't
+/// get their own XKB group), then check it against `keymap`.
+fn gnome_layout_index(mru_output: &str, sources_output: &str, keymap: &xkb::Keymap) -> Option<u32> {
+    // mru-sources can be empty (@a(ss) []) if never switched
+    if mru_output.starts_with("@") || mru_output == "[]" {
+        debug!("mru-sources is empty, assuming layout index 0");
+        return Some(0);
+    }
+
+    let mru_entries = parse_gnome_input_sources(mru_output);
+    let source_entries = parse_gnome_input_sources(sources_output);
+
+    if mru_entries.is_empty() || source_entries.is_empty() {
+        warn!(
+            "Failed to parse GNOME input sources: mru={:?}, sources={:?}",
+            mru_output, sources_output
+        );
+        return None;
+    }
+
+    let active = &mru_entries[0];
+    let xkb_sources = source_entries.iter().filter(|e| e.0 == "xkb").count();
+
+    // Find the position of the active source in the sources list,
+    // counting only xkb sources (IBus engines don't get their own XKB group).
+    let mut xkb_index = 0u32;
+    for entry in &source_entries {
+        if entry.0 == "xkb" {
+            if entry == active {
+                info!(
+                    "GNOME: detected active layout '{}' at XKB group index {}",
+                    active.1, xkb_index
+                );
+                return check_gnome_layout(keymap, &active.1, xkb_index, xkb_sources);
+            }
+            xkb_index += 1;
+        } else if entry == active {
+            // Active source is an IBus engine, not an XKB layout — use group 0
+            info!(
+                "GNOME: active source '{}' is IBus, using XKB group index 0",
+                active.1
+            );
+            return Some(0);
+        }
+    }
+
+    warn!(
+        "GNOME: active source {:?} not found in sources list {:?}",
+        active, source_entries
+    );
+    None
+}
+
+/// Check GNOME's XKB input source `source_id` (e.g. "us+dvp"), found at
+/// `index` of `xkb_sources` sources, against the loaded `keymap`.
+///
+/// Mismatches (a different number of layouts, or another layout at `index`)
+/// are logged, since they mean the keymap isn't the one GNOME is using and
+/// text may come out in the wrong layout. Returns `index`, or `None` if the
+/// keymap has no such layout.
+fn check_gnome_layout(
+    keymap: &xkb::Keymap,
+    source_id: &str,
+    index: u32,
+    xkb_sources: usize,
+) -> Option<u32> {
+    let num_layouts = keymap.num_layouts();
+    if xkb_sources != num_layouts as usize {
+        warn!(
+            "GNOME has {} XKB input sources but the keymap has {} layouts",
+            xkb_sources, num_layouts
+        );
+    }
+    if index >= num_layouts {
+        warn!(
+            "GNOME: layout index {} ('{}') is out of range for the keymap, ignoring it",
+            index, source_id
+        );
+        return None;
+    }
+
+    // Compare layout names, e.g. "English (programmer Dvorak)"
+    let (layout, variant) = source_id.split_once('+').unwrap_or((source_id, ""));
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let expected = xkb::Keymap::new_from_names(
+        &context,
+        "",
+        "",
+        layout,
+        variant,
+        None,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    );
+    if let Some(expected) = expected {
+        let expected_name = expected.layout_get_name(0);
+        let actual_name = keymap.layout_get_name(index);
+        if expected_name != actual_name {
+            warn!(
+                "GNOME: active source '{}' is \"{}\", but layout {} of the keymap is \"{}\"",
+                source_id, expected_name, index, actual_name
+            );
+        }
+    }
+    Some(index)
+}
+
+/// Parse GNOME GVariant input source list format.
+/// Input: `[('xkb', 'us+dvp'), ('xkb', 'us')]`
+/// Output: `vec![("xkb", "us+dvp"), ("xkb", "us")]`
+fn parse_gnome_input_sources(input: &str) -> Vec<(String, String)> {
+    let trimmed = input.trim().trim_start_matches('[').trim_end_matches(']');
+    if trimmed.is_empty() {
+        return Vec::new();
+    }
+
+    let mut results = Vec::new();
+    // Split on "), (" to separate entries, handling the surrounding parens/quotes
+    for entry in trimmed.split("), (") {
+        let entry = entry.trim().trim_start_matches('(').trim_end_matches(')');
+        // entry is now: 'type', 'id'
+        let parts: Vec<&str> = entry.splitn(2, ", ").collect();
+        if parts.len() == 2 {
+            let source_type = parts[0].trim().trim_matches('\'');
+            let source_id = parts[1].trim().trim_matches('\'');
+            results.push((source_type.to_string(), source_id.to_string()));
+        }
+    }
+    results
+}
+
+/// Detect active layout index on KDE Plasma via D-Bus.
+fn detect_kde_layout_index() -> Option<u32> {
+    use std::process::Command;
+
+    // Try qdbus6 first (Plasma 6 / Qt6), then qdbus (Plasma 5 / Qt5)
+    for cmd in &["qdbus6", "qdbus"] {
+        let output = Command::new(cmd)
+            .args([
+                "org.kde.keyboard",
+                "/Layouts",
+                "org.kde.KeyboardLayouts.getLayout",
+            ])
+            .output();
+
+        if let Ok(o) = output {
+            if o.status.success() {
+                let text = String::from_utf8_lossy(&o.stdout).trim().to_string();
+                if let Ok(idx) = text.parse::<u32>() {
+                    info!("KDE: detected active layout index {} via {}", idx, cmd);
+                    return Some(idx);
+                }
+            }
+        }
+    }
+
+    // Fall back to dbus-send (no Qt dependency)
+    let output = Command::new("dbus-send")
+        .args([
+            "--session",
+            "--print-reply",
+            "--dest=org.kde.keyboard",
+            "/Layouts",
+            "org.kde.KeyboardLayouts.getLayout",
+        ])
+        .output();
+
+    match output {
+        Ok(o) if o.status.success() => {
+            let text = String::from_utf8_lossy(&o.stdout);
+            // dbus-send output format: "   int32 1\n"
+            for line in text.lines() {
+                let line = line.trim();
+                if let Some(val) = line
+                    .strip_prefix("int32 ")
+                    .or_else(|| line.strip_prefix("uint32 "))
+                {
+                    if let Ok(idx) = val.trim().parse::<u32>() {
+                        info!("KDE: detected active layout index {} via dbus-send", idx);
+                        return Some(idx);
+                    }
+                }
+            }
+            warn!("KDE: could not parse dbus-send output: {}", text.trim());
+            None
+        }
+        Ok(_) => {
+            debug!("KDE keyboard D-Bus service not available");
+            None
+        }
+        Err(e) => {
+            warn!("Failed to run dbus-send: {}", e);
+            None
+        }
+    }
+}
+
+/// Standard output of `program` run with `args`, or `None` (logged) if it
+/// couldn't be run or failed
+fn command_stdout(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program).args(args).output();
+    match output {
+        Ok(o) if o.status.success() => Some(String::from_utf8_lossy(&o.stdout).to_string()),
+        Ok(o) => {
+            warn!(
+                "{} failed: {}",
+                program,
+                String::from_utf8_lossy(&o.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Failed to run {}: {}", program, e);
+            None
+        }
+    }
+}
+
+/// Detect active layout index on Sway via `swaymsg -t get_inputs`.
+fn detect_sway_layout_index() -> Option<u32> {
+    let output = command_stdout("swaymsg", &["-t", "get_inputs", "--raw"])?;
+
+    // Parse JSON without adding serde_json dependency.
+    // Look for keyboard devices and extract xkb_active_layout_index.
+    // We find the first keyboard device's layout index.
+    parse_sway_layout_index(&output)
+}
+
+/// Extract `xkb_active_layout_index` from swaymsg JSON output.
+/// Uses simple string matching to avoid adding serde_json as a dependency.
+fn parse_sway_layout_index(json: &str) -> Option<u32> {
+    // Strategy: find blocks containing `"type": "keyboard"` and extract
+    // `xkb_active_layout_index` from the same object.
+    //
+    // We look for xkb_active_layout_index near a keyboard type indicator.
+    // Since swaymsg returns a flat array of input devices, we scan for
+    // the pattern in order.
+    let mut in_keyboard = false;
+
+    for line in json.lines() {
+        let trimmed = line.trim();
+
+        if trimmed.contains("\"type\"") && trimmed.contains("\"keyboard\"") {
+            in_keyboard = true;
+        } else if in_keyboard && trimmed.contains("\"type\"") {
+            // Moved past the keyboard device into another device type
+            in_keyboard = false;
+        }
+
+        if in_keyboard {
+            if let Some(idx_str) = trimmed.strip_prefix("\"xkb_active_layout_index\":") {
+                let idx_str = idx_str.trim().trim_end_matches(',');
+                if let Ok(idx) = idx_str.trim().parse::<u32>() {
+                    info!("Sway: detected active layout index {}", idx);
+                    return Some(idx);
+                }
+            }
+        }
+    }
+
+    warn!("Sway: no keyboard device with xkb_active_layout_index found");
+    None
+}
+
+/// Fail with `FocusGuardTriggered` if `detect` (compositor-specific
+/// detection) reports that `app_id` has keyboard focus
+fn check_focus_guard(
+    app_id: &str,
+    detect: impl FnOnce() -> Option<String>,
+) -> Result<(), EiTypeError> {
+    match detect() {
+        Some(focused) if focused.eq_ignore_ascii_case(app_id) => {
+            Err(EiTypeError::FocusGuardTriggered { focused })
+        }
+        focused => {
+            debug!("Focus guard: focused app {:?}", focused);
+            Ok(())
+        }
+    }
+}
+
+/// App id of the window with keyboard focus, asked of the compositor
+/// (Sway or Hyprland). `None` if nothing has focus or the compositor can't
+/// be asked; the latter is logged once, as the focus guard can't be enforced.
+fn detect_focused_app() -> Option<String> {
+    if std::env::var("SWAYSOCK").is_ok() {
+        let json = command_stdout("swaymsg", &["-t", "get_tree", "--raw"])?;
+        return parse_sway_focused_app(&json);
+    }
+    if std::env::var("HYPRLAND_INSTANCE_SIGNATURE").is_ok() {
+        let json = command_stdout("hyprctl", &["activewindow", "-j"])?;
+        return parse_hyprland_focused_app(&json);
+    }
+
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| {
+        warn!("Focus guard: this compositor can't report the focused app, typing anyway")
+    });
+    None
+}
+
+/// App id of the focused window in `swaymsg -t get_tree` JSON, or its X11
+/// class for an Xwayland window.
+///
+/// Sway lists a node's `app_id` and `window_properties` after its `focused`
+/// member, so only the members following `"focused": true` are searched.
+fn parse_sway_focused_app(json: &str) -> Option<String> {
+    const FOCUSED: &str = "\"focused\"";
+    let mut rest = json;
+    while let Some(pos) = rest.find(FOCUSED) {
+        rest = rest[pos + FOCUSED.len()..].trim_start();
+        let Some(node) = rest
+            .strip_prefix(':')
+            .and_then(|value| value.trim_start().strip_prefix("true"))
+        else {
+            continue;
+        };
+        let app_id = json_member(node, "app_id").and_then(json_string);
+        return app_id.or_else(|| {
+            let properties = json_member(node, "window_properties")?.strip_prefix('{')?;
+            json_member(properties, "class").and_then(json_string)
+        });
+    }
+    None
+}
+
+/// `class` (the app id) of the window in `hyprctl activewindow -j` JSON,
+/// which is `{}` when no window has focus
+fn parse_hyprland_focused_app(json: &str) -> Option<String> {
+    let window = json.trim_start().strip_prefix('{')?;
+    json_member(window, "class")
+        .and_then(json_string)
+        .filter(|class| !class.is_empty())
+}
+
+/// A compositor that can list and focus windows over IPC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompositorIpc {
+    Sway,
+    Hyprland,
+}
+
+impl CompositorIpc {
+    /// The compositor of this session, if its IPC is supported
+    fn detect() -> Option<Self> {
+        if std::env::var("SWAYSOCK").is_ok() {
+            Some(CompositorIpc::Sway)
+        } else if std::env::var("HYPRLAND_INSTANCE_SIGNATURE").is_ok() {
+            Some(CompositorIpc::Hyprland)
+        } else {
+            None
+        }
+    }
+
+    /// Program and arguments that print the window list as JSON
+    fn list_windows(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            CompositorIpc::Sway => ("swaymsg", &["-t", "get_tree", "--raw"]),
+            CompositorIpc::Hyprland => ("hyprctl", &["clients", "-j"]),
+        }
+    }
+
+    /// The id of the first window in `json` (from `list_windows`) that
+    /// matches `target`
+    fn find_window(self, json: &str, target: &FocusTarget) -> Option<String> {
+        match self {
+            CompositorIpc::Sway => parse_sway_window_id(json, target),
+            CompositorIpc::Hyprland => parse_hyprland_window_address(json, target),
+        }
+    }
+
+    /// Program and arguments that focus the window `id` (from `find_window`)
+    fn focus(self, id: &str) -> (&'static str, Vec<String>) {
+        match self {
+            CompositorIpc::Sway => ("swaymsg", vec![format!("[con_id={}]", id), "focus".into()]),
+            CompositorIpc::Hyprland => (
+                "hyprctl",
+                vec![
+                    "dispatch".into(),
+                    "focuswindow".into(),
+                    format!("address:{}", id),
+                ],
+            ),
+        }
+    }
+}
+
+/// Focus the window matching `target` through `ipc`, running commands with
+/// `run` (which returns their standard output, or `None` if they failed).
+/// `Ok(false)` (logged) if there is no `ipc` to do it with.
+fn focus_window_with(
+    target: &FocusTarget,
+    ipc: Option<CompositorIpc>,
+    mut run: impl FnMut(&str, &[&str]) -> Option<String>,
+) -> Result<bool, EiTypeError> {
+    let Some(ipc) = ipc else {
+        warn!(
+            "Can't focus the window with {}: this compositor has no supported IPC, typing into the focused window",
+            target
+        );
+        return Ok(false);
+    };
+    let not_found = || EiTypeError::FocusTargetNotFound(target.to_string());
+    let (program, args) = ipc.list_windows();
+    let json = run(program, args).ok_or_else(not_found)?;
+    let id = ipc.find_window(&json, target).ok_or_else(not_found)?;
+    info!("Focusing window {} ({})", id, target);
+    let (program, args) = ipc.focus(&id);
+    run(
+        program,
+        &args.iter().map(String::as_str).collect::<Vec<_>>(),
+    )
+    .ok_or_else(not_found)?;
+    Ok(true)
+}
+
+/// Con id of the first window in `swaymsg -t get_tree` JSON matching
+/// `target` by app id (or X11 class, for an Xwayland window) or title.
+///
+/// Every object in the tree is a candidate; only windows have a `pid`.
+fn parse_sway_window_id(json: &str, target: &FocusTarget) -> Option<String> {
+    json_objects(json).find_map(|node| {
+        json_member(node, "pid").filter(|pid| *pid != "null")?;
+        let app_id = json_member(node, "app_id")
+            .and_then(json_string)
+            .or_else(|| {
+                let properties = json_member(node, "window_properties")?.strip_prefix('{')?;
+                json_member(properties, "class").and_then(json_string)
+            });
+        let title = json_member(node, "name").and_then(json_string);
+        target
+            .matches(app_id.as_deref(), title.as_deref())
+            .then(|| json_member(node, "id").map(str::to_string))
+            .flatten()
+    })
+}
+
+/// Address of the first mapped window in `hyprctl clients -j` JSON
+/// matching `target` by class (the app id) or title
+fn parse_hyprland_window_address(json: &str, target: &FocusTarget) -> Option<String> {
+    json_objects(json).find_map(|client| {
+        let address = json_member(client, "address").and_then(json_string)?;
+        if json_member(client, "mapped") == Some("false") {
+            return None;
+        }
+        let class = json_member(client, "class").and_then(json_string);
+        let title = json_member(client, "title").and_then(json_string);
+        target
+            .matches(class.as_deref(), title.as_deref())
+            .then_some(address)
+    })
+}
+
+/// The inside of every JSON object in `json`, nested ones included, in
+/// document order (each running on to the end of `json`, as `json_member`
+/// expects)
+fn json_objects(json: &str) -> impl Iterator<Item = &str> {
+    let bytes = json.as_bytes();
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => i = json_string_end(bytes, i),
+                b'{' => {
+                    i += 1;
+                    return Some(&json[i..]);
+                }
+                _ => i += 1,
+            }
+        }
+        None
+    })
+}
+
+/// The raw value of member `key` of the JSON object `json` starts inside
+/// of, searching only members from there to the end of that object. Nested
+/// values and strings are skipped whole, so their contents never match.
+fn json_member<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let bytes = json.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let end = json_string_end(bytes, i);
+                if depth == 0 && &json[i + 1..end - 1] == key {
+                    let after = json[end..].trim_start();
+                    if let Some(value) = after.strip_prefix(':') {
+                        let value = value.trim_start();
+                        let start = json.len() - value.len();
+                        return Some(json[start..json_value_end(bytes, start)].trim_end());
+                    }
+                }
+                i = end;
+                continue;
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth == 0 => return None,
+            b'}' | b']' => depth -= 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Index just past the JSON string starting with the quote at `start`
+fn json_string_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Index just past the JSON value starting at `start`
+fn json_value_end(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0usize;
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                i = json_string_end(bytes, i);
+                if depth == 0 {
+                    return i;
+                }
+                continue;
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth == 0 => return i,
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            b',' if depth == 0 => return i,
+            _ => {}
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+/// The contents of a raw JSON string value (`None` for anything else, such
+/// as `null`)
+fn json_string(raw: &str) -> Option<String> {
+    let inner = raw.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next()? {
+            'n' => out.push('\n'),
+            't' => out.push('\t'),
+            'r' => out.push('\r'),
+            'u' => {
+                let hex: String = chars.by_ref().take(4).collect();
+                let code = u32::from_str_radix(&hex, 16).ok()?;
+                out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            other => out.push(other),
+        }
+    }
+    Some(out)
+}
+
+/// Replace the file at `path` with `contents`, creating its directory if
+/// need be. The contents go to a temporary file next to it first (created
+/// with `mode`, less the umask), renamed over `path` once written, so a
+/// crash or a concurrent reader never sees a partial file.
+fn write_file_atomically(path: &Path, contents: &[u8], mode: u32) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = dir.join(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Layout indices chosen by the user, keyed by keymap fingerprint.
+///
+/// Stored as a flat TOML table (`"<fingerprint>" = <index>`) so an index set
+/// once via `layout_index` or `set_layout_index` is reused by later
+/// connections with the same keymap instead of re-running auto-detection.
+struct LayoutCache {
+    path: PathBuf,
+}
+
+impl LayoutCache {
+    /// Cache in `$XDG_STATE_HOME/eitype/layout-cache.toml`
+    /// (default `~/.local/state/eitype/layout-cache.toml`)
+    fn open_default() -> Option<Self> {
+        let state_dir = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })?;
+        Some(Self::at(state_dir.join("eitype").join("layout-cache.toml")))
+    }
+
+    fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn load(&self) -> HashMap<u64, u32> {
+        let Ok(contents) = std::fs::read_to_string(&self.path) else {
+            return HashMap::new();
+        };
+        contents
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                let key = key.trim().trim_matches('"');
+                let fingerprint = u64::from_str_radix(key, 16).ok()?;
+                let index = value.trim().parse().ok()?;
+                Some((fingerprint, index))
+            })
+            .collect()
+    }
+
+    fn save(&self, entries: &HashMap<u64, u32>) -> std::io::Result<()> {
+        let mut sorted: Vec<_> = entries.iter().collect();
+        sorted.sort();
+        let mut contents =
+            String::from("# Layout index per keymap fingerprint, written by eitype\n");
+        for (fingerprint, index) in sorted {
+            contents.push_str(&format!("\"{:016x}\" = {}\n", fingerprint, index));
+        }
+        write_file_atomically(&self.path, contents.as_bytes(), 0o666)
+    }
+
+    /// Cached index for `fingerprint`, dropping the entry if it no longer
+    /// fits a keymap with `num_layouts` layouts
+    fn get(&self, fingerprint: u64, num_layouts: u32) -> Option<u32> {
+        let mut entries = self.load();
+        let index = *entries.get(&fingerprint)?;
+        if index < num_layouts {
+            return Some(index);
+        }
+        debug!(
+            "Dropping cached layout index {} (keymap has {} layouts)",
+            index, num_layouts
+        );
+        entries.remove(&fingerprint);
+        if let Err(e) = self.save(&entries) {
+            warn!("Failed to update layout cache {:?}: {}", self.path, e);
+        }
+        None
+    }
+
+    fn set(&self, fingerprint: u64, index: u32) {
+        let mut entries = self.load();
+        if entries.insert(fingerprint, index) == Some(index) {
+            return;
+        }
+        match self.save(&entries) {
+            Ok(()) => debug!("Cached layout index {} in {:?}", index, self.path),
+            Err(e) => warn!("Failed to write layout cache {:?}: {}", self.path, e),
+        }
+    }
+}
+
+/// Stable 64-bit fingerprint of a keymap: FNV-1a over its text serialization.
+///
+/// FNV is used instead of `DefaultHasher` because the value must not change
+/// between Rust releases or process runs; callers may persist it.
+fn keymap_fingerprint(keymap: &xkb::Keymap) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    keymap
+        .get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1)
+        .bytes()
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Compile the keymap to use for a connection and report where it came from.
+///
+/// Precedence: an explicit `keymap_string`, then `keymap_file`, then configured RMLVO names, then the
+/// keymap the EI server sent for the device (`server_keymap`, an fd + size), and
+/// finally the system default.
+fn load_keymap(
+    config: &EiTypeConfig,
+    server_keymap: Option<(OwnedFd, usize)>,
+) -> Result<(xkb::Keymap, KeymapSource), EiTypeError> {
+    let xkb_context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+
+    if (config.keymap_string.is_some() || config.keymap_file.is_some()) && config.is_specified() {
+        warn!("Ignoring configured layout names: an explicit keymap takes precedence");
+    }
+
+    if let Some(keymap_string) = &config.keymap_string {
+        info!("Loading keymap from configured keymap string");
+        let keymap = xkb::Keymap::new_from_string(
+            &xkb_context,
+            keymap_string.clone(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| EiTypeError::Keymap("Failed to compile keymap string".to_string()))?;
+        return Ok((keymap, KeymapSource::CustomString));
+    }
+
+    if let Some(path) = &config.keymap_file {
+        info!("Loading keymap from file {:?}", path);
+        let keymap_string = std::fs::read_to_string(path).map_err(|e| {
+            EiTypeError::Keymap(format!("Failed to read keymap file {:?}: {}", path, e))
+        })?;
+        let keymap = xkb::Keymap::new_from_string(
+            &xkb_context,
+            keymap_string,
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| EiTypeError::Keymap(format!("Failed to compile keymap file {:?}", path)))?;
+        return Ok((keymap, KeymapSource::File));
+    }
+
+    if config.is_specified() {
+        if server_keymap.is_some() {
+            info!("Using configured layout names instead of the EI server's keymap");
+        }
+        let rules = "";
+        let model = config.model.as_deref().unwrap_or("");
+        let layout = config.layout.as_deref().unwrap_or("");
+        let variant = config.variant.as_deref().unwrap_or("");
+        let options = config.options.clone();
+
+        info!(
+            "Loading keymap from configuration: layout={}, variant={}, model={}",
+            if layout.is_empty() {
+                "(default)"
+            } else {
+                layout
+            },
+            if variant.is_empty() {
+                "(none)"
+            } else {
+                variant
+            },
+            if model.is_empty() { "(default)" } else { model }
+        );
+
+        let keymap = xkb::Keymap::new_from_names(
+            &xkb_context,
+            rules,
+            model,
+            layout,
+            variant,
+            options,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| {
+            EiTypeError::Keymap("Failed to load keymap from configuration".to_string())
+        })?;
+
+        return Ok((keymap, KeymapSource::ConfigNames));
+    }
+
+    // Try to use the keymap provided by the EI server
+    if let Some((fd, size)) = server_keymap {
+        let keymap = unsafe {
+            xkb::Keymap::new_from_fd(
+                &xkb_context,
+                fd,
+                size,
+                xkb::KEYMAP_FORMAT_TEXT_V1,
+                xkb::KEYMAP_COMPILE_NO_FLAGS,
+            )
+        }
+        .map_err(|e| EiTypeError::Keymap(format!("Failed to read keymap from fd: {}", e)))?
+        .ok_or_else(|| EiTypeError::Keymap("Failed to compile keymap".to_string()))?;
+
+        let num_layouts = keymap.num_layouts();
+        if num_layouts > 0 {
+            let layout_name = keymap.layout_get_name(0);
+            info!(
+                "Keymap loaded from EI server: layout=\"{}\" ({} layout(s) available)",
+                layout_name, num_layouts
+            );
+            for i in 0..num_layouts {
+                debug!("  Layout {}: \"{}\"", i, keymap.layout_get_name(i));
+            }
+        } else {
+            info!("Keymap loaded from EI server (no layout name available)");
+        }
+
+        return Ok((keymap, KeymapSource::Server));
+    }
+
+    // Fallback: use system default keymap
+    info!("No keymap configured or sent by the EI server; loading system default keymap");
+
+    let keymap = xkb::Keymap::new_from_names(
+        &xkb_context,
+        "",
+        "",
+        "",
+        "",
+        None,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    )
+    .ok_or_else(|| EiTypeError::Keymap("Failed to load system default keymap".to_string()))?;
+
+    Ok((keymap, KeymapSource::SystemDefault))
+}
+
+/// A copy of the keymap the EI server sent for `device`, as an fd + size
+fn device_keymap(device: &reis::event::Device) -> Result<Option<(OwnedFd, usize)>, EiTypeError> {
+    let Some(keymap_info) = device.keymap() else {
+        return Ok(None);
+    };
+    use std::os::fd::FromRawFd;
+    use std::os::fd::IntoRawFd;
+    let fd_dup = rustix::io::dup(&keymap_info.fd)
+        .map_err(|e| EiTypeError::Keymap(format!("Failed to duplicate keymap fd: {}", e)))?;
+    let owned_fd = unsafe { OwnedFd::from_raw_fd(fd_dup.into_raw_fd()) };
+    Ok(Some((owned_fd, keymap_info.size as usize)))
+}
+
+/// Layout index to type in for `keymap`: an explicit index wins and is
+/// remembered in `cache`; otherwise prefer the index remembered for this
+/// keymap, then the server's (`from_server`), then auto-detection, then 0
+fn choose_layout_index(
+    config: &EiTypeConfig,
+    keymap: &xkb::Keymap,
+    cache: Option<&LayoutCache>,
+    from_server: impl FnOnce() -> Option<u32>,
+) -> u32 {
+    let fingerprint = keymap_fingerprint(keymap);
+    match config.layout_index {
+        Some(index) => {
+            if let Some(cache) = cache {
+                cache.set(fingerprint, index);
+            }
+            index
+        }
+        None => cache
+            .and_then(|cache| cache.get(fingerprint, keymap.num_layouts()))
+            .inspect(|index| info!("Using cached layout index {}", index))
+            .or_else(from_server)
+            .or_else(|| {
+                auto_detected_layout_index(config, &DETECTED_LAYOUT, || {
+                    detect_active_layout_index(keymap)
+                })
+            })
+            .unwrap_or(0),
+    }
+}
+
+/// Get current timestamp in microseconds
+fn get_timestamp() -> u64 {
+    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    let start = START.get_or_init(Instant::now);
+    start.elapsed().as_micros() as u64
+}
+
+/// Get the current `CLOCK_MONOTONIC` time in microseconds
+fn monotonic_timestamp() -> u64 {
+    let now = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+// ============================================================================
+// Restore Token Storage
+// ============================================================================
+
+/// Namespace whose token file takes over the one eitype used before tokens
+/// were namespaced (`$XDG_CACHE_HOME/eitype/restore_token`): the CLI's
+/// default, so an existing setup keeps its token
+const LEGACY_TOKEN_NAMESPACE: &str = "eitype";
+
+/// Whether `ch` may be in a token namespace
+fn is_namespace_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || matches!(ch, '.' | '_' | '-')
+}
+
+/// Check that `namespace` can be part of a token file name
+fn validate_token_namespace(namespace: &str) -> Result<(), EiTypeError> {
+    if namespace.is_empty() || !namespace.chars().all(is_namespace_char) {
+        return Err(EiTypeError::InvalidConfig(format!(
+            "token_namespace {:?} may only have letters, digits, '.', '_' and '-'",
+            namespace
+        )));
+    }
+    Ok(())
+}
+
+/// The token namespace for this program: the name it was run as (so a
+/// symlink to eitype under another name gets a token of its own), with
+/// characters a namespace can't have replaced by `_`. `eitype` if there is
+/// no name.
+pub fn default_token_namespace() -> String {
+    let name = std::env::args_os()
+        .next()
+        .and_then(|arg0| {
+            Path::new(&arg0)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default();
+    let namespace: String = name
+        .chars()
+        .map(|ch| if is_namespace_char(ch) { ch } else { '_' })
+        .collect();
+    if namespace.is_empty() {
+        LEGACY_TOKEN_NAMESPACE.to_string()
+    } else {
+        namespace
+    }
+}
+
+/// A saved portal restore token, one file per namespace:
+/// `$XDG_STATE_HOME/eitype/restore-token-<namespace>` (default
+/// `~/.local/state/eitype/restore-token-<namespace>`).
+///
+/// The portal ties what it grants to the app ID of the program asking, and
+/// a restore token is good for one use: restoring a session with it makes
+/// the portal issue a replacement and forget the old one. Two programs
+/// sharing one token file therefore keep invalidating each other's token,
+/// and whichever connects second sees the dialog again. A namespace per
+/// program keeps their tokens apart.
+///
+/// Namespaces don't separate permissions, though. Programs outside a
+/// sandbox all have the same (empty) app ID, so to the portal they are one
+/// app: revoking remote control for it in the desktop's settings makes
+/// every namespace's token fail at once. A Flatpak app has an app ID of its
+/// own, and its grants are its own whatever the namespace.
+///
+/// The `eitype` namespace also takes over the token file of eitype versions
+/// before namespaces, the first time it is loaded.
+#[derive(Debug, Clone)]
+pub struct TokenStore {
+    path: PathBuf,
+    /// The pre-namespace token file, moved to `path` by `load`
+    legacy_path: Option<PathBuf>,
+}
+
+impl TokenStore {
+    /// The store for `namespace` in the default state directory. Fails with
+    /// `EiTypeError::InvalidConfig` for a namespace that can't be a file
+    /// name, or when there is no home directory to put it in.
+    pub fn open(namespace: &str) -> Result<Self, EiTypeError> {
+        let home = || std::env::var_os("HOME").map(PathBuf::from);
+        let env_dir = |name| {
+            std::env::var_os(name)
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+        };
+        let state_dir = env_dir("XDG_STATE_HOME")
+            .or_else(|| home().map(|home| home.join(".local/state")))
+            .ok_or_else(|| {
+                EiTypeError::InvalidConfig(
+                    "No state directory for the restore token (HOME is unset)".to_string(),
+                )
+            })?;
+        let cache_dir =
+            env_dir("XDG_CACHE_HOME").or_else(|| home().map(|home| home.join(".cache")));
+        Self::in_dirs(&state_dir, cache_dir.as_deref(), namespace)
+    }
+
+    /// The store for `namespace` under `state_dir`, taking over the
+    /// pre-namespace token under `cache_dir` if it is the legacy namespace
+    fn in_dirs(
+        state_dir: &Path,
+        cache_dir: Option<&Path>,
+        namespace: &str,
+    ) -> Result<Self, EiTypeError> {
+        validate_token_namespace(namespace)?;
+        let legacy_path = cache_dir
+            .filter(|_| namespace == LEGACY_TOKEN_NAMESPACE)
+            .map(|dir| dir.join("eitype").join("restore_token"));
+        Ok(Self {
+            path: state_dir
+                .join("eitype")
+                .join(format!("restore-token-{}", namespace)),
+            legacy_path,
+        })
+    }
+
+    /// The token file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved token, if there is a non-empty one
+    pub fn load(&self) -> Option<String> {
+        if !self.path.exists() {
+            self.migrate_legacy();
+        }
+        let token = std::fs::read_to_string(&self.path).ok()?;
+        let token = token.trim();
+        if token.is_empty() {
+            return None;
+        }
+        info!("Loaded restore token from {:?}", self.path);
+        Some(token.to_string())
+    }
+
+    /// Move the pre-namespace token file to `path`, if there is one
+    fn migrate_legacy(&self) {
+        let Some(legacy) = &self.legacy_path else {
+            return;
+        };
+        let Ok(token) = std::fs::read_to_string(legacy) else {
+            return;
+        };
+        match self.save(token.trim()) {
+            Ok(()) => {
+                info!("Moved restore token from {:?} to {:?}", legacy, self.path);
+                if let Err(e) = std::fs::remove_file(legacy) {
+                    debug!("Failed to remove old token file {:?}: {}", legacy, e);
+                }
+            }
+            Err(e) => warn!("Failed to move restore token from {:?}: {}", legacy, e),
+        }
+    }
+
+    /// Save `token`, readable only by the user
+    pub fn save(&self, token: &str) -> std::io::Result<()> {
+        write_file_atomically(&self.path, token.as_bytes(), 0o600)?;
+        info!("Saved restore token to {:?}", self.path);
+        Ok(())
+    }
+
+    /// Forget the saved token (and the pre-namespace one it would take over)
+    pub fn clear(&self) -> std::io::Result<()> {
+        for path in std::iter::once(&self.path).chain(&self.legacy_path) {
+            match std::fs::remove_file(path) {
+                Ok(()) => info!("Cleared restore token from {:?}", path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Save `token` if there is a store, warning rather than failing
+    fn save_or_warn(store: Option<&Self>, token: Option<&str>) {
+        if let (Some(store), Some(token)) = (store, token) {
+            if let Err(e) = store.save(token) {
+                warn!("Failed to save restore token to {:?}: {}", store.path, e);
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Connection Functions
+// ============================================================================
+
+/// Environment variable holding a portal restore token, used when no token
+/// is passed explicitly
+pub const RESTORE_TOKEN_ENV: &str = "EITYPE_RESTORE_TOKEN";
+
+/// The restore token in `EITYPE_RESTORE_TOKEN`, if set and non-empty
+pub fn restore_token_from_env() -> Option<String> {
+    env_restore_token(std::env::var(RESTORE_TOKEN_ENV).ok().as_deref())
+}
+
+/// The restore token in `value`, the value of `EITYPE_RESTORE_TOKEN`, if
+/// non-empty
+fn env_restore_token(value: Option<&str>) -> Option<String> {
+    value
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// The token to restore the portal session with: `explicit` if given,
+/// otherwise `from_env` (see `restore_token_from_env`), otherwise the one in
+/// `store`
+fn effective_restore_token(
+    explicit: Option<&str>,
+    from_env: Option<String>,
+    store: Option<&TokenStore>,
+) -> Option<String> {
+    match explicit {
+        Some(token) => Some(token.to_string()),
+        None => from_env.or_else(|| store.and_then(TokenStore::load)),
+    }
+}
+
+/// Connect to EI via the XDG RemoteDesktop portal.
+/// Returns the stream and optionally a new restore token for future sessions.
+fn connect_via_portal(
+    restore_token: Option<&str>,
+    capabilities: &[DeviceCapability],
+) -> Result<(UnixStream, Option<String>), EiTypeError> {
+    use ashpd::desktop::remote_desktop::RemoteDesktop;
+    use ashpd::desktop::PersistMode;
+
+    info!("Connecting via XDG RemoteDesktop portal...");
+    if restore_token.is_some() {
+        info!("Using saved restore token for session persistence");
+    }
+
+    // Use global runtime to avoid DBus connection issues when runtime is dropped.
+    // Creating a new runtime per connection causes zbus to leave stale state
+    // that blocks subsequent connections.
+    let rt = get_tokio_runtime();
+
+    rt.block_on(async {
+        let proxy = RemoteDesktop::new()
+            .await
+            .map_err(|e| portal_error("Failed to create RemoteDesktop proxy", e))?;
+
+        let session = proxy
+            .create_session()
+            .await
+            .map_err(|e| portal_error("Failed to create session", e))?;
+
+        proxy
+            .select_devices(
+                &session,
+                portal_device_types(capabilities),
+                restore_token,
+                PersistMode::ExplicitlyRevoked,
+            )
+            .await
+            .map_err(|e| portal_error("Failed to select devices", e))?;
+
+        let response = proxy
+            .start(&session, None)
+            .await
+            .map_err(|e| portal_error("Failed to start session", e))?
+            .response()
+            .map_err(|e| portal_error("Failed to get session response", e))?;
+
+        let new_token = response.restore_token().map(|s| s.to_string());
+        if new_token.is_some() {
+            debug!("Received new restore token from portal");
+        }
+
+        let fd = proxy
+            .connect_to_eis(&session)
+            .await
+            .map_err(|e| portal_error("Failed to connect to EIS", e))?;
+
+        let stream = UnixStream::from(fd);
+        stream
+            .set_nonblocking(true)
+            .map_err(|e| EiTypeError::Connection(format!("Failed to set non-blocking: {}", e)))?;
+
+        Ok((stream, new_token))
+    })
+}
+
+/// Run `attempt`, a whole portal flow, again up to
+/// `EiTypeConfig::connect_retries` times, `connect_retry_delay_ms` apart,
+/// while it fails with a connection error. `AuthorizationDenied`,
+/// `PortalRefused` and any other error are returned at once.
+fn with_portal_retries<T>(
+    config: &EiTypeConfig,
+    mut attempt: impl FnMut() -> Result<T, EiTypeError>,
+) -> Result<T, EiTypeError> {
+    let mut retries_left = config.connect_retries;
+    loop {
+        match attempt() {
+            Err(EiTypeError::Connection(message)) if retries_left > 0 => {
+                warn!(
+                    "Portal connection failed ({}); retrying in {}ms, {} retries left",
+                    message, config.connect_retry_delay_ms, retries_left
+                );
+                retries_left -= 1;
+                std::thread::sleep(Duration::from_millis(config.connect_retry_delay_ms));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The portal device types to select for `capabilities`
+fn portal_device_types(
+    capabilities: &[DeviceCapability],
+) -> ashpd::enumflags2::BitFlags<ashpd::desktop::remote_desktop::DeviceType> {
+    use ashpd::desktop::remote_desktop::DeviceType;
+
+    let mut device_types = DeviceType::Keyboard.into();
+    if capabilities.iter().any(|c| {
+        matches!(
+            c,
+            DeviceCapability::Pointer
+                | DeviceCapability::PointerAbsolute
+                | DeviceCapability::Button
+                | DeviceCapability::Scroll
+        )
+    }) {
+        device_types |= DeviceType::Pointer;
+    }
+    if capabilities.contains(&DeviceCapability::Touch) {
+        device_types |= DeviceType::Touchscreen;
+    }
+    device_types
+}
+
+/// Map a failed portal call (`what` failed) to `AuthorizationDenied` if the
+/// user said no, a connection error (retried by `connect_retries`) if the
+/// portal couldn't be reached or didn't answer, or `PortalRefused` if it
+/// turned the request down
+fn portal_error(what: &str, error: ashpd::Error) -> EiTypeError {
+    use ashpd::{Error, PortalError};
+
+    match error {
+        Error::Response(ashpd::desktop::ResponseError::Cancelled) => {
+            EiTypeError::AuthorizationDenied
+        }
+        Error::Zbus(_)
+        | Error::Portal(PortalError::ZBus(_))
+        | Error::NoResponse
+        | Error::IO(_)
+        | Error::PortalNotFound(_) => EiTypeError::Connection(format!("{}: {}", what, error)),
+        e => EiTypeError::PortalRefused(format!("{}: {}", what, e)),
+    }
+}
+
+/// The RemoteDesktop portal as far as authorization, so the permission
+/// flow can be tested without a portal
+trait AuthorizationPortal {
+    /// Go through the portal flow for `capabilities` (asking the user unless
+    /// `restore_token` is honored), then disconnect again. Returns the
+    /// restore token the portal issued, if any.
+    fn authorize(
+        &self,
+        restore_token: Option<&str>,
+        capabilities: &[DeviceCapability],
+    ) -> Result<Option<String>, EiTypeError>;
+}
+
+/// The real XDG RemoteDesktop portal
+struct XdgPortal;
+
+impl AuthorizationPortal for XdgPortal {
+    fn authorize(
+        &self,
+        restore_token: Option<&str>,
+        capabilities: &[DeviceCapability],
+    ) -> Result<Option<String>, EiTypeError> {
+        // Connecting is as far as the portal goes; the connection has
+        // served its purpose once made
+        let (stream, token) = connect_via_portal(restore_token, capabilities)?;
+        drop(stream);
+        Ok(token)
+    }
+}
+
+/// Ask the user to authorize remote control through the XDG RemoteDesktop
+/// portal ahead of time, e.g. from a settings toggle at setup time rather
+/// than in the middle of typing.
+///
+/// Runs the portal flow as connecting does, then disconnects again. A token
+/// saved in the `token_namespace` store (or set in `EITYPE_RESTORE_TOKEN`) is
+/// passed along, so a grant that still holds is renewed without asking.
+/// Returns the restore token to persist and pass to
+/// `EiType::connect_portal_with_token` later, if the portal issued one; with
+/// `token_namespace` set it is also saved in that `TokenStore`.
+/// Fails with `EiTypeError::AuthorizationDenied` if the user says no.
+pub fn request_portal_permission(config: &EiTypeConfig) -> Result<Option<String>, EiTypeError> {
+    config.validate()?;
+    request_permission_with(&XdgPortal, config, config.token_store()?.as_ref())
+}
+
+/// Body of `request_portal_permission`, saving the token in `store`
+fn request_permission_with(
+    portal: &impl AuthorizationPortal,
+    config: &EiTypeConfig,
+    store: Option<&TokenStore>,
+) -> Result<Option<String>, EiTypeError> {
+    config.validate()?;
+    info!("Requesting remote control permission from the portal...");
+    // A saved grant that still holds is renewed without asking
+    let restore_token = effective_restore_token(None, restore_token_from_env(), store);
+    let token = with_portal_retries(config, || {
+        portal.authorize(restore_token.as_deref(), &config.requested_capabilities())
+    })?;
+    if token.is_none() {
+        warn!("The portal granted access but issued no restore token; it will ask again when connecting");
+    }
+    TokenStore::save_or_warn(store, token.as_deref());
+    Ok(token)
+}
+
+/// Connect to EI via socket
+fn connect_via_socket(path: &Path) -> Result<UnixStream, EiTypeError> {
+    info!("Connecting to socket: {:?}", path);
+
+    let stream = UnixStream::connect(path).map_err(|e| {
+        EiTypeError::Connection(format!("Failed to connect to socket {:?}: {}", path, e))
+    })?;
+
+    stream
+        .set_nonblocking(true)
+        .map_err(|e| EiTypeError::Connection(format!("Failed to set non-blocking: {}", e)))?;
+
+    Ok(stream)
+}
+
+// ============================================================================
+// Clipboard
+// ============================================================================
+
+/// Which selection to paste from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+pub enum Selection {
+    /// The regular clipboard (Ctrl+C / Ctrl+V)
+    #[default]
+    Clipboard,
+    /// The primary selection (select to copy, middle-click to paste)
+    Primary,
+}
+
+impl std::str::FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "clipboard" => Ok(Selection::Clipboard),
+            "primary" => Ok(Selection::Primary),
+            _ => Err(format!(
+                "unknown selection {:?} (expected clipboard or primary)",
+                s
+            )),
+        }
+    }
+}
+
+/// Input used to make the focused application paste.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+pub enum PasteTrigger {
+    /// Ctrl+V, understood by most GUI applications
+    #[default]
+    CtrlV,
+    /// Ctrl+Shift+V, used by most terminal emulators
+    CtrlShiftV,
+    /// Shift+Insert
+    ShiftInsert,
+    /// Middle mouse button; needs `EiTypeConfig::pointer`
+    MiddleClick,
+}
+
+impl std::str::FromStr for PasteTrigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ctrl-v" => Ok(PasteTrigger::CtrlV),
+            "ctrl-shift-v" => Ok(PasteTrigger::CtrlShiftV),
+            "shift-insert" => Ok(PasteTrigger::ShiftInsert),
+            "middle-click" => Ok(PasteTrigger::MiddleClick),
+            _ => Err(format!(
+                "unknown paste trigger {:?} (expected ctrl-v, ctrl-shift-v, shift-insert or middle-click)",
+                s
+            )),
+        }
+    }
+}
+
+/// How `EiType::paste_text` pastes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PasteOptions {
+    /// Selection the text is placed in
+    pub selection: Selection,
+    /// Input that makes the application paste
+    pub trigger: PasteTrigger,
+    /// Restore the selection's previous contents this long after pasting
+    /// (default: leave the pasted text in place)
+    pub restore_after: Option<Duration>,
+}
+
+/// Linux evdev code of the middle mouse button (BTN_MIDDLE)
+const BTN_MIDDLE: u32 = 0x112;
+
+/// Clipboard used by `EiType::paste_text` and `EiType::insert_text`.
+pub trait Clipboard {
+    /// Current text in `selection`, or `None` if it is empty or not text
+    fn get_text(&self, selection: Selection) -> Result<Option<String>, EiTypeError>;
+    /// Replace the contents of `selection` with `text`
+    fn set_text(&self, selection: Selection, text: &str) -> Result<(), EiTypeError>;
+}
+
+/// Clipboard backed by the `wl-copy` and `wl-paste` commands from wl-clipboard.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WlClipboard;
+
+impl Clipboard for WlClipboard {
+    fn get_text(&self, selection: Selection) -> Result<Option<String>, EiTypeError> {
+        use std::process::Command;
+
+        let mut command = Command::new("wl-paste");
+        command.args(["--no-newline", "--type", "text"]);
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        let output = command
+            .output()
+            .map_err(|e| EiTypeError::Clipboard(format!("Failed to run wl-paste: {}", e)))?;
+        // wl-paste fails when the selection is empty
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(String::from_utf8(output.stdout).ok())
+    }
+
+    fn set_text(&self, selection: Selection, text: &str) -> Result<(), EiTypeError> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let mut command = Command::new("wl-copy");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| EiTypeError::Clipboard(format!("Failed to run wl-copy: {}", e)))?;
+
+        let written = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(text.as_bytes());
+        let status = child
+            .wait()
+            .map_err(|e| EiTypeError::Clipboard(format!("Failed to wait for wl-copy: {}", e)))?;
+        written
+            .map_err(|e| EiTypeError::Clipboard(format!("Failed to write to wl-copy: {}", e)))?;
+
+        if !status.success() {
+            return Err(EiTypeError::Clipboard(format!(
+                "wl-copy exited with {}",
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Exclusive Lock
+// ============================================================================
+
+/// Advisory lock (`flock`) on a file shared by all eitype processes of a
+/// session, held while input is being sent.
+///
+/// Re-entrant within one `EiType`: nested operations (e.g. `insert_text`
+/// falling back to `type_text`) share the outermost acquisition.
+struct ExclusiveLock {
+    file: std::fs::File,
+    wait: Option<Duration>,
+    depth: Cell<u32>,
+}
+
+/// Releases an `ExclusiveLock` acquisition when dropped
+struct ExclusiveGuard(Rc<ExclusiveLock>);
+
+impl ExclusiveLock {
+    /// `$XDG_RUNTIME_DIR/eitype.lock`, or the temp dir if that's unset
+    fn default_path() -> PathBuf {
+        std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join("eitype.lock")
+    }
+
+    fn open(path: &Path, wait: Option<Duration>) -> Result<Self, EiTypeError> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| {
+                EiTypeError::Typing(format!("Failed to open lock file {:?}: {}", path, e))
+            })?;
+        Ok(Self {
+            file,
+            wait,
+            depth: Cell::new(0),
+        })
+    }
+
+    fn acquire(self: &Rc<Self>) -> Result<ExclusiveGuard, EiTypeError> {
+        if self.depth.get() == 0 {
+            self.lock()?;
+        }
+        self.depth.set(self.depth.get() + 1);
+        Ok(ExclusiveGuard(Rc::clone(self)))
+    }
+
+    fn lock(&self) -> Result<(), EiTypeError> {
+        use rustix::fs::{flock, FlockOperation};
+        let failed = |e: rustix::io::Errno| EiTypeError::Typing(format!("Failed to lock: {}", e));
+
+        let Some(wait) = self.wait else {
+            debug!("Waiting for exclusive lock");
+            return flock(&self.file, FlockOperation::LockExclusive).map_err(failed);
+        };
+        // flock has no timeout, so poll
+        let deadline = Instant::now() + wait;
+        loop {
+            match flock(&self.file, FlockOperation::NonBlockingLockExclusive) {
+                Ok(()) => return Ok(()),
+                Err(rustix::io::Errno::WOULDBLOCK) => {}
+                Err(e) => return Err(failed(e)),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(EiTypeError::WouldConflict);
+            }
+            std::thread::sleep((deadline - now).min(Duration::from_millis(10)));
+        }
+    }
+}
+
+impl Drop for ExclusiveGuard {
+    fn drop(&mut self) {
+        let depth = self.0.depth.get() - 1;
+        self.0.depth.set(depth);
+        if depth == 0 {
+            if let Err(e) = rustix::fs::flock(&self.0.file, rustix::fs::FlockOperation::Unlock) {
+                warn!("Failed to release exclusive lock: {}", e);
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Event Sink
+// ============================================================================
+
+/// Destination for the low-level events `EiType` emits.
+///
+/// The real implementation (`EiSink`) writes to the EI connection; tests swap in
+/// a recording sink so the typing logic can be exercised without a compositor.
+trait EventSink {
+    fn start_emulating(&self, sequence: u32);
+    fn stop_emulating(&self);
+    fn key(&self, keycode: u32, state: KeyState);
+    fn frame(&self, timestamp: u64);
+    /// Capabilities of the bound devices
+    fn capabilities(&self) -> Vec<DeviceCapability>;
+    /// Bind `capabilities` on the keyboard's seat, replacing the ones bound
+    /// so far; devices the server offers for them are picked up by `dispatch`
+    fn bind_capabilities(&self, capabilities: &[DeviceCapability]) -> Result<(), EiTypeError>;
+    /// Press or release a pointer button, followed by a frame on the pointer device
+    fn button(&self, button: u32, pressed: bool, timestamp: u64) -> Result<(), EiTypeError>;
+    /// Scroll in 1/120ths of a wheel notch, followed by a frame on the pointer device
+    fn scroll_discrete(&self, dx: i32, dy: i32, timestamp: u64) -> Result<(), EiTypeError>;
+    /// Scroll by pixel deltas, followed by a frame on the pointer device
+    fn scroll_smooth(&self, dx: f32, dy: f32, timestamp: u64) -> Result<(), EiTypeError>;
+    /// Put down touch `slot` at a position, followed by a frame on the touch device
+    fn touch_down(&self, slot: u32, x: f32, y: f32, timestamp: u64) -> Result<(), EiTypeError>;
+    /// Move touch `slot`, followed by a frame on the touch device
+    fn touch_motion(&self, slot: u32, x: f32, y: f32, timestamp: u64) -> Result<(), EiTypeError>;
+    /// Lift touch `slot`, followed by a frame on the touch device
+    fn touch_up(&self, slot: u32, timestamp: u64) -> Result<(), EiTypeError>;
+    /// Regions of the touch device; touches outside all of them are dropped
+    /// by the server. Empty when the device doesn't restrict positions.
+    fn touch_regions(&self) -> Vec<DeviceRegion>;
+    /// Flush queued requests. Errors carry the raw errno so callers can retry EAGAIN.
+    fn flush(&self) -> std::io::Result<()>;
+    /// Bytes written to the socket that the server hasn't read yet (0 where
+    /// the kernel can't tell)
+    fn unsent_bytes(&self) -> std::io::Result<usize>;
+    /// Process whatever the server has sent so far, without blocking.
+    fn dispatch(&self) -> Result<(), EiTypeError>;
+    /// Queue a request for the server to confirm it has processed every
+    /// request sent before it. The returned flag is set once `dispatch` sees
+    /// the confirmation. Fails if the server has disconnected us.
+    fn sync(&self) -> Result<Rc<Cell<bool>>, EiTypeError>;
+    /// A copy of the keymap the server sent for the keyboard, if any
+    fn server_keymap(&self) -> Result<Option<(OwnedFd, usize)>, EiTypeError>;
+    /// Whether `dispatch` saw a device resume since the last call. A resumed
+    /// device needs a new emulation session, in which no keys are held.
+    fn take_resumed(&self) -> bool;
+    /// Handle whatever the server has sent, if anything, and report whether
+    /// the keyboard device was removed (with no replacement yet) or replaced
+    /// by a new one since the last call
+    fn check_keyboard(&self) -> Result<KeyboardChange, EiTypeError>;
+    fn disconnect(&self);
+    /// The connection's socket, for registering with an external event loop
+    fn as_fd(&self) -> BorrowedFd<'_>;
+}
+
+/// What became of the keyboard device, from `EventSink::check_keyboard`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyboardChange {
+    Unchanged,
+    /// The server removed the keyboard device and hasn't offered another
+    Removed,
+    /// A new keyboard device on the same seat took the removed one's place
+    Replaced,
+}
+
+/// Rectangle of a device's coordinate space that maps onto the screen, in
+/// logical pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DeviceRegion {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+impl DeviceRegion {
+    fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+impl From<&reis::event::Region> for DeviceRegion {
+    fn from(region: &reis::event::Region) -> Self {
+        Self {
+            x: region.x.into(),
+            y: region.y.into(),
+            width: region.width.into(),
+            height: region.height.into(),
+        }
+    }
+}
+
+/// Reader for incoming EI events.
+///
+/// Drives reis' event converter directly rather than its blocking iterator so
+/// that, once connected, pending events can be drained without ever stalling
+/// the typing path.
+struct EventReader {
+    context: ei::Context,
+    converter: EiEventConverter,
+    /// Clone of the socket used only for `poll`
+    poll_stream: UnixStream,
+}
+
+impl EventReader {
+    /// Convert any complete messages already read from the socket
+    fn process_pending(&mut self) -> Result<(), EiTypeError> {
+        while let Some(result) = self.context.pending_event() {
+            let request = match result {
+                PendingRequestResult::Request(request) => request,
+                PendingRequestResult::ParseError(e) => {
+                    return Err(EiTypeError::Connection(format!(
+                        "Error parsing event: {}",
+                        reis::Error::from(e)
+                    )))
+                }
+                PendingRequestResult::InvalidObject(_) => continue,
+            };
+            self.converter
+                .handle_event(request)
+                .map_err(|e| EiTypeError::Connection(format!("Error processing event: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Return the next event if one is available, without blocking.
+    fn try_next(&mut self) -> Result<Option<EiEvent>, EiTypeError> {
+        if let Some(event) = self.converter.next_event() {
+            return Ok(Some(event));
+        }
+        self.context.read().map_err(|e| {
+            EiTypeError::Connection(format!("Failed to read from EI socket: {}", e))
+        })?;
+        self.process_pending()?;
+        Ok(self.converter.next_event())
+    }
+
+    /// Wait until the socket is readable or `timeout` expires (`None` waits forever).
+    /// Returns whether the socket became readable.
+    fn wait_readable(&self, timeout: Option<Duration>) -> bool {
+        use rustix::event::{poll, PollFd, PollFlags};
+        use rustix::time::Timespec;
+
+        let mut pollfd = [PollFd::new(&self.poll_stream, PollFlags::IN)];
+        let timeout = timeout.map(|t| Timespec {
+            tv_sec: t.as_secs() as i64,
+            tv_nsec: t.subsec_nanos() as i64,
+        });
+        matches!(poll(&mut pollfd, timeout.as_ref()), Ok(n) if n > 0)
+    }
+}
+
+impl Iterator for EventReader {
+    type Item = Result<EiEvent, EiTypeError>;
+
+    /// Block until the next event; `None` once the server closes the socket.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Messages may already be buffered (e.g. read along with the handshake)
+            if let Err(e) = self.process_pending() {
+                return Some(Err(e));
+            }
+            if let Some(event) = self.converter.next_event() {
+                return Some(Ok(event));
+            }
+            self.wait_readable(None);
+            match self.context.read() {
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => {
+                    return Some(Err(EiTypeError::Connection(format!(
+                        "Failed to read from EI socket: {}",
+                        e
+                    ))))
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+/// Device with pointer-type capabilities (motion, buttons, scrolling or
+/// touch), bound when `EiTypeConfig::capabilities` asks for any
+struct PointerDevice {
+    device: reis::event::Device,
+    button: Option<ei::Button>,
+    scroll: Option<ei::Scroll>,
+}
+
+/// Device with the touchscreen capability, bound when
+/// `EiTypeConfig::capabilities` asks for touch
+struct TouchDevice {
+    device: reis::event::Device,
+    touchscreen: ei::Touchscreen,
+}
+
+/// Devices discovered while connecting
+#[derive(Default)]
+struct FoundDevices {
+    keyboard: Option<(reis::event::Device, ei::Keyboard)>,
+    pointer: Option<PointerDevice>,
+    touch: Option<TouchDevice>,
+}
+
+impl FoundDevices {
+    /// Capabilities of the devices found so far
+    fn capabilities(&self) -> Vec<DeviceCapability> {
+        let mut capabilities = Vec::new();
+        if self.keyboard.is_some() {
+            capabilities.push(DeviceCapability::Keyboard);
+        }
+        capabilities.extend(pointer_capabilities(self.pointer.as_ref()));
+        if self.touch.is_some() {
+            capabilities.push(DeviceCapability::Touch);
+        }
+        capabilities
+    }
+
+    /// Whether there is a device for every kind of capability in `capabilities`
+    fn complete(&self, capabilities: &[EiCapability]) -> bool {
+        let wants_pointer = capabilities
+            .iter()
+            .any(|&c| !matches!(c, EiCapability::Keyboard | EiCapability::Touch));
+        let wants_touch = capabilities.contains(&EiCapability::Touch);
+        self.keyboard.is_some()
+            && (!wants_pointer || self.pointer.is_some())
+            && (!wants_touch || self.touch.is_some())
+    }
+}
+
+/// The pointer-type capabilities `pointer` has, in `DeviceCapability::ALL` order
+fn pointer_capabilities(pointer: Option<&PointerDevice>) -> Vec<DeviceCapability> {
+    let Some(pointer) = pointer else {
+        return Vec::new();
+    };
+    DeviceCapability::ALL
+        .into_iter()
+        .filter(|&c| {
+            !matches!(c, DeviceCapability::Keyboard | DeviceCapability::Touch)
+                && pointer.device.has_capability(c.to_ei())
+        })
+        .collect()
+}
+
+/// How long to keep waiting for pointer and touch devices once the keyboard
+/// is ready, or once `EiType::enable_capability` has bound them
+const POINTER_WAIT: Duration = Duration::from_secs(1);
+
+/// How long `EiType::sync` waits for the server's confirmation
+const SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long `EiType::close` waits in all for the server to read what was
+/// sent and confirm it
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `EiTypeError::Disconnected` for the server's disconnect event
+fn disconnected_error(disconnected: &reis::event::Disconnected) -> EiTypeError {
+    use ei::connection::DisconnectReason;
+    let reason = match disconnected.reason {
+        DisconnectReason::Disconnected => "disconnected",
+        DisconnectReason::Error => "error",
+        DisconnectReason::Mode => "mode",
+        DisconnectReason::Protocol => "protocol",
+        DisconnectReason::Value => "value",
+        DisconnectReason::Transport => "transport",
+    };
+    EiTypeError::Disconnected {
+        reason: reason.to_string(),
+        explanation: disconnected.explanation.clone(),
+    }
+}
+
+/// Handle one event received while connecting: bind seats and record devices.
+fn handle_setup_event(
+    event: EiEvent,
+    connection: &reis::event::Connection,
+    capabilities: &[EiCapability],
+    found: &mut FoundDevices,
+) -> Result<(), EiTypeError> {
+    trace!("Received event: {:?}", event);
+
+    match event {
+        EiEvent::Disconnected(disconnected) => {
+            error!(
+                "Disconnected: {:?} - {}",
+                disconnected.reason, disconnected.explanation
+            );
+            return Err(disconnected_error(&disconnected));
+        }
+
+        EiEvent::SeatAdded(seat_added) => {
+            let seat = &seat_added.seat;
+            debug!("Seat added: {:?}", seat.name());
+            seat.bind_capabilities(capabilities);
+            connection
+                .flush()
+                .map_err(|e| EiTypeError::Connection(e.to_string()))?;
+        }
+
+        EiEvent::DeviceAdded(device_added) => {
+            let device = &device_added.device;
+            debug!("Device added: {:?}", device.name());
+        }
+
+        EiEvent::DeviceResumed(device_resumed) => {
+            let device = device_resumed.device;
+            debug!("Device resumed: {:?}", device.name());
+
+            if found.keyboard.is_none() {
+                if let Some(keyboard) = device.interface::<ei::Keyboard>() {
+                    info!("Keyboard device available: {:?}", device.name());
+                    found.keyboard = Some((device.clone(), keyboard));
+                }
+            }
+            if found.pointer.is_none() {
+                found.pointer = as_pointer_device(&device, capabilities);
+            }
+            if found.touch.is_none() {
+                found.touch = as_touch_device(&device, capabilities);
+            }
+        }
+
+        EiEvent::DevicePaused(paused) => {
+            debug!("Device paused: {:?}", paused.device.name());
+        }
+
+        EiEvent::DeviceRemoved(removed) => {
+            debug!("Device removed: {:?}", removed.device.name());
+        }
+
+        _ => {
+            trace!("Other event");
+        }
+    }
+    Ok(())
+}
+
+/// `device` as the pointer device, if it has any of the pointer-type
+/// capabilities in `capabilities`
+fn as_pointer_device(
+    device: &reis::event::Device,
+    capabilities: &[EiCapability],
+) -> Option<PointerDevice> {
+    let pointer_like = capabilities.iter().any(|&c| {
+        !matches!(c, EiCapability::Keyboard | EiCapability::Touch) && device.has_capability(c)
+    });
+    if !pointer_like {
+        return None;
+    }
+    info!("Pointer device available: {:?}", device.name());
+    Some(PointerDevice {
+        button: device.interface::<ei::Button>(),
+        scroll: device.interface::<ei::Scroll>(),
+        device: device.clone(),
+    })
+}
+
+/// `device` as the touch device, if `capabilities` include touch and it is
+/// a touchscreen
+fn as_touch_device(
+    device: &reis::event::Device,
+    capabilities: &[EiCapability],
+) -> Option<TouchDevice> {
+    if !capabilities.contains(&EiCapability::Touch) {
+        return None;
+    }
+    let touchscreen = device.interface::<ei::Touchscreen>()?;
+    info!("Touch device available: {:?}", device.name());
+    Some(TouchDevice {
+        device: device.clone(),
+        touchscreen,
+    })
+}
+
+/// After the keyboard is ready, give the server a little longer to offer
+/// pointer and touch devices. Not finding one is not an error: only the
+/// operations that need it (e.g. middle-click paste) fail.
+fn wait_for_devices(
+    events: &mut EventReader,
+    connection: &reis::event::Connection,
+    capabilities: &[EiCapability],
+    found: &mut FoundDevices,
+) -> Result<(), EiTypeError> {
+    let deadline = Instant::now() + POINTER_WAIT;
+    loop {
+        while let Some(event) = events.try_next()? {
+            handle_setup_event(event, connection, capabilities, found)?;
+        }
+        if found.complete(capabilities) {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("Not every requested device was offered");
+            return Ok(());
+        }
+        events.wait_readable(Some(deadline - now));
+    }
+}
+
+/// A connection that has completed the EI handshake but may not have a
+/// keyboard yet, from `EiType::connect`.
+///
+/// `dispatch` handles whatever the server has sent without blocking (binding
+/// seats as they appear), so a caller with its own event loop can poll the
+/// socket (`as_fd`) and watch devices arrive; `wait_for_keyboard` blocks until
+/// the keyboard is ready and finishes setting up the `EiType`.
+pub struct PendingEiType {
+    events: EventReader,
+    connection: reis::event::Connection,
+    capabilities: Vec<EiCapability>,
+    found: FoundDevices,
+    config: EiTypeConfig,
+}
+
+impl PendingEiType {
+    /// Whether the server has resumed a keyboard device yet
+    pub fn has_keyboard(&self) -> bool {
+        self.found.keyboard.is_some()
+    }
+
+    /// Capabilities of the devices the server has resumed so far
+    pub fn capabilities(&self) -> Vec<DeviceCapability> {
+        self.found.capabilities()
+    }
+
+    /// Handle the events the server has sent so far, without blocking.
+    /// Returns whether there is a keyboard; events after it are left for
+    /// `wait_for_keyboard`, which reads the active layout from them.
+    pub fn dispatch(&mut self) -> Result<bool, EiTypeError> {
+        // Events may already be buffered (e.g. read along with the handshake)
+        self.events.process_pending()?;
+        loop {
+            while self.found.keyboard.is_none() {
+                let Some(event) = self.events.converter.next_event() else {
+                    break;
+                };
+                handle_setup_event(event, &self.connection, &self.capabilities, &mut self.found)?;
+            }
+            if self.found.keyboard.is_some() {
+                return Ok(true);
+            }
+            match self.events.context.read() {
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(EiTypeError::NoDevice(DeviceCapability::Keyboard))
+                }
+                Err(e) => {
+                    return Err(EiTypeError::Connection(format!(
+                        "Failed to read from EI socket: {}",
+                        e
+                    )))
+                }
+                Ok(0) => return Ok(false),
+                Ok(_) => self.events.process_pending()?,
+            }
+        }
+    }
+
+    /// Wait for a keyboard device, up to `timeout` (`None` waits as long as
+    /// the connection lasts), then finish connecting: wait briefly for any
+    /// pointer or touch device asked for, load the keymap, choose the layout
+    /// and start emulating.
+    pub fn wait_for_keyboard(mut self, timeout: Option<Duration>) -> Result<EiType, EiTypeError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while !self.dispatch()? {
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(EiTypeError::Connection(format!(
+                            "No keyboard device was offered within {:?}",
+                            timeout.unwrap_or_default()
+                        )));
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            self.events.wait_readable(remaining);
+        }
+        self.finish()
+    }
+
+    /// Set up the `EiType` once the keyboard is there
+    fn finish(mut self) -> Result<EiType, EiTypeError> {
+        if !self.found.complete(&self.capabilities) {
+            wait_for_devices(
+                &mut self.events,
+                &self.connection,
+                &self.capabilities,
+                &mut self.found,
+            )?;
+        }
+
+        let (device, keyboard) = self
+            .found
+            .keyboard
+            .ok_or(EiTypeError::NoDevice(DeviceCapability::Keyboard))?;
+        let mut events = self.events;
+        let config = self.config;
+
+        let (keymap, source) = load_keymap(&config, device_keymap(&device)?)?;
+
+        let cache = if config.ignore_layout_cache {
+            None
+        } else {
+            LayoutCache::open_default()
+        };
+        let layout_index = choose_layout_index(&config, &keymap, cache.as_ref(), || {
+            poll_for_layout_group(&mut events)
+        });
+        info!("Using layout index: {}", layout_index);
+
+        let socket = events
+            .poll_stream
+            .try_clone()
+            .map_err(|e| EiTypeError::Connection(format!("Failed to clone stream: {}", e)))?;
+        let sink = EiSink {
+            connection: self.connection,
+            device: RefCell::new(device),
+            keyboard: RefCell::new(keyboard),
+            keyboard_removed: Cell::new(false),
+            keyboard_replaced: Cell::new(false),
+            pointer: RefCell::new(self.found.pointer),
+            touch: RefCell::new(self.found.touch),
+            bound: RefCell::new(self.capabilities),
+            events: RefCell::new(events),
+            status: StatusBroadcast::default(),
+            resumed: Cell::new(false),
+            disconnected: RefCell::new(None),
+            socket,
+        };
+        let status = sink.status.clone();
+        let mut eitype =
+            EiType::with_sink(Box::new(sink), (keymap, source), layout_index, &config)?;
+        eitype.layout_cache = cache;
+        eitype.status = status;
+        Ok(eitype)
+    }
+}
+
+impl AsFd for PendingEiType {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.events.poll_stream.as_fd()
+    }
+}
+
+/// Fans `StatusEvent`s out to every receiver from `EiType::status_receiver`.
+#[derive(Clone, Default)]
+struct StatusBroadcast {
+    senders: Rc<RefCell<Vec<std::sync::mpsc::Sender<StatusEvent>>>>,
+}
+
+impl StatusBroadcast {
+    /// A new receiver that gets `current`, then every event sent from now on
+    fn subscribe(&self, current: StatusEvent) -> std::sync::mpsc::Receiver<StatusEvent> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let _ = sender.send(current);
+        self.senders.borrow_mut().push(sender);
+        receiver
+    }
+
+    /// Move every receiver of `old` over to this broadcast
+    fn take_over(&self, old: &StatusBroadcast) {
+        if !Rc::ptr_eq(&self.senders, &old.senders) {
+            let mut moved = std::mem::take(&mut *old.senders.borrow_mut());
+            self.senders.borrow_mut().append(&mut moved);
+        }
+    }
+
+    /// Send `event` to every receiver, forgetting the ones that were dropped
+    fn send(&self, event: StatusEvent) {
+        self.senders
+            .borrow_mut()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+/// Name of `device` for a `StatusEvent`
+fn status_device_name(device: &reis::event::Device) -> String {
+    device.name().unwrap_or_default().to_string()
+}
+
+/// `EventSink` backed by a live EI connection.
+struct EiSink {
+    connection: reis::event::Connection,
+    /// The keyboard device, replaced by `dispatch` if the server swaps it
+    device: RefCell<reis::event::Device>,
+    keyboard: RefCell<ei::Keyboard>,
+    /// Set by `dispatch` when the keyboard device is removed, until another
+    /// one is resumed on its seat
+    keyboard_removed: Cell<bool>,
+    /// Set by `dispatch` when it switches to a new keyboard device, until
+    /// `check_keyboard`
+    keyboard_replaced: Cell<bool>,
+    /// Set by `dispatch` too when a device with a newly bound capability
+    /// resumes
+    pointer: RefCell<Option<PointerDevice>>,
+    touch: RefCell<Option<TouchDevice>>,
+    /// Capabilities bound on the seat
+    bound: RefCell<Vec<EiCapability>>,
+    events: RefCell<EventReader>,
+    status: StatusBroadcast,
+    /// Set by `dispatch` when a device resumes, until `take_resumed`
+    resumed: Cell<bool>,
+    /// Set by `dispatch` when the server disconnects us
+    disconnected: RefCell<Option<reis::event::Disconnected>>,
+    /// Clone of the socket, kept outside the `RefCell` so it can be lent out
+    socket: UnixStream,
+}
+
+impl EiSink {
+    /// Fail with `EiTypeError::Disconnected` once the server has
+    /// disconnected us
+    fn check_connected(&self) -> Result<(), EiTypeError> {
+        match &*self.disconnected.borrow() {
+            Some(disconnected) => Err(disconnected_error(disconnected)),
+            None => Ok(()),
+        }
+    }
+
+    /// The keyboard device, plus the pointer and touch devices if they are
+    /// separate ones
+    fn devices(&self) -> Vec<reis::event::Device> {
+        let keyboard = self.device.borrow().clone();
+        let pointer = self.pointer.borrow();
+        let pointer = pointer
+            .as_ref()
+            .map(|p| &p.device)
+            .filter(|device| **device != keyboard);
+        let touch = self.touch.borrow();
+        let touch = touch
+            .as_ref()
+            .map(|t| &t.device)
+            .filter(|device| **device != keyboard && Some(*device) != pointer);
+        let others: Vec<_> = pointer.into_iter().chain(touch).cloned().collect();
+        std::iter::once(keyboard).chain(others).collect()
+    }
+
+    /// Switch to `device` if the keyboard device was removed and `device` is
+    /// a keyboard on the same seat
+    fn adopt_keyboard(&self, device: &reis::event::Device) {
+        if !self.keyboard_removed.get() || device.seat() != self.device.borrow().seat() {
+            return;
+        }
+        let Some(keyboard) = device.interface::<ei::Keyboard>() else {
+            return;
+        };
+        info!(
+            "Switching to replacement keyboard device {:?}",
+            device.name()
+        );
+        *self.device.borrow_mut() = device.clone();
+        *self.keyboard.borrow_mut() = keyboard;
+        self.keyboard_removed.set(false);
+        self.keyboard_replaced.set(true);
+    }
+
+    /// Use `device` for pointer or touch input if it has a bound capability
+    /// that no device offered so far
+    fn adopt_device(&self, device: &reis::event::Device) {
+        let bound = self.bound.borrow();
+        let mut pointer = self.pointer.borrow_mut();
+        if pointer.is_none() {
+            *pointer = as_pointer_device(device, &bound);
+        }
+        let mut touch = self.touch.borrow_mut();
+        if touch.is_none() {
+            *touch = as_touch_device(device, &bound);
+        }
+    }
+
+    fn touch_device(&self) -> Result<std::cell::Ref<'_, TouchDevice>, EiTypeError> {
+        std::cell::Ref::filter_map(self.touch.borrow(), Option::as_ref)
+            .map_err(|_| EiTypeError::NoDevice(DeviceCapability::Touch))
+    }
+}
+
+impl EventSink for EiSink {
+    fn start_emulating(&self, sequence: u32) {
+        for device in self.devices() {
+            let serial = self.connection.serial();
+            device.device().start_emulating(serial, sequence);
+        }
+    }
+
+    fn stop_emulating(&self) {
+        for device in self.devices() {
+            let serial = self.connection.serial();
+            device.device().stop_emulating(serial);
+        }
+    }
+
+    fn key(&self, keycode: u32, state: KeyState) {
+        self.keyboard.borrow().key(keycode, state);
+    }
+
+    fn frame(&self, timestamp: u64) {
+        let serial = self.connection.serial();
+        self.device.borrow().device().frame(serial, timestamp);
+    }
+
+    fn capabilities(&self) -> Vec<DeviceCapability> {
+        let mut capabilities = vec![DeviceCapability::Keyboard];
+        capabilities.extend(pointer_capabilities(self.pointer.borrow().as_ref()));
+        if self.touch.borrow().is_some() {
+            capabilities.push(DeviceCapability::Touch);
+        }
+        capabilities
+    }
+
+    fn bind_capabilities(&self, capabilities: &[DeviceCapability]) -> Result<(), EiTypeError> {
+        self.check_connected()?;
+        let capabilities: Vec<EiCapability> = capabilities.iter().map(|c| c.to_ei()).collect();
+        self.device.borrow().seat().bind_capabilities(&capabilities);
+        *self.bound.borrow_mut() = capabilities;
+        Ok(())
+    }
+
+    fn button(&self, button: u32, pressed: bool, timestamp: u64) -> Result<(), EiTypeError> {
+        let missing = || EiTypeError::NoDevice(DeviceCapability::Button);
+        let pointer = self.pointer.borrow();
+        let pointer = pointer.as_ref().ok_or_else(missing)?;
+        let interface = pointer.button.as_ref().ok_or_else(missing)?;
+        let state = if pressed {
+            ei::button::ButtonState::Press
+        } else {
+            ei::button::ButtonState::Released
+        };
+        interface.button(button, state);
+        let serial = self.connection.serial();
+        pointer.device.device().frame(serial, timestamp);
+        Ok(())
+    }
+
+    fn scroll_discrete(&self, dx: i32, dy: i32, timestamp: u64) -> Result<(), EiTypeError> {
+        let missing = || EiTypeError::NoDevice(DeviceCapability::Scroll);
+        let pointer = self.pointer.borrow();
+        let pointer = pointer.as_ref().ok_or_else(missing)?;
+        let scroll = pointer.scroll.as_ref().ok_or_else(missing)?;
+        scroll.scroll_discrete(dx, dy);
+        let serial = self.connection.serial();
+        pointer.device.device().frame(serial, timestamp);
+        Ok(())
+    }
+
+    fn scroll_smooth(&self, dx: f32, dy: f32, timestamp: u64) -> Result<(), EiTypeError> {
+        let missing = || EiTypeError::NoDevice(DeviceCapability::Scroll);
+        let pointer = self.pointer.borrow();
+        let pointer = pointer.as_ref().ok_or_else(missing)?;
+        let scroll = pointer.scroll.as_ref().ok_or_else(missing)?;
+        scroll.scroll(dx, dy);
+        let serial = self.connection.serial();
+        pointer.device.device().frame(serial, timestamp);
+        Ok(())
+    }
+
+    fn touch_down(&self, slot: u32, x: f32, y: f32, timestamp: u64) -> Result<(), EiTypeError> {
+        let touch = self.touch_device()?;
+        touch.touchscreen.down(slot, x, y);
+        let serial = self.connection.serial();
+        touch.device.device().frame(serial, timestamp);
+        Ok(())
+    }
+
+    fn touch_motion(&self, slot: u32, x: f32, y: f32, timestamp: u64) -> Result<(), EiTypeError> {
+        let touch = self.touch_device()?;
+        touch.touchscreen.motion(slot, x, y);
+        let serial = self.connection.serial();
+        touch.device.device().frame(serial, timestamp);
+        Ok(())
+    }
+
+    fn touch_up(&self, slot: u32, timestamp: u64) -> Result<(), EiTypeError> {
+        let touch = self.touch_device()?;
+        touch.touchscreen.up(slot);
+        let serial = self.connection.serial();
+        touch.device.device().frame(serial, timestamp);
+        Ok(())
+    }
+
+    fn touch_regions(&self) -> Vec<DeviceRegion> {
+        self.touch.borrow().as_ref().map_or_else(Vec::new, |touch| {
+            touch
+                .device
+                .regions()
+                .iter()
+                .map(DeviceRegion::from)
+                .collect()
+        })
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        // Convert via raw errno: reis uses a different rustix version than we do
+        self.connection
+            .flush()
+            .map_err(|e| std::io::Error::from_raw_os_error(e.raw_os_error()))
+    }
+
+    fn sync(&self) -> Result<Rc<Cell<bool>>, EiTypeError> {
+        self.check_connected()?;
+        let done = Rc::new(Cell::new(false));
+        let callback = self.connection.connection().sync(1);
+        let flag = Rc::clone(&done);
+        self.events
+            .borrow_mut()
+            .converter
+            .add_callback_handler(callback, move |_| flag.set(true));
+        Ok(done)
+    }
+
+    fn dispatch(&self) -> Result<(), EiTypeError> {
+        let mut events = self.events.borrow_mut();
+        while let Some(event) = events.try_next()? {
+            match event {
+                EiEvent::Disconnected(disconnected) => {
+                    error!(
+                        "Disconnected: {:?} - {}",
+                        disconnected.reason, disconnected.explanation
+                    );
+                    self.status.send(StatusEvent::Disconnected {
+                        reason: format!("{:?}: {}", disconnected.reason, disconnected.explanation),
+                    });
+                    let error = disconnected_error(&disconnected);
+                    *self.disconnected.borrow_mut() = Some(disconnected);
+                    return Err(error);
+                }
+                EiEvent::DevicePaused(paused) => {
+                    warn!("Device paused: {:?}", paused.device.name());
+                    self.status.send(StatusEvent::DevicePaused {
+                        device: status_device_name(&paused.device),
+                    });
+                }
+                EiEvent::DeviceResumed(resumed) => {
+                    debug!("Device resumed: {:?}", resumed.device.name());
+                    self.adopt_keyboard(&resumed.device);
+                    self.adopt_device(&resumed.device);
+                    self.resumed.set(true);
+                    self.status.send(StatusEvent::DeviceResumed {
+                        device: status_device_name(&resumed.device),
+                    });
+                }
+                EiEvent::DeviceRemoved(removed) => {
+                    warn!("Device removed: {:?}", removed.device.name());
+                    if removed.device == *self.device.borrow() {
+                        self.keyboard_removed.set(true);
+                    }
+                    self.status.send(StatusEvent::DeviceRemoved {
+                        device: status_device_name(&removed.device),
+                    });
+                }
+                other => trace!("Received event: {:?}", other),
+            }
+        }
+        Ok(())
+    }
+
+    fn server_keymap(&self) -> Result<Option<(OwnedFd, usize)>, EiTypeError> {
+        device_keymap(&self.device.borrow())
+    }
+
+    fn take_resumed(&self) -> bool {
+        self.resumed.take()
+    }
+
+    fn check_keyboard(&self) -> Result<KeyboardChange, EiTypeError> {
+        if self.events.borrow().wait_readable(Some(Duration::ZERO)) {
+            self.dispatch()?;
+        }
+        Ok(if self.keyboard_replaced.take() {
+            KeyboardChange::Replaced
+        } else if self.keyboard_removed.get() {
+            KeyboardChange::Removed
+        } else {
+            KeyboardChange::Unchanged
+        })
+    }
+
+    fn disconnect(&self) {
+        self.connection.connection().disconnect();
+    }
+
+    fn unsent_bytes(&self) -> std::io::Result<usize> {
+        // SIOCOUTQ, the same number as TIOCOUTQ, which differs on a few
+        // other architectures
+        #[cfg(any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        {
+            const SIOCOUTQ: rustix::ioctl::Opcode = 0x5411;
+            // SAFETY: SIOCOUTQ writes the socket's unsent byte count as a C int
+            let unsent = unsafe {
+                rustix::ioctl::ioctl(
+                    &self.socket,
+                    rustix::ioctl::Getter::<SIOCOUTQ, std::ffi::c_int>::new(),
+                )
+            }?;
+            Ok(unsent.max(0) as usize)
+        }
+        #[cfg(not(any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )))]
+        Ok(0)
+    }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+/// `EventSink` that drops everything, for `EiType::null`
+struct NullSink {
+    /// Stands in for the connection socket
+    socket: UnixStream,
+}
+
+impl EventSink for NullSink {
+    fn start_emulating(&self, _sequence: u32) {}
+    fn stop_emulating(&self) {}
+    fn key(&self, _keycode: u32, _state: KeyState) {}
+    fn frame(&self, _timestamp: u64) {}
+
+    fn capabilities(&self) -> Vec<DeviceCapability> {
+        vec![DeviceCapability::Keyboard]
+    }
+
+    fn bind_capabilities(&self, _capabilities: &[DeviceCapability]) -> Result<(), EiTypeError> {
+        Ok(())
+    }
+
+    fn button(&self, _button: u32, _pressed: bool, _timestamp: u64) -> Result<(), EiTypeError> {
+        Err(EiTypeError::NoDevice(DeviceCapability::Button))
+    }
+
+    fn scroll_discrete(&self, _dx: i32, _dy: i32, _timestamp: u64) -> Result<(), EiTypeError> {
+        Err(EiTypeError::NoDevice(DeviceCapability::Scroll))
+    }
+
+    fn scroll_smooth(&self, _dx: f32, _dy: f32, _timestamp: u64) -> Result<(), EiTypeError> {
+        Err(EiTypeError::NoDevice(DeviceCapability::Scroll))
+    }
+
+    fn touch_down(&self, _slot: u32, _x: f32, _y: f32, _timestamp: u64) -> Result<(), EiTypeError> {
+        Err(EiTypeError::NoDevice(DeviceCapability::Touch))
+    }
+
+    fn touch_motion(
+        &self,
+        _slot: u32,
+        _x: f32,
+        _y: f32,
+        _timestamp: u64,
+    ) -> Result<(), EiTypeError> {
+        Err(EiTypeError::NoDevice(DeviceCapability::Touch))
+    }
+
+    fn touch_up(&self, _slot: u32, _timestamp: u64) -> Result<(), EiTypeError> {
+        Err(EiTypeError::NoDevice(DeviceCapability::Touch))
+    }
+
+    fn touch_regions(&self) -> Vec<DeviceRegion> {
+        Vec::new()
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn unsent_bytes(&self) -> std::io::Result<usize> {
+        Ok(0)
+    }
+
+    fn dispatch(&self) -> Result<(), EiTypeError> {
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<Rc<Cell<bool>>, EiTypeError> {
+        Ok(Rc::new(Cell::new(true)))
+    }
+
+    fn server_keymap(&self) -> Result<Option<(OwnedFd, usize)>, EiTypeError> {
+        Ok(None)
+    }
+
+    fn take_resumed(&self) -> bool {
+        false
+    }
+
+    fn check_keyboard(&self) -> Result<KeyboardChange, EiTypeError> {
+        Ok(KeyboardChange::Unchanged)
+    }
+
+    fn disconnect(&self) {}
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+// ============================================================================
+// Planner
+// ============================================================================
+
+/// One step of a typing plan, as produced by `Planner`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedEvent {
+    /// Press the key with this evdev keycode
+    Press(u32),
+    /// Release the key with this evdev keycode
+    Release(u32),
+    /// End of a frame; every press and release is followed by one
+    Frame,
+    /// Pause this long (`EiTypeConfig::settle_after_word_ms` and
+    /// `EiTypeConfig::modifier_settle_ms`). The key delay
+    /// isn't planned: `EiType` waits `EiTypeConfig::delay_ms` after every
+    /// key event it sends.
+    Delay(Duration),
+}
+
+/// Key events that type one character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharPlan {
+    pub ch: char,
+    pub events: Vec<PlannedEvent>,
+    /// Whether the events switch to another group first
+    pub group_switch: bool,
+    /// Whether the character was placed with the built-in US QWERTY table
+    /// because there is no keymap
+    pub qwerty_fallback: bool,
+}
+
+/// A character that can't be typed on the planner's keymap
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Character {ch:?} at index {index} not found in keymap")]
+pub struct PlanError {
+    pub ch: char,
+    /// Position of `ch` in the planned text, in characters (always 0 from
+    /// `Planner::plan_char`)
+    pub index: usize,
+}
+
+impl From<PlanError> for EiTypeError {
+    fn from(e: PlanError) -> Self {
+        EiTypeError::CharNotFound(e.ch)
+    }
+}
+
+/// Translates text into the key events that type it, without a connection.
+///
+/// `EiType` executes these plans against the EI device; other injection
+/// mechanisms (or tests) can use a planner on its own. Planning is stateful:
+/// a run of characters from another group switches group once, and the
+/// switch stays in effect until `finish` plans the way back.
+pub struct Planner {
+    keymap: Option<xkb::Keymap>,
+    layout_index: u32,
+    key_to_keycode: HashMap<String, u32>,
+    /// Real-modifier name (e.g. "Mod5") -> evdev keycode that produces it,
+    /// derived from the keymap's modmap
+    keymap_mod_keycodes: HashMap<String, u32>,
+    /// Keys that switch groups in the keymap
+    group_switch_keys: Vec<GroupSwitchKey>,
+    /// Group switch currently in effect for a run of characters from another group
+    active_group_switch: Option<GroupSwitchPlan>,
+    /// Pause after whitespace (`EiTypeConfig::settle_after_word_ms`)
+    settle_after_word: Option<Duration>,
+    /// Pause around modifiers a level needs (`EiTypeConfig::modifier_settle_ms`)
+    modifier_settle: Option<Duration>,
+    /// `EiTypeConfig::prefer_keymap_keys`
+    prefer_keymap_keys: bool,
+    /// `EiTypeConfig::literal_whitespace`
+    literal_whitespace: bool,
+    /// `EiTypeConfig::altgr_mode`
+    altgr_mode: AltGrMode,
+    /// `EiTypeConfig::modifier_order`
+    modifier_order: ModifierOrder,
+    /// Keycode of the modifier `modifier_order` puts first
+    first_modifier: Option<u32>,
+    /// Keycode of Shift for typing with the US QWERTY table
+    qwerty_shift: u32,
+    /// Special keys of `key_to_keycode` checked against the keymap
+    key_table: Vec<KeyTableEntry>,
+}
+
+impl Planner {
+    /// Plan for `keymap`, typing in group `layout_index`
+    pub fn new(keymap: xkb::Keymap, layout_index: u32, config: &EiTypeConfig) -> Self {
+        let mut planner = Self::without_keymap(config);
+        planner.layout_index = layout_index;
+        planner.set_keymap(keymap);
+        planner
+    }
+
+    /// Plan with the built-in US QWERTY table, for when no keymap is available
+    pub fn without_keymap(config: &EiTypeConfig) -> Self {
+        let key_to_keycode = build_key_to_keycode_map();
+        Self {
+            keymap: None,
+            layout_index: 0,
+            qwerty_shift: qwerty_shift_keycode(&key_to_keycode),
+            key_to_keycode,
+            keymap_mod_keycodes: HashMap::new(),
+            group_switch_keys: Vec::new(),
+            active_group_switch: None,
+            settle_after_word: config
+                .settle_after_word_ms
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            modifier_settle: Some(config.modifier_settle_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            prefer_keymap_keys: config.prefer_keymap_keys,
+            literal_whitespace: config.literal_whitespace,
+            altgr_mode: config.altgr_mode,
+            modifier_order: config.modifier_order,
+            first_modifier: None,
+            key_table: Vec::new(),
+        }
+    }
+
+    /// Replace the keymap and recompute the modifier and group switch keys,
+    /// and which keycodes special key names use
+    fn set_keymap(&mut self, keymap: xkb::Keymap) {
+        self.key_to_keycode = build_key_to_keycode_map();
+        self.key_table = audit_key_table(&keymap, self.layout_index, &self.key_to_keycode);
+        for entry in &mut self.key_table {
+            if entry.matches_keymap() {
+                continue;
+            }
+            if let Some(keycode) = entry.keymap_keycode.filter(|_| self.prefer_keymap_keys) {
+                entry.keycode = keycode;
+                self.key_to_keycode.insert(entry.name.clone(), keycode);
+            }
+            warn!("Key table mismatch: {}", entry);
+        }
+        self.qwerty_shift = qwerty_shift_keycode(&self.key_to_keycode);
+
+        self.keymap_mod_keycodes = build_keymap_modifier_map(&keymap);
+        if self.altgr_mode == AltGrMode::Level3Keysym {
+            match find_level3_shift_keycode(&keymap, self.layout_index) {
+                Some(keycode) => {
+                    self.keymap_mod_keycodes.insert("Mod5".to_string(), keycode);
+                }
+                None => warn!("No key produces ISO_Level3_Shift; using the Mod5 key for AltGr"),
+            }
+        }
+        debug!(
+            "Resolved modifier keycodes from keymap: {:?}",
+            self.keymap_mod_keycodes
+        );
+        let first = match self.modifier_order {
+            ModifierOrder::ShiftFirst => "Shift",
+            ModifierOrder::Level3First => "Mod5",
+        };
+        self.first_modifier = self.keymap_mod_keycodes.get(first).copied();
+        self.group_switch_keys = find_group_switch_keys(&keymap);
+        if !self.group_switch_keys.is_empty() {
+            debug!("Group switch keys: {:?}", self.group_switch_keys);
+        }
+        self.keymap = Some(keymap);
+    }
+
+    pub fn keymap(&self) -> Option<&xkb::Keymap> {
+        self.keymap.as_ref()
+    }
+
+    pub fn layout_index(&self) -> u32 {
+        self.layout_index
+    }
+
+    /// How the special key names fit the keymap (empty without a keymap)
+    pub fn key_table_report(&self) -> &[KeyTableEntry] {
+        &self.key_table
+    }
+
+    /// Every character a key produces in the configured group, sorted (the
+    /// US QWERTY table's without a keymap). Characters only another group
+    /// has are left out, as are control characters other than Tab and
+    /// newline.
+    pub fn typeable_chars(&self) -> Vec<char> {
+        let mut chars: Vec<char> = match &self.keymap {
+            Some(keymap) => layout_chars(keymap, self.layout_index),
+            None => ('0'..='9')
+                .chain('a'..='z')
+                .chain('A'..='Z')
+                .chain(QWERTY_SYMBOLS.iter().map(|&(symbol, _, _)| symbol))
+                .collect(),
+        };
+        chars.retain(|&ch| !ch.is_control() || ch == '\n' || ch == '\t');
+        chars.sort_unstable();
+        chars.dedup();
+        chars
+    }
+
+    /// Plan the key events for a whole string, ending back in the configured
+    /// group
+    pub fn plan_text(&mut self, text: &str) -> Result<Vec<PlannedEvent>, PlanError> {
+        let mut events = Vec::new();
+        let mut plan = CharPlan {
+            ch: '\0',
+            events: Vec::new(),
+            group_switch: false,
+            qwerty_fallback: false,
+        };
+        for (index, ch) in text.chars().enumerate() {
+            match self.plan_char_into(&mut plan, ch, &[]) {
+                Ok(()) => events.extend_from_slice(&plan.events),
+                Err(e) => {
+                    // Nothing of the text is planned, so no group switch is in effect
+                    self.active_group_switch = None;
+                    return Err(PlanError { index, ..e });
+                }
+            }
+        }
+        events.extend(self.finish());
+        Ok(events)
+    }
+
+    /// Check that every character of `text` can be typed, without leaving
+    /// anything planned (e.g. a group switch) in effect
+    pub fn check_text(&mut self, text: &str) -> Result<(), PlanError> {
+        let active_group_switch = self.active_group_switch.clone();
+        let result = text.chars().enumerate().try_for_each(|(index, ch)| {
+            self.plan_char(ch)
+                .map(drop)
+                .map_err(|e| PlanError { index, ..e })
+        });
+        self.active_group_switch = active_group_switch;
+        result
+    }
+
+    /// The key whose plain tap types `ch` on the configured group: no
+    /// modifiers, group switch or pause, and nothing else planned. `None`
+    /// when `ch` takes more than that, or no keymap is loaded.
+    pub fn single_tap(&mut self, ch: char) -> Option<u32> {
+        if self.keymap.is_none() || self.active_group_switch.is_some() {
+            return None;
+        }
+        let plan = self.plan_char(ch);
+        self.active_group_switch = None;
+        match plan.ok()?.events[..] {
+            [PlannedEvent::Press(keycode), PlannedEvent::Frame, PlannedEvent::Release(released), PlannedEvent::Frame]
+                if released == keycode =>
+            {
+                Some(keycode)
+            }
+            _ => None,
+        }
+    }
+
+    /// Plan the key events for one character. A group switch it needs is left
+    /// in effect for the next character; call `finish` at the end of the text.
+    pub fn plan_char(&mut self, ch: char) -> Result<CharPlan, PlanError> {
+        self.plan_char_holding(ch, &[])
+    }
+
+    /// Plan `ch` while also holding the `held` keycodes (pressed after any
+    /// group switch, so they can't combine with the switch keys)
+    fn plan_char_holding(&mut self, ch: char, held: &[u32]) -> Result<CharPlan, PlanError> {
+        let mut plan = CharPlan {
+            ch,
+            events: Vec::new(),
+            group_switch: false,
+            qwerty_fallback: false,
+        };
+        self.plan_char_into(&mut plan, ch, held)?;
+        Ok(plan)
+    }
+
+    /// Like `plan_char_holding`, but overwriting `plan`, so typing can reuse
+    /// its event buffer instead of allocating one per character
+    fn plan_char_into(
+        &mut self,
+        plan: &mut CharPlan,
+        ch: char,
+        held: &[u32],
+    ) -> Result<(), PlanError> {
+        let not_found = PlanError { ch, index: 0 };
+        plan.ch = ch;
+        plan.events.clear();
+        plan.group_switch = false;
+        plan.qwerty_fallback = false;
+
+        if let Some(keymap) = self.keymap.clone() {
+            let key_match = if self.literal_whitespace && ch == '\n' {
+                // Only the Linefeed keysym, not Return, is the character
+                let key_match = find_keycode_for_keysym(
+                    xkb::Keysym::new(XK_LINEFEED),
+                    &keymap,
+                    self.layout_index,
+                )
+                .ok_or(not_found)?;
+                plan.events.extend(self.finish());
+                key_match
+            } else {
+                match find_keycode_for_char(ch, &keymap, self.layout_index) {
+                    Ok(key_match) => {
+                        plan.events.extend(self.finish());
+                        key_match
+                    }
+                    Err(_) => {
+                        let key_match = find_char_in_other_group(ch, &keymap, self.layout_index)
+                            .ok_or(not_found)?;
+                        plan.group_switch =
+                            self.enter_group(&keymap, &key_match, ch, &mut plan.events)?;
+                        key_match
+                    }
+                }
+            };
+            push_holding(&mut plan.events, held, |events| {
+                self.push_key_match(events, &keymap, &key_match, held)
+            });
+        } else {
+            let fallback = if self.literal_whitespace && ch == '\n' {
+                Some((KEY_LINEFEED, false))
+            } else {
+                qwerty_fallback(ch, &self.key_to_keycode)
+            };
+            let Some((keycode, shifted)) = fallback else {
+                warn!("Could not find keycode for character: {:?}", ch);
+                return Err(not_found);
+            };
+            let shift_keycode = self.qwerty_shift;
+            let need_shift = shifted && !held.contains(&shift_keycode);
+
+            push_holding(&mut plan.events, held, |events| {
+                if need_shift {
+                    push_key(events, shift_keycode, true);
+                    self.push_modifier_settle(events);
+                }
+                push_tap(events, keycode);
+                if need_shift {
+                    self.push_modifier_settle(events);
+                    push_key(events, shift_keycode, false);
+                }
+            });
+            plan.qwerty_fallback = true;
+        }
+        if let Some(settle) = self.settle_after_word.filter(|_| ch.is_whitespace()) {
+            plan.events.push(PlannedEvent::Delay(settle));
+        }
+        Ok(())
+    }
+
+    /// Plan switching back from any active group switch to the configured group
+    pub fn finish(&mut self) -> Vec<PlannedEvent> {
+        let mut events = Vec::new();
+        if let Some(plan) = self.active_group_switch.take() {
+            debug!("Switching back from group {}", plan.target);
+            push_key_events(&mut events, &plan.exit);
+        }
+        events
+    }
+
+    /// Plan switching to the group `key_match` lives in, unless that switch is
+    /// already active. Returns whether a switch was planned.
+    fn enter_group(
+        &mut self,
+        keymap: &xkb::Keymap,
+        key_match: &KeyMatch,
+        ch: char,
+        events: &mut Vec<PlannedEvent>,
+    ) -> Result<bool, PlanError> {
+        if let Some(active) = &self.active_group_switch {
+            if active.target == key_match.layout {
+                return Ok(false);
+            }
+        }
+
+        // Found before leaving the active switch, so on failure it is still
+        // held and `EiType::leave_group` undoes it
+        let plan = plan_group_switch(
+            keymap,
+            &self.group_switch_keys,
+            self.layout_index,
+            key_match.layout,
+            key_match.evdev_keycode,
+        )
+        .ok_or_else(|| {
+            debug!(
+                "{:?} is only in group {} and no usable group switch key was found",
+                ch, key_match.layout
+            );
+            PlanError { ch, index: 0 }
+        })?;
+        events.extend(self.finish());
+
+        debug!(
+            "Switching to group {} ({:?}) for {:?}",
+            plan.target, plan.kind, ch
+        );
+        push_key_events(events, &plan.enter);
+        // A latch only lasts for one key, so there is nothing to undo later
+        if plan.kind != GroupSwitchKind::Latch {
+            self.active_group_switch = Some(plan);
+        }
+        Ok(true)
+    }
+
+    /// Plan a tap of the key in `key_match`, holding whatever modifiers its
+    /// level needs apart from those in `already_held`
+    fn push_key_match(
+        &self,
+        events: &mut Vec<PlannedEvent>,
+        keymap: &xkb::Keymap,
+        key_match: &KeyMatch,
+        already_held: &[u32],
+    ) {
+        let mut mod_keycodes =
+            modifier_keycodes_for_match(keymap, key_match, &self.keymap_mod_keycodes);
+        mod_keycodes.retain(|kc| !already_held.contains(kc));
+        mod_keycodes.sort_by_key(|&kc| Some(kc) != self.first_modifier);
+
+        for &mkc in &mod_keycodes {
+            push_key(events, mkc, true);
+        }
+        if !mod_keycodes.is_empty() {
+            self.push_modifier_settle(events);
+        }
+        push_tap(events, key_match.evdev_keycode);
+        if !mod_keycodes.is_empty() {
+            self.push_modifier_settle(events);
+        }
+        for &mkc in mod_keycodes.iter().rev() {
+            push_key(events, mkc, false);
+        }
+    }
+
+    /// Plan the `EiTypeConfig::modifier_settle_ms` pause, if any
+    fn push_modifier_settle(&self, events: &mut Vec<PlannedEvent>) {
+        if let Some(settle) = self.modifier_settle {
+            events.push(PlannedEvent::Delay(settle));
+        }
+    }
+
+    /// Evdev keycode for a key name, case-insensitively. Common localized
+    /// names (e.g. "Eingabe", "пробел") are accepted too.
+    ///
+    /// "altgr" and "level3" resolve to whichever key the keymap uses as the
+    /// level 3 switch (e.g. Right Ctrl with `lv3:switch`), not always Right Alt.
+    ///
+    /// "kc:N" is evdev keycode N itself, for keys (e.g. remapped modifiers)
+    /// that have no name here.
+    fn keycode_for_name(&self, name: &str) -> Result<u32, EiTypeError> {
+        if let Some(code) = name.strip_prefix("kc:") {
+            return parse_raw_keycode(code);
+        }
+        let lower = name.to_lowercase();
+        let canonical = key_names::canonical_key_name(&lower).unwrap_or(&lower);
+        if canonical == "altgr" || canonical == "level3" {
+            if let Some(&keycode) = self.keymap_mod_keycodes.get("Mod5") {
+                return Ok(keycode);
+            }
+        }
+        self.key_to_keycode
+            .get(canonical)
+            .copied()
+            .ok_or_else(|| EiTypeError::UnknownKey(name.to_string()))
+    }
+
+    /// Keycodes to hold and the keycode to tap for a key name: a single key,
+    /// a "+"-joined combo such as "shift+tab" or "ctrl+kc:46", or a named
+    /// combo from `NAMED_COMBOS`. Each part resolves like `keycode_for_name`.
+    fn combo_for_name(&self, name: &str) -> Result<(Vec<u32>, u32), EiTypeError> {
+        let lower = name.to_lowercase();
+        if let Some((_, combo)) = NAMED_COMBOS.iter().find(|(named, _)| *named == lower) {
+            return self.combo_for_name(combo);
+        }
+        let mut parts: Vec<&str> = name.split('+').collect();
+        if parts.len() == 1 || parts.iter().any(|part| part.is_empty()) {
+            // A lone key, or "+" itself
+            return Ok((Vec::new(), self.keycode_for_name(name)?));
+        }
+        let key = self.keycode_for_name(parts.pop().unwrap_or_default())?;
+        let held = parts
+            .into_iter()
+            .map(|part| self.keycode_for_name(part))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((held, key))
+    }
+}
+
+/// The modifier names and character of a key name that names its key by
+/// the non-ASCII character on the keycap, e.g. "ж" or "ctrl+ж"
+fn keycap_combo(name: &str) -> Option<(Vec<&str>, char)> {
+    let (mods, key) = match name.rsplit_once('+') {
+        Some((mods, key)) => (mods.split('+').collect(), key),
+        None => (Vec::new(), name),
+    };
+    let mut chars = key.chars();
+    let ch = chars.next().filter(|ch| !ch.is_ascii())?;
+    chars.next().is_none().then_some((mods, ch))
+}
+
+/// Evdev keycodes of both Shifts, Ctrls, Alts and Supers
+const STANDARD_MODIFIER_KEYCODES: [u32; 8] = [42, 54, 29, 97, 56, 100, 125, 126];
+
+/// Highest evdev keycode (`KEY_MAX` in linux/input-event-codes.h)
+const EVDEV_KEY_MAX: u32 = 0x2ff;
+
+/// The evdev keycode `code` from a "kc:" key name, e.g. "100"
+fn parse_raw_keycode(code: &str) -> Result<u32, EiTypeError> {
+    match code.parse::<u32>() {
+        Ok(keycode) if keycode <= EVDEV_KEY_MAX => Ok(keycode),
+        _ => Err(EiTypeError::UnknownKey(format!(
+            "kc:{} (keycodes are 0 to {})",
+            code, EVDEV_KEY_MAX
+        ))),
+    }
+}
+
+/// Plan a single press or release, followed by a frame
+fn push_key(events: &mut Vec<PlannedEvent>, keycode: u32, pressed: bool) {
+    events.push(if pressed {
+        PlannedEvent::Press(keycode)
+    } else {
+        PlannedEvent::Release(keycode)
+    });
+    events.push(PlannedEvent::Frame);
+}
+
+/// Plan a press and release of `keycode`
+fn push_tap(events: &mut Vec<PlannedEvent>, keycode: u32) {
+    push_key(events, keycode, true);
+    push_key(events, keycode, false);
+}
+
+fn push_key_events(events: &mut Vec<PlannedEvent>, keys: &[(u32, bool)]) {
+    for &(keycode, pressed) in keys {
+        push_key(events, keycode, pressed);
+    }
+}
+
+/// Plan pressing `keycodes` in order, the events from `f`, then releasing
+/// them in reverse order
+fn push_holding(
+    events: &mut Vec<PlannedEvent>,
+    keycodes: &[u32],
+    f: impl FnOnce(&mut Vec<PlannedEvent>),
+) {
+    for &keycode in keycodes {
+        push_key(events, keycode, true);
+    }
+    f(events);
+    for &keycode in keycodes.iter().rev() {
+        push_key(events, keycode, false);
+    }
+}
+
+/// Keys that are down, in press order, each with how many times it was
+/// pressed without being released
+#[derive(Debug, Default)]
+struct KeyHolds(Vec<(u32, usize)>);
+
+impl KeyHolds {
+    /// Add a press of `keycode`, returning how many it now has
+    fn hold(&mut self, keycode: u32) -> usize {
+        match self.0.iter_mut().find(|(k, _)| *k == keycode) {
+            Some((_, count)) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                self.0.push((keycode, 1));
+                1
+            }
+        }
+    }
+
+    /// Remove a press of `keycode`, forgetting it after its last one
+    fn release(&mut self, keycode: u32) {
+        if let Some(position) = self.0.iter().position(|&(k, _)| k == keycode) {
+            self.0[position].1 -= 1;
+            if self.0[position].1 == 0 {
+                self.0.remove(position);
+            }
+        }
+    }
+
+    /// How many presses of `keycode` haven't been released
+    fn count(&self, keycode: u32) -> usize {
+        self.0
+            .iter()
+            .find(|&&(k, _)| k == keycode)
+            .map_or(0, |&(_, count)| count)
+    }
+
+    /// The keys that are down, in press order
+    fn keycodes(&self) -> impl DoubleEndedIterator<Item = u32> + '_ {
+        self.0.iter().map(|&(keycode, _)| keycode)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Forget every key, returning them in press order
+    fn take(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.0)
+            .into_iter()
+            .map(|(keycode, _)| keycode)
+            .collect()
+    }
+}
+
+/// How `EiType::emit_with` sends a key event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    /// Paced by the key delay and adaptive throttling
+    Paced,
+    /// With no pacing, for `tap_key_fast`
+    Unpaced,
+    /// Paced, with a release sent whether or not the key is down, for
+    /// `reset_keys` and `release_stale`
+    Forced,
+}
+
+// ============================================================================
+// Main EiType Struct
+// ============================================================================
+
+/// Main interface for typing text via EI protocol
+pub struct EiType {
+    sink: Box<dyn EventSink>,
+    /// Turns text into key events; holds the keymap and layout index
+    planner: RefCell<Planner>,
+    keymap_source: KeymapSource,
+    keymap_fingerprint: Cell<u64>,
+    clamped_layout_index: Cell<Option<u32>>,
+    keymap_changed: Vec<KeymapChangedCallback>,
+    delay: Duration,
+    /// Keys held down by `hold_modifier` and `key_down`, in press order;
+    /// nested holds of a key are counted
+    held_modifiers: KeyHolds,
+    /// Keys pressed on the device in this emulation session, so a press of
+    /// a key that is already down (or a stray release) is never sent
+    keys_down: RefCell<KeyHolds>,
+    /// Event buffer `emit_char` plans each character into, kept between
+    /// characters so typing doesn't allocate one for each
+    plan_buffer: Cell<Vec<PlannedEvent>>,
+    strict_key_state: bool,
+    /// `EiTypeConfig::ascii_transliterate`
+    ascii_transliterate: bool,
+    /// `EiTypeConfig::strip_format_chars`
+    strip_format_chars: bool,
+    /// `EiTypeConfig::terminal_safe`
+    terminal_safe: bool,
+    sequence: Cell<u32>,
+    /// Whether the device is currently emulating (between start/stop_emulating)
+    emulating: Cell<bool>,
+    /// Time of the last key event, for `idle_stop`
+    last_activity: Cell<Instant>,
+    idle_stop: Option<Duration>,
+    /// Stop emulating after every `type_text` (`EiTypeConfig::stop_emulating_after_each`)
+    stop_after_each: bool,
+    /// Set by `try_type_text`: flush once without sleeping, and skip key delays
+    nonblocking: Cell<bool>,
+    /// Set when a non-blocking flush hit EAGAIN and data is still queued
+    write_blocked: Cell<bool>,
+    clock: Clock,
+    stats: Cell<TypingStats>,
+    /// Per-character timings, when `EiTypeConfig::collect_timing` is set
+    timing: Option<RefCell<TimingTrace>>,
+    /// Where `set_layout_index` remembers the index (`None` when ignored)
+    layout_cache: Option<LayoutCache>,
+    /// Held while sending input when `EiTypeConfig::exclusive` is set
+    exclusive: Option<Rc<ExclusiveLock>>,
+    /// Capabilities asked for via `EiTypeConfig::capabilities` or `pointer`
+    requested_capabilities: Vec<DeviceCapability>,
+    /// Receivers of connection and device status changes
+    status: StatusBroadcast,
+    /// Adaptive pacing state (`None` when `EiTypeConfig::adaptive_throttle` is off)
+    throttle: Cell<Option<AdaptiveThrottle>>,
+    chunk: Option<ChunkPolicy>,
+    progress: Option<ProgressCallback>,
+    keystroke_hook: RefCell<Option<KeystrokeHook>>,
+    /// Hide characters from the keystroke hook
+    redact_keystrokes: bool,
+    /// Character whose keys are being sent, for the keystroke hook
+    current_char: Cell<Option<char>>,
+    /// Key of the character being typed once its press has been flushed,
+    /// until the character is done (see `type_text_reliable`)
+    delivered_key: Cell<Option<u32>>,
+    /// Touch slots that are down (see `touch_down`)
+    touches: RefCell<Vec<u32>>,
+    cancel: Arc<AtomicBool>,
+    paste_threshold: Option<usize>,
+    max_text_len: Option<usize>,
+    refuse_if_focused_app: Option<String>,
+    focus_settle: Duration,
+    /// `EiTypeConfig::device_failover_ms`
+    device_failover: Duration,
+    /// `EiTypeConfig::max_modifier_hold_ms`
+    max_modifier_hold: Option<Duration>,
+    /// When the held modifiers were last pressed (`clock` microseconds), for
+    /// `max_modifier_hold`
+    modifiers_pressed_at: Cell<u64>,
+    /// Profiles `apply_profile` can select from
+    profiles: Profiles,
+    /// The profile settings as configured at connect time, which every
+    /// profile is applied over
+    base_profile: TypingProfile,
+    /// Name of the profile last applied
+    active_profile: Option<String>,
+    /// Key delay from `set_delay`, which profiles don't change
+    delay_override: Option<Duration>,
+    clipboard: Option<Box<dyn Clipboard>>,
+    /// How to connect again (`None` when given a stream to begin with)
+    reconnector: Option<Reconnector>,
+    /// Track whether close() has been called to avoid double-close
+    closed: bool,
+}
+
+impl EiType {
+    /// Connect via the XDG RemoteDesktop portal (simple version, no token management)
+    pub fn connect_portal(config: EiTypeConfig) -> Result<Self, EiTypeError> {
+        let (eitype, _token) = Self::connect_portal_with_token(config, None)?;
+        Ok(eitype)
+    }
+
+    /// Connect via the XDG RemoteDesktop portal with token support.
+    ///
+    /// If `restore_token` is provided and valid, the portal will skip the authorization dialog.
+    /// Without one, the token in `EITYPE_RESTORE_TOKEN` (if set) is used instead,
+    /// or else the one saved for `token_namespace`.
+    /// Returns the EiType instance and optionally a new restore token to save for future use
+    /// (with `token_namespace` set it is saved already, as are the tokens of reconnections).
+    pub fn connect_portal_with_token(
+        config: EiTypeConfig,
+        restore_token: Option<&str>,
+    ) -> Result<(Self, Option<String>), EiTypeError> {
+        config.validate()?;
+        let store = config.token_store()?;
+        let restore_token =
+            effective_restore_token(restore_token, restore_token_from_env(), store.as_ref());
+        let (stream, new_token) = with_portal_retries(&config, || {
+            connect_via_portal(restore_token.as_deref(), &config.requested_capabilities())
+        })?;
+        TokenStore::save_or_warn(store.as_ref(), new_token.as_deref());
+        let mut eitype = Self::from_stream(stream, config.clone())?;
+
+        // Reconnect with the newest token, so no dialog appears again
+        let mut token = new_token.clone().or(restore_token);
+        eitype.reconnector = Some(Box::new(move || {
+            let (stream, new_token) = with_portal_retries(&config, || {
+                connect_via_portal(token.as_deref(), &config.requested_capabilities())
+            })?;
+            if new_token.is_some() {
+                TokenStore::save_or_warn(store.as_ref(), new_token.as_deref());
+                token = new_token;
+            }
+            Self::from_stream(stream, config.clone())
+        }));
+        Ok((eitype, new_token))
+    }
+
+    /// An `EiType` connected to nothing: characters are planned, paced and
+    /// flushed as usual, but the events go nowhere. Useful for measuring
+    /// eitype's own overhead (see `bench`) apart from the compositor's. The
+    /// keymap comes from `config` or the system default; there is no
+    /// pointer or touch device.
+    pub fn null(config: EiTypeConfig) -> Result<Self, EiTypeError> {
+        config.validate()?;
+        let keymap = load_keymap(&config, None)?;
+        let layout_index = choose_layout_index(&config, &keymap.0, None, || None);
+        let (socket, _) = UnixStream::pair()
+            .map_err(|e| EiTypeError::Connection(format!("Failed to create socket: {}", e)))?;
+        Self::with_sink(Box::new(NullSink { socket }), keymap, layout_index, &config)
+    }
+
+    /// Connect via a Unix socket (for testing or direct EIS connections)
+    pub fn connect_socket(path: &Path, config: EiTypeConfig) -> Result<Self, EiTypeError> {
+        config.validate()?;
+        let stream = connect_via_socket(path)?;
+        let mut eitype = Self::from_stream(stream, config.clone())?;
+        let path = path.to_path_buf();
+        eitype.reconnector = Some(Box::new(move || {
+            Self::from_stream(connect_via_socket(&path)?, config.clone())
+        }));
+        Ok(eitype)
+    }
+
+    /// Connect over an already-connected EI socket, waiting as long as it
+    /// takes for a keyboard device; `connect` then
+    /// `PendingEiType::wait_for_keyboard` in one step.
+    pub fn from_stream(stream: UnixStream, config: EiTypeConfig) -> Result<Self, EiTypeError> {
+        Self::connect(stream, config)?.wait_for_keyboard(None)
+    }
+
+    /// Perform the EI handshake over an already-connected socket, returning
+    /// before any device is offered; see `PendingEiType`.
+    pub fn connect(stream: UnixStream, config: EiTypeConfig) -> Result<PendingEiType, EiTypeError> {
+        config.validate()?;
+        // Clone fd for non-blocking polling later (to detect layout group)
+        let poll_stream = stream
+            .try_clone()
+            .map_err(|e| EiTypeError::Connection(format!("Failed to clone stream: {}", e)))?;
+
+        let context = ei::Context::new(stream)
+            .map_err(|e| EiTypeError::Connection(format!("Failed to create EI context: {}", e)))?;
+
+        info!("Performing handshake...");
+        let handshake =
+            reis::handshake::ei_handshake_blocking(&context, "eitype", ContextType::Sender)
+                .map_err(|e| EiTypeError::Connection(format!("Handshake failed: {}", e)))?;
+        let events = EventReader {
+            converter: EiEventConverter::new(&context, handshake),
+            context,
+            poll_stream,
+        };
+        let connection = events.converter.connection().clone();
+
+        info!("Connected! Waiting for devices...");
+
+        let capabilities = config
+            .requested_capabilities()
+            .iter()
+            .map(|c| c.to_ei())
+            .collect();
+        Ok(PendingEiType {
+            events,
+            connection,
+            capabilities,
+            found: FoundDevices::default(),
+            config,
+        })
+    }
+
+    /// Internal: finish setting up an EiType around a connected event sink.
+    fn with_sink(
+        sink: Box<dyn EventSink>,
+        keymap: (xkb::Keymap, KeymapSource),
+        layout_index: u32,
+        config: &EiTypeConfig,
+    ) -> Result<Self, EiTypeError> {
+        let mut eitype = Self {
+            sink,
+            planner: RefCell::new(Planner {
+                layout_index,
+                ..Planner::without_keymap(config)
+            }),
+            keymap_source: KeymapSource::SystemDefault,
+            keymap_fingerprint: Cell::new(0),
+            clamped_layout_index: Cell::new(None),
+            keymap_changed: Vec::new(),
+            delay: Duration::from_millis(config.delay_ms),
+            held_modifiers: KeyHolds::default(),
+            keys_down: RefCell::new(KeyHolds::default()),
+            plan_buffer: Cell::new(Vec::new()),
+            strict_key_state: config.strict_key_state,
+            ascii_transliterate: config.ascii_transliterate,
+            strip_format_chars: config.strip_format_chars,
+            terminal_safe: config.terminal_safe,
+            sequence: Cell::new(1),
+            emulating: Cell::new(false),
+            last_activity: Cell::new(Instant::now()),
+            idle_stop: config.idle_stop_emulating_ms.map(Duration::from_millis),
+            stop_after_each: config.stop_emulating_after_each,
+            nonblocking: Cell::new(false),
+            write_blocked: Cell::new(false),
+            clock: Clock::Real(config.timestamp_source),
+            stats: Cell::new(TypingStats::default()),
+            timing: config.collect_timing.then(RefCell::default),
+            layout_cache: None,
+            exclusive: None,
+            requested_capabilities: config.requested_capabilities(),
+            status: StatusBroadcast::default(),
+            throttle: Cell::new(config.adaptive_throttle.then(AdaptiveThrottle::default)),
+            chunk: config.chunk,
+            progress: None,
+            keystroke_hook: RefCell::new(None),
+            redact_keystrokes: false,
+            current_char: Cell::new(None),
+            delivered_key: Cell::new(None),
+            touches: RefCell::new(Vec::new()),
+            cancel: Arc::new(AtomicBool::new(false)),
+            paste_threshold: config.paste_threshold,
+            max_text_len: config.max_text_len,
+            refuse_if_focused_app: config.refuse_if_focused_app.clone(),
+            focus_settle: Duration::from_millis(config.focus_settle_ms),
+            device_failover: Duration::from_millis(config.device_failover_ms),
+            max_modifier_hold: config.max_modifier_hold_ms.map(Duration::from_millis),
+            modifiers_pressed_at: Cell::new(0),
+            profiles: Profiles::default(),
+            base_profile: TypingProfile::of_config(config),
+            active_profile: None,
+            delay_override: None,
+            clipboard: Some(Box::new(WlClipboard)),
+            reconnector: None,
+            closed: false,
+        };
+
+        if config.exclusive {
+            let wait = config.exclusive_timeout_ms.map(Duration::from_millis);
+            let lock = ExclusiveLock::open(&ExclusiveLock::default_path(), wait)?;
+            eitype.exclusive = Some(Rc::new(lock));
+        }
+
+        // Setup keymap
+        let (keymap, source) = keymap;
+        debug!("Keymap source: {:?}", source);
+        eitype.keymap_source = source;
+        eitype.install_keymap(keymap);
+
+        // Start emulating, unless each call starts (and stops) it anyway
+        if !eitype.stop_after_each {
+            eitype.start_session()?;
+        }
+
+        Ok(eitype)
+    }
+
+    /// Install a freshly-loaded keymap in the planner. Centralizes the
+    /// bookkeeping so every load path stays in sync, including the
+    /// fingerprint, the keymap-changed notification and falling back to
+    /// layout 0 when the layout index is out of range for `keymap`.
+    fn install_keymap(&self, keymap: xkb::Keymap) {
+        let mut planner = self.planner.borrow_mut();
+        let num_layouts = keymap.num_layouts();
+        if num_layouts > 0 && planner.layout_index >= num_layouts {
+            warn!(
+                "Layout index {} is out of range for a keymap with {} layout(s); typing in layout 0 (\"{}\") instead",
+                planner.layout_index,
+                num_layouts,
+                keymap.layout_get_name(0)
+            );
+            self.clamped_layout_index.set(Some(planner.layout_index));
+            planner.layout_index = 0;
+        } else {
+            self.clamped_layout_index.set(None);
+        }
+        drop(planner);
+
+        let fingerprint = keymap_fingerprint(&keymap);
+        if fingerprint != self.keymap_fingerprint.get() {
+            debug!("Keymap fingerprint: {:016x}", fingerprint);
+            self.keymap_fingerprint.set(fingerprint);
+            for callback in &self.keymap_changed {
+                callback(fingerprint);
+            }
+        }
+        self.planner.borrow_mut().set_keymap(keymap);
+    }
+
+    fn start_session(&self) -> Result<(), EiTypeError> {
+        // A new session starts with no keys down
+        self.keys_down.borrow_mut().take();
+        let sequence = self.sequence.get();
+        self.sink.start_emulating(sequence);
+        self.sequence.set(sequence + 1);
+        self.emulating.set(true);
+        self.last_activity.set(Instant::now());
+        self.flush_with_retry()
+    }
+
+    fn stop_session(&self) -> Result<(), EiTypeError> {
+        self.emulating.set(false);
+        self.sink.stop_emulating();
+        self.flush_with_retry()
+    }
+
+    /// Start an emulation session now instead of at the next input, e.g. to
+    /// bracket a burst of typing with `stop_emulating`. Does nothing if a
+    /// session is already running.
+    pub fn start_emulating(&mut self) -> Result<(), EiTypeError> {
+        if self.emulating.get() {
+            debug!("Already emulating");
+            return Ok(());
+        }
+        self.start_session()
+    }
+
+    /// End the emulation session, letting the compositor release its
+    /// emulation grab until `start_emulating` or the next input starts a new
+    /// one. Held modifiers are released first, since the keys of a stopped
+    /// session don't stay down. Does nothing if no session is running.
+    pub fn stop_emulating(&mut self) -> Result<(), EiTypeError> {
+        if !self.emulating.get() {
+            debug!("Not emulating");
+            return Ok(());
+        }
+        if !self.held_modifiers.is_empty() {
+            self.release_modifiers()?;
+        }
+        self.stop_session()
+    }
+
+    /// Stop emulating if the idle timeout (`EiTypeConfig::idle_stop_emulating_ms`)
+    /// has passed since the last input. Returns whether emulation was stopped.
+    ///
+    /// eitype has no timer thread: the timeout is checked only here and at
+    /// the next input, so long-lived callers (REPLs, daemons) should call
+    /// this periodically from their own loop, or emulation stays on until
+    /// then. Input that arrives
+    /// after the timeout starts emulating again automatically, whether or not
+    /// this was called. Never stops while modifiers are held.
+    pub fn check_idle(&self) -> Result<bool, EiTypeError> {
+        let Some(idle_stop) = self.idle_stop else {
+            return Ok(false);
+        };
+        if !self.emulating.get()
+            || !self.held_modifiers.is_empty()
+            || self.last_activity.get().elapsed() < idle_stop
+        {
+            return Ok(false);
+        }
+        debug!("Idle for {:?}, stopping emulation", idle_stop);
+        self.stop_session()?;
+        Ok(true)
+    }
+
+    /// Take the exclusive lock, if configured, until the guard is dropped
+    fn lock_exclusive(&self) -> Result<Option<ExclusiveGuard>, EiTypeError> {
+        self.exclusive
+            .as_ref()
+            .map(ExclusiveLock::acquire)
+            .transpose()
+    }
+
+    /// Make sure the device is emulating before sending a key event,
+    /// restarting emulation after an idle stop or a server-side resume.
+    fn ensure_emulating(&self) -> Result<(), EiTypeError> {
+        if self.sink.take_resumed() && self.emulating.get() {
+            self.restart_after_resume()?;
+        }
+        self.check_idle()?;
+        if !self.emulating.get() {
+            debug!("Resuming emulation");
+            self.start_session()?;
+        }
+        self.last_activity.set(Instant::now());
+        Ok(())
+    }
+
+    /// Start a new emulation session after the server resumed the device, and
+    /// press the held modifiers again, in order: the old session's keys are
+    /// gone, so what follows would otherwise be typed without them.
+    fn restart_after_resume(&self) -> Result<(), EiTypeError> {
+        debug!("Device resumed, restarting emulation");
+        self.start_session()?;
+        self.repress_held_modifiers()
+    }
+
+    /// Carry on with a replacement keyboard device if the server removed
+    /// ours: wait up to `EiTypeConfig::device_failover_ms` for a new one on
+    /// the same seat, then start emulating on it, with its keymap if it sent
+    /// a different one, and press the held modifiers again. `chars_typed`
+    /// goes into the `DeviceLost` error if none turns up.
+    fn follow_keyboard(&self, chars_typed: usize) -> Result<(), EiTypeError> {
+        const SLICE: Duration = Duration::from_millis(10);
+        let mut change = self.sink.check_keyboard()?;
+        if change == KeyboardChange::Removed {
+            warn!(
+                "Keyboard device removed, waiting up to {:?} for a replacement",
+                self.device_failover
+            );
+            let deadline = Instant::now() + self.device_failover;
+            while change == KeyboardChange::Removed {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(EiTypeError::DeviceLost { chars_typed });
+                }
+                self.cancellable_sleep((deadline - now).min(SLICE))?;
+                change = self.sink.check_keyboard()?;
+            }
+        }
+        if change == KeyboardChange::Unchanged {
+            return Ok(());
+        }
+
+        if self.keymap_source == KeymapSource::Server {
+            let (keymap, source) =
+                load_keymap(&EiTypeConfig::default(), self.sink.server_keymap()?)?;
+            if source == KeymapSource::Server {
+                self.install_keymap(keymap);
+            }
+        }
+        // The resume this came with is handled here
+        self.sink.take_resumed();
+        self.start_session()?;
+        self.repress_held_modifiers()
+    }
+
+    /// Press the held modifiers again, in order, at the start of a new
+    /// emulation session
+    fn repress_held_modifiers(&self) -> Result<(), EiTypeError> {
+        if self.held_modifiers.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Re-pressing {} held modifier(s) after resume",
+            self.held_modifiers.len()
+        );
+        self.modifiers_pressed_at.set(self.clock.now());
+        for keycode in self.held_modifiers.keycodes() {
+            self.emit(keycode, KeyState::Press)
+                .map_err(|e| EiTypeError::HeldKeysLost(e.to_string()))?;
+            self.update_stats(|stats| stats.held_key_replays += 1);
+        }
+        Ok(())
+    }
+
+    /// Send a frame and flush it, returning the frame's timestamp
+    fn send_frame(&self) -> Result<u64, EiTypeError> {
+        let timestamp = self.clock.now();
+        self.sink.frame(timestamp);
+        self.update_stats(|stats| stats.frames_sent += 1);
+        self.flush_with_retry()?;
+        Ok(timestamp)
+    }
+
+    /// Feed a blocking flush's outcome to adaptive throttling, if enabled
+    fn record_flush(&self, pressured: bool) {
+        if let Some(mut throttle) = self.throttle.get() {
+            throttle.record(pressured);
+            self.throttle.set(Some(throttle));
+        }
+    }
+
+    /// Take adaptive throttling's current pause, if any (never in
+    /// non-blocking mode)
+    fn throttle_pause(&self) {
+        let Some(throttle) = self.throttle.get() else {
+            return;
+        };
+        if throttle.pause.is_zero() || self.nonblocking.get() {
+            return;
+        }
+        self.clock.sleep(throttle.pause);
+        self.update_stats(|stats| {
+            stats.throttle_events += 1;
+            stats.throttle_time += throttle.pause;
+        });
+    }
+
+    /// Tell the keystroke hook, if any, about a key event that was just sent.
+    /// A hook that panics is removed so it can't break typing.
+    fn notify_keystroke(&self, keycode: u32, pressed: bool, timestamp: u64) {
+        let mut hook = self.keystroke_hook.borrow_mut();
+        let Some(callback) = hook.as_mut() else {
+            return;
+        };
+        let info = KeystrokeInfo {
+            keycode,
+            pressed,
+            ch: self.current_char.get().filter(|_| !self.redact_keystrokes),
+            timestamp,
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(info)));
+        if result.is_err() {
+            warn!("Keystroke hook panicked, disabling it");
+            *hook = None;
+        }
+    }
+
+    /// Flush the connection with retry logic for EAGAIN (WouldBlock) errors.
+    ///
+    /// When the socket buffer is full (common with long text input), flush()
+    /// returns EAGAIN. Instead of failing immediately, we wait for the buffer
+    /// to drain and retry.
+    ///
+    /// In non-blocking mode (`try_type_text`) there is a single attempt; EAGAIN
+    /// leaves the data queued and sets `write_blocked` instead of sleeping.
+    fn flush_with_retry(&self) -> Result<(), EiTypeError> {
+        let Some(timing) = &self.timing else {
+            return self.flush_untimed();
+        };
+        let started = Instant::now();
+        let result = self.flush_untimed();
+        timing.borrow_mut().flush += started.elapsed();
+        result
+    }
+
+    /// Body of `flush_with_retry`
+    fn flush_untimed(&self) -> Result<(), EiTypeError> {
+        const MAX_RETRIES: u32 = 50;
+        const INITIAL_DELAY_MS: u64 = 1;
+        const MAX_DELAY_MS: u64 = 100;
+
+        if self.nonblocking.get() {
+            return match self.sink.flush() {
+                Ok(()) => {
+                    self.write_blocked.set(false);
+                    Ok(())
+                }
+                Err(e) if e.raw_os_error() == Some(11) => {
+                    trace!("Socket buffer full (EAGAIN), leaving data queued");
+                    self.write_blocked.set(true);
+                    Ok(())
+                }
+                Err(e) => Err(self.flush_error(e)),
+            };
+        }
+
+        let mut retries = 0;
+        let mut delay_ms = INITIAL_DELAY_MS;
+        let started = self.clock.now();
+
+        loop {
+            match self.sink.flush() {
+                Ok(()) => {
+                    let took = Duration::from_micros(self.clock.now().saturating_sub(started));
+                    self.record_flush(retries > 0 || took > AdaptiveThrottle::SLOW_FLUSH);
+                    return Ok(());
+                }
+                Err(e) => {
+                    // Check if this is EAGAIN/EWOULDBLOCK (errno 11 on Linux)
+                    let raw_errno = e.raw_os_error();
+                    let is_would_block = raw_errno == Some(11); // EAGAIN == EWOULDBLOCK on Linux
+
+                    if !is_would_block {
+                        // Not a recoverable error, fail immediately
+                        return Err(self.flush_error(e));
+                    }
+
+                    retries += 1;
+                    self.update_stats(|stats| stats.flush_retries += 1);
+                    if retries > MAX_RETRIES {
+                        return Err(EiTypeError::Flush(format!(
+                            "Socket buffer full after {} retries: {}",
+                            MAX_RETRIES, e
+                        )));
+                    }
+
+                    trace!(
+                        "Socket buffer full (EAGAIN), waiting {}ms before retry {}/{}",
+                        delay_ms,
+                        retries,
+                        MAX_RETRIES
+                    );
+
+                    self.clock.sleep(Duration::from_millis(delay_ms));
+
+                    // Exponential backoff with cap
+                    delay_ms = (delay_ms * 2).min(MAX_DELAY_MS);
+                }
+            }
+        }
+    }
+
+    /// The error for a failed flush: `EiTypeError::Disconnected` if the
+    /// server sent its reason before closing the socket, else `Flush`
+    fn flush_error(&self, error: std::io::Error) -> EiTypeError {
+        match self.sink.dispatch() {
+            Err(disconnected @ EiTypeError::Disconnected { .. }) => disconnected,
+            _ => EiTypeError::Flush(error.to_string()),
+        }
+    }
+
+    fn press_key_internal(&self, keycode: u32) -> Result<(), EiTypeError> {
+        self.emit(keycode, KeyState::Press)
+    }
+
+    fn release_key_internal(&self, keycode: u32) -> Result<(), EiTypeError> {
+        self.emit(keycode, KeyState::Released)
+    }
+
+    /// Send one key press or release in a frame of its own. Every key event
+    /// goes through here, so they are all paced, tracked, counted and shown
+    /// to the keystroke hook the same way: the key delay follows each one.
+    ///
+    /// A press of a key that is already down is skipped, along with the
+    /// release that matches it, and so is the release of a key that isn't
+    /// down: some compositors ignore the single release after a doubled
+    /// press, leaving the key stuck. With `strict_key_state` both fail.
+    fn emit(&self, keycode: u32, state: KeyState) -> Result<(), EiTypeError> {
+        self.emit_with(keycode, state, Emit::Paced)
+    }
+
+    /// `emit`, paced and checked against the keys down as `mode` says
+    fn emit_with(&self, keycode: u32, state: KeyState, mode: Emit) -> Result<(), EiTypeError> {
+        self.ensure_emulating()?;
+        let pressed = state == KeyState::Press;
+        let paced = mode != Emit::Unpaced;
+        let count = self.keys_down.borrow().count(keycode);
+        if mode == Emit::Forced && !pressed {
+            self.keys_down.borrow_mut().release(keycode);
+            trace!("Releasing key: {} (forced)", keycode);
+        } else if pressed {
+            if count > 0 && self.strict_key_state {
+                return Err(EiTypeError::Typing(format!(
+                    "key {} is already pressed",
+                    keycode
+                )));
+            }
+            self.keys_down.borrow_mut().hold(keycode);
+            if count > 0 {
+                debug!("Key {} is already pressed, not pressing it again", keycode);
+                return Ok(());
+            }
+            trace!("Pressing key: {}", keycode);
+        } else {
+            if count == 0 && self.strict_key_state {
+                return Err(EiTypeError::Typing(format!(
+                    "key {} is not pressed",
+                    keycode
+                )));
+            }
+            self.keys_down.borrow_mut().release(keycode);
+            if count != 1 {
+                if count == 0 {
+                    debug!("Key {} is not pressed, not releasing it", keycode);
+                } else {
+                    debug!("Key {} was pressed again, keeping it down", keycode);
+                }
+                return Ok(());
+            }
+            trace!("Releasing key: {}", keycode);
+        }
+
+        self.sink.key(keycode, state);
+        if pressed {
+            self.update_stats(|stats| stats.keys_pressed += 1);
+        }
+        if paced {
+            self.throttle_pause();
+        }
+        let timestamp = self.send_frame()?;
+        self.notify_keystroke(keycode, pressed, timestamp);
+        if paced {
+            self.key_delay();
+        }
+        Ok(())
+    }
+
+    /// Send planned key events to the device through `emit`
+    fn execute(&self, events: &[PlannedEvent]) -> Result<(), EiTypeError> {
+        for &event in events {
+            match event {
+                PlannedEvent::Press(keycode) => self.emit(keycode, KeyState::Press)?,
+                PlannedEvent::Release(keycode) => self.emit(keycode, KeyState::Released)?,
+                // Planned after every key event; `emit` sends it
+                PlannedEvent::Frame => {}
+                PlannedEvent::Delay(delay) => {
+                    if !self.nonblocking.get() {
+                        self.clock.sleep(delay);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn click_button_internal(&self, button: u32) -> Result<(), EiTypeError> {
+        trace!("Clicking button: {:#x}", button);
+        self.require_capability(DeviceCapability::Button)?;
+        self.ensure_emulating()?;
+        self.sink.button(button, true, self.clock.now())?;
+        self.flush_with_retry()?;
+        self.key_delay();
+        self.ensure_emulating()?;
+        self.sink.button(button, false, self.clock.now())?;
+        self.flush_with_retry()?;
+        self.key_delay();
+        Ok(())
+    }
+
+    /// Send one pointer or touch event (with its frame) through `send`, once
+    /// `capability` is known to be bound
+    fn device_event_internal(
+        &self,
+        capability: DeviceCapability,
+        send: impl FnOnce(u64) -> Result<(), EiTypeError>,
+    ) -> Result<(), EiTypeError> {
+        self.require_capability(capability)?;
+        self.ensure_emulating()?;
+        send(self.clock.now())?;
+        self.flush_with_retry()?;
+        self.key_delay();
+        Ok(())
+    }
+
+    fn tap_key_internal(&self, keycode: u32) -> Result<(), EiTypeError> {
+        self.press_key_internal(keycode)?;
+        self.release_key_internal(keycode)
+    }
+
+    /// Whether `tap_key_fast` can tap `keycode`: there is no key delay to
+    /// keep, and the key isn't down already
+    fn can_tap_fast(&self, keycode: u32) -> bool {
+        self.delay.is_zero() && self.keys_down.borrow().count(keycode) == 0
+    }
+
+    /// Tap `keycode` with as little as possible in the way, for single-key
+    /// calls where latency matters, e.g. a hotkey pressed for the user:
+    /// press and release through `emit` with no pacing (adaptive
+    /// throttling included). Check `can_tap_fast` first.
+    fn tap_key_fast(&self, keycode: u32) -> Result<(), EiTypeError> {
+        trace!("Tapping key {} (fast path)", keycode);
+        self.emit_with(keycode, KeyState::Press, Emit::Unpaced)?;
+        self.emit_with(keycode, KeyState::Released, Emit::Unpaced)
+    }
+
+    /// Sleep for the configured key delay (never in non-blocking mode)
+    fn key_delay(&self) {
+        if !self.delay.is_zero() && !self.nonblocking.get() {
+            self.clock.sleep(self.delay);
+        }
+    }
+
+    fn type_char(&self, ch: char) -> Result<(), EiTypeError> {
+        self.type_char_holding(ch, &[])
+    }
+
+    /// Type `ch` while also holding the `held` keycodes (pressed after any group
+    /// switch, so they can't combine with the switch keys).
+    fn type_char_holding(&self, ch: char, held: &[u32]) -> Result<(), EiTypeError> {
+        self.current_char.set(Some(ch));
+        let result = self.emit_char(ch, held);
+        self.current_char.set(None);
+        result
+    }
+
+    /// Body of `type_char_holding`, run while `current_char` is set
+    fn emit_char(&self, ch: char, held: &[u32]) -> Result<(), EiTypeError> {
+        trace!("Typing character: {:?}", ch);
+        let started = self.timing.as_ref().map(|t| t.borrow_mut().start());
+        let mut plan = CharPlan {
+            ch,
+            events: self.plan_buffer.take(),
+            group_switch: false,
+            qwerty_fallback: false,
+        };
+        self.planner
+            .borrow_mut()
+            .plan_char_into(&mut plan, ch, held)?;
+        let planned = started.map(|started| started.elapsed());
+        // The character reached the server with its own key's press, the
+        // last one planned; anything after that only releases keys
+        let through_press = plan
+            .events
+            .iter()
+            .rposition(|event| matches!(event, PlannedEvent::Press(_)))
+            .map_or(0, |i| i + 1);
+        let (pressing, releasing) = plan.events.split_at(through_press);
+        self.delivered_key.set(None);
+        let executed = self.execute(pressing).and_then(|()| {
+            if let Some(&PlannedEvent::Press(keycode)) = pressing.last() {
+                self.delivered_key.set(Some(keycode));
+            }
+            self.execute(releasing)
+        });
+        self.plan_buffer.set(plan.events);
+        executed?;
+        self.delivered_key.set(None);
+        if let (Some(timing), Some(started), Some(planned)) = (&self.timing, started, planned) {
+            timing.borrow_mut().record(ch, started, planned);
+        }
+        self.update_stats(|stats| {
+            stats.group_switches += usize::from(plan.group_switch);
+            stats.qwerty_fallback_chars += usize::from(plan.qwerty_fallback);
+            stats.chars_typed += 1;
+        });
+        Ok(())
+    }
+
+    /// What to type instead of `text`, which the keymap can't produce: its
+    /// ASCII approximation, when `EiTypeConfig::ascii_transliterate` is set
+    /// and the keymap can produce that
+    fn transliterate(&self, text: &str) -> Option<String> {
+        if !self.ascii_transliterate {
+            return None;
+        }
+        let ascii = ascii_approximation(text)?;
+        self.planner.borrow_mut().check_text(&ascii).ok()?;
+        Some(ascii)
+    }
+
+    /// Type `ascii` in place of `original`, character `index` of the text
+    /// (see `transliterate`)
+    fn type_transliterated(
+        &self,
+        index: usize,
+        original: &str,
+        ascii: String,
+    ) -> Result<Substitution, EiTypeError> {
+        info!("Typing {:?} as {:?}", original, ascii);
+        ascii
+            .chars()
+            .try_for_each(|ch| self.type_char(ch))
+            // Its error is at `original`, so a retry types it all again
+            .inspect_err(|_| self.delivered_key.set(None))?;
+        Ok(Substitution {
+            index,
+            original: original.to_string(),
+            replacement: ascii,
+        })
+    }
+
+    /// Check that every character of a grapheme cluster can be typed, so a
+    /// cluster is typed whole or not at all. Single characters are left to
+    /// `type_char` to find out.
+    fn check_cluster(&self, cluster: &str) -> Result<(), PlanError> {
+        if cluster.chars().nth(1).is_none() {
+            return Ok(());
+        }
+        self.planner.borrow_mut().check_text(cluster)
+    }
+
+    /// Undo any active group switch, returning to the configured group.
+    fn leave_group(&self) -> Result<(), EiTypeError> {
+        let events = self.planner.borrow_mut().finish();
+        self.execute(&events)
+    }
+
+    /// Type a sequence of keys given by XKB keysym name (e.g. "a", "eacute",
+    /// "Cyrillic_de"), the way xdotool scripts refer to keys.
+    ///
+    /// Every name is resolved before anything is typed, so an unknown name
+    /// doesn't leave the sequence half-typed.
+    pub fn type_keysym_names(&self, names: &[&str]) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        let planner = self.planner.borrow();
+        let keymap = planner
+            .keymap()
+            .ok_or_else(|| EiTypeError::Keymap("No keymap loaded".to_string()))?;
+
+        let mut plans = Vec::with_capacity(names.len());
+        for name in names {
+            let keysym =
+                keysym_from_name(name).ok_or_else(|| EiTypeError::UnknownKey(name.to_string()))?;
+            let key_match = find_keycode_for_keysym(keysym, keymap, planner.layout_index)
+                .ok_or_else(|| EiTypeError::KeysymNotFound(name.to_string()))?;
+            debug!(
+                "Planned keysym {} (keycode {}, level {})",
+                name, key_match.evdev_keycode, key_match.level
+            );
+            let mut events = Vec::new();
+            planner.push_key_match(&mut events, keymap, &key_match, &[]);
+            plans.push(events);
+        }
+        drop(planner);
+
+        plans.iter().try_for_each(|events| self.execute(events))
+    }
+
+    /// Type a string of text
+    ///
+    /// Errors are wrapped in `EiTypeError::InText` with the position of the
+    /// failing character; use `EiTypeError::root` to get the underlying error.
+    ///
+    /// If a `ChunkPolicy` is configured, the text is typed in chunks with a
+    /// pause between them; the pause can be interrupted with a `CancelHandle`.
+    pub fn type_text(&self, text: &str) -> Result<(), EiTypeError> {
+        let text = self.terminal_filtered(text);
+        if self.type_single_char_fast(&text)? {
+            return Ok(());
+        }
+        self.type_text_with(&text, |_, _, error| Err(error), |_| {})
+    }
+
+    /// `text` through `terminal_safe_text` if `EiTypeConfig::terminal_safe`
+    /// is set
+    fn terminal_filtered<'a>(&self, text: &'a str) -> std::borrow::Cow<'a, str> {
+        if !self.terminal_safe {
+            return std::borrow::Cow::Borrowed(text);
+        }
+        if text.ends_with(is_line_break) {
+            info!("Not typing the line break at the end of the text (terminal_safe)");
+        }
+        terminal_safe_text(text)
+    }
+
+    /// `type_timed` items filtered like `terminal_filtered`; a character
+    /// left out takes its pause with it
+    fn terminal_filtered_items<'a>(
+        &self,
+        items: &'a [(char, Duration)],
+    ) -> std::borrow::Cow<'a, [(char, Duration)]> {
+        let text: String = items.iter().map(|&(ch, _)| ch).collect();
+        if let std::borrow::Cow::Borrowed(_) = self.terminal_filtered(&text) {
+            return std::borrow::Cow::Borrowed(items);
+        }
+        let safe = items
+            .iter()
+            .zip(terminal_safe_chars(&text))
+            .filter_map(|(&(_, delay), ch)| Some((ch?, delay)));
+        std::borrow::Cow::Owned(safe.collect())
+    }
+
+    /// Type characters with a pause of its own after each one, e.g. to
+    /// replay recorded human typing with its original rhythm
+    ///
+    /// Each delay comes after its character and on top of
+    /// `EiTypeConfig::delay_ms`; pauses can be interrupted with a
+    /// `CancelHandle`. Errors are wrapped in `EiTypeError::InText` as for
+    /// `type_text`, and `EiTypeConfig::terminal_safe` filters the
+    /// characters the same way.
+    pub fn type_timed(&self, items: &[(char, Duration)]) -> Result<(), EiTypeError> {
+        let items = &*self.terminal_filtered_items(items);
+        let text: String = items.iter().map(|&(ch, _)| ch).collect();
+        self.check_text_len(&text)?;
+        self.check_focus()?;
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Typing timed text: {:?}", text);
+        let total = items.len();
+        let result = text.char_indices().zip(items).enumerate().try_for_each(
+            |(index, ((offset, ch), &(_, delay)))| {
+                self.type_text_step(ch, index, total, false, Err, |_| {})
+                    .and_then(|()| self.cancellable_sleep(delay))
+                    .map_err(|e| error_in_text(e, &text, index, offset))
+            },
+        );
+        let restored = self.leave_group();
+        let stopped = self.stop_after_text();
+        result.and(restored).and(stopped)
+    }
+
+    /// Type `text` through `tap_key_fast` if it is a single character typed
+    /// by a plain key tap and no per-character progress or timing is
+    /// wanted; returns false, having sent nothing, otherwise
+    fn type_single_char_fast(&self, text: &str) -> Result<bool, EiTypeError> {
+        let mut chars = text.chars();
+        let (Some(ch), None) = (chars.next(), chars.next()) else {
+            return Ok(false);
+        };
+        if self.progress.is_some() || self.timing.is_some() {
+            return Ok(false);
+        }
+        // Left out by `type_text_with`, even if a key types it
+        if self.strip_format_chars && is_format_char_cluster(text) {
+            return Ok(false);
+        }
+        let Some(keycode) = self.planner.borrow_mut().single_tap(ch) else {
+            return Ok(false);
+        };
+        if !self.can_tap_fast(keycode) {
+            return Ok(false);
+        }
+
+        self.check_text_len(text)?;
+        self.check_focus()?;
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Typing text: {:?} (fast path)", text);
+        self.current_char.set(Some(ch));
+        let result = self
+            .check_cancelled()
+            .and_then(|()| self.follow_keyboard(0))
+            .and_then(|()| self.tap_key_fast(keycode));
+        self.current_char.set(None);
+        result.map_err(|e| error_in_text(e, text, 0, 0))?;
+        self.update_stats(|stats| stats.chars_typed += 1);
+        self.stop_after_text()?;
+        Ok(true)
+    }
+
+    /// Type `text` best effort: characters no key produces are skipped
+    /// rather than failing the call, and listed in the returned report.
+    ///
+    /// Other errors (a lost connection, cancellation, `max_text_len`) are
+    /// still returned, as from `type_text`.
+    pub fn type_text_report(&self, text: &str) -> Result<TypeOutcome, EiTypeError> {
+        let before = self.stats();
+        let text = &*self.terminal_filtered(text);
+        let mut skipped = Vec::new();
+        let mut substitutions = Vec::new();
+        self.type_text_with(
+            text,
+            |index, cluster, error| {
+                warn!("Skipping {:?} at {}: {}", cluster, index, error);
+                skipped.extend(cluster.chars().enumerate().map(|(i, ch)| SkippedChar {
+                    index: index + i,
+                    ch,
+                    reason: error.to_string(),
+                }));
+                Ok(())
+            },
+            |substitution| substitutions.push(substitution),
+        )?;
+
+        let stats = self.stats().since(&before);
+        let mut fallbacks = Vec::new();
+        if stats.group_switches > 0 {
+            fallbacks.push("group_switch".to_string());
+        }
+        if stats.qwerty_fallback_chars > 0 {
+            fallbacks.push("qwerty_fallback".to_string());
+        }
+        let chars_requested = text.chars().count();
+        Ok(TypeOutcome {
+            chars_requested,
+            // A substituted character counts once, however long its replacement
+            chars_delivered: chars_requested - skipped.len() - stats.format_chars_stripped,
+            skipped,
+            substitutions,
+            format_chars_stripped: stats.format_chars_stripped,
+            fallbacks,
+            stats,
+        })
+    }
+
+    /// Type `text` like `type_text`, returning how many key presses that
+    /// took: modifiers (Shift, AltGr), dead keys and group switch keys count
+    /// as well as the keys for the characters themselves, so e.g. "Hi" takes
+    /// three. Callers can use it to predict per-keystroke undo depth.
+    ///
+    /// Counted as the planned key events are sent, so a modifier already
+    /// held (e.g. with `hold_modifier`) isn't pressed, or counted, again.
+    pub fn type_text_counting(&self, text: &str) -> Result<usize, EiTypeError> {
+        let before = self.stats();
+        self.type_text(text)?;
+        Ok(self.stats().since(&before).keys_pressed)
+    }
+
+    /// Type `text` like `type_text`, but if the connection fails partway,
+    /// reconnect (see `reconnect`) and carry on where it broke rather than
+    /// from the start, so nothing is typed twice: from the character that
+    /// failed, or after it if its key press got through (its key is then
+    /// released on the new connection). Gives up after three reconnects.
+    ///
+    /// Only connection failures (`Connection`, `Disconnected`, `Flush`) are
+    /// retried; anything else (e.g. a character the keymap can't produce)
+    /// is returned at once, as is the original error when the connection
+    /// can't be made again. Error positions are relative to the text still
+    /// left to type.
+    pub fn type_text_reliable(&mut self, text: &str) -> Result<(), EiTypeError> {
+        const MAX_RECONNECTS: usize = 3;
+
+        // Filtered up front, so error offsets are into the text typed
+        let text = &*self.terminal_filtered(text);
+        let mut offset = 0;
+        let mut reconnects = 0;
+        loop {
+            let error = match self.type_text(&text[offset..]) {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            let EiTypeError::InText {
+                byte_offset,
+                source,
+                ..
+            } = &error
+            else {
+                return Err(error);
+            };
+            let delivered = self.delivered_key.take();
+            let transient = matches!(
+                source.root(),
+                EiTypeError::Connection(_)
+                    | EiTypeError::Disconnected { .. }
+                    | EiTypeError::Flush(_)
+            );
+            if !transient || self.reconnector.is_none() || reconnects == MAX_RECONNECTS {
+                return Err(error);
+            }
+            warn!("Typing failed ({}), reconnecting", error);
+            offset += byte_offset;
+            if delivered.is_some() {
+                offset += text[offset..].chars().next().map_or(0, char::len_utf8);
+                self.update_stats(|stats| stats.chars_typed += 1);
+            }
+            reconnects += 1;
+            let reconnected = self
+                .reconnect()
+                .and_then(|()| delivered.map_or(Ok(()), |keycode| self.release_stale(keycode)));
+            if let Err(e) = reconnected {
+                warn!("Reconnecting failed: {}", e);
+                return Err(error);
+            }
+        }
+    }
+
+    /// Body of `type_text` and `type_text_report`. `not_found(index,
+    /// cluster, error)` decides what happens to a grapheme cluster with a
+    /// character no key produces: returning Ok skips the whole cluster,
+    /// returning the error fails the call. Nothing of such a cluster is
+    /// typed, so e.g. half an emoji ZWJ sequence never arrives.
+    fn type_text_with(
+        &self,
+        text: &str,
+        mut not_found: impl FnMut(usize, &str, EiTypeError) -> Result<(), EiTypeError>,
+        mut substituted: impl FnMut(Substitution),
+    ) -> Result<(), EiTypeError> {
+        self.check_text_len(text)?;
+        self.check_focus()?;
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Typing text: {:?}", text);
+        let total = text.chars().count();
+        let chunk_size = self.chunk.map(|c| c.chars_per_chunk).filter(|&n| n > 0);
+
+        let mut typed = 0;
+        let result = grapheme_indices(text).try_for_each(|(cluster_offset, cluster)| {
+            let start = typed;
+            typed += cluster.chars().count();
+            if self.strip_format_chars && is_format_char_cluster(cluster) {
+                debug!(
+                    "Leaving out {} at {}",
+                    describe_char(cluster.chars().next().unwrap()),
+                    start
+                );
+                self.update_stats(|stats| stats.format_chars_stripped += 1);
+                return Ok(());
+            }
+            if let Err(e) = self.check_cluster(cluster) {
+                if let Some(ascii) = self.transliterate(cluster) {
+                    return self
+                        .type_transliterated(start, cluster, ascii)
+                        .map(&mut substituted)
+                        .map_err(|err| error_in_text(err, text, start, cluster_offset));
+                }
+                let byte_offset = cluster_offset
+                    + cluster
+                        .char_indices()
+                        .nth(e.index)
+                        .map_or(0, |(offset, _)| offset);
+                return not_found(start, cluster, e.into())
+                    .map_err(|err| error_in_text(err, text, start + e.index, byte_offset));
+            }
+            // Pause for a chunk boundary only between clusters
+            let chunk_boundary = chunk_size.is_some_and(|n| typed / n > start / n) && typed < total;
+            let last = typed - start - 1;
+            cluster
+                .char_indices()
+                .enumerate()
+                .try_for_each(|(i, (offset, ch))| {
+                    let index = start + i;
+                    self.type_text_step(
+                        ch,
+                        index,
+                        total,
+                        chunk_boundary && i == last,
+                        |e| not_found(index, cluster, e),
+                        &mut substituted,
+                    )
+                    .map_err(|e| error_in_text(e, text, index, cluster_offset + offset))
+                })
+        });
+        // Always restore the user's group, even if typing failed partway
+        let restored = self.leave_group();
+        let stopped = self.stop_after_text();
+        result.and(restored).and(stopped)
+    }
+
+    /// Stop emulating at the end of a `type_text` call when configured to
+    /// (`EiTypeConfig::stop_emulating_after_each`). Never stops while
+    /// modifiers are held.
+    fn stop_after_text(&self) -> Result<(), EiTypeError> {
+        if self.stop_after_each && self.emulating.get() && self.held_modifiers.is_empty() {
+            debug!("Text done, stopping emulation");
+            self.stop_session()?;
+        }
+        Ok(())
+    }
+
+    /// Fail if `text` is longer than `EiTypeConfig::max_text_len` characters
+    fn check_text_len(&self, text: &str) -> Result<(), EiTypeError> {
+        match self.max_text_len {
+            Some(max) if text.chars().nth(max).is_some() => Err(EiTypeError::Typing(format!(
+                "text exceeds max length {}",
+                max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if `EiTypeConfig::refuse_if_focused_app` has keyboard focus
+    fn check_focus(&self) -> Result<(), EiTypeError> {
+        match &self.refuse_if_focused_app {
+            Some(app_id) => check_focus_guard(app_id, detect_focused_app),
+            None => Ok(()),
+        }
+    }
+
+    /// Focus the window matching `target` and wait
+    /// `EiTypeConfig::focus_settle_ms` for keyboard focus to follow, so what
+    /// is typed next goes there.
+    ///
+    /// Windows are looked up and focused through the compositor's IPC
+    /// (`swaymsg` on Sway, `hyprctl` on Hyprland). Fails with
+    /// `FocusTargetNotFound` if no window matches; on other compositors
+    /// there is no way to look, so a warning is logged and nothing focused.
+    pub fn focus_window(&self, target: &FocusTarget) -> Result<(), EiTypeError> {
+        if focus_window_with(target, CompositorIpc::detect(), command_stdout)? {
+            self.cancellable_sleep(self.focus_settle)?;
+        }
+        Ok(())
+    }
+
+    /// Type a string of text, then press Return, e.g. to submit a command.
+    ///
+    /// Return is sent as the Return key itself, whatever the layout maps the
+    /// typed text to.
+    pub fn type_line(&self, text: &str) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        self.type_text(text)?;
+        let keycode = self.keycode_for_name("return")?;
+        debug!("Submitting line (keycode {})", keycode);
+        self.tap_key_internal(keycode)
+    }
+
+    /// Fix up `text` that was typed on the wrong layout: delete it with one
+    /// Backspace per character, then type its `convert_layout` conversion
+    /// from layout `from` to layout `to`.
+    pub fn retype_converted(&self, text: &str, from: u32, to: u32) -> Result<(), EiTypeError> {
+        let converted = {
+            let planner = self.planner.borrow();
+            let keymap = planner
+                .keymap()
+                .ok_or_else(|| EiTypeError::Keymap("No keymap to convert with".to_string()))?;
+            convert_layout(text, from, to, keymap)
+        };
+        debug!("Retyping {:?} as {:?}", text, converted);
+
+        let _exclusive = self.lock_exclusive()?;
+        self.delete_chars(text.chars().count())?;
+        self.type_text(&converted)
+    }
+
+    /// Delete the `count` characters before the cursor with one Backspace
+    /// each. Cancellable like `type_text`.
+    pub fn delete_chars(&self, count: usize) -> Result<(), EiTypeError> {
+        if count == 0 {
+            return Ok(());
+        }
+        self.check_focus()?;
+        let backspace = self.keycode_for_name("backspace")?;
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Deleting {} characters", count);
+        for _ in 0..count {
+            self.check_cancelled()?;
+            self.tap_key_internal(backspace)?;
+        }
+        Ok(())
+    }
+
+    /// Type as much of `cursor`'s text as possible without ever blocking.
+    ///
+    /// For event-loop driven applications: this never sleeps, makes only
+    /// single non-blocking flush attempts, and ignores `delay_ms` and chunking.
+    /// When the socket is full it returns `TryTypeStatus::WouldBlock`; wait
+    /// for the connection fd to become writable, then call again with the same
+    /// cursor. Each call types at least one character once the socket drains.
+    ///
+    /// Errors are wrapped in `EiTypeError::InText`; the cursor stays on the
+    /// failing character.
+    pub fn try_type_text(&self, cursor: &mut TypeCursor) -> Result<TryTypeStatus, EiTypeError> {
+        self.nonblocking.set(true);
+        let result = self.try_type_text_inner(cursor);
+        self.nonblocking.set(false);
+        result
+    }
+
+    fn try_type_text_inner(&self, cursor: &mut TypeCursor) -> Result<TryTypeStatus, EiTypeError> {
+        if cursor.typed == 0 {
+            if let std::borrow::Cow::Owned(safe) = self.terminal_filtered(&cursor.text) {
+                cursor.text = safe;
+            }
+            self.check_text_len(&cursor.text)?;
+            self.check_focus()?;
+        }
+        // Push out whatever an earlier call left queued
+        self.flush_with_retry()?;
+
+        while let Some(ch) = cursor.remaining().chars().next() {
+            if self.write_blocked.get() {
+                trace!("Socket full after {} characters", cursor.typed);
+                return Ok(TryTypeStatus::WouldBlock);
+            }
+            if self.strip_format_chars && format_char_name(ch).is_some() {
+                self.update_stats(|stats| stats.format_chars_stripped += 1);
+            } else {
+                self.check_cancelled()
+                    .and_then(|()| self.type_char(ch))
+                    .map_err(|e| {
+                        error_in_text(e, &cursor.text, cursor.typed, cursor.byte_offset)
+                    })?;
+            }
+            cursor.byte_offset += ch.len_utf8();
+            cursor.typed += 1;
+        }
+
+        self.leave_group()?;
+        if self.write_blocked.get() {
+            return Ok(TryTypeStatus::WouldBlock);
+        }
+        Ok(TryTypeStatus::Completed)
+    }
+
+    /// Process whatever the server has sent so far, without blocking.
+    ///
+    /// Call this when the connection fd (see the `AsFd` impl) becomes readable
+    /// in an external event loop. Returns an error if the server disconnected.
+    pub fn dispatch_pending(&self) -> Result<(), EiTypeError> {
+        self.sink.dispatch()
+    }
+
+    /// Flush, then wait until the server has read everything written to the
+    /// socket, or `timeout` passes. Returns whether it did.
+    fn drain(&self, timeout: Duration) -> Result<bool, EiTypeError> {
+        self.flush_with_retry()?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let unsent = self
+                .sink
+                .unsent_bytes()
+                .map_err(|e| EiTypeError::Connection(e.to_string()))?;
+            if unsent == 0 {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            trace!("{} bytes not yet read by the server", unsent);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// `drain`, then `sync_within` whatever is left of `timeout`; returns
+    /// whether the server read and confirmed everything in time
+    fn settle_input(&self, timeout: Duration) -> Result<bool, EiTypeError> {
+        let deadline = Instant::now() + timeout;
+        if !self.drain(timeout)? {
+            return Ok(false);
+        }
+        self.sync_within(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Flush, then block until the server confirms it has processed
+    /// everything sent so far.
+    ///
+    /// A flush only hands events to the socket; a server that hasn't read
+    /// them yet when the connection closes may drop them. `close` syncs
+    /// before disconnecting. Fails with `Connection` if the server doesn't
+    /// confirm within a couple of seconds.
+    pub fn sync(&self) -> Result<(), EiTypeError> {
+        if !self.sync_within(SYNC_TIMEOUT)? {
+            return Err(EiTypeError::Connection(
+                "Timed out waiting for the server to process input".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Like `sync`, but wait at most `timeout`; returns false if the server
+    /// hasn't confirmed by then
+    fn sync_within(&self, timeout: Duration) -> Result<bool, EiTypeError> {
+        let done = self.sink.sync()?;
+        self.flush_with_retry()?;
+        self.dispatch_until(timeout, || done.get())
+    }
+
+    /// Handle server events as they arrive until `done` returns true, for at
+    /// most `timeout`; returns false if it still doesn't by then
+    fn dispatch_until(
+        &self,
+        timeout: Duration,
+        done: impl Fn() -> bool,
+    ) -> Result<bool, EiTypeError> {
+        use rustix::event::{poll, PollFd, PollFlags};
+
+        let deadline = Instant::now() + timeout;
+        while !done() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            let remaining = deadline - now;
+            let timeout = rustix::time::Timespec {
+                tv_sec: remaining.as_secs() as i64,
+                tv_nsec: remaining.subsec_nanos() as i64,
+            };
+            let fd = self.sink.as_fd();
+            let mut pollfd = [PollFd::new(&fd, PollFlags::IN)];
+            let _ = poll(&mut pollfd, Some(&timeout));
+            self.sink.dispatch()?;
+        }
+        Ok(true)
+    }
+
+    /// Receive connection and device status changes, e.g. to show "paused"
+    /// in a UI. The receiver gets `StatusEvent::Connected` (or `Disconnected`
+    /// after `close`) straight away, then every change seen from now on.
+    ///
+    /// Server events are only read while typing pauses between chunks or
+    /// when `dispatch_pending` is called, so call that whenever the
+    /// connection fd is readable. The receiver may be moved to another thread.
+    pub fn status_receiver(&self) -> std::sync::mpsc::Receiver<StatusEvent> {
+        let current = if self.closed {
+            StatusEvent::Disconnected {
+                reason: "Closed".to_string(),
+            }
+        } else {
+            StatusEvent::Connected
+        };
+        self.status.subscribe(current)
+    }
+
+    /// Whether `try_type_text` left data queued because the socket was full.
+    ///
+    /// While this is true, wait for the connection fd to become writable and
+    /// call `try_type_text` again.
+    pub fn needs_flush(&self) -> bool {
+        self.write_blocked.get()
+    }
+
+    /// Type one character of a `type_text` call and handle what follows it
+    /// (progress reporting, and a chunk pause if `chunk_boundary`).
+    /// `not_found` handles a character no key produces, and `substituted`
+    /// hears of one typed as something else.
+    fn type_text_step(
+        &self,
+        ch: char,
+        index: usize,
+        total: usize,
+        chunk_boundary: bool,
+        not_found: impl FnOnce(EiTypeError) -> Result<(), EiTypeError>,
+        substituted: impl FnOnce(Substitution),
+    ) -> Result<(), EiTypeError> {
+        self.check_cancelled()?;
+        self.follow_keyboard(index)?;
+        self.refresh_long_holds()?;
+        match self.type_char(ch) {
+            Err(error @ EiTypeError::CharNotFound(_)) => {
+                let original = ch.to_string();
+                match self.transliterate(&original) {
+                    Some(ascii) => substituted(self.type_transliterated(index, &original, ascii)?),
+                    None => not_found(error)?,
+                }
+            }
+            result => result?,
+        }
+
+        let typed = index + 1;
+        if let Some(progress) = &self.progress {
+            progress(&TypingProgress {
+                typed,
+                total,
+                chunk_boundary,
+            });
+        }
+        if chunk_boundary {
+            self.chunk_pause()?;
+        }
+        Ok(())
+    }
+
+    /// Release the held modifier keys and press them again, in order, once
+    /// they have been down for `EiTypeConfig::max_modifier_hold_ms`. Keys
+    /// held with `key_down` that aren't modifiers stay down.
+    fn refresh_long_holds(&self) -> Result<(), EiTypeError> {
+        let Some(max_hold) = self.max_modifier_hold else {
+            return Ok(());
+        };
+        let held_for = self
+            .clock
+            .now()
+            .saturating_sub(self.modifiers_pressed_at.get());
+        if self.held_modifiers.is_empty() || held_for < max_hold.as_micros() as u64 {
+            return Ok(());
+        }
+        let modifiers: Vec<u32> = {
+            let planner = self.planner.borrow();
+            self.held_modifiers
+                .keycodes()
+                .filter(|keycode| {
+                    STANDARD_MODIFIER_KEYCODES.contains(keycode)
+                        || planner.keymap_mod_keycodes.values().any(|k| k == keycode)
+                })
+                .collect()
+        };
+        debug!(
+            "Modifiers held for {:?}, pressing {:?} again",
+            max_hold, modifiers
+        );
+        for &keycode in modifiers.iter().rev() {
+            self.release_key_internal(keycode)?;
+        }
+        for &keycode in &modifiers {
+            self.press_key_internal(keycode)?;
+        }
+        self.modifiers_pressed_at.set(self.clock.now());
+        Ok(())
+    }
+
+    /// Give the compositor breathing room between chunks: flush everything,
+    /// drain incoming events, then sleep for the configured pause.
+    fn chunk_pause(&self) -> Result<(), EiTypeError> {
+        self.flush_with_retry()?;
+        self.sink.dispatch()?;
+        if let Some(chunk) = self.chunk {
+            trace!("Chunk boundary, pausing {:?}", chunk.pause_between);
+            self.cancellable_sleep(chunk.pause_between)?;
+        }
+        Ok(())
+    }
+
+    /// Return `Cancelled` (consuming the request) if cancellation was requested
+    fn check_cancelled(&self) -> Result<(), EiTypeError> {
+        if self.cancel.swap(false, Ordering::SeqCst) {
+            debug!("Typing cancelled");
+            return Err(EiTypeError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Sleep for `duration`, waking early if cancellation is requested
+    fn cancellable_sleep(&self, duration: Duration) -> Result<(), EiTypeError> {
+        const SLICE: Duration = Duration::from_millis(10);
+        #[cfg(test)]
+        if let Clock::Fake(_) = &self.clock {
+            self.check_cancelled()?;
+            self.clock.sleep(duration);
+            return Ok(());
+        }
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check_cancelled()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            std::thread::sleep((deadline - now).min(SLICE));
+        }
+    }
+
+    /// Get a handle that can cancel typing from another thread
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            flag: Arc::clone(&self.cancel),
+        }
+    }
+
+    /// Set a callback invoked after each character typed by `type_text`
+    pub fn set_progress_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&TypingProgress) + 'static,
+    {
+        self.progress = Some(Box::new(callback));
+    }
+
+    /// Remove a previously set progress callback
+    pub fn clear_progress_callback(&mut self) {
+        self.progress = None;
+    }
+
+    /// Set a hook invoked after every key press or release frame is flushed,
+    /// e.g. to play click sounds in sync with the actual key events.
+    ///
+    /// The hook runs on the typing thread between key events, so it should
+    /// return quickly. If it panics it is removed and typing carries on.
+    pub fn set_keystroke_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(KeystrokeInfo) + 'static,
+    {
+        *self.keystroke_hook.get_mut() = Some(Box::new(hook));
+    }
+
+    /// Remove a previously set keystroke hook
+    pub fn clear_keystroke_hook(&mut self) {
+        *self.keystroke_hook.get_mut() = None;
+    }
+
+    /// Whether to hide typed characters from the keystroke hook (`ch` is
+    /// always `None`), e.g. while typing a password
+    pub fn set_redact_keystrokes(&mut self, redact: bool) {
+        self.redact_keystrokes = redact;
+    }
+
+    /// Change the delay after every key press and release
+    /// (`EiTypeConfig::delay_ms`), e.g. to slow down for an app that drops
+    /// keys, without reconnecting. It stays in effect whatever profiles are
+    /// applied or cleared, until `reset_delay`.
+    pub fn set_delay(&mut self, delay: Duration) {
+        debug!("Key delay set to {:?}", delay);
+        self.delay_override = Some(delay);
+        self.delay = delay;
+    }
+
+    /// Undo `set_delay`: back to the active profile's delay, or the
+    /// configured one
+    pub fn reset_delay(&mut self) {
+        self.delay_override = None;
+        let profile = (self.active_profile.as_deref())
+            .and_then(|name| self.profiles.get(name).ok())
+            .cloned()
+            .unwrap_or_default();
+        let settings = self.base_profile.merged(&profile);
+        self.delay = Duration::from_millis(settings.delay_ms.unwrap_or_default());
+    }
+
+    /// The current delay after every key press and release
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Replace the profiles `apply_profile` selects from
+    pub fn set_profiles(&mut self, profiles: Profiles) {
+        self.profiles = profiles;
+    }
+
+    /// Switch to the profile called `name`, e.g. when typing moves to
+    /// another app: its settings apply over the configured ones, and
+    /// settings it leaves unset go back to their configured values, whatever
+    /// an earlier profile set. Unknown names fail, listing the profiles
+    /// there are.
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), EiTypeError> {
+        let settings = self.base_profile.merged(self.profiles.get(name)?);
+        if settings.ascii_transliterate == Some(true) && !cfg!(feature = "transliterate") {
+            return Err(EiTypeError::InvalidConfig(format!(
+                "profile {:?} sets ascii_transliterate, but eitype was built without the \
+                 transliterate feature",
+                name
+            )));
+        }
+        debug!("Applying profile {:?}: {:?}", name, settings);
+        self.apply_profile_settings(&settings);
+        self.active_profile = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Go back to the configured settings, undoing `apply_profile`
+    pub fn clear_profile(&mut self) {
+        let base = self.base_profile.clone();
+        self.apply_profile_settings(&base);
+        self.active_profile = None;
+    }
+
+    /// Name of the profile last applied, if any
+    pub fn active_profile(&self) -> Option<&str> {
+        self.active_profile.as_deref()
+    }
+
+    fn apply_profile_settings(&mut self, settings: &TypingProfile) {
+        let millis = |ms: Option<u64>| ms.filter(|&ms| ms > 0).map(Duration::from_millis);
+        if let Some(ms) = settings.delay_ms {
+            self.delay = self.delay_override.unwrap_or(Duration::from_millis(ms));
+        }
+        let planner = self.planner.get_mut();
+        planner.settle_after_word = millis(settings.settle_after_word_ms);
+        planner.modifier_settle = millis(settings.modifier_settle_ms);
+        if let Some(threshold) = settings.paste_threshold {
+            self.paste_threshold = threshold;
+        }
+        if let Some(transliterate) = settings.ascii_transliterate {
+            self.ascii_transliterate = transliterate;
+        }
+        if let Some(strip) = settings.strip_format_chars {
+            self.strip_format_chars = strip;
+        }
+    }
+
+    /// Insert text, pasting it if it is longer than
+    /// `EiTypeConfig::paste_threshold` characters and typing it otherwise.
+    ///
+    /// Pasting is more reliable for large blocks; typing is more precise for
+    /// small edits. Pastes with the default `PasteOptions`. If the clipboard
+    /// can't be set (e.g. `wl-copy` is not installed), the text is typed instead.
+    pub fn insert_text(&self, text: &str) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        let long = self
+            .paste_threshold
+            .is_some_and(|threshold| text.chars().count() > threshold);
+        if long {
+            match self.paste_text(text, &PasteOptions::default()) {
+                Err(EiTypeError::Clipboard(e)) => warn!("{}; typing text instead", e),
+                result => return result,
+            }
+        }
+        self.type_text(text)
+    }
+
+    /// Paste `text` by placing it in a selection and triggering a paste.
+    ///
+    /// With `restore_after`, the selection's previous contents are put back
+    /// after that delay, giving the application time to read the pasted text.
+    pub fn paste_text(&self, text: &str, options: &PasteOptions) -> Result<(), EiTypeError> {
+        let clipboard = self
+            .clipboard
+            .as_ref()
+            .ok_or_else(|| EiTypeError::Clipboard("No clipboard configured".to_string()))?;
+        // Fail before touching the selection if the trigger can't be sent
+        if options.trigger == PasteTrigger::MiddleClick {
+            self.require_capability(DeviceCapability::Button)?;
+        }
+        self.check_focus()?;
+        let _exclusive = self.lock_exclusive()?;
+
+        let saved = match options.restore_after {
+            Some(_) => clipboard.get_text(options.selection)?,
+            None => None,
+        };
+        let text = &*self.terminal_filtered(text);
+        clipboard.set_text(options.selection, text)?;
+
+        debug!(
+            "Pasting {} characters from {:?} with {:?}",
+            text.chars().count(),
+            options.selection,
+            options.trigger
+        );
+        let result = self.trigger_paste(options.trigger);
+
+        let Some(delay) = options.restore_after else {
+            return result;
+        };
+        std::thread::sleep(delay);
+        let restored = match saved {
+            Some(previous) => clipboard.set_text(options.selection, &previous),
+            None => {
+                debug!(
+                    "{:?} was empty before pasting; leaving it",
+                    options.selection
+                );
+                Ok(())
+            }
+        };
+        result.and(restored)
+    }
+
+    /// Send the input that makes the focused application paste
+    fn trigger_paste(&self, trigger: PasteTrigger) -> Result<(), EiTypeError> {
+        let keycode = |name: &str, default: u32| {
+            let planner = self.planner.borrow();
+            planner.key_to_keycode.get(name).copied().unwrap_or(default)
+        };
+        let ctrl = keycode("ctrl", 29);
+        let shift = keycode("shift", 42);
+        match trigger {
+            PasteTrigger::CtrlV => self.while_holding(&[ctrl], || self.tap_v()),
+            PasteTrigger::CtrlShiftV => self.while_holding(&[ctrl, shift], || self.tap_v()),
+            PasteTrigger::ShiftInsert => {
+                let insert = keycode("insert", 110);
+                self.while_holding(&[shift], || self.tap_key_internal(insert))
+            }
+            PasteTrigger::MiddleClick => self.click_button_internal(BTN_MIDDLE),
+        }
+    }
+
+    /// Tap whichever key produces "v" in the active layout
+    fn tap_v(&self) -> Result<(), EiTypeError> {
+        let planner = self.planner.borrow();
+        let v = planner
+            .keymap()
+            .and_then(|keymap| find_keycode_for_char('v', keymap, planner.layout_index).ok())
+            .map(|key_match| key_match.evdev_keycode)
+            .or_else(|| planner.key_to_keycode.get("v").copied())
+            .unwrap_or(47);
+        drop(planner);
+        self.tap_key_internal(v)
+    }
+
+    /// Scroll by whole wheel notches, like a mouse wheel.
+    ///
+    /// Positive `dy` scrolls down, positive `dx` scrolls right. Needs a pointer
+    /// device (see `EiTypeConfig::pointer`).
+    pub fn scroll_discrete(&self, dx: i32, dy: i32) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Scrolling {}, {} notches", dx, dy);
+        // EI counts discrete scroll in 1/120ths of a notch
+        let (dx, dy) = (dx.saturating_mul(120), dy.saturating_mul(120));
+        self.device_event_internal(DeviceCapability::Scroll, |timestamp| {
+            self.sink.scroll_discrete(dx, dy, timestamp)
+        })
+    }
+
+    /// Scroll by pixel deltas, like a touchpad.
+    ///
+    /// Positive `dy` scrolls down, positive `dx` scrolls right. Needs a pointer
+    /// device (see `EiTypeConfig::pointer`).
+    pub fn scroll_smooth(&self, dx: f64, dy: f64) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Scrolling {}, {} pixels", dx, dy);
+        self.device_event_internal(DeviceCapability::Scroll, |timestamp| {
+            self.sink.scroll_smooth(dx as f32, dy as f32, timestamp)
+        })
+    }
+
+    /// Put a finger down at (`x`, `y`), in logical pixels.
+    ///
+    /// `slot` names the touch for the `touch_motion` and `touch_up` that
+    /// follow, so several touches can be in progress at once. The position
+    /// must lie in one of the touch device's regions, if it has any. Needs
+    /// the touch capability (see `EiTypeConfig::capabilities`).
+    pub fn touch_down(&self, slot: u32, x: f64, y: f64) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        if self.touches.borrow().contains(&slot) {
+            return Err(EiTypeError::Touch(format!("slot {} is already down", slot)));
+        }
+        self.check_touch_position(x, y)?;
+        debug!("Touch {} down at {}, {}", slot, x, y);
+        self.device_event_internal(DeviceCapability::Touch, |timestamp| {
+            self.sink.touch_down(slot, x as f32, y as f32, timestamp)
+        })?;
+        self.touches.borrow_mut().push(slot);
+        Ok(())
+    }
+
+    /// Move the touch in `slot` to (`x`, `y`), in logical pixels
+    pub fn touch_motion(&self, slot: u32, x: f64, y: f64) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        self.check_touch_slot(slot)?;
+        self.check_touch_position(x, y)?;
+        trace!("Touch {} moved to {}, {}", slot, x, y);
+        self.device_event_internal(DeviceCapability::Touch, |timestamp| {
+            self.sink.touch_motion(slot, x as f32, y as f32, timestamp)
+        })
+    }
+
+    /// Lift the finger in `slot`
+    pub fn touch_up(&self, slot: u32) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        self.check_touch_slot(slot)?;
+        debug!("Touch {} up", slot);
+        self.device_event_internal(DeviceCapability::Touch, |timestamp| {
+            self.sink.touch_up(slot, timestamp)
+        })?;
+        self.touches.borrow_mut().retain(|&s| s != slot);
+        Ok(())
+    }
+
+    fn check_touch_slot(&self, slot: u32) -> Result<(), EiTypeError> {
+        if self.touches.borrow().contains(&slot) {
+            Ok(())
+        } else {
+            Err(EiTypeError::Touch(format!("slot {} is not down", slot)))
+        }
+    }
+
+    /// Fail if (`x`, `y`) is outside every region of the touch device, where
+    /// the server would silently drop the touch
+    fn check_touch_position(&self, x: f64, y: f64) -> Result<(), EiTypeError> {
+        let regions = self.sink.touch_regions();
+        if regions.is_empty() || regions.iter().any(|r| r.contains(x, y)) {
+            Ok(())
+        } else {
+            Err(EiTypeError::Touch(format!(
+                "position {}, {} is outside the device's regions",
+                x, y
+            )))
+        }
+    }
+
+    /// Replace the clipboard used for pasting (default: `WlClipboard`)
+    pub fn set_clipboard<C>(&mut self, clipboard: C)
+    where
+        C: Clipboard + 'static,
+    {
+        self.clipboard = Some(Box::new(clipboard));
+    }
+
+    /// Disable pasting, so `insert_text` always types
+    pub fn clear_clipboard(&mut self) {
+        self.clipboard = None;
+    }
+
+    /// Press `keycodes` in order, run `f`, then release them in reverse order,
+    /// even if `f` failed.
+    fn while_holding(
+        &self,
+        keycodes: &[u32],
+        f: impl FnOnce() -> Result<(), EiTypeError>,
+    ) -> Result<(), EiTypeError> {
+        for &keycode in keycodes {
+            self.press_key_internal(keycode)?;
+        }
+        let result = f();
+        let released = keycodes
+            .iter()
+            .rev()
+            .try_for_each(|&keycode| self.release_key_internal(keycode));
+        result.and(released)
+    }
+
+    /// Type a single character while additionally holding the named modifiers,
+    /// e.g. Ctrl+é: `type_char_with_mods('é', &["ctrl"])`.
+    ///
+    /// The extra modifiers are pressed first and released last; any modifiers
+    /// the character's level needs (Shift, AltGr) are pressed inside them.
+    pub fn type_char_with_mods(&self, ch: char, extra_mods: &[&str]) -> Result<(), EiTypeError> {
+        let held = extra_mods
+            .iter()
+            .map(|name| self.keycode_for_name(name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Typing {:?} with modifiers {:?}", ch, extra_mods);
+        let result = self.type_char_holding(ch, &held);
+        let restored = self.leave_group();
+        result.and(restored)
+    }
+
+    /// Evdev keycode for a key name (see `Planner::keycode_for_name`)
+    fn keycode_for_name(&self, name: &str) -> Result<u32, EiTypeError> {
+        self.planner.borrow().keycode_for_name(name)
+    }
+
+    /// Press and release a special key (e.g., "Return", "Tab", "Escape"), or
+    /// a combo: "shift+tab", or a named one such as "backtab" or "paste" (see
+    /// `NAMED_COMBOS`). A combo's modifiers are pressed in order, the last key
+    /// tapped, then the modifiers released in reverse order.
+    ///
+    /// A non-ASCII character that is no key name ("ж", "ctrl+ж") means the
+    /// key that types it, found as `type_char_with_mods` would, with the
+    /// modifiers its level needs, in another layout group if need be.
+    pub fn press_key(&self, key_name: &str) -> Result<(), EiTypeError> {
+        let combo = self.planner.borrow().combo_for_name(key_name);
+        let (held, keycode) = match combo {
+            Err(EiTypeError::UnknownKey(name)) => {
+                let Some((mods, ch)) = keycap_combo(key_name) else {
+                    return Err(EiTypeError::UnknownKey(name));
+                };
+                debug!("Pressing the key that types {:?}", ch);
+                return self.type_char_with_mods(ch, &mods);
+            }
+            combo => combo?,
+        };
+
+        let _exclusive = self.lock_exclusive()?;
+        debug!(
+            "Pressing special key: {} (keycode {}, holding {:?})",
+            key_name, keycode, held
+        );
+        if held.is_empty() && self.can_tap_fast(keycode) {
+            return self.tap_key_fast(keycode);
+        }
+        self.while_holding(&held, || self.tap_key_internal(keycode))
+    }
+
+    /// Like `press_key`, then wait up to `timeout` for the server to confirm
+    /// it has processed the key events, for keys that commit something
+    /// (Enter on a payment form). Fails with `ConfirmationTimeout` if it
+    /// doesn't.
+    ///
+    /// The confirmation is a round trip behind the key events, so it shows
+    /// the compositor read them, not that the focused app acted on them.
+    pub fn press_key_confirmed(
+        &self,
+        key_name: &str,
+        timeout: Duration,
+    ) -> Result<(), EiTypeError> {
+        self.press_key(key_name)?;
+        if !self.sync_within(timeout)? {
+            return Err(EiTypeError::ConfirmationTimeout {
+                key: key_name.to_string(),
+                timeout,
+            });
+        }
+        debug!("Server confirmed {}", key_name);
+        Ok(())
+    }
+
+    /// Press a key by name and keep it down until `key_up` (or
+    /// `release_modifiers`, or `close`, which release every held key)
+    pub fn key_down(&mut self, key_name: &str) -> Result<(), EiTypeError> {
+        let keycode = self.keycode_for_name(key_name)?;
+        if self.held_modifiers.count(keycode) > 0 {
+            return Err(EiTypeError::Typing(format!("{} is already down", key_name)));
+        }
+
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Key down: {} (keycode {})", key_name, keycode);
+        self.press_key_internal(keycode)?;
+        if self.held_modifiers.is_empty() {
+            self.modifiers_pressed_at.set(self.clock.now());
+        }
+        self.held_modifiers.hold(keycode);
+        Ok(())
+    }
+
+    /// Release a key held with `key_down` (or `hold_modifier`). A modifier
+    /// held several times is only released by the last of them.
+    pub fn key_up(&mut self, key_name: &str) -> Result<(), EiTypeError> {
+        let keycode = self.keycode_for_name(key_name)?;
+        let holds = self.held_modifiers.count(keycode);
+        if holds == 0 {
+            return Err(EiTypeError::Typing(format!("{} is not down", key_name)));
+        }
+
+        if holds == 1 {
+            let _exclusive = self.lock_exclusive()?;
+            debug!("Key up: {} (keycode {})", key_name, keycode);
+            self.release_key_internal(keycode)?;
+        } else {
+            debug!(
+                "Key up: {} is still held {} more time(s)",
+                key_name,
+                holds - 1
+            );
+        }
+        self.held_modifiers.release(keycode);
+        Ok(())
+    }
+
+    /// Hold a modifier key (will be released when release_modifiers is called).
+    ///
+    /// Holding a modifier that is already held only counts the hold, so each
+    /// `key_up` undoes one of them.
+    pub fn hold_modifier(&mut self, mod_name: &str) -> Result<(), EiTypeError> {
+        let keycode = self.keycode_for_name(mod_name)?;
+
+        if self.held_modifiers.count(keycode) > 0 {
+            debug!("Modifier {} is already held, nesting", mod_name);
+        } else {
+            debug!("Holding modifier: {} (keycode {})", mod_name, keycode);
+            self.press_key_internal(keycode)?;
+        }
+        if self.held_modifiers.is_empty() {
+            self.modifiers_pressed_at.set(self.clock.now());
+        }
+        self.held_modifiers.hold(keycode);
+        Ok(())
+    }
+
+    /// Hold a modifier key while `f` runs (typing or pressing keys through
+    /// the `EiType` it is given), then release it, also when `f` fails.
+    ///
+    /// Calls nest like `Action::WithModifiers` scopes: a modifier an outer
+    /// scope already holds isn't pressed again, nor released by the inner
+    /// one, and each modifier comes up in reverse order of going down.
+    pub fn with_modifier<R>(
+        &mut self,
+        mod_name: &str,
+        f: impl FnOnce(&mut Self) -> Result<R, EiTypeError>,
+    ) -> Result<R, EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        self.hold_modifier(mod_name)?;
+        let result = f(self);
+        // Release even if `f` failed, so the error never leaves it stuck
+        let released = self.key_up(mod_name);
+        let value = result?;
+        released.map(|()| value)
+    }
+
+    /// Press and release a modifier key (like a regular key press)
+    pub fn press_modifier(&self, mod_name: &str) -> Result<(), EiTypeError> {
+        let keycode = self.keycode_for_name(mod_name)?;
+
+        let _exclusive = self.lock_exclusive()?;
+        debug!("Pressing modifier: {} (keycode {})", mod_name, keycode);
+        self.tap_key_internal(keycode)
+    }
+
+    /// Release all held modifiers
+    pub fn release_modifiers(&mut self) -> Result<(), EiTypeError> {
+        for keycode in self.held_modifiers.take().into_iter().rev() {
+            debug!("Releasing held modifier keycode {}", keycode);
+            self.release_key_internal(keycode)?;
+        }
+        Ok(())
+    }
+
+    /// Send a release of every key this session has down and every known
+    /// modifier key (both Shifts, Ctrls, Alts and Supers, and whatever keys
+    /// the keymap's modifiers are on, e.g. AltGr), whether or not they are
+    /// down. Holds from `hold_modifier` and `key_down` are forgotten.
+    ///
+    /// Use it to get back to a known state after an interruption, or after
+    /// another input tool may have left keys down. Other keys that tool
+    /// pressed stay down: only these are released.
+    pub fn reset_keys(&mut self) -> Result<(), EiTypeError> {
+        let _exclusive = self.lock_exclusive()?;
+        self.held_modifiers.take();
+        let mut keycodes: Vec<u32> = self.keys_down.borrow_mut().take();
+        keycodes.reverse();
+        let mut keymap_modifiers: Vec<u32> = (self.planner.borrow().keymap_mod_keycodes)
+            .values()
+            .copied()
+            .collect();
+        keymap_modifiers.sort_unstable();
+        for keycode in STANDARD_MODIFIER_KEYCODES
+            .into_iter()
+            .chain(keymap_modifiers)
+        {
+            if !keycodes.contains(&keycode) {
+                keycodes.push(keycode);
+            }
+        }
+
+        debug!("Resetting keys {:?}", keycodes);
+        for keycode in keycodes {
+            self.emit_with(keycode, KeyState::Released, Emit::Forced)?;
+        }
+        Ok(())
+    }
+
+    /// Execute a sequence of actions
+    ///
+    /// Held modifiers are released at the end even if an action fails partway,
+    /// so an error never leaves Ctrl/Shift stuck in the user's session.
+    pub fn execute_actions(&mut self, actions: &[Action]) -> Result<(), EiTypeError> {
+        let (_, result) = self.execute_actions_reported(actions);
+        result
+    }
+
+    /// Like `execute_actions`, but also report the outcome of every action.
+    ///
+    /// Actions after the first failure are reported as `Skipped`.
+    pub fn execute_actions_reported(
+        &mut self,
+        actions: &[Action],
+    ) -> (Vec<ActionReport>, Result<(), EiTypeError>) {
+        let mut reports: Vec<ActionReport> = actions
+            .iter()
+            .enumerate()
+            .map(|(index, action)| ActionReport {
+                index,
+                kind: action.kind(),
+                status: ActionStatus::Skipped,
+                chars_typed: 0,
+            })
+            .collect();
+
+        // Held for the whole sequence, including held-modifier releases
+        let _exclusive = match self.lock_exclusive() {
+            Ok(guard) => guard,
+            Err(e) => return (reports, Err(e)),
+        };
+        info!("Executing {} actions", actions.len());
+
+        let mut result = Ok(());
+        for (action, report) in actions.iter().zip(&mut reports) {
+            let before = self.stats.get().chars_typed;
+            let outcome = self.execute_action(action);
+            report.chars_typed = self.stats.get().chars_typed - before;
+            match outcome {
+                Ok(()) => report.status = ActionStatus::Ok,
+                Err(e) => {
+                    report.status = ActionStatus::Failed(e.to_string());
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // Release any held modifiers
+        let released = self.release_modifiers();
+
+        (reports, result.and(released))
+    }
+
+    /// Like `execute_actions_reported`, but run `actions` `times` times over
+    /// one connection, pausing `delay` between iterations.
+    ///
+    /// Every iteration ends with held modifiers released, so each one starts
+    /// from a clean slate. A `CancelHandle` interrupts the pause, and is also
+    /// checked before every iteration. Stops at the first failure; returns
+    /// the number of iterations completed and the last iteration's reports.
+    pub fn execute_actions_repeated(
+        &mut self,
+        actions: &[Action],
+        times: u32,
+        delay: Duration,
+    ) -> (u32, Vec<ActionReport>, Result<(), EiTypeError>) {
+        let mut completed = 0;
+        let mut reports = Vec::new();
+        while completed < times {
+            if completed > 0 {
+                if let Err(e) = self.cancellable_sleep(delay) {
+                    return (completed, reports, Err(e));
+                }
+            }
+            info!("Iteration {}/{}", completed + 1, times);
+            let (iteration_reports, result) = self.execute_actions_reported(actions);
+            reports = iteration_reports;
+            if let Err(e) = result {
+                return (completed, reports, Err(e));
+            }
+            completed += 1;
+        }
+        (completed, reports, Ok(()))
+    }
+
+    fn execute_action(&mut self, action: &Action) -> Result<(), EiTypeError> {
+        match action {
+            Action::Type(text) => self.type_text(text),
+            Action::Key(key_name) => self.press_key(key_name),
+            Action::ModifierHold(mod_name) => self.hold_modifier(mod_name),
+            Action::ModifierPress(mod_name) => self.press_modifier(mod_name),
+            Action::KeyDown(key_name) => self.key_down(key_name),
+            Action::KeyUp(key_name) => self.key_up(key_name),
+            Action::Keysym(name) => self.type_keysym_names(&[name.as_str()]),
+            Action::Paste(text, options) => self.paste_text(text, options),
+            Action::ScrollDiscrete(dx, dy) => self.scroll_discrete(*dx, *dy),
+            Action::ScrollSmooth(dx, dy) => self.scroll_smooth(*dx, *dy),
+            Action::Tap(x, y) => self.touch_down(0, *x, *y).and_then(|()| self.touch_up(0)),
+            Action::WithModifiers { mods, inner } => self.execute_with_modifiers(mods, inner),
+            Action::Sleep(duration) => self.cancellable_sleep(*duration),
+            Action::Focus(target) => self.focus_window(target),
+        }
+    }
+
+    /// Type text with inline keys (see `parse_inline`). Nothing is typed if
+    /// it doesn't parse.
+    pub fn type_inline(&mut self, input: &str) -> Result<(), EiTypeError> {
+        let actions = parse_inline(input)?;
+        self.execute_actions(&actions)
+    }
+
+    /// Run `inner` with `mods` held (see `Action::WithModifiers`)
+    fn execute_with_modifiers(
+        &mut self,
+        mods: &[String],
+        inner: &[Action],
+    ) -> Result<(), EiTypeError> {
+        let mut held = 0;
+        let mut result = Ok(());
+        for mod_name in mods {
+            result = self.hold_modifier(mod_name);
+            if result.is_err() {
+                break;
+            }
+            held += 1;
+        }
+        if result.is_ok() {
+            result = inner
+                .iter()
+                .try_for_each(|action| self.execute_action(action));
+        }
+        // Release what this scope held even if something failed, so the
+        // error never leaves a modifier stuck
+        for mod_name in mods[..held].iter().rev() {
+            let released = self.key_up(mod_name);
+            result = result.and(released);
+        }
+        result
+    }
+
+    /// Where the active keymap came from (server, configuration, or fallback).
+    ///
+    /// Useful when diagnosing "wrong characters" reports: a `SystemDefault`
+    /// source usually means the compositor didn't send a keymap.
+    pub fn keymap_source(&self) -> KeymapSource {
+        self.keymap_source
+    }
+
+    /// The keymap typing uses, e.g. to `diff_keymaps` it against another;
+    /// `None` when typing falls back to the built-in US QWERTY table
+    pub fn keymap(&self) -> Option<xkb::Keymap> {
+        self.planner.borrow().keymap().cloned()
+    }
+
+    /// How long each character typed so far took, oldest first. Empty unless
+    /// `EiTypeConfig::collect_timing` is set; only the latest 100,000
+    /// characters are kept (see `timings_truncated`).
+    pub fn timings(&self) -> Vec<CharTiming> {
+        self.timing
+            .as_ref()
+            .map_or_else(Vec::new, |t| t.borrow().chars.iter().copied().collect())
+    }
+
+    /// Whether `timings` dropped older characters to stay bounded
+    pub fn timings_truncated(&self) -> bool {
+        self.timing.as_ref().is_some_and(|t| t.borrow().truncated)
+    }
+
+    /// What this connection has sent so far
+    pub fn stats(&self) -> TypingStats {
+        self.stats.get()
+    }
+
+    /// Find how fast the server keeps up with typing, to choose `delay_ms`.
+    ///
+    /// This types `options.probe` into whatever has focus, at each of
+    /// `options.delays_ms` in turn, erasing it again after each unless
+    /// disabled, and waits for the server to process it. A rate where the
+    /// socket filled up (flush retries) or adaptive throttling stepped in
+    /// didn't keep up, and calibration stops there. The configured delay is
+    /// restored afterwards.
+    pub fn calibrate(&mut self, options: &CalibrateOptions) -> Result<Calibration, EiTypeError> {
+        let configured = self.delay;
+        let mut steps = Vec::new();
+        let mut result = Ok(());
+        for &delay_ms in &options.delays_ms {
+            self.delay = Duration::from_millis(delay_ms);
+            match self.calibration_step(delay_ms, options) {
+                Ok(step) => {
+                    let kept_up = step.kept_up;
+                    steps.push(step);
+                    if !kept_up {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.delay = configured;
+        result.map(|()| Calibration::from_steps(steps))
+    }
+
+    /// Type (and erase) the probe at the current delay and measure it
+    fn calibration_step(
+        &self,
+        delay_ms: u64,
+        options: &CalibrateOptions,
+    ) -> Result<CalibrationStep, EiTypeError> {
+        let before = self.stats();
+        let started = self.clock.now();
+        self.type_text(&options.probe)?;
+        let mut chars = options.probe.chars().count();
+        if options.erase {
+            self.delete_chars(chars)?;
+            chars *= 2;
+        }
+        self.sync()?;
+        let elapsed = Duration::from_micros(self.clock.now().saturating_sub(started));
+
+        let stats = self.stats().since(&before);
+        let step = CalibrationStep {
+            delay_ms,
+            chars_per_sec: chars as f64 / elapsed.as_secs_f64(),
+            flush_retries: stats.flush_retries,
+            throttle_events: stats.throttle_events,
+            kept_up: stats.flush_retries == 0 && stats.throttle_events == 0,
+        };
+        info!(
+            "Calibration at {} ms: {:.0} chars/s, {} flush retries, {} throttle pauses",
+            delay_ms, step.chars_per_sec, step.flush_retries, step.throttle_events
+        );
+        Ok(step)
+    }
+
+    /// Measure how fast typing goes over this connection.
+    ///
+    /// This types `options.chars` characters of pangrams into whatever has
+    /// focus, waits for the server to process them, and erases them again
+    /// unless disabled. Per-character times come from a timing trace of its
+    /// own, whether or not `EiTypeConfig::collect_timing` is set; the
+    /// trace `timings` returns is left as it was.
+    pub fn bench(&mut self, options: &BenchOptions) -> Result<BenchReport, EiTypeError> {
+        let text = bench_corpus(options.chars);
+        let earlier = self.timing.replace(RefCell::default());
+        let before = self.stats();
+        let started = Instant::now();
+        let typed = self.type_text(&text).and_then(|()| self.sync());
+        let elapsed = started.elapsed();
+        let stats = self.stats().since(&before);
+        let trace = std::mem::replace(&mut self.timing, earlier);
+        typed?;
+        if options.erase {
+            self.delete_chars(text.chars().count())?;
+            self.sync()?;
+        }
+
+        let trace = trace.map(RefCell::into_inner).unwrap_or_default();
+        if trace.truncated {
+            warn!(
+                "Bench: per-character times are for the last {} characters only",
+                trace.chars.len()
+            );
+        }
+        let timings: Vec<CharTiming> = trace.chars.into();
+        let part = |f: fn(&CharTiming) -> Duration| {
+            LatencyPercentiles::of(timings.iter().map(f).collect())
+        };
+        let chars = text.chars().count();
+        let report = BenchReport {
+            chars,
+            elapsed,
+            chars_per_sec: chars as f64 / elapsed.as_secs_f64(),
+            flush_retries: stats.flush_retries,
+            throttle_events: stats.throttle_events,
+            sampled: timings.len(),
+            plan: part(|t| t.plan),
+            flush: part(|t| t.flush),
+            gap: part(|t| t.gap),
+        };
+        info!(
+            "Bench: {} chars in {:?} ({:.0} chars/s)",
+            report.chars, report.elapsed, report.chars_per_sec
+        );
+        Ok(report)
+    }
+
+    fn update_stats(&self, f: impl FnOnce(&mut TypingStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Stable hash of the active keymap's text.
+    ///
+    /// Identical keymaps always produce the same value (across runs, too), so
+    /// callers can cache data derived from the keymap and rebuild it only when
+    /// the fingerprint changes.
+    pub fn keymap_fingerprint(&self) -> u64 {
+        self.keymap_fingerprint.get()
+    }
+
+    /// Register a callback invoked with the new fingerprint whenever a
+    /// different keymap is installed.
+    pub fn on_keymap_changed<F>(&mut self, callback: F)
+    where
+        F: Fn(u64) + 'static,
+    {
+        self.keymap_changed.push(Box::new(callback));
+    }
+
+    /// Switch to the keymap `config` describes (`keymap_string`,
+    /// `keymap_file` or RMLVO names, else the server's keymap, as when
+    /// connecting) without reconnecting. The layout index is chosen as when
+    /// connecting, too; other settings in `config` are ignored.
+    ///
+    /// Held modifiers are released first, since their keys may mean something
+    /// else in the new keymap. If the keymap can't be loaded, nothing changes.
+    pub fn reload_keymap(&mut self, config: &EiTypeConfig) -> Result<(), EiTypeError> {
+        let (keymap, source) = load_keymap(config, self.sink.server_keymap()?)?;
+        let layout_index =
+            choose_layout_index(config, &keymap, self.layout_cache.as_ref(), || None);
+        self.release_modifiers()?;
+
+        self.keymap_source = source;
+        self.planner.get_mut().layout_index = layout_index;
+        self.install_keymap(keymap);
+        info!(
+            "Reloaded keymap ({:?}), layout index {}",
+            source,
+            self.planner.get_mut().layout_index
+        );
+        Ok(())
+    }
+
+    /// Switch to layout `index` and remember it for this keymap, so later
+    /// connections use it instead of auto-detecting (unless
+    /// `EiTypeConfig::ignore_layout_cache` is set).
+    pub fn set_layout_index(&mut self, index: u32) -> Result<(), EiTypeError> {
+        let planner = self.planner.get_mut();
+        let num_layouts = planner.keymap().map_or(0, |k| k.num_layouts());
+        if index >= num_layouts {
+            return Err(EiTypeError::Keymap(format!(
+                "Layout index {} out of range (keymap has {} layouts)",
+                index, num_layouts
+            )));
+        }
+        info!("Switching to layout index {}", index);
+        planner.layout_index = index;
+        if let Some(cache) = &self.layout_cache {
+            cache.set(self.keymap_fingerprint.get(), index);
+        }
+        Ok(())
+    }
+
+    /// Capabilities the server actually granted. With `EiTypeConfig::pointer`
+    /// this may still be keyboard-only, in which case pointer operations fail
+    /// with `EiTypeError::CapabilityUnavailable` while typing keeps working.
+    pub fn capabilities(&self) -> Vec<DeviceCapability> {
+        self.sink.capabilities()
+    }
+
+    /// Bind `capability` on the keyboard's seat too and wait for the server to
+    /// offer a device with it, e.g. to gain a pointer later in a
+    /// keyboard-only session without reconnecting (and asking the user to
+    /// authorize it again). Does nothing if the capability is already bound.
+    ///
+    /// Fails with `EiTypeError::CapabilityUnavailable` if the server doesn't
+    /// offer a device with it; later binds don't ask for it again.
+    pub fn enable_capability(&mut self, capability: DeviceCapability) -> Result<(), EiTypeError> {
+        if self.sink.capabilities().contains(&capability) {
+            return Ok(());
+        }
+        let newly_requested = !self.requested_capabilities.contains(&capability);
+        if newly_requested {
+            self.requested_capabilities.push(capability);
+        }
+        // The session is restarted so that it includes the new device
+        let emulating = self.emulating.get();
+        if emulating {
+            self.stop_session()?;
+        }
+        info!("Binding {:?} capability", capability);
+        self.sink.bind_capabilities(&self.requested_capabilities)?;
+        self.flush_with_retry()?;
+        let granted = self.dispatch_until(POINTER_WAIT, || {
+            self.sink.capabilities().contains(&capability)
+        })?;
+        self.sink.take_resumed();
+        if emulating {
+            self.start_session()?;
+            self.repress_held_modifiers()?;
+        }
+        if !granted {
+            warn!("The server offered no device with {:?}", capability);
+            let denied = self.require_capability(capability);
+            if newly_requested {
+                self.requested_capabilities.retain(|&c| c != capability);
+            }
+            return denied;
+        }
+        Ok(())
+    }
+
+    /// Fail unless the pointer or touch `capability` is bound, telling a
+    /// device the server refused apart from one that was never requested
+    fn require_capability(&self, capability: DeviceCapability) -> Result<(), EiTypeError> {
+        if self.sink.capabilities().contains(&capability) {
+            Ok(())
+        } else if self.requested_capabilities.contains(&capability) {
+            Err(EiTypeError::CapabilityUnavailable(capability))
+        } else {
+            Err(EiTypeError::NoDevice(capability))
+        }
+    }
+
+    /// Summary of this connection: keymap source and fingerprint, layouts
+    pub fn connection_info(&self) -> ConnectionInfo {
+        let (keymap_source, layout_index, layout_name) = self.resolved_layout();
+        ConnectionInfo {
+            keymap_source,
+            keymap_fingerprint: self.keymap_fingerprint.get(),
+            num_layouts: self
+                .planner
+                .borrow()
+                .keymap()
+                .map_or(0, |k| k.num_layouts()),
+            layout_index,
+            layout_name,
+            clamped_layout_index: self.clamped_layout_index.get(),
+        }
+    }
+
+    /// Where the keymap came from, and the index and name of the layout
+    /// typed in, after any fallback for an out-of-range layout index
+    pub fn resolved_layout(&self) -> (KeymapSource, u32, String) {
+        let planner = self.planner.borrow();
+        let layout_name = planner
+            .keymap()
+            .map(|k| k.layout_get_name(planner.layout_index).to_string())
+            .unwrap_or_default();
+        (self.keymap_source, planner.layout_index, layout_name)
+    }
+
+    /// The evdev keycode and shift level that type `ch` in the active layout,
+    /// without sending anything. `None` if no key in the layout produces it
+    /// (`type_text` may still reach it via another layout) or there is no
+    /// keymap.
+    pub fn keycode_for_char(&self, ch: char) -> Option<(u32, u8)> {
+        let planner = self.planner.borrow();
+        let key_match = find_keycode_for_char(ch, planner.keymap()?, planner.layout_index).ok()?;
+        Some((key_match.evdev_keycode, u8::try_from(key_match.level).ok()?))
+    }
+
+    /// How the special key names (`press_key("escape")`, ...) fit the active
+    /// keymap, checked when it was loaded. See `EiTypeConfig::prefer_keymap_keys`.
+    pub fn key_table_report(&self) -> Vec<KeyTableEntry> {
+        self.planner.borrow().key_table_report().to_vec()
+    }
+
+    /// Every character the active layout has a key for, sorted (see
+    /// `Planner::typeable_chars`)
+    pub fn typeable_chars(&self) -> Vec<char> {
+        self.planner.borrow().typeable_chars()
+    }
+
+    /// Replace a failed connection with a fresh one, made the way this one
+    /// was: through the portal (with the newest restore token, so no dialog
+    /// appears) or to the same socket. Held modifiers are pressed again on
+    /// the new connection; settings, callbacks, stats and status receivers
+    /// carry over, and the receivers get `StatusEvent::Connected`.
+    ///
+    /// Only `connect_portal`, `connect_portal_with_token` and
+    /// `connect_socket` connections can reconnect.
+    pub fn reconnect(&mut self) -> Result<(), EiTypeError> {
+        let Some(reconnector) = self.reconnector.as_mut() else {
+            return Err(EiTypeError::Connection(
+                "Can't reconnect a connection made from a stream".to_string(),
+            ));
+        };
+        info!("Reconnecting...");
+        let mut fresh = reconnector()?;
+        // The old connection is gone: leave it to `fresh` to drop, unclosed
+        std::mem::swap(&mut self.sink, &mut fresh.sink);
+        fresh.closed = true;
+        // The new sink reports to its own broadcast
+        fresh.status.take_over(&self.status);
+        self.status = fresh.status.clone();
+        self.status.send(StatusEvent::Connected);
+        self.sequence.set(fresh.sequence.get());
+        self.emulating.set(fresh.emulating.get());
+        self.keys_down.borrow_mut().take();
+        self.touches.borrow_mut().clear();
+        self.write_blocked.set(false);
+        self.keymap_source = fresh.keymap_source;
+        self.planner.get_mut().layout_index = fresh.planner.get_mut().layout_index;
+        if let Some(keymap) = fresh.keymap() {
+            self.install_keymap(keymap);
+        }
+        if !self.held_modifiers.is_empty() && !self.emulating.get() {
+            self.start_session()?;
+        }
+        self.repress_held_modifiers()
+    }
+
+    /// Release `keycode`, pressed on a connection that has since been
+    /// replaced, on the current one. It isn't down as far as `keys_down`
+    /// knows, so the release is forced.
+    fn release_stale(&self, keycode: u32) -> Result<(), EiTypeError> {
+        trace!("Releasing key {} pressed before reconnecting", keycode);
+        self.emit_with(keycode, KeyState::Released, Emit::Forced)
+    }
+
+    /// Explicitly close the connection and release all resources.
+    ///
+    /// This method should be called when you're done with the EiType instance,
+    /// especially before attempting to create a new connection. While the Drop
+    /// implementation will clean up automatically, calling close() explicitly
+    /// ensures proper cleanup and avoids potential issues with reconnection.
+    ///
+    /// After calling close(), this instance should not be used anymore.
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+
+        debug!("Closing EiType connection");
+
+        // Release any held modifiers, and lift any touches
+        let _ = self.release_modifiers();
+        let touches = self.touches.borrow().clone();
+        for slot in touches {
+            let _ = self.touch_up(slot);
+        }
+
+        // Stop emulating
+        if self.emulating.get() {
+            let _ = self.stop_session();
+        }
+
+        // Make sure the server has read everything before the connection
+        // goes away, or it may drop the last characters typed
+        match self.settle_input(CLOSE_TIMEOUT) {
+            Ok(true) => {}
+            Ok(false) => warn!("Server hasn't taken the last input, closing anyway"),
+            Err(e) => debug!("Couldn't wait for the server to take the last input: {}", e),
+        }
+
+        // Send disconnect request to the EI server
+        // This tells the server we're intentionally disconnecting
+        self.sink.disconnect();
+
+        // Flush to ensure the disconnect message is sent
+        let _ = self.sink.flush();
+
+        self.status.send(StatusEvent::Disconnected {
+            reason: "Closed".to_string(),
+        });
+
+        debug!("EiType connection closed");
+    }
+}
+
+/// The EI connection's socket.
+///
+/// Register it with an external event loop (calloop, glib, mio): readable means
+/// `dispatch_pending` has work to do, writable means a `try_type_text` that
+/// returned `WouldBlock` can continue. Don't read from or write to it directly.
+impl AsFd for EiType {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.sink.as_fd()
+    }
+}
+
+impl Drop for EiType {
+    fn drop(&mut self) {
+        // Use close() which handles all cleanup including EI disconnect
+        self.close();
+    }
+}
+
+// ============================================================================
+// Threaded Wrapper
+// ============================================================================
+
+/// Work sent to the thread that owns the `EiType`
+type ThreadedCommand = Box<dyn FnOnce(&mut EiType) + Send>;
+
+/// An `EiType` that lives on its own thread, usable from any thread.
+///
+/// `EiType` holds non-`Send` connection and XKB state, so it can't move between
+/// threads. This wrapper creates it on a dedicated thread and forwards calls
+/// there; each call blocks until that thread has run it. Calls from different
+/// threads are run one at a time, so their key events never interleave.
+/// Share it between threads with an `Arc`.
+///
+/// Dropping the wrapper closes the connection and joins the thread.
+pub struct ThreadedEiType {
+    commands: Option<std::sync::mpsc::Sender<ThreadedCommand>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    /// Cancels the running call directly, rather than queueing behind it
+    cancel: CancelHandle,
+    /// Calls sent and not answered yet
+    pending: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl ThreadedEiType {
+    /// Create the `EiType` on a new thread with `connect`, e.g.
+    /// `ThreadedEiType::spawn(|| EiType::connect_portal(config))`.
+    pub fn spawn<F>(connect: F) -> Result<Self, EiTypeError>
+    where
+        F: FnOnce() -> Result<EiType, EiTypeError> + Send + 'static,
+    {
+        let (commands, receiver) = std::sync::mpsc::channel::<ThreadedCommand>();
+        let (connected_tx, connected_rx) = std::sync::mpsc::sync_channel(1);
+
+        let thread = std::thread::Builder::new()
+            .name("eitype".to_string())
+            .spawn(move || {
+                let mut eitype = match connect() {
+                    Ok(eitype) => {
+                        let _ = connected_tx.send(Ok(eitype.cancel_handle()));
+                        eitype
+                    }
+                    Err(e) => {
+                        let _ = connected_tx.send(Err(e));
+                        return;
+                    }
+                };
+                // Runs until every handle is gone
+                for command in receiver {
+                    // A cancel aimed at an earlier call doesn't reach this one
+                    eitype.cancel.store(false, Ordering::SeqCst);
+                    command(&mut eitype);
+                }
+            })
+            .map_err(|e| EiTypeError::Connection(format!("Failed to spawn thread: {}", e)))?;
+
+        let connected = connected_rx.recv().unwrap_or_else(|_| {
+            Err(EiTypeError::Connection(
+                "eitype thread exited while connecting".to_string(),
+            ))
+        });
+        let cancel = match connected {
+            Ok(cancel) => cancel,
+            Err(e) => {
+                // The thread has returned (or panicked) without an EiType
+                let _ = thread.join();
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            commands: Some(commands),
+            thread: Some(thread),
+            cancel,
+            pending: Arc::default(),
+        })
+    }
+
+    /// Connect via the XDG RemoteDesktop portal on a new thread
+    pub fn connect_portal(config: EiTypeConfig) -> Result<Self, EiTypeError> {
+        Self::spawn(move || EiType::connect_portal(config))
+    }
+
+    /// Connect via a Unix socket on a new thread
+    pub fn connect_socket(path: &Path, config: EiTypeConfig) -> Result<Self, EiTypeError> {
+        let path = path.to_path_buf();
+        Self::spawn(move || EiType::connect_socket(&path, config))
+    }
+
+    /// Run `f` with the `EiType` on its thread and return the result, after
+    /// any calls queued before it
+    pub fn with<F, R>(&self, f: F) -> Result<R, EiTypeError>
+    where
+        F: FnOnce(&mut EiType) -> Result<R, EiTypeError> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let result = self.call(f);
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Like `with`, but fail with `EiTypeError::Busy` instead of queueing
+    /// if another call is running or queued
+    pub fn try_with<F, R>(&self, f: F) -> Result<R, EiTypeError>
+    where
+        F: FnOnce(&mut EiType) -> Result<R, EiTypeError> + Send + 'static,
+        R: Send + 'static,
+    {
+        if self
+            .pending
+            .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(EiTypeError::Busy);
+        }
+        let result = self.call(f);
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Send `f` to the thread and wait for its result
+    fn call<F, R>(&self, f: F) -> Result<R, EiTypeError>
+    where
+        F: FnOnce(&mut EiType) -> Result<R, EiTypeError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply_tx, reply_rx) = std::sync::mpsc::sync_channel(1);
+        let command: ThreadedCommand = Box::new(move |eitype| {
+            let _ = reply_tx.send(f(eitype));
+        });
+        let gone = || EiTypeError::Connection("eitype thread has exited".to_string());
+        self.commands
+            .as_ref()
+            .ok_or_else(gone)?
+            .send(command)
+            .map_err(|_| gone())?;
+        reply_rx.recv().map_err(|_| gone())?
+    }
+
+    /// Type a string of text (see `EiType::type_text`)
+    pub fn type_text(&self, text: &str) -> Result<(), EiTypeError> {
+        let text = text.to_string();
+        self.with(move |eitype| eitype.type_text(&text))
+    }
+
+    /// Type a string of text now, or fail with `EiTypeError::Busy` if
+    /// another call is running or queued
+    pub fn type_text_if_idle(&self, text: &str) -> Result<(), EiTypeError> {
+        let text = text.to_string();
+        self.try_with(move |eitype| eitype.type_text(&text))
+    }
+
+    /// Cancel the running call at its next character (see `CancelHandle`),
+    /// from any thread. Only that call is cancelled: calls queued behind it
+    /// still run, and with nothing running this does nothing.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Press and release a special key (see `EiType::press_key`)
+    pub fn press_key(&self, key_name: &str) -> Result<(), EiTypeError> {
+        let key_name = key_name.to_string();
+        self.with(move |eitype| eitype.press_key(&key_name))
+    }
+
+    /// Execute a sequence of actions (see `EiType::execute_actions`)
+    pub fn execute_actions(&self, actions: Vec<Action>) -> Result<(), EiTypeError> {
+        self.with(move |eitype| eitype.execute_actions(&actions))
+    }
+
+    /// Replace the profiles `apply_profile` selects from (see
+    /// `EiType::set_profiles`)
+    pub fn set_profiles(&self, profiles: Profiles) -> Result<(), EiTypeError> {
+        self.with(move |eitype| {
+            eitype.set_profiles(profiles);
+            Ok(())
+        })
+    }
+
+    /// Switch to a named profile, e.g. before each request from a different
+    /// app (see `EiType::apply_profile`)
+    pub fn apply_profile(&self, name: &str) -> Result<(), EiTypeError> {
+        let name = name.to_string();
+        self.with(move |eitype| eitype.apply_profile(&name))
+    }
+}
+
+impl Drop for ThreadedEiType {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread's loop, which drops (and closes) the EiType
+        self.commands.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// ============================================================================
+// Python Bindings
+// ============================================================================
+/// The Python `EiType`: an `EiType` on a `ThreadedEiType` worker thread, so
+/// it can be used from any Python thread. Calls run one at a time on the
+/// connection's thread with the GIL released while they wait, so calls from
+/// different threads queue rather than interleave.
+#[cfg(feature = "python")]
+#[pyclass(name = "EiType")]
+pub struct PyEiType {
+    inner: ThreadedEiType,
+}
+
+#[cfg(feature = "python")]
+impl PyEiType {
+    /// Create the `EiType` with `connect` on a new thread, without holding
+    /// the GIL
+    fn spawn<F>(py: Python<'_>, connect: F) -> PyResult<Self>
+    where
+        F: FnOnce() -> Result<EiType, EiTypeError> + Send + 'static,
+    {
+        py.detach(|| ThreadedEiType::spawn(connect))
+            .map(|inner| Self { inner })
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    }
+
+    /// Run `f` on the connection's thread, without holding the GIL; errors
+    /// are raised as `RuntimeError`
+    fn call<R: Send + 'static>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&mut EiType) -> Result<R, EiTypeError> + Send + 'static,
+    ) -> PyResult<R> {
+        py.detach(|| self.inner.with(f))
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    }
+
+    /// Like `call`, for typing: errors are raised as `TypingError`
+    fn call_typing<R: Send + 'static>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&mut EiType) -> Result<R, EiTypeError> + Send + 'static,
+    ) -> PyResult<R> {
+        py.detach(|| self.inner.with(f))
+            .map_err(|e| typing_error_to_py(py, e))
+    }
+
+    /// Like `call`, for methods that can't fail
+    fn get<R: Send + 'static>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&mut EiType) -> R + Send + 'static,
+    ) -> PyResult<R> {
+        self.call(py, move |eitype| Ok(f(eitype)))
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl PyEiType {
+    /// Connect via the XDG RemoteDesktop portal (simple version)
+    #[staticmethod]
+    #[pyo3(signature = (config=None))]
+    fn py_connect_portal(py: Python<'_>, config: Option<EiTypeConfig>) -> PyResult<Self> {
+        let config = config.unwrap_or_default();
+        Self::spawn(py, move || EiType::connect_portal(config))
+    }
+
+    /// Ask for remote control authorization through the portal ahead of
+    /// time; returns the restore token to save, if one was issued
+    #[staticmethod]
+    #[pyo3(name = "request_portal_permission", signature = (config=None))]
+    fn py_request_portal_permission(
+        py: Python<'_>,
+        config: Option<EiTypeConfig>,
+    ) -> PyResult<Option<String>> {
+        let config = config.unwrap_or_default();
+        py.detach(|| request_portal_permission(&config))
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    }
+
+    /// Connect via the XDG RemoteDesktop portal with token support; without
+    /// `restore_token`, the one in `EITYPE_RESTORE_TOKEN` is used if set
+    #[staticmethod]
+    #[pyo3(signature = (restore_token=None, config=None))]
+    fn py_connect_portal_with_token(
+        py: Python<'_>,
+        restore_token: Option<String>,
+        config: Option<EiTypeConfig>,
+    ) -> PyResult<(Self, Option<String>)> {
+        let config = config.unwrap_or_default();
+        let (token_tx, token_rx) = std::sync::mpsc::channel();
+        let typer = Self::spawn(py, move || {
+            let (eitype, token) =
+                EiType::connect_portal_with_token(config, restore_token.as_deref())?;
+            let _ = token_tx.send(token);
+            Ok(eitype)
+        })?;
+        Ok((typer, token_rx.try_recv().ok().flatten()))
+    }
+
+    /// Connect via a Unix socket
+    #[staticmethod]
+    #[pyo3(signature = (path, config=None))]
+    fn py_connect_socket(
+        py: Python<'_>,
+        path: PathBuf,
+        config: Option<EiTypeConfig>,
+    ) -> PyResult<Self> {
+        let config = config.unwrap_or_default();
+        Self::spawn(py, move || EiType::connect_socket(&path, config))
+    }
+
+    /// Forget the layout index detected by earlier connections
+    #[staticmethod]
+    #[pyo3(name = "clear_detected_layout_cache")]
+    fn py_clear_detected_layout_cache() {
+        clear_detected_layout_cache();
+    }
+
+    /// An EiType connected to nothing, whose events are dropped (see `bench`)
+    #[staticmethod]
+    #[pyo3(signature = (config=None))]
+    fn py_null(py: Python<'_>, config: Option<EiTypeConfig>) -> PyResult<Self> {
+        let config = config.unwrap_or_default();
+        Self::spawn(py, move || EiType::null(config))
+    }
+
+    /// Type a string of text, after any calls from other threads queued
+    /// before it
+    ///
+    /// Raises `TypingError` (a `RuntimeError`) with `.index`, `.byte_offset`
+    /// and `.context` attributes describing where typing failed.
+    #[pyo3(name = "type_text")]
+    fn py_type_text(&self, py: Python<'_>, text: String) -> PyResult<()> {
+        self.call_typing(py, move |eitype| eitype.type_text(&text))
+    }
+
+    /// Type a string of text now, or raise `TypingError` if another call is
+    /// running or queued
+    #[pyo3(name = "type_text_if_idle")]
+    fn py_type_text_if_idle(&self, py: Python<'_>, text: &str) -> PyResult<()> {
+        py.detach(|| self.inner.type_text_if_idle(text))
+            .map_err(|e| typing_error_to_py(py, e))
+    }
+
+    /// Cancel the running call at its next character, from any thread;
+    /// calls queued behind it still run, and with nothing running this does
+    /// nothing
+    #[pyo3(name = "cancel")]
+    fn py_cancel(&self) {
+        self.inner.cancel();
+    }
+
+    /// Type characters from a list of (char, timedelta) tuples, pausing for
+    /// each delay after its character
+    #[pyo3(name = "type_timed")]
+    fn py_type_timed(&self, py: Python<'_>, items: Vec<(char, Duration)>) -> PyResult<()> {
+        self.call_typing(py, move |eitype| eitype.type_timed(&items))
+    }
+
+    /// Type text best effort, skipping characters no key produces; returns a
+    /// TypeOutcome listing what was skipped
+    #[pyo3(name = "type_text_report")]
+    fn py_type_text_report(&self, py: Python<'_>, text: String) -> PyResult<TypeOutcome> {
+        self.call_typing(py, move |eitype| eitype.type_text_report(&text))
+    }
+
+    /// Type text and return the number of key presses it took, modifiers
+    /// included
+    #[pyo3(name = "type_text_counting")]
+    fn py_type_text_counting(&self, py: Python<'_>, text: String) -> PyResult<usize> {
+        self.call_typing(py, move |eitype| eitype.type_text_counting(&text))
+    }
+
+    /// Type text, reconnecting and carrying on from where it stopped if the
+    /// connection fails partway
+    #[pyo3(name = "type_text_reliable")]
+    fn py_type_text_reliable(&self, py: Python<'_>, text: String) -> PyResult<()> {
+        self.call_typing(py, move |eitype| eitype.type_text_reliable(&text))
+    }
+
+    /// Type text with inline keys: {key:NAME}, {combo:SPEC}, {sleep:MS},
+    /// \t and \n for Tab and Return, {{ for a {. Raises ValueError, typing
+    /// nothing, if it doesn't parse.
+    #[pyo3(name = "type_inline")]
+    fn py_type_inline(&self, py: Python<'_>, text: String) -> PyResult<()> {
+        py.detach(|| self.inner.with(move |eitype| eitype.type_inline(&text)))
+            .map_err(|e| match e {
+                EiTypeError::InlineSyntax { .. } => {
+                    pyo3::exceptions::PyValueError::new_err(e.to_string())
+                }
+                e => typing_error_to_py(py, e),
+            })
+    }
+
+    /// Replace a failed connection with a fresh one, made the same way
+    #[pyo3(name = "reconnect")]
+    fn py_reconnect(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.reconnect())
+    }
+
+    /// Type a probe into the focused window at increasing rates (erasing it
+    /// again unless erase is false) and return a Calibration with the
+    /// fastest delay_ms the server kept up with
+    #[pyo3(name = "calibrate", signature = (probe=None, erase=true, delays_ms=None))]
+    fn py_calibrate(
+        &self,
+        py: Python<'_>,
+        probe: Option<String>,
+        erase: bool,
+        delays_ms: Option<Vec<u64>>,
+    ) -> PyResult<Calibration> {
+        let defaults = CalibrateOptions::default();
+        let options = CalibrateOptions {
+            probe: probe.unwrap_or(defaults.probe),
+            erase,
+            delays_ms: delays_ms.unwrap_or(defaults.delays_ms),
+        };
+        self.call_typing(py, move |eitype| eitype.calibrate(&options))
+    }
+
+    /// Type `chars` characters of pangrams into the focused window (erasing
+    /// them again unless erase is false) and return a BenchReport of the
+    /// rate and per-character times
+    #[pyo3(name = "bench", signature = (chars=2000, erase=true))]
+    fn py_bench(&self, py: Python<'_>, chars: usize, erase: bool) -> PyResult<BenchReport> {
+        self.call_typing(py, move |eitype| {
+            eitype.bench(&BenchOptions { chars, erase })
+        })
+    }
+
+    /// Start an emulation session now instead of at the next input
+    #[pyo3(name = "start_emulating")]
+    fn py_start_emulating(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.start_emulating())
+    }
+
+    /// End the emulation session until the next input; releases held modifiers
+    #[pyo3(name = "stop_emulating")]
+    fn py_stop_emulating(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.stop_emulating())
+    }
+
+    /// Stop emulating if idle past idle_stop_emulating_ms; returns whether it
+    /// stopped. Nothing else checks the timeout between calls, so call this
+    /// periodically
+    #[pyo3(name = "check_idle")]
+    fn py_check_idle(&self, py: Python<'_>) -> PyResult<bool> {
+        self.call(py, |eitype| eitype.check_idle())
+    }
+
+    /// File descriptor of the EI connection, for select/poll/asyncio loops
+    fn fileno(&self, py: Python<'_>) -> PyResult<i32> {
+        use std::os::fd::AsRawFd;
+        self.get(py, |eitype| eitype.as_fd().as_raw_fd())
+    }
+
+    /// Process pending events from the server without blocking
+    #[pyo3(name = "dispatch_pending")]
+    fn py_dispatch_pending(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.dispatch_pending())
+    }
+
+    /// Flush and block until the server confirms it has processed everything sent so far
+    #[pyo3(name = "sync")]
+    fn py_sync(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.sync())
+    }
+
+    /// Whether queued data is waiting for the socket to become writable
+    #[pyo3(name = "needs_flush")]
+    fn py_needs_flush(&self, py: Python<'_>) -> PyResult<bool> {
+        self.get(py, |eitype| eitype.needs_flush())
+    }
+
+    /// Type a single character while also holding the given modifiers
+    #[pyo3(name = "type_char_with_mods")]
+    fn py_type_char_with_mods(
+        &self,
+        py: Python<'_>,
+        ch: char,
+        extra_mods: Vec<String>,
+    ) -> PyResult<()> {
+        self.call(py, move |eitype| {
+            let extra_mods: Vec<&str> = extra_mods.iter().map(String::as_str).collect();
+            eitype.type_char_with_mods(ch, &extra_mods)
+        })
+    }
+
+    /// Paste text via the clipboard or primary selection
+    #[pyo3(name = "paste_text")]
+    #[pyo3(signature = (text, selection=Selection::Clipboard, trigger=PasteTrigger::CtrlV, restore_after_ms=None))]
+    fn py_paste_text(
+        &self,
+        py: Python<'_>,
+        text: String,
+        selection: Selection,
+        trigger: PasteTrigger,
+        restore_after_ms: Option<u64>,
+    ) -> PyResult<()> {
+        let options = PasteOptions {
+            selection,
+            trigger,
+            restore_after: restore_after_ms.map(Duration::from_millis),
+        };
+        self.call(py, move |eitype| eitype.paste_text(&text, &options))
+    }
+
+    /// Insert text, pasting it if it exceeds the configured paste_threshold
+    #[pyo3(name = "insert_text")]
+    fn py_insert_text(&self, py: Python<'_>, text: String) -> PyResult<()> {
+        self.call_typing(py, move |eitype| eitype.insert_text(&text))
+    }
+
+    /// Type text, then press Return
+    #[pyo3(name = "type_line")]
+    fn py_type_line(&self, py: Python<'_>, text: String) -> PyResult<()> {
+        self.call_typing(py, move |eitype| eitype.type_line(&text))
+    }
+
+    /// Delete text typed on the wrong layout and type it converted from
+    /// layout index `from` to `to`
+    #[pyo3(name = "retype_converted")]
+    fn py_retype_converted(
+        &self,
+        py: Python<'_>,
+        text: String,
+        from: u32,
+        to: u32,
+    ) -> PyResult<()> {
+        self.call_typing(py, move |eitype| eitype.retype_converted(&text, from, to))
+    }
+
+    /// Scroll by whole wheel notches (needs pointer=True)
+    #[pyo3(name = "scroll_discrete")]
+    fn py_scroll_discrete(&self, py: Python<'_>, dx: i32, dy: i32) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.scroll_discrete(dx, dy))
+    }
+
+    /// Scroll by pixel deltas (needs pointer=True)
+    #[pyo3(name = "scroll_smooth")]
+    fn py_scroll_smooth(&self, py: Python<'_>, dx: f64, dy: f64) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.scroll_smooth(dx, dy))
+    }
+
+    /// Put touch `slot` down at (x, y) in logical pixels (needs the Touch
+    /// capability in the config's capabilities)
+    #[pyo3(name = "touch_down")]
+    fn py_touch_down(&self, py: Python<'_>, slot: u32, x: f64, y: f64) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.touch_down(slot, x, y))
+    }
+
+    /// Move touch `slot` to (x, y)
+    #[pyo3(name = "touch_motion")]
+    fn py_touch_motion(&self, py: Python<'_>, slot: u32, x: f64, y: f64) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.touch_motion(slot, x, y))
+    }
+
+    /// Lift touch `slot`
+    #[pyo3(name = "touch_up")]
+    fn py_touch_up(&self, py: Python<'_>, slot: u32) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.touch_up(slot))
+    }
+
+    /// Type keys by XKB keysym name (e.g. ["eacute", "Cyrillic_de"])
+    #[pyo3(name = "type_keysym_names")]
+    fn py_type_keysym_names(&self, py: Python<'_>, names: Vec<String>) -> PyResult<()> {
+        self.call(py, move |eitype| {
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            eitype.type_keysym_names(&names)
+        })
+    }
+
+    /// Press and release a special key. With confirm=True, then wait up to
+    /// confirm_timeout_ms for the server to confirm it processed the key
+    /// events (raises RuntimeError if it doesn't).
+    #[pyo3(name = "press_key", signature = (key_name, confirm=false, confirm_timeout_ms=2000))]
+    fn py_press_key(
+        &self,
+        py: Python<'_>,
+        key_name: String,
+        confirm: bool,
+        confirm_timeout_ms: u64,
+    ) -> PyResult<()> {
+        self.call(py, move |eitype| {
+            if confirm {
+                eitype.press_key_confirmed(&key_name, Duration::from_millis(confirm_timeout_ms))
+            } else {
+                eitype.press_key(&key_name)
+            }
+        })
+    }
+
+    /// Focus the window of app_id, or the one whose title matches the regex
+    /// title, before typing into it (Sway and Hyprland; elsewhere this only
+    /// logs a warning). Raises RuntimeError if no window matches.
+    #[pyo3(name = "focus_window", signature = (app_id=None, title=None))]
+    fn py_focus_window(
+        &self,
+        py: Python<'_>,
+        app_id: Option<String>,
+        title: Option<&str>,
+    ) -> PyResult<()> {
+        let target = match (app_id, title) {
+            (Some(app_id), None) => FocusTarget::AppId(app_id),
+            (None, Some(title)) => FocusTarget::title(title)
+                .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?,
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "give exactly one of app_id and title",
+                ))
+            }
+        };
+        self.call(py, move |eitype| eitype.focus_window(&target))
+    }
+
+    /// Hold a modifier key by name, or by evdev keycode as "kc:N"
+    #[pyo3(name = "hold_modifier")]
+    fn py_hold_modifier(&self, py: Python<'_>, mod_name: String) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.hold_modifier(&mod_name))
+    }
+
+    /// Press a key and keep it down until key_up (or close)
+    #[pyo3(name = "key_down")]
+    fn py_key_down(&self, py: Python<'_>, key_name: String) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.key_down(&key_name))
+    }
+
+    /// Release a key pressed with key_down
+    #[pyo3(name = "key_up")]
+    fn py_key_up(&self, py: Python<'_>, key_name: String) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.key_up(&key_name))
+    }
+
+    /// Press and release a modifier key
+    #[pyo3(name = "press_modifier")]
+    fn py_press_modifier(&self, py: Python<'_>, mod_name: String) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.press_modifier(&mod_name))
+    }
+
+    /// Release all held modifiers
+    #[pyo3(name = "release_modifiers")]
+    fn py_release_modifiers(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.release_modifiers())
+    }
+
+    /// Release every key this session has down and every known modifier,
+    /// whether or not they are down, to get back to a known state
+    #[pyo3(name = "reset_keys")]
+    fn py_reset_keys(&self, py: Python<'_>) -> PyResult<()> {
+        self.call(py, |eitype| eitype.reset_keys())
+    }
+
+    /// Where the active keymap came from
+    #[getter(keymap_source)]
+    fn py_keymap_source(&self, py: Python<'_>) -> PyResult<KeymapSource> {
+        self.get(py, |eitype| eitype.keymap_source())
+    }
+
+    /// Stable hash of the active keymap
+    #[getter(keymap_fingerprint)]
+    fn py_keymap_fingerprint(&self, py: Python<'_>) -> PyResult<u64> {
+        self.get(py, |eitype| eitype.keymap_fingerprint())
+    }
+
+    /// Switch to the keymap an EiTypeConfig describes, without reconnecting
+    #[pyo3(name = "reload_keymap")]
+    fn py_reload_keymap(&self, py: Python<'_>, config: EiTypeConfig) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.reload_keymap(&config))
+    }
+
+    /// Switch to another layout index and remember it for this keymap
+    #[pyo3(name = "set_layout_index")]
+    fn py_set_layout_index(&self, py: Python<'_>, index: u32) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.set_layout_index(index))
+    }
+
+    /// Capabilities the server granted (a list of `DeviceCapability`)
+    #[pyo3(name = "capabilities")]
+    fn py_capabilities(&self, py: Python<'_>) -> PyResult<Vec<DeviceCapability>> {
+        self.get(py, |eitype| eitype.capabilities())
+    }
+
+    /// Bind another capability without reconnecting and wait for its device
+    #[pyo3(name = "enable_capability")]
+    fn py_enable_capability(&self, py: Python<'_>, capability: DeviceCapability) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.enable_capability(capability))
+    }
+
+    /// Summary of this connection
+    #[getter(connection_info)]
+    fn py_connection_info(&self, py: Python<'_>) -> PyResult<ConnectionInfo> {
+        self.get(py, |eitype| eitype.connection_info())
+    }
+
+    /// How the special key names fit the active keymap
+    #[pyo3(name = "key_table_report")]
+    fn py_key_table_report(&self, py: Python<'_>) -> PyResult<Vec<KeyTableEntry>> {
+        self.get(py, |eitype| eitype.key_table_report())
+    }
+
+    /// Every character the active layout has a key for, sorted
+    #[pyo3(name = "typeable_chars")]
+    fn py_typeable_chars(&self, py: Python<'_>) -> PyResult<Vec<char>> {
+        self.get(py, |eitype| eitype.typeable_chars())
+    }
+
+    /// (evdev keycode, level) that types `ch` in the active layout, or None
+    #[pyo3(name = "keycode_for_char")]
+    fn py_keycode_for_char(&self, py: Python<'_>, ch: char) -> PyResult<Option<(u32, u8)>> {
+        self.get(py, move |eitype| eitype.keycode_for_char(ch))
+    }
+
+    /// Per-character timings as CharTiming objects (needs collect_timing=True)
+    #[pyo3(name = "timings")]
+    fn py_timings(&self, py: Python<'_>) -> PyResult<Vec<CharTiming>> {
+        self.get(py, |eitype| eitype.timings())
+    }
+
+    /// Whether timings() dropped older characters to stay bounded
+    #[pyo3(name = "timings_truncated")]
+    fn py_timings_truncated(&self, py: Python<'_>) -> PyResult<bool> {
+        self.get(py, |eitype| eitype.timings_truncated())
+    }
+
+    /// Call `hook(info)` after every key press or release is sent, with a
+    /// `KeystrokeInfo`; pass None to remove the hook. The hook runs on the
+    /// connection's thread. Exceptions raised by the hook are reported as
+    /// unraisable and don't stop typing.
+    #[pyo3(name = "set_keystroke_hook")]
+    fn py_set_keystroke_hook(&self, py: Python<'_>, hook: Option<Py<PyAny>>) -> PyResult<()> {
+        self.get(py, move |eitype| match hook {
+            Some(hook) => eitype.set_keystroke_hook(move |info| {
+                Python::attach(|py| {
+                    if let Err(e) = hook.call1(py, (info,)) {
+                        e.write_unraisable(py, Some(hook.bind(py)));
+                    }
+                })
+            }),
+            None => eitype.clear_keystroke_hook(),
+        })
+    }
+
+    /// Hide typed characters from the keystroke hook (`ch` is always None)
+    #[pyo3(name = "set_redact_keystrokes")]
+    fn py_set_redact_keystrokes(&self, py: Python<'_>, redact: bool) -> PyResult<()> {
+        self.get(py, move |eitype| eitype.set_redact_keystrokes(redact))
+    }
+
+    /// Change the delay after every key press and release (a timedelta),
+    /// whatever profiles are applied, until reset_delay
+    #[pyo3(name = "set_delay")]
+    fn py_set_delay(&self, py: Python<'_>, delay: Duration) -> PyResult<()> {
+        self.get(py, move |eitype| eitype.set_delay(delay))
+    }
+
+    /// Undo set_delay: back to the active profile's delay, or the configured one
+    #[pyo3(name = "reset_delay")]
+    fn py_reset_delay(&self, py: Python<'_>) -> PyResult<()> {
+        self.get(py, |eitype| eitype.reset_delay())
+    }
+
+    /// The current delay after every key press and release (a timedelta)
+    #[pyo3(name = "delay")]
+    fn py_delay(&self, py: Python<'_>) -> PyResult<Duration> {
+        self.get(py, |eitype| eitype.delay())
+    }
+
+    /// Read the `[profile.NAME]` tables `apply_profile` selects from, by
+    /// default from ~/.config/eitype/config.toml
+    #[pyo3(name = "load_profiles", signature = (path=None))]
+    fn py_load_profiles(&self, py: Python<'_>, path: Option<PathBuf>) -> PyResult<()> {
+        let path = path.or_else(Profiles::default_path).ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("No config directory for profiles")
+        })?;
+        let profiles = Profiles::load(&path)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        self.get(py, move |eitype| eitype.set_profiles(profiles))
+    }
+
+    /// Switch to the named profile loaded with `load_profiles`
+    #[pyo3(name = "apply_profile")]
+    fn py_apply_profile(&self, py: Python<'_>, name: String) -> PyResult<()> {
+        self.call(py, move |eitype| eitype.apply_profile(&name))
+    }
+
+    /// Go back to the configured settings, undoing `apply_profile`
+    #[pyo3(name = "clear_profile")]
+    fn py_clear_profile(&self, py: Python<'_>) -> PyResult<()> {
+        self.get(py, |eitype| eitype.clear_profile())
+    }
+
+    /// Name of the profile last applied, or None
+    #[pyo3(name = "active_profile")]
+    fn py_active_profile(&self, py: Python<'_>) -> PyResult<Option<String>> {
+        self.get(py, |eitype| eitype.active_profile().map(str::to_string))
+    }
+
+    /// Close the connection and release all resources, after any calls
+    /// queued before it.
+    ///
+    /// This method should be called when you're done with the EiType instance,
+    /// especially before attempting to create a new connection. While the
+    /// destructor will clean up automatically, calling close() explicitly
+    /// ensures proper cleanup and avoids potential issues with reconnection.
+    ///
+    /// After calling close(), this instance should not be used anymore.
+    #[pyo3(name = "close")]
+    fn py_close(&self, py: Python<'_>) -> PyResult<()> {
+        self.get(py, |eitype| eitype.close())
+    }
+
+    /// Context manager entry - returns self for use with `with` statement.
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Context manager exit - closes the connection.
+    ///
+    /// This ensures proper cleanup when used with `with` statements:
+    /// ```python
+    /// with EiType.connect_portal() as typer:
+    ///     typer.type_text("Hello!")
+    /// # Connection is automatically closed here
+    /// ```
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        self.py_close(py)?;
+        Ok(false) // Don't suppress exceptions
+    }
+}
+
+/// A `TypeSession` typing with an `EiType`, for Python
+#[cfg(feature = "python")]
+#[pyclass(name = "TypeSession")]
+pub struct PyTypeSession {
+    typer: Py<PyEiType>,
+    /// None once finished
+    session: Option<TypeSession>,
+}
+
+#[cfg(feature = "python")]
+impl PyTypeSession {
+    /// Run `f` with the session on the typer's thread
+    fn run(
+        &mut self,
+        py: Python<'_>,
+        f: impl FnOnce(&mut TypeSession, &EiType) -> Result<(), EiTypeError> + Send + 'static,
+    ) -> PyResult<()> {
+        let mut session = self
+            .session
+            .take()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("session is finished"))?;
+        let (session, result) = self.typer.borrow(py).get(py, move |eitype| {
+            let result = f(&mut session, eitype);
+            (session, result)
+        })?;
+        self.session = Some(session);
+        result.map_err(|e| typing_error_to_py(py, e))
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl PyTypeSession {
+    /// Start a session typing with typer, with nothing committed
+    #[new]
+    fn py_new(py: Python<'_>, typer: Py<PyEiType>) -> PyResult<Self> {
+        let session = typer
+            .borrow(py)
+            .get(py, |eitype| TypeSession::new(eitype))?;
+        Ok(Self {
+            typer,
+            session: Some(session),
+        })
+    }
+
+    /// The text committed so far
+    #[getter]
+    fn text(&self) -> PyResult<String> {
+        self.session
+            .as_ref()
+            .map(|session| session.text().to_string())
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("session is finished"))
+    }
+
+    /// Type text after the committed text
+    fn feed(&mut self, py: Python<'_>, text: String) -> PyResult<()> {
+        self.run(py, move |session, eitype| session.feed(eitype, &text))
+    }
+
+    /// Replace the committed text from character from_index on with
+    /// new_text, deleting and retyping only what differs
+    fn revise(&mut self, py: Python<'_>, from_index: usize, new_text: String) -> PyResult<()> {
+        self.run(py, move |session, eitype| {
+            session.revise(eitype, from_index, &new_text)
+        })
+    }
+
+    /// End the session; returns a TypeOutcome for the final text
+    fn finish(&mut self, py: Python<'_>) -> PyResult<TypeOutcome> {
+        let session = std::mem::take(&mut self.session)
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("session is finished"))?;
+        self.typer
+            .borrow(py)
+            .get(py, move |eitype| session.finish(eitype))
+    }
+}
+
+/// Convert a `type_text` error into a Python `TypingError` carrying its position.
+#[cfg(feature = "python")]
+fn typing_error_to_py(py: Python<'_>, error: EiTypeError) -> PyErr {
+    let err = TypingError::new_err(error.to_string());
+    if let EiTypeError::InText {
+        index,
+        byte_offset,
+        context,
+        ..
+    } = &error
+    {
+        let value = err.value(py);
+        let _ = value.setattr("index", *index);
+        let _ = value.setattr("byte_offset", *byte_offset);
+        let _ = value.setattr("context", context.as_str());
+    }
+    err
+}
+
+#[cfg(feature = "python")]
+#[pymodule]
+fn eitype(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyEiType>()?;
+    m.add_class::<EiTypeConfig>()?;
+    m.add_class::<ChunkPolicy>()?;
+    m.add_class::<KeymapSource>()?;
+    m.add_class::<ConnectionInfo>()?;
+    m.add_class::<KeyTableEntry>()?;
+    m.add_class::<TypingStats>()?;
+    m.add_class::<CharTiming>()?;
+    m.add_class::<TypeOutcome>()?;
+    m.add_class::<Calibration>()?;
+    m.add_class::<CalibrationStep>()?;
+    m.add_class::<BenchReport>()?;
+    m.add_class::<LatencyPercentiles>()?;
+    m.add_class::<PyTypeSession>()?;
+    m.add_class::<SkippedChar>()?;
+    m.add_class::<Substitution>()?;
+    m.add_class::<Selection>()?;
+    m.add_class::<PasteTrigger>()?;
+    m.add_class::<TimestampSource>()?;
+    m.add_class::<AltGrMode>()?;
+    m.add_class::<ModifierOrder>()?;
+    m.add_class::<KeystrokeInfo>()?;
+    m.add_class::<DeviceCapability>()?;
+    m.add("TypingError", m.py().get_type::<TypingError>())?;
+    Ok(())
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reis::eis::connection::DisconnectReason;
+
+    /// System allocator that counts allocations made on each thread, so a
+    /// test can check what it allocated without the other tests interfering
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Number of allocations (and reallocations) this thread has made
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: std::alloc::Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_build_key_to_keycode_map_modifiers() {
+        let map = build_key_to_keycode_map();
+        assert_eq!(map.get("shift"), Some(&42));
+        assert_eq!(map.get("ctrl"), Some(&29));
+        assert_eq!(map.get("alt"), Some(&56));
+        assert_eq!(map.get("super"), Some(&125));
+    }
+
+    #[test]
+    fn test_build_key_to_keycode_map_special_keys() {
+        let map = build_key_to_keycode_map();
+        assert_eq!(map.get("escape"), Some(&1));
+        assert_eq!(map.get("return"), Some(&28));
+        assert_eq!(map.get("tab"), Some(&15));
+        assert_eq!(map.get("space"), Some(&57));
+    }
+
+    #[test]
+    fn test_build_key_to_keycode_map_letters() {
+        let map = build_key_to_keycode_map();
+        assert_eq!(map.get("a"), Some(&30));
+        assert_eq!(map.get("z"), Some(&44));
+    }
+
+    #[test]
+    fn test_keysym_to_char_ascii() {
+        assert_eq!(keysym_to_char(0x20), Some(' '));
+        assert_eq!(keysym_to_char(0x41), Some('A'));
+        assert_eq!(keysym_to_char(0x61), Some('a'));
+    }
+
+    #[test]
+    fn test_keysym_to_char_unicode() {
+        assert_eq!(keysym_to_char(0x1000000 + 0x00e9), Some('é'));
+    }
+
+    #[test]
+    fn test_keysym_to_char_cyrillic() {
+        // Cyrillic_PE / Cyrillic_pe — regression test for issue #8
+        assert_eq!(keysym_to_char(0x06f0), Some('П'));
+        assert_eq!(keysym_to_char(0x06d0), Some('п'));
+    }
+
+    #[test]
+    fn test_keysym_to_char_greek() {
+        // Greek_alpha → α, Greek_ALPHA → Α
+        assert_eq!(keysym_to_char(0x07e1), Some('α'));
+        assert_eq!(keysym_to_char(0x07c1), Some('Α'));
+    }
+
+    #[test]
+    fn test_keysym_to_char_special() {
+        assert_eq!(keysym_to_char(0xff0d), Some('\n')); // XK_Return → '\n'
+        assert_eq!(keysym_to_char(0xff09), Some('\t')); // XK_Tab
+    }
+
+    #[test]
+    fn test_keysym_to_char_unmapped() {
+        assert_eq!(keysym_to_char(0xffe1), None); // XK_Shift_L has no Unicode mapping
+    }
+
+    #[test]
+    fn test_config_default() {
+        let config = EiTypeConfig::default();
+        assert!(config.layout.is_none());
+        assert!(!config.is_specified());
+    }
+
+    #[test]
+    fn test_config_is_specified() {
+        let mut config = EiTypeConfig::default();
+        assert!(!config.is_specified());
+
+        config.layout = Some("us".to_string());
+        assert!(config.is_specified());
+    }
+
+    #[test]
+    fn test_config_validate() {
+        assert!(EiTypeConfig::default().validate().is_ok());
+        let valid = EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            layout_index: Some(1),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+
+        let invalid = [
+            EiTypeConfig {
+                layout: Some(" ".to_string()),
+                ..Default::default()
+            },
+            EiTypeConfig {
+                refuse_if_focused_app: Some(String::new()),
+                ..Default::default()
+            },
+            EiTypeConfig {
+                keymap_file: Some(PathBuf::new()),
+                ..Default::default()
+            },
+            EiTypeConfig {
+                layout_index: Some(2),
+                ..valid.clone()
+            },
+        ];
+        for config in invalid {
+            assert!(
+                matches!(config.validate(), Err(EiTypeError::InvalidConfig(_))),
+                "{:?}",
+                config
+            );
+        }
+
+        // Without a layout, the index refers to the server's keymap
+        let config = EiTypeConfig {
+            layout_index: Some(3),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_get_timestamp_monotonic() {
+        let t1 = get_timestamp();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let t2 = get_timestamp();
+        assert!(t2 >= t1);
+    }
+
+    #[test]
+    fn test_monotonic_timestamp_source() {
+        let t1 = TimestampSource::ClockMonotonic.now();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let t2 = TimestampSource::ClockMonotonic.now();
+        assert!(t2 >= t1 + 1_000, "{} -> {}", t1, t2);
+
+        // Counted from boot, not from process start: at least the process's
+        // own age, and no more than the time since boot (which includes suspend)
+        assert!(t2 > get_timestamp());
+        let uptime = std::fs::read_to_string("/proc/uptime")
+            .ok()
+            .and_then(|s| s.split_whitespace().next()?.parse::<f64>().ok());
+        if let Some(uptime) = uptime {
+            let uptime_us = (uptime * 1e6) as u64;
+            assert!(t2 <= uptime_us + 1_000_000, "{} vs {}", t2, uptime_us);
+        }
+    }
+
+    #[test]
+    fn test_timestamp_source_from_str() {
+        assert_eq!("monotonic".parse(), Ok(TimestampSource::ClockMonotonic));
+        assert_eq!(
+            "Process-Relative".parse(),
+            Ok(TimestampSource::ProcessRelative)
+        );
+        assert!("realtime".parse::<TimestampSource>().is_err());
+    }
+
+    #[test]
+    fn test_exponential_backoff_calculation() {
+        // Verify the backoff formula used in flush_with_retry():
+        // delay_ms = (delay_ms * 2).min(MAX_DELAY_MS)
+        let max_delay_ms: u64 = 100;
+        let mut delay_ms: u64 = 1;
+
+        // Should double each time until capped at 100ms
+        let expected_delays = [2, 4, 8, 16, 32, 64, 100, 100];
+        for expected in expected_delays {
+            delay_ms = (delay_ms * 2).min(max_delay_ms);
+            assert_eq!(delay_ms, expected);
+        }
+    }
+
+    #[test]
+    fn test_eagain_errno_value() {
+        // Document the expected errno for EAGAIN on Linux.
+        // flush_with_retry() checks raw_os_error() == 11 to detect
+        // when the socket buffer is full and we should retry.
+        assert_eq!(libc::EAGAIN, 11);
+        // On Linux, EWOULDBLOCK is the same as EAGAIN
+        assert_eq!(libc::EWOULDBLOCK, libc::EAGAIN);
+    }
+
+    /// Create a minimal two-layout XKB keymap where SPCE only defines one group.
+    /// This reproduces the real-world scenario where layout-independent keys
+    /// like space have fewer groups than the global keymap layout count.
+    fn make_two_layout_keymap_with_single_group_space() -> xkb::Keymap {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_DEFAULT_INCLUDES);
+        let keymap_str = r#"
+xkb_keymap {
+    xkb_keycodes "test" {
+        minimum = 8;
+        maximum = 255;
+        <SPCE> = 65;
+        <AC01> = 38;
+        <AD01> = 24;
+    };
+    xkb_types "test" {
+        virtual_modifiers NumLock;
+        type "ONE_LEVEL" {
+            modifiers = none;
+            level_name[Level1] = "Any";
+        };
+        type "TWO_LEVEL" {
+            modifiers = Shift;
+            map[Shift] = Level2;
+            level_name[Level1] = "Base";
+            level_name[Level2] = "Shift";
+        };
+    };
+    xkb_compatibility "test" {
+        interpret Any+AnyOf(all) { action = SetMods(modifiers=modMapMods,clearLocks); };
+    };
+    xkb_symbols "test" {
+        name[group1] = "English (US)";
+        name[group2] = "English (Programmer Dvorak)";
+
+        // Space: only ONE group (layout-independent) — triggers the bug
+        key <SPCE> { [ space ] };
+
+        // Letter keys: two groups (different in each layout)
+        key <AC01> {
+            symbols[Group1] = [ a, A ],
+            symbols[Group2] = [ a, A ]
+        };
+        key <AD01> {
+            symbols[Group1] = [ q, Q ],
+            symbols[Group2] = [ semicolon, colon ]
+        };
+    };
+};
+"#;
+        xkb::Keymap::new_from_string(
+            &context,
+            keymap_str.to_string(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .expect("Failed to create test keymap")
+    }
+
+    #[test]
+    fn test_find_keycode_for_char_space_at_layout_1() {
+        let keymap = make_two_layout_keymap_with_single_group_space();
+        // Space should be findable regardless of layout index.
+        // This reproduces the bug: with layout_index=1, keys that only
+        // define 1 layout (like space) are skipped, causing CharNotFound.
+        let result = find_keycode_for_char(' ', &keymap, 1);
+        assert!(
+            result.is_ok(),
+            "Space should be found at layout_index=1, got: {:?}",
+            result
+        );
+        let m = result.unwrap();
+        // evdev keycode for space is 57 (XKB 65 - 8)
+        assert_eq!(m.evdev_keycode, 57, "Space should map to evdev keycode 57");
+    }
+
+    #[test]
+    fn test_find_keycode_for_char_space_at_layout_0() {
+        let keymap = make_two_layout_keymap_with_single_group_space();
+        // Baseline: space at layout 0 should always work
+        let result = find_keycode_for_char(' ', &keymap, 0);
+        assert!(result.is_ok(), "Space should be found at layout_index=0");
+        let m = result.unwrap();
+        assert_eq!(m.evdev_keycode, 57);
+    }
+
+    #[test]
+    fn test_find_keycode_for_char_letter_at_layout_1() {
+        let keymap = make_two_layout_keymap_with_single_group_space();
+        // Letters should be findable at layout_index=1 (dvp layout)
+        let result = find_keycode_for_char('a', &keymap, 1);
+        assert!(
+            result.is_ok(),
+            "'a' should be found at layout_index=1, got: {:?}",
+            result
+        );
+    }
+
+    /// Build a keymap from system xkb data. Used by AltGr tests to exercise the
+    /// real key types (FOUR_LEVEL_ALPHABETIC, ISO_Level3_Shift -> Mod5, etc.)
+    /// rather than approximating them by hand.
+    fn system_keymap(layout: &str, variant: &str) -> xkb::Keymap {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            layout,
+            variant,
+            None,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .expect("system xkb data required for this test (install xkeyboard-config)")
+    }
+
+    #[test]
+    fn test_find_keycode_for_char_altgr_us_intl() {
+        // Regression for issue #15: ä lives on the Q key at level 2 (AltGr) on
+        // us-intl. The lookup must report level 2, not flatten it to "no modifier".
+        let keymap = system_keymap("us", "intl");
+        let m = find_keycode_for_char('ä', &keymap, 0).expect("ä should be found");
+        assert_eq!(m.evdev_keycode, 16, "ä is on the Q key");
+        assert_eq!(m.level, 2, "ä is at level 2 (AltGr) on us-intl");
+    }
+
+    #[test]
+    fn test_modifier_keycodes_for_match_altgr_level2() {
+        // Level 2 -> AltGr. Default us-intl modmap binds Mod5 to RAlt (evdev 100).
+        let keymap = system_keymap("us", "intl");
+        let m = find_keycode_for_char('ä', &keymap, 0).unwrap();
+        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
+        assert_eq!(mods[..], [100], "ä on us-intl needs AltGr (evdev 100)");
+    }
+
+    #[test]
+    fn test_modifier_keycodes_for_match_altgr_shift_level3() {
+        // Ä on us-intl is at level 3: AltGr + Shift.
+        let keymap = system_keymap("us", "intl");
+        let m = find_keycode_for_char('Ä', &keymap, 0).unwrap();
+        assert_eq!(m.level, 3, "Ä is at level 3 (AltGr+Shift) on us-intl");
+        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
+        // Order isn't fixed; just assert set membership and count.
+        assert_eq!(mods.len(), 2);
+        assert!(mods.contains(&42), "Ä needs Shift (evdev 42)");
+        assert!(mods.contains(&100), "Ä needs AltGr (evdev 100)");
+    }
+
+    #[test]
+    fn test_modifier_keycodes_for_match_level0_no_mods() {
+        let keymap = system_keymap("us", "");
+        let m = find_keycode_for_char('a', &keymap, 0).unwrap();
+        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
+        assert!(mods.is_empty(), "level 0 needs no modifiers");
+    }
+
+    #[test]
+    fn test_modifier_keycodes_for_match_level1_shift() {
+        let keymap = system_keymap("us", "");
+        let m = find_keycode_for_char('A', &keymap, 0).unwrap();
+        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
+        assert_eq!(mods[..], [42], "level 1 needs Shift only");
+    }
+
+    #[test]
+    fn test_keymap_modifier_map_default_us_intl() {
+        // Sanity: on the default us-intl modmap, the canonical bindings hold.
+        let keymap = system_keymap("us", "intl");
+        let map = build_keymap_modifier_map(&keymap);
+        assert_eq!(map.get("Shift").copied(), Some(42), "Shift -> LShift");
+        assert_eq!(map.get("Mod5").copied(), Some(100), "Mod5 -> RAlt (AltGr)");
+    }
+
+    #[test]
+    fn test_altgr_mode_picks_level3_keysym_key() {
+        // Right Alt is plain Alt here; Menu is the level 3 switch
+        let keymap = || keymap_with_options("us(intl)", "lv3:ralt_alt,lv3:menu_switch");
+        let altgr_keycode = |altgr_mode| {
+            let config = EiTypeConfig {
+                altgr_mode,
+                ..Default::default()
+            };
+            let mut planner = Planner::new(keymap(), 0, &config);
+            let altgr = planner.keycode_for_name("altgr").unwrap();
+            // Typing a level 3 character presses the same key
+            let events = planner.plan_text("ä").unwrap();
+            assert_eq!(events[0], PlannedEvent::Press(altgr));
+            altgr
+        };
+
+        let right_alt = altgr_keycode(AltGrMode::RightAlt);
+        let level3_keysym = altgr_keycode(AltGrMode::Level3Keysym);
+        assert_eq!(right_alt, 127);
+        assert_ne!(level3_keysym, right_alt);
+        let keymap = keymap();
+        let syms = keymap.key_get_syms_by_level(xkb::Keycode::new(level3_keysym + 8), 0, 0);
+        assert_eq!(
+            syms,
+            [xkb::Keysym::new(xkb::keysyms::KEY_ISO_Level3_Shift)],
+            "keycode {}",
+            level3_keysym
+        );
+    }
+
+    #[test]
+    fn test_diff_keymaps_us_vs_us_intl() {
+        let diff = diff_keymaps(&system_keymap("us", ""), &system_keymap("us", "intl"));
+        let find = |list: &[KeysymDiff], keysym: &str| {
+            list.iter().find(|entry| entry.keysym == keysym).cloned()
+        };
+
+        // Only us(intl) has ä, behind AltGr
+        let adiaeresis = find(&diff.only_in_b, "adiaeresis").expect("ä only in us(intl)");
+        assert_eq!(adiaeresis.ch, Some('ä'));
+        assert_eq!(adiaeresis.a, None);
+        assert_eq!(adiaeresis.b.unwrap().modifiers, vec!["AltGr"]);
+        assert!(find(&diff.only_in_b, "dead_acute").is_some());
+        assert!(diff.only_in_a.iter().all(|entry| entry.ch != Some('a')));
+
+        // The quote keys are dead keys on us(intl); the quotes need AltGr
+        let apostrophe = find(&diff.moved, "apostrophe").expect("' moved");
+        let (a, b) = (apostrophe.a.unwrap(), apostrophe.b.unwrap());
+        assert_eq!((a.keycode, a.level), (40, 0));
+        assert_eq!(b.keycode, 40);
+        assert!(b.level > 0, "{}", b);
+        let quotedbl = find(&diff.moved, "quotedbl").expect("\" moved");
+        assert_eq!(quotedbl.a.unwrap().modifiers, vec!["Shift"]);
+        assert_eq!(quotedbl.b.unwrap().modifiers, vec!["Shift", "AltGr"]);
+
+        // Unchanged keys are left out
+        assert!(find(&diff.moved, "a").is_none());
+        assert!(diff_keymaps(&system_keymap("us", ""), &system_keymap("us", "")).is_empty());
+    }
+
+    #[test]
+    fn test_altgr_mode_from_str() {
+        assert_eq!("right-alt".parse(), Ok(AltGrMode::RightAlt));
+        assert_eq!("Level3-Keysym".parse(), Ok(AltGrMode::Level3Keysym));
+        assert!("ralt".parse::<AltGrMode>().is_err());
+    }
+
+    #[test]
+    fn test_modifier_order_nests_shift_and_altgr() {
+        // Ä is Shift+AltGr+q on us(intl)
+        let plan = |modifier_order| {
+            let config = EiTypeConfig {
+                layout: Some("us".to_string()),
+                variant: Some("intl".to_string()),
+                modifier_order,
+                ..Default::default()
+            };
+            let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+            let mut events = planner.plan_text("Ä").unwrap();
+            events.retain(|e| *e != PlannedEvent::Frame);
+            events
+        };
+        let nested = |outer, inner| {
+            vec![
+                PlannedEvent::Press(outer),
+                PlannedEvent::Press(inner),
+                PlannedEvent::Press(16),
+                PlannedEvent::Release(16),
+                PlannedEvent::Release(inner),
+                PlannedEvent::Release(outer),
+            ]
+        };
+
+        assert_eq!(plan(ModifierOrder::ShiftFirst), nested(42, 100));
+        assert_eq!(plan(ModifierOrder::Level3First), nested(100, 42));
+        assert_eq!("Level3-First".parse(), Ok(ModifierOrder::Level3First));
+        assert!("altgr-first".parse::<ModifierOrder>().is_err());
+    }
+
+    #[test]
+    fn test_keymap_modifier_map_honors_lv3_ralt_alt() {
+        // Regression: with `lv3:ralt_alt`, RAlt is Alt (Mod1), not AltGr (Mod5).
+        // The Menu key (evdev 127) should pick up Mod5 via `lv3:menu_switch`.
+        // This is the configuration that broke PR #16's hardcoded AltGr=100.
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            "us",
+            "intl",
+            Some("lv3:ralt_alt,lv3:menu_switch".to_string()),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .expect("system xkb data required (install xkeyboard-config)");
+        let map = build_keymap_modifier_map(&keymap);
+        assert_eq!(
+            map.get("Mod5").copied(),
+            Some(127),
+            "with lv3:menu_switch, Mod5 should resolve to the Menu key (evdev 127), \
+             not the hardcoded RAlt=100"
+        );
+        // RAlt is now Mod1 under lv3:ralt_alt — Mod1 should resolve to it (or LAlt;
+        // either is fine, both produce Mod1).
+        let mod1 = map.get("Mod1").copied();
+        assert!(
+            mod1 == Some(56) || mod1 == Some(100),
+            "Mod1 should be LAlt (56) or RAlt (100); got {:?}",
+            mod1
+        );
+    }
+
+    #[test]
+    fn test_lv3_switch_uses_right_ctrl_for_level3() {
+        // `lv3:switch` makes Right Ctrl the level 3 switch; with `lv3:ralt_alt`
+        // Right Alt is plain Alt, so AltGr characters must be typed with RCtrl.
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us".to_string()),
+            variant: Some("intl".to_string()),
+            options: Some("lv3:switch,lv3:ralt_alt".to_string()),
+            ..Default::default()
+        });
+        typer.type_text("ä").unwrap();
+        // ä is AltGr+q on us(intl)
+        assert_eq!(
+            key_events(&sink),
+            vec![(97, true), (16, true), (16, false), (97, false)]
+        );
+
+        // The AltGr key name follows the keymap too
+        sink.events.borrow_mut().clear();
+        typer.press_key("AltGr").unwrap();
+        typer.hold_modifier("level3").unwrap();
+        typer.release_modifiers().unwrap();
+        assert_eq!(sink.presses(), vec![97, 97]);
+    }
+
+    #[test]
+    fn test_load_keymap_source_config_names() {
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            ..Default::default()
+        };
+        let (_, source) = load_keymap(&config, None).unwrap();
+        assert_eq!(source, KeymapSource::ConfigNames);
+    }
+
+    #[test]
+    fn test_load_keymap_source_server_fd() {
+        // Serialize a real keymap to a file and hand it over as if the EI
+        // server had sent it for the keyboard device.
+        let keymap_str = system_keymap("us", "").get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, keymap_str.as_bytes()).unwrap();
+
+        let server_keymap = Some((OwnedFd::from(file), keymap_str.len()));
+        let (keymap, source) = load_keymap(&EiTypeConfig::default(), server_keymap).unwrap();
+        assert_eq!(source, KeymapSource::Server);
+        assert!(find_keycode_for_char('a', &keymap, 0).is_ok());
+    }
+
+    #[test]
+    fn test_load_keymap_config_names_win_over_server() {
+        let keymap_str = system_keymap("us", "").get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, keymap_str.as_bytes()).unwrap();
+
+        let config = EiTypeConfig {
+            layout: Some("de".to_string()),
+            ..Default::default()
+        };
+        let server_keymap = Some((OwnedFd::from(file), keymap_str.len()));
+        let (_, source) = load_keymap(&config, server_keymap).unwrap();
+        assert_eq!(source, KeymapSource::ConfigNames);
+    }
+
+    /// Event recorded by `RecordingSink`, with the time it was emitted.
+    #[derive(Debug, Clone, PartialEq)]
+    enum Recorded {
+        StartEmulating,
+        StopEmulating,
+        Key(u32, bool),
+        Button(u32, bool),
+        ScrollDiscrete(i32, i32),
+        ScrollSmooth(f32, f32),
+        TouchDown(u32, f32, f32),
+        TouchMotion(u32, f32, f32),
+        TouchUp(u32),
+        Frame,
+        Dispatch,
+        UnsentBytes,
+        Sync,
+        Disconnect,
+    }
+
+    /// `EventSink` that records everything instead of talking to a server.
+    #[derive(Clone)]
+    struct RecordingSink {
+        events: std::rc::Rc<RefCell<Vec<(Instant, Recorded)>>>,
+        /// Number of flushes that succeed before the socket stalls and every
+        /// flush fails with EAGAIN (`None` = never stalls)
+        flush_budget: std::rc::Rc<Cell<Option<u32>>>,
+        /// Errno of the flushes past the budget (default: EAGAIN)
+        flush_errno: std::rc::Rc<Cell<i32>>,
+        /// Whether each of the next flushes goes through (false = EAGAIN),
+        /// before `flush_budget` applies (default: none)
+        flush_outcomes: std::rc::Rc<RefCell<VecDeque<bool>>>,
+        /// Stands in for the connection socket
+        socket: std::rc::Rc<UnixStream>,
+        /// Whether a pointer device is bound (default: true)
+        pointer: std::rc::Rc<Cell<bool>>,
+        /// Whether a touch device is bound (default: false)
+        touch: std::rc::Rc<Cell<bool>>,
+        /// Regions of the touch device (default: none)
+        regions: std::rc::Rc<RefCell<Vec<DeviceRegion>>>,
+        /// Returned (and cleared) by `take_resumed`
+        resumed: std::rc::Rc<Cell<bool>>,
+        /// Successive results of `unsent_bytes`; the last one repeats
+        /// (default: always 0)
+        unsent: std::rc::Rc<RefCell<Vec<usize>>>,
+        /// Successive results of `check_keyboard`; the last one repeats
+        /// (default: always unchanged)
+        keyboard_checks: std::rc::Rc<RefCell<Vec<KeyboardChange>>>,
+        /// Timestamps of the keyboard frames, in order
+        frame_timestamps: std::rc::Rc<RefCell<Vec<u64>>>,
+        /// Microseconds the server takes to read a keyboard frame: flushing
+        /// one that came sooner after the previous fails once with EAGAIN
+        /// (default: none, the server keeps up with anything)
+        drain_gap: std::rc::Rc<Cell<Option<u64>>>,
+        /// Keyboard frames sent when a flush last failed for `drain_gap`
+        drained_frames: std::rc::Rc<Cell<usize>>,
+    }
+
+    impl Default for RecordingSink {
+        fn default() -> Self {
+            let (socket, _) = UnixStream::pair().unwrap();
+            Self {
+                events: Default::default(),
+                flush_budget: Default::default(),
+                flush_errno: std::rc::Rc::new(Cell::new(11)),
+                flush_outcomes: Default::default(),
+                socket: std::rc::Rc::new(socket),
+                pointer: std::rc::Rc::new(Cell::new(true)),
+                touch: Default::default(),
+                regions: Default::default(),
+                resumed: Default::default(),
+                unsent: Default::default(),
+                keyboard_checks: Default::default(),
+                frame_timestamps: Default::default(),
+                drain_gap: Default::default(),
+                drained_frames: Default::default(),
+            }
+        }
+    }
+
+    impl RecordingSink {
+        fn record(&self, event: Recorded) {
+            self.events.borrow_mut().push((Instant::now(), event));
+        }
+
+        fn events(&self) -> Vec<Recorded> {
+            self.events
+                .borrow()
+                .iter()
+                .map(|(_, e)| e.clone())
+                .collect()
+        }
+
+        /// Evdev keycodes of key presses, in order
+        fn presses(&self) -> Vec<u32> {
+            self.events()
+                .into_iter()
+                .filter_map(|e| match e {
+                    Recorded::Key(kc, true) => Some(kc),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl EventSink for RecordingSink {
+        fn start_emulating(&self, _sequence: u32) {
+            self.record(Recorded::StartEmulating);
+        }
+        fn stop_emulating(&self) {
+            self.record(Recorded::StopEmulating);
+        }
+        fn key(&self, keycode: u32, state: KeyState) {
+            self.record(Recorded::Key(keycode, state == KeyState::Press));
+        }
+        fn frame(&self, timestamp: u64) {
+            self.frame_timestamps.borrow_mut().push(timestamp);
+            self.record(Recorded::Frame);
+        }
+        fn capabilities(&self) -> Vec<DeviceCapability> {
+            let mut capabilities = vec![DeviceCapability::Keyboard];
+            if self.pointer.get() {
+                capabilities.extend([DeviceCapability::Button, DeviceCapability::Scroll]);
+            }
+            if self.touch.get() {
+                capabilities.push(DeviceCapability::Touch);
+            }
+            capabilities
+        }
+        fn bind_capabilities(&self, capabilities: &[DeviceCapability]) -> Result<(), EiTypeError> {
+            if capabilities.contains(&DeviceCapability::Button) {
+                self.pointer.set(true);
+            }
+            if capabilities.contains(&DeviceCapability::Touch) {
+                self.touch.set(true);
+            }
+            Ok(())
+        }
+        fn button(&self, button: u32, pressed: bool, _timestamp: u64) -> Result<(), EiTypeError> {
+            if !self.pointer.get() {
+                return Err(EiTypeError::NoDevice(DeviceCapability::Button));
+            }
+            self.record(Recorded::Button(button, pressed));
+            self.record(Recorded::Frame);
+            Ok(())
+        }
+        fn scroll_discrete(&self, dx: i32, dy: i32, _timestamp: u64) -> Result<(), EiTypeError> {
+            if !self.pointer.get() {
+                return Err(EiTypeError::NoDevice(DeviceCapability::Scroll));
+            }
+            self.record(Recorded::ScrollDiscrete(dx, dy));
+            self.record(Recorded::Frame);
+            Ok(())
+        }
+        fn scroll_smooth(&self, dx: f32, dy: f32, _timestamp: u64) -> Result<(), EiTypeError> {
+            if !self.pointer.get() {
+                return Err(EiTypeError::NoDevice(DeviceCapability::Scroll));
+            }
+            self.record(Recorded::ScrollSmooth(dx, dy));
+            self.record(Recorded::Frame);
+            Ok(())
+        }
+        fn touch_down(
+            &self,
+            slot: u32,
+            x: f32,
+            y: f32,
+            _timestamp: u64,
+        ) -> Result<(), EiTypeError> {
+            self.record(Recorded::TouchDown(slot, x, y));
+            self.record(Recorded::Frame);
+            Ok(())
+        }
+        fn touch_motion(
+            &self,
+            slot: u32,
+            x: f32,
+            y: f32,
+            _timestamp: u64,
+        ) -> Result<(), EiTypeError> {
+            self.record(Recorded::TouchMotion(slot, x, y));
+            self.record(Recorded::Frame);
+            Ok(())
+        }
+        fn touch_up(&self, slot: u32, _timestamp: u64) -> Result<(), EiTypeError> {
+            self.record(Recorded::TouchUp(slot));
+            self.record(Recorded::Frame);
+            Ok(())
+        }
+        fn touch_regions(&self) -> Vec<DeviceRegion> {
+            self.regions.borrow().clone()
+        }
+        fn flush(&self) -> std::io::Result<()> {
+            if self.flush_outcomes.borrow_mut().pop_front() == Some(false) {
+                return Err(std::io::Error::from_raw_os_error(11));
+            }
+            if let Some(gap) = self.drain_gap.get() {
+                let timestamps = self.frame_timestamps.borrow();
+                if let [.., previous, last] = timestamps[..] {
+                    if last - previous < gap && self.drained_frames.get() != timestamps.len() {
+                        self.drained_frames.set(timestamps.len());
+                        return Err(std::io::Error::from_raw_os_error(11));
+                    }
+                }
+            }
+            match self.flush_budget.get() {
+                Some(0) => Err(std::io::Error::from_raw_os_error(self.flush_errno.get())),
+                Some(n) => {
+                    self.flush_budget.set(Some(n - 1));
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+        fn dispatch(&self) -> Result<(), EiTypeError> {
+            self.record(Recorded::Dispatch);
+            Ok(())
+        }
+        fn sync(&self) -> Result<Rc<Cell<bool>>, EiTypeError> {
+            self.record(Recorded::Sync);
+            Ok(Rc::new(Cell::new(true)))
+        }
+        fn server_keymap(&self) -> Result<Option<(OwnedFd, usize)>, EiTypeError> {
+            Ok(None)
+        }
+        fn take_resumed(&self) -> bool {
+            self.resumed.take()
+        }
+        fn check_keyboard(&self) -> Result<KeyboardChange, EiTypeError> {
+            let mut checks = self.keyboard_checks.borrow_mut();
+            Ok(match checks.len() {
+                0 => KeyboardChange::Unchanged,
+                1 => checks[0],
+                _ => checks.remove(0),
+            })
+        }
+        fn unsent_bytes(&self) -> std::io::Result<usize> {
+            let mut unsent = self.unsent.borrow_mut();
+            self.record(Recorded::UnsentBytes);
+            if unsent.len() > 1 {
+                Ok(unsent.remove(0))
+            } else {
+                Ok(unsent.first().copied().unwrap_or(0))
+            }
+        }
+        fn disconnect(&self) {
+            self.record(Recorded::Disconnect);
+        }
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.socket.as_fd()
+        }
+    }
+
+    /// What the mock EIS server received from the client
+    #[derive(Debug, Clone, PartialEq)]
+    enum ServerEvent {
+        StartEmulating,
+        StopEmulating,
+        Key(u32, bool),
+        Button(u32, bool),
+        ScrollDiscrete(i32, i32),
+        ScrollSmooth(f32, f32),
+        Frame,
+        Disconnect,
+    }
+
+    /// In-process EIS server offering a keyboard (and a pointer, if the client
+    /// binds one), running on its own thread. Dropping the client end of the
+    /// socket stops it.
+    struct MockServer {
+        control: std::sync::mpsc::Sender<ServerControl>,
+        events: std::sync::mpsc::Receiver<ServerEvent>,
+        /// Set by `ignore_syncs`
+        ignore_syncs: Arc<AtomicBool>,
+    }
+
+    /// How the mock EIS server behaves
+    #[derive(Debug, Clone, Copy)]
+    struct MockOptions {
+        /// Offer a keyboard device when the client binds; otherwise hang up
+        /// instead, like a server with no keyboard to offer
+        grant_keyboard: bool,
+        /// Offer a pointer device when the client binds one
+        grant_pointer: bool,
+        /// Sleep this long after each read once connected, like a server
+        /// that can't keep up
+        read_pause: Duration,
+        /// Shrink the client's socket send buffer to this many bytes, so a
+        /// slow reader fills it quickly
+        send_buffer: Option<usize>,
+        /// Resume devices as soon as they are added; otherwise they wait for
+        /// `resume_devices`
+        resume_on_bind: bool,
+        /// Disconnect the client with this reason and explanation when it
+        /// binds, instead of offering devices
+        disconnect_on_bind: Option<(DisconnectReason, &'static str)>,
+    }
+
+    impl Default for MockOptions {
+        fn default() -> Self {
+            Self {
+                grant_keyboard: true,
+                grant_pointer: true,
+                read_pause: Duration::ZERO,
+                send_buffer: None,
+                resume_on_bind: true,
+                disconnect_on_bind: None,
+            }
+        }
+    }
+
+    /// Requests from a test to the mock EIS server
+    enum ServerControl {
+        Disconnect(DisconnectReason, &'static str),
+        PauseDevices,
+        ResumeDevices,
+        /// Remove the keyboard device, and offer a new one on its seat if true;
+        /// the sender hears once the change is flushed to the client
+        RemoveKeyboard(bool, std::sync::mpsc::Sender<()>),
+    }
+
+    impl MockServer {
+        /// Start a server and return it with the client end of its socket
+        fn start() -> (Self, UnixStream) {
+            Self::start_with(MockOptions::default())
+        }
+
+        /// Start a server that behaves as `options` say
+        fn start_with(options: MockOptions) -> (Self, UnixStream) {
+            let (client, server) = UnixStream::pair().unwrap();
+            client.set_nonblocking(true).unwrap();
+            server.set_nonblocking(true).unwrap();
+            if let Some(size) = options.send_buffer {
+                let size = size as libc::c_int;
+                // SAFETY: the fd is open and `size` outlives the call
+                let result = unsafe {
+                    libc::setsockopt(
+                        std::os::fd::AsRawFd::as_raw_fd(&client),
+                        libc::SOL_SOCKET,
+                        libc::SO_SNDBUF,
+                        (&size as *const libc::c_int).cast(),
+                        std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                    )
+                };
+                assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+            }
+            let (control_tx, control_rx) = std::sync::mpsc::channel();
+            let (events_tx, events_rx) = std::sync::mpsc::channel();
+            let ignore_syncs = Arc::new(AtomicBool::new(false));
+            let server_ignore_syncs = Arc::clone(&ignore_syncs);
+            std::thread::spawn(move || {
+                Self::run(server, options, control_rx, events_tx, server_ignore_syncs)
+            });
+            let server = Self {
+                control: control_tx,
+                events: events_rx,
+                ignore_syncs,
+            };
+            (server, client)
+        }
+
+        /// Connect an `EiType` to a fresh mock server
+        fn connect() -> (Self, EiType) {
+            Self::connect_with(EiTypeConfig::default())
+        }
+
+        /// Connect an `EiType` with `config` (on a US keymap) to a fresh mock server
+        fn connect_with(config: EiTypeConfig) -> (Self, EiType) {
+            let (server, client) = Self::start();
+            let config = EiTypeConfig {
+                layout: Some("us".to_string()),
+                layout_index: Some(0),
+                ignore_layout_cache: true,
+                ..config
+            };
+            let eitype = EiType::from_stream(client, config).unwrap();
+            (server, eitype)
+        }
+
+        /// Ask the server to disconnect the client
+        fn disconnect_client(&self) {
+            self.disconnect_client_with(DisconnectReason::Disconnected, "test");
+        }
+
+        /// Ask the server to disconnect the client with this reason and
+        /// explanation
+        fn disconnect_client_with(&self, reason: DisconnectReason, explanation: &'static str) {
+            self.control
+                .send(ServerControl::Disconnect(reason, explanation))
+                .unwrap();
+        }
+
+        /// Ask the server to pause every device it offered
+        fn pause_devices(&self) {
+            self.control.send(ServerControl::PauseDevices).unwrap();
+        }
+
+        /// Ask the server to resume every device it offered
+        fn resume_devices(&self) {
+            self.control.send(ServerControl::ResumeDevices).unwrap();
+        }
+
+        /// Ask the server to remove the keyboard device, then offer a new
+        /// one on the same seat if `replace` is true
+        fn remove_keyboard(&self, replace: bool) {
+            remove_keyboard(&self.control, replace);
+        }
+        /// Make the server stop answering sync requests, like one that has
+        /// hung; requests sent from now on see it
+        fn ignore_syncs(&self) {
+            self.ignore_syncs.store(true, Ordering::SeqCst);
+        }
+
+        /// Wait for the next event the server receives
+        fn next_event(&self) -> ServerEvent {
+            self.events.recv_timeout(Duration::from_secs(5)).unwrap()
+        }
+
+        fn run(
+            stream: UnixStream,
+            options: MockOptions,
+            control: std::sync::mpsc::Receiver<ServerControl>,
+            events: std::sync::mpsc::Sender<ServerEvent>,
+            ignore_syncs: Arc<AtomicBool>,
+        ) {
+            use reis::eis;
+            use reis::request::{EisRequest, EisRequestConverter};
+
+            let poll_stream = stream.try_clone().unwrap();
+            let context = eis::Context::new(stream).unwrap();
+            let mut handshaker = reis::handshake::EisHandshaker::new(&context, 1);
+            let mut converter: Option<EisRequestConverter> = None;
+            let mut devices: Vec<reis::request::Device> = Vec::new();
+            loop {
+                match control.try_recv() {
+                    Ok(ServerControl::Disconnect(reason, explanation)) => {
+                        if let Some(converter) = &converter {
+                            converter.handle().disconnected(reason, explanation);
+                            let _ = converter.handle().flush();
+                        }
+                    }
+                    Ok(ServerControl::PauseDevices) => {
+                        for device in &devices {
+                            device.paused();
+                        }
+                        let _ = context.flush();
+                    }
+                    Ok(ServerControl::ResumeDevices) => {
+                        for device in &devices {
+                            device.resumed();
+                        }
+                        let _ = context.flush();
+                    }
+                    Ok(ServerControl::RemoveKeyboard(replace, done)) => {
+                        let keyboard = devices
+                            .iter()
+                            .position(|d| d.has_capability(EiCapability::Keyboard));
+                        if let Some(index) = keyboard {
+                            let removed = devices.remove(index);
+                            removed.remove();
+                            if replace {
+                                let device = removed.seat().add_device(
+                                    Some("keyboard"),
+                                    eis::device::DeviceType::Virtual,
+                                    &[EiCapability::Keyboard],
+                                    |_| {},
+                                );
+                                device.resumed();
+                                devices.push(device);
+                            }
+                        }
+                        let _ = context.flush();
+                        let _ = done.send(());
+                    }
+                    Err(_) => {}
+                }
+
+                let mut pollfd = [rustix::event::PollFd::new(
+                    &poll_stream,
+                    rustix::event::PollFlags::IN,
+                )];
+                let timeout = rustix::time::Timespec {
+                    tv_sec: 0,
+                    tv_nsec: 10_000_000,
+                };
+                let _ = rustix::event::poll(&mut pollfd, Some(&timeout));
+                if context.read().is_err() {
+                    return;
+                }
+                if converter.is_some() {
+                    std::thread::sleep(options.read_pause);
+                }
+
+                while let Some(result) = context.pending_request() {
+                    let PendingRequestResult::Request(request) = result else {
+                        continue;
+                    };
+                    let Some(converter) = converter.as_mut() else {
+                        if let Some(resp) = handshaker.handle_request(request).unwrap() {
+                            let new = EisRequestConverter::new(&context, resp, 1);
+                            new.handle().add_seat(
+                                Some("default"),
+                                &[
+                                    EiCapability::Keyboard,
+                                    EiCapability::Pointer,
+                                    EiCapability::Button,
+                                    EiCapability::Scroll,
+                                ],
+                            );
+                            converter = Some(new);
+                        }
+                        continue;
+                    };
+                    if ignore_syncs.load(Ordering::SeqCst)
+                        && matches!(
+                            request,
+                            eis::Request::Connection(_, eis::connection::Request::Sync { .. })
+                        )
+                    {
+                        continue;
+                    }
+                    converter.handle_request(request).unwrap();
+                    while let Some(request) = converter.next_request() {
+                        let event = match request {
+                            EisRequest::Bind(_) if !options.grant_keyboard => return,
+                            EisRequest::Bind(_) if options.disconnect_on_bind.is_some() => {
+                                let (reason, explanation) = options.disconnect_on_bind.unwrap();
+                                converter.handle().disconnected(reason, explanation);
+                                let _ = converter.handle().flush();
+                                return;
+                            }
+                            EisRequest::Bind(bind) => {
+                                let bound = |capability: EiCapability| {
+                                    bind.capabilities & (2 << capability as u64) != 0
+                                };
+                                // Binding again only adds devices for
+                                // capabilities no device has yet
+                                let offered_already = |capability| {
+                                    devices.iter().any(|d| d.has_capability(capability))
+                                };
+                                let mut offered = Vec::new();
+                                if !offered_already(EiCapability::Keyboard) {
+                                    offered.push(("keyboard", vec![EiCapability::Keyboard]));
+                                }
+                                // The pointer device has exactly the pointer
+                                // capabilities the client bound
+                                let pointer: Vec<EiCapability> = [
+                                    EiCapability::Pointer,
+                                    EiCapability::Button,
+                                    EiCapability::Scroll,
+                                ]
+                                .into_iter()
+                                .filter(|&capability| {
+                                    bound(capability) && !offered_already(capability)
+                                })
+                                .collect();
+                                if options.grant_pointer && !pointer.is_empty() {
+                                    offered.push(("pointer", pointer));
+                                }
+                                for (name, capabilities) in offered {
+                                    let device = bind.seat.add_device(
+                                        Some(name),
+                                        eis::device::DeviceType::Virtual,
+                                        &capabilities,
+                                        |_| {},
+                                    );
+                                    if options.resume_on_bind {
+                                        device.resumed();
+                                    }
+                                    devices.push(device);
+                                }
+                                continue;
+                            }
+                            EisRequest::DeviceStartEmulating(_) => ServerEvent::StartEmulating,
+                            EisRequest::DeviceStopEmulating(_) => ServerEvent::StopEmulating,
+                            EisRequest::KeyboardKey(key) => ServerEvent::Key(
+                                key.key,
+                                key.state == eis::keyboard::KeyState::Press,
+                            ),
+                            EisRequest::Button(button) => ServerEvent::Button(
+                                button.button,
+                                button.state == eis::button::ButtonState::Press,
+                            ),
+                            EisRequest::ScrollDiscrete(scroll) => {
+                                ServerEvent::ScrollDiscrete(scroll.discrete_dx, scroll.discrete_dy)
+                            }
+                            EisRequest::ScrollDelta(scroll) => {
+                                ServerEvent::ScrollSmooth(scroll.dx, scroll.dy)
+                            }
+                            EisRequest::Frame(_) => ServerEvent::Frame,
+                            EisRequest::Disconnect => ServerEvent::Disconnect,
+                            _ => continue,
+                        };
+                        let _ = events.send(event);
+                    }
+                }
+                let _ = context.flush();
+            }
+        }
+    }
+
+    /// Send `MockServer::remove_keyboard` over `control`, and wait until the
+    /// server has flushed the change to the client
+    fn remove_keyboard(control: &std::sync::mpsc::Sender<ServerControl>, replace: bool) {
+        let (done, flushed) = std::sync::mpsc::channel();
+        control
+            .send(ServerControl::RemoveKeyboard(replace, done))
+            .unwrap();
+        flushed.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_mock_server_receives_typed_keys() {
+        let (server, mut typer) = MockServer::connect();
+        typer.type_text("hi").unwrap();
+        typer.close();
+
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| *e != ServerEvent::Frame)
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(35, true),
+                ServerEvent::Key(35, false),
+                ServerEvent::Key(23, true),
+                ServerEvent::Key(23, false),
+                ServerEvent::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_server_resume_re_presses_held_modifiers() {
+        let (server, mut typer) = MockServer::connect();
+        let status = typer.status_receiver();
+        assert_eq!(status.try_recv(), Ok(StatusEvent::Connected));
+        typer.hold_modifier("ctrl").unwrap();
+
+        server.pause_devices();
+        server.resume_devices();
+        assert!(matches!(
+            next_status(&typer, &status).unwrap(),
+            StatusEvent::DevicePaused { .. }
+        ));
+        assert!(matches!(
+            next_status(&typer, &status).unwrap(),
+            StatusEvent::DeviceResumed { .. }
+        ));
+        typer.press_key("a").unwrap();
+        assert_eq!(typer.stats().held_key_replays, 1);
+        typer.close();
+
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| *e != ServerEvent::Frame)
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(29, true),
+                // New session after the resume: ctrl is pressed again first
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(29, true),
+                ServerEvent::Key(30, true),
+                ServerEvent::Key(30, false),
+                ServerEvent::Key(29, false),
+                ServerEvent::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_server_keyboard_replaced_mid_text() {
+        let (server, mut typer) = MockServer::connect();
+        typer.hold_modifier("ctrl").unwrap();
+        // The server swaps the keyboard device after the first character
+        let control = server.control.clone();
+        typer.set_progress_callback(move |progress| {
+            if progress.typed == 1 {
+                remove_keyboard(&control, true);
+            }
+        });
+        typer.type_text("ab").unwrap();
+        assert_eq!(typer.stats().held_key_replays, 1);
+        typer.close();
+
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| *e != ServerEvent::Frame)
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(29, true),
+                ServerEvent::Key(30, true),
+                ServerEvent::Key(30, false),
+                // The new device starts a session with ctrl pressed again,
+                // and typing goes on from 'b'
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(29, true),
+                ServerEvent::Key(48, true),
+                ServerEvent::Key(48, false),
+                ServerEvent::Key(29, false),
+                ServerEvent::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_server_keyboard_lost() {
+        let (server, typer) = MockServer::connect_with(EiTypeConfig {
+            device_failover_ms: 50,
+            ..Default::default()
+        });
+        typer.type_text("a").unwrap();
+        server.remove_keyboard(false);
+        std::thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let err = typer.type_text("bc").unwrap_err();
+        assert!(
+            matches!(err.root(), EiTypeError::DeviceLost { chars_typed: 0 }),
+            "{:?}",
+            err
+        );
+        // It waited out the failover time first
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_keyboard_removed_then_replaced() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        *sink.keyboard_checks.borrow_mut() = vec![
+            KeyboardChange::Unchanged,
+            KeyboardChange::Unchanged,
+            KeyboardChange::Removed,
+            KeyboardChange::Removed,
+            KeyboardChange::Replaced,
+            KeyboardChange::Unchanged,
+        ];
+        typer.type_text("abc").unwrap();
+        assert_eq!(sink.presses(), vec![30, 48, 46]);
+        let events = sink.events();
+        let starts: Vec<usize> = events
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| **e == Recorded::StartEmulating)
+            .map(|(i, _)| i)
+            .collect();
+        // A new session begins right before 'c'
+        assert_eq!(starts.len(), 2);
+        assert_eq!(events[starts[1] + 1..][0], Recorded::Key(46, true));
+
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            device_failover_ms: 0,
+            ..Default::default()
+        });
+        *sink.keyboard_checks.borrow_mut() =
+            vec![KeyboardChange::Unchanged, KeyboardChange::Removed];
+        let err = typer.type_text("abc").unwrap_err();
+        assert!(
+            matches!(err, EiTypeError::InText { index: 1, ref source, .. }
+                if matches!(**source, EiTypeError::DeviceLost { chars_typed: 1 })),
+            "{:?}",
+            err
+        );
+        assert_eq!(sink.presses(), vec![30]);
+    }
+
+    #[test]
+    fn test_failed_re_press_after_resume_is_an_error() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.hold_modifier("shift").unwrap();
+        sink.resumed.set(true);
+        // The new session starts, but then the connection breaks (EPIPE)
+        sink.flush_budget.set(Some(1));
+        sink.flush_errno.set(32);
+        let err = typer.press_key("a").unwrap_err();
+        assert!(matches!(err, EiTypeError::HeldKeysLost(_)), "{:?}", err);
+        assert_eq!(typer.stats().held_key_replays, 0);
+    }
+
+    #[test]
+    fn test_press_key_confirmed() {
+        let (server, typer) = MockServer::connect();
+        typer
+            .press_key_confirmed("return", Duration::from_secs(5))
+            .unwrap();
+        let keys: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| matches!(e, ServerEvent::Key(..)))
+            .take(2)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![ServerEvent::Key(28, true), ServerEvent::Key(28, false)]
+        );
+
+        // A server that stops answering times the confirmation out, though
+        // the key was sent
+        server.ignore_syncs();
+        let started = Instant::now();
+        let err = typer
+            .press_key_confirmed("tab", Duration::from_millis(100))
+            .unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(
+            matches!(&err, EiTypeError::ConfirmationTimeout { key, timeout }
+                if key == "tab" && *timeout == Duration::from_millis(100)),
+            "{:?}",
+            err
+        );
+        let key = std::iter::from_fn(|| Some(server.next_event()))
+            .find(|e| matches!(e, ServerEvent::Key(..)));
+        assert_eq!(key, Some(ServerEvent::Key(15, true)));
+    }
+
+    #[test]
+    fn test_mock_server_missing_devices() {
+        let (_server, client) = MockServer::start_with(MockOptions {
+            grant_keyboard: false,
+            ..Default::default()
+        });
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            ignore_layout_cache: true,
+            ..Default::default()
+        };
+        let Err(err) = EiType::from_stream(client, config) else {
+            panic!("connected without a keyboard");
+        };
+        assert!(matches!(
+            err,
+            EiTypeError::NoDevice(DeviceCapability::Keyboard)
+        ));
+        assert_eq!(err.missing_capability(), Some(DeviceCapability::Keyboard));
+
+        // Only a keyboard was bound
+        let (_server, mut typer) = MockServer::connect();
+        typer.set_clipboard(MockClipboard::default());
+        let missing = |result: Result<(), EiTypeError>| result.unwrap_err().missing_capability();
+        let middle_click = PasteOptions {
+            selection: Selection::Primary,
+            trigger: PasteTrigger::MiddleClick,
+            restore_after: None,
+        };
+        assert_eq!(
+            missing(typer.paste_text("x", &middle_click)),
+            Some(DeviceCapability::Button)
+        );
+        assert_eq!(
+            missing(typer.scroll_discrete(0, 1)),
+            Some(DeviceCapability::Scroll)
+        );
+        assert_eq!(
+            missing(typer.touch_down(0, 1.0, 1.0)),
+            Some(DeviceCapability::Touch)
+        );
+        typer.type_text("ok").unwrap();
+        assert_eq!(EiTypeError::Cancelled.missing_capability(), None);
+    }
+
+    #[test]
+    fn test_mock_server_enable_capability() {
+        let (server, mut typer) = MockServer::connect();
+        assert_eq!(typer.capabilities(), vec![DeviceCapability::Keyboard]);
+        typer.hold_modifier("shift").unwrap();
+
+        typer.enable_capability(DeviceCapability::Scroll).unwrap();
+        assert_eq!(
+            typer.capabilities(),
+            vec![DeviceCapability::Keyboard, DeviceCapability::Scroll]
+        );
+        // Already bound: nothing to do
+        typer.enable_capability(DeviceCapability::Scroll).unwrap();
+        typer.scroll_discrete(0, 1).unwrap();
+        typer.release_modifiers().unwrap();
+        typer.close();
+
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| *e != ServerEvent::Frame)
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(42, true),
+                // The session restarts with the pointer device in it
+                ServerEvent::StopEmulating,
+                ServerEvent::StartEmulating,
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(42, true),
+                ServerEvent::ScrollDiscrete(0, 120),
+                ServerEvent::Key(42, false),
+                ServerEvent::StopEmulating,
+                ServerEvent::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_server_enable_capability_denied() {
+        let (_server, client) = MockServer::start_with(MockOptions {
+            grant_pointer: false,
+            ..Default::default()
+        });
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            ..Default::default()
+        };
+        let mut typer = EiType::from_stream(client, config).unwrap();
+        let err = typer
+            .enable_capability(DeviceCapability::Scroll)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            EiTypeError::CapabilityUnavailable(DeviceCapability::Scroll)
+        ));
+        assert_eq!(typer.capabilities(), vec![DeviceCapability::Keyboard]);
+        // Not bound again along with the next capability enabled
+        assert_eq!(
+            typer.requested_capabilities,
+            vec![DeviceCapability::Keyboard]
+        );
+        typer.type_text("a").unwrap();
+    }
+
+    #[test]
+    fn test_mock_server_pointer_denied_keeps_typing() {
+        let (server, client) = MockServer::start_with(MockOptions {
+            grant_pointer: false,
+            ..Default::default()
+        });
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            pointer: true,
+            ..Default::default()
+        };
+        let mut typer = EiType::from_stream(client, config).unwrap();
+        assert_eq!(typer.capabilities(), vec![DeviceCapability::Keyboard]);
+
+        let err = typer.scroll_discrete(0, 1).unwrap_err();
+        assert!(matches!(
+            err,
+            EiTypeError::CapabilityUnavailable(DeviceCapability::Scroll)
+        ));
+        assert_eq!(
+            err.to_string(),
+            "The Scroll capability was requested but not granted"
+        );
+        typer.set_clipboard(MockClipboard::default());
+        let options = PasteOptions {
+            selection: Selection::Primary,
+            trigger: PasteTrigger::MiddleClick,
+            restore_after: None,
+        };
+        assert!(matches!(
+            typer.paste_text("x", &options),
+            Err(EiTypeError::CapabilityUnavailable(_))
+        ));
+
+        typer.type_text("a").unwrap();
+        typer.close();
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| *e != ServerEvent::Frame)
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::StartEmulating,
+                ServerEvent::Key(30, true),
+                ServerEvent::Key(30, false),
+                ServerEvent::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_adaptive_throttle_backs_off_and_recovers() {
+        let mut throttle = AdaptiveThrottle::default();
+        throttle.record(false);
+        assert!(throttle.pause.is_zero());
+
+        // Pressure doubles the pause, up to the cap
+        throttle.record(true);
+        assert_eq!(throttle.pause, AdaptiveThrottle::MIN_PAUSE);
+        throttle.record(true);
+        assert_eq!(throttle.pause, AdaptiveThrottle::MIN_PAUSE * 2);
+        for _ in 0..20 {
+            throttle.record(true);
+        }
+        assert_eq!(throttle.pause, AdaptiveThrottle::MAX_PAUSE);
+
+        // The pause holds until enough clean flushes in a row, then decays to zero
+        for _ in 1..AdaptiveThrottle::CALM_FLUSHES {
+            throttle.record(false);
+        }
+        assert_eq!(throttle.pause, AdaptiveThrottle::MAX_PAUSE);
+        throttle.record(false);
+        assert_eq!(throttle.pause, AdaptiveThrottle::MAX_PAUSE / 2);
+        for _ in 0..10 {
+            throttle.record(false);
+        }
+        assert!(throttle.pause.is_zero());
+    }
+
+    #[test]
+    fn test_adaptive_throttle_paces_frames_after_full_socket() {
+        let stalled = |adaptive_throttle| {
+            let (mut typer, sink) = recording_eitype(EiTypeConfig {
+                adaptive_throttle,
+                ..Default::default()
+            });
+            typer.clock = Clock::Fake(Cell::new(0));
+            // The first two frames hit a full socket once each
+            *sink.flush_outcomes.borrow_mut() = [false, true, false].into();
+            typer.type_text("aaa").unwrap();
+            (typer.stats(), key_event_gaps(&sink))
+        };
+
+        // Each retry waits 1 ms, and every frame after the first stall is
+        // paced, by a pause that doubled with each pressured flush
+        let (stats, gaps) = stalled(true);
+        let min = AdaptiveThrottle::MIN_PAUSE;
+        assert_eq!(stats.flush_retries, 2);
+        assert_eq!(stats.throttle_events, 5);
+        assert_eq!(stats.throttle_time, min + min * 2 * 4);
+        assert_eq!(gaps, vec![1_100, 1_200, 200, 200, 200]);
+
+        // Off by default: the same stalls are retried, with no pauses
+        let (stats, gaps) = stalled(false);
+        assert_eq!(stats.flush_retries, 2);
+        assert_eq!(stats.throttle_events, 0);
+        assert_eq!(gaps, vec![1_000, 1_000, 0, 0, 0]);
+    }
+
+    /// A config for `EiType::connect` against the mock server
+    fn mock_config() -> EiTypeConfig {
+        EiTypeConfig {
+            layout: Some("us".to_string()),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_restore_token_env_is_read_without_explicit_token() {
+        let from_env = || env_restore_token(Some(" from-env\n"));
+        assert_eq!(from_env().as_deref(), Some("from-env"));
+        assert_eq!(env_restore_token(Some(" ")), None);
+        assert_eq!(env_restore_token(None), None);
+
+        assert_eq!(
+            effective_restore_token(None, from_env(), None).as_deref(),
+            Some("from-env")
+        );
+        assert_eq!(
+            effective_restore_token(Some("explicit"), from_env(), None).as_deref(),
+            Some("explicit")
+        );
+        // The environment comes before a saved token
+        let (_dir, store) = temp_token_store("app");
+        store.save("from-store").unwrap();
+        assert_eq!(
+            effective_restore_token(None, from_env(), Some(&store)).as_deref(),
+            Some("from-env")
+        );
+        assert_eq!(effective_restore_token(None, None, None), None);
+        assert_eq!(
+            effective_restore_token(None, None, Some(&store)).as_deref(),
+            Some("from-store")
+        );
+    }
+
+    /// A `TokenStore` for `namespace` under a fresh directory (removed when
+    /// it is dropped), with `state` and `cache` subdirectories
+    fn temp_token_store(namespace: &str) -> (tempfile::TempDir, TokenStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::in_dirs(
+            &dir.path().join("state"),
+            Some(&dir.path().join("cache")),
+            namespace,
+        )
+        .unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn test_token_store_namespaces_are_isolated() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, first) = temp_token_store("first-app");
+        let dir = dir.path();
+        let second =
+            TokenStore::in_dirs(&dir.join("state"), Some(&dir.join("cache")), "second_app.2")
+                .unwrap();
+        assert_eq!(
+            first.path(),
+            dir.join("state/eitype/restore-token-first-app")
+        );
+        assert_eq!(first.load(), None);
+
+        first.save("token-1").unwrap();
+        assert_eq!(first.load().as_deref(), Some("token-1"));
+        assert_eq!(second.load(), None);
+        let mode = std::fs::metadata(first.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Each namespace's token is replaced and cleared on its own
+        second.save("token-2").unwrap();
+        first.save("token-3").unwrap();
+        assert_eq!(second.load().as_deref(), Some("token-2"));
+        first.clear().unwrap();
+        assert_eq!(first.load(), None);
+        assert_eq!(second.load().as_deref(), Some("token-2"));
+        // Clearing what isn't there is fine
+        first.clear().unwrap();
+    }
+
+    #[test]
+    fn test_token_store_migrates_legacy_file() {
+        let (dir, store) = temp_token_store(LEGACY_TOKEN_NAMESPACE);
+        let legacy = dir.path().join("cache/eitype/restore_token");
+        std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        std::fs::write(&legacy, "old-token\n").unwrap();
+
+        // Other namespaces leave the old file alone
+        let (other_dir, other) = temp_token_store("other");
+        let other_legacy = other_dir.path().join("cache/eitype/restore_token");
+        std::fs::create_dir_all(other_legacy.parent().unwrap()).unwrap();
+        std::fs::copy(&legacy, &other_legacy).unwrap();
+        assert_eq!(other.load(), None);
+        assert!(other_legacy.exists());
+
+        // The CLI's namespace takes it over, once
+        assert_eq!(store.load().as_deref(), Some("old-token"));
+        assert!(!legacy.exists());
+        assert_eq!(std::fs::read_to_string(store.path()).unwrap(), "old-token");
+        assert_eq!(store.load().as_deref(), Some("old-token"));
+
+        // A saved token wins over an old file that turns up again, and
+        // clearing removes both
+        store.save("new-token").unwrap();
+        std::fs::write(&legacy, "old-token").unwrap();
+        assert_eq!(store.load().as_deref(), Some("new-token"));
+        store.clear().unwrap();
+        assert!(!legacy.exists());
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_token_namespace_validation() {
+        let config = |namespace: &str| EiTypeConfig {
+            token_namespace: Some(namespace.to_string()),
+            ..Default::default()
+        };
+        assert!(config("my-app.v2_1").validate().is_ok());
+        for bad in ["", "../app", "a/b", "my app", "ä"] {
+            assert!(
+                matches!(config(bad).validate(), Err(EiTypeError::InvalidConfig(_))),
+                "{:?}",
+                bad
+            );
+            assert!(TokenStore::in_dirs(Path::new("/state"), None, bad).is_err());
+        }
+        assert!(validate_token_namespace(&default_token_namespace()).is_ok());
+        assert!(EiTypeConfig::default().token_store().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_connect_then_wait_for_keyboard() {
+        let (server, client) = MockServer::start_with(MockOptions {
+            resume_on_bind: false,
+            ..Default::default()
+        });
+        let mut pending = EiType::connect(client, mock_config()).unwrap();
+
+        // Handshake done, but the keyboard isn't resumed yet
+        let deadline = Instant::now() + Duration::from_millis(200);
+        while Instant::now() < deadline {
+            assert!(!pending.dispatch().unwrap());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!pending.has_keyboard());
+        assert!(pending.capabilities().is_empty());
+
+        server.resume_devices();
+        let mut typer = pending
+            .wait_for_keyboard(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(typer.capabilities(), [DeviceCapability::Keyboard]);
+        typer.type_text("a").unwrap();
+        typer.close();
+        let presses: Vec<ServerEvent> = server
+            .events
+            .try_iter()
+            .filter(|e| matches!(e, ServerEvent::Key(_, true)))
+            .collect();
+        assert_eq!(presses, [ServerEvent::Key(30, true)]);
+    }
+
+    #[test]
+    fn test_wait_for_keyboard_times_out() {
+        let (_server, client) = MockServer::start_with(MockOptions {
+            resume_on_bind: false,
+            ..Default::default()
+        });
+        let pending = EiType::connect(client, mock_config()).unwrap();
+        let started = Instant::now();
+        let result = pending.wait_for_keyboard(Some(Duration::from_millis(100)));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let Err(EiTypeError::Connection(message)) = result else {
+            panic!("expected a timeout");
+        };
+        assert!(
+            message.contains("No keyboard device was offered"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_wait_for_keyboard_server_gone() {
+        let (server, client) = MockServer::start_with(MockOptions {
+            resume_on_bind: false,
+            ..Default::default()
+        });
+        let pending = EiType::connect(client, mock_config()).unwrap();
+        server.disconnect_client();
+        assert!(pending.wait_for_keyboard(None).is_err());
+    }
+
+    /// Calibrate on the fake clock against a server that reads a keyboard
+    /// frame per `drain_gap` microseconds (or keeps up with anything)
+    fn calibrate_against(drain_gap: Option<u64>) -> Calibration {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            delay_ms: 3,
+            ..Default::default()
+        });
+        typer.clock = Clock::Fake(Cell::new(0));
+        sink.drain_gap.set(drain_gap);
+        let calibration = typer
+            .calibrate(&CalibrateOptions {
+                probe: "abcdefghij".to_string(),
+                erase: true,
+                delays_ms: vec![4, 2, 1, 0],
+            })
+            .unwrap();
+        assert_eq!(typer.delay, Duration::from_millis(3));
+        typer.close();
+        calibration
+    }
+
+    #[test]
+    fn test_bench_corpus_and_percentiles() {
+        let corpus = bench_corpus(2000);
+        assert_eq!(corpus.chars().count(), 2000);
+        assert!(corpus.starts_with("The quick brown fox jumps over the lazy dog. Pack my box"));
+        assert!(corpus.chars().all(|ch| ch.is_ascii() && ch != '\n'));
+
+        let millis = |ms| Duration::from_millis(ms);
+        let latency = LatencyPercentiles::of((1..=100).rev().map(millis).collect());
+        assert_eq!(
+            latency,
+            LatencyPercentiles {
+                p50: millis(50),
+                p90: millis(90),
+                p99: millis(99),
+                max: millis(100),
+            }
+        );
+        assert_eq!(
+            LatencyPercentiles::of(Vec::new()),
+            LatencyPercentiles::default()
+        );
+    }
+
+    #[test]
+    fn test_bench_types_and_erases_corpus() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let report = typer
+            .bench(&BenchOptions {
+                chars: 30,
+                erase: true,
+            })
+            .unwrap();
+        assert_eq!(report.chars, 30);
+        assert_eq!(typer.stats().chars_typed, 30);
+        let backspaces = sink.presses().iter().filter(|&&kc| kc == 14).count();
+        assert_eq!(backspaces, 30);
+        // The bench's own trace doesn't end up in `timings`
+        assert!(typer.timings().is_empty());
+    }
+
+    #[test]
+    fn test_bench_null() {
+        let mut typer = EiType::null(EiTypeConfig {
+            layout: Some("us".to_string()),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let report = typer.bench(&BenchOptions::default()).unwrap();
+        assert_eq!(report.chars, 2000);
+        assert_eq!(report.sampled, 2000);
+        assert!(report.chars_per_sec > 0.0);
+        assert_eq!(report.flush_retries, 0);
+        for latency in [report.plan, report.flush, report.gap] {
+            assert!(latency.p50 <= latency.p90);
+            assert!(latency.p90 <= latency.p99);
+            assert!(latency.p99 <= latency.max);
+        }
+        assert!(typer.scroll_discrete(0, 1).is_err());
+        typer.close();
+    }
+
+    #[test]
+    fn test_bench_counts_chars_past_trace_capacity() {
+        let mut typer = EiType::null(EiTypeConfig {
+            layout: Some("us".to_string()),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let chars = TimingTrace::CAPACITY + 10;
+        let report = typer
+            .bench(&BenchOptions {
+                chars,
+                erase: false,
+            })
+            .unwrap();
+        // The rate is over every character; percentiles over the trace
+        assert_eq!(report.chars, chars);
+        assert_eq!(report.sampled, TimingTrace::CAPACITY);
+        let rate = chars as f64 / report.elapsed.as_secs_f64();
+        assert!((report.chars_per_sec - rate).abs() < 1e-6 * rate);
+        typer.close();
+    }
+
+    #[test]
+    fn test_calibrate_recommendation_tracks_server_drain_rate() {
+        let fast = calibrate_against(None);
+        let slow = calibrate_against(Some(1_500));
+
+        // A server that drains as fast as it's fed keeps up at every rate
+        assert!(fast.steps.iter().all(|step| step.kept_up), "{:#?}", fast);
+        assert_eq!(fast.steps.len(), 4);
+        assert_eq!(fast.recommended_delay_ms, Some(0));
+
+        // A slow one falls behind at 1 ms a key event, and calibration stops
+        // there; the 20 characters of probe and erasure are 40 key events,
+        // 80 ms at 2 ms
+        let delays: Vec<u64> = slow.steps.iter().map(|step| step.delay_ms).collect();
+        assert_eq!(delays, [4, 2, 1]);
+        let last = slow.steps.last().unwrap();
+        assert!(!last.kept_up, "{:#?}", slow);
+        assert!(last.flush_retries > 0);
+        assert_eq!(slow.recommended_delay_ms, Some(2));
+        assert_eq!(slow.recommended_chars_per_sec, Some(250.0));
+        assert!(slow.ceiling_chars_per_sec > 250.0, "{:#?}", slow);
+    }
+
+    #[test]
+    fn test_calibration_from_steps() {
+        let step = |delay_ms, chars_per_sec, kept_up: bool| CalibrationStep {
+            delay_ms,
+            chars_per_sec,
+            flush_retries: usize::from(!kept_up),
+            throttle_events: 0,
+            kept_up,
+        };
+        let calibration = Calibration::from_steps(vec![
+            step(4, 100.0, true),
+            step(2, 180.0, true),
+            step(1, 250.0, false),
+        ]);
+        assert_eq!(calibration.recommended_delay_ms, Some(2));
+        assert_eq!(calibration.recommended_chars_per_sec, Some(180.0));
+        assert_eq!(calibration.ceiling_chars_per_sec, 250.0);
+
+        let calibration = Calibration::from_steps(vec![step(4, 90.0, false)]);
+        assert_eq!(calibration.recommended_delay_ms, None);
+        assert_eq!(calibration.recommended_chars_per_sec, None);
+        assert_eq!(calibration.ceiling_chars_per_sec, 90.0);
+    }
+
+    #[test]
+    fn test_close_waits_for_slow_server() {
+        let (server, client) = MockServer::start_with(MockOptions {
+            read_pause: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            ..Default::default()
+        };
+        let mut typer = EiType::from_stream(client, config).unwrap();
+        typer.type_text("hello").unwrap();
+        typer.close();
+
+        // Everything typed was processed before close returned
+        let presses = server
+            .events
+            .try_iter()
+            .filter(|e| matches!(e, ServerEvent::Key(_, true)))
+            .count();
+        assert_eq!(presses, 5);
+    }
+
+    #[test]
+    fn test_close_syncs_before_disconnect() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.hold_modifier("shift").unwrap();
+        typer.close();
+        let events = sink.events();
+        let tail = &events[events.len() - 6..];
+        assert_eq!(
+            tail,
+            [
+                Recorded::Key(42, false),
+                Recorded::Frame,
+                Recorded::StopEmulating,
+                Recorded::UnsentBytes,
+                Recorded::Sync,
+                Recorded::Disconnect,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_close_drains_socket_before_disconnect() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text("hi").unwrap();
+        *sink.unsent.borrow_mut() = vec![300, 120, 0];
+        typer.close();
+
+        // Polled until the server had read everything, then disconnected
+        let events = sink.events();
+        let polls: Vec<usize> = events
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| **e == Recorded::UnsentBytes)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(polls.len(), 3);
+        let disconnect = events.iter().position(|e| *e == Recorded::Disconnect);
+        assert!(disconnect > polls.last().copied());
+        assert_eq!(key_events(&sink).len(), 4);
+    }
+
+    #[test]
+    fn test_drain_gives_up_after_timeout() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        *sink.unsent.borrow_mut() = vec![10];
+        let started = Instant::now();
+        assert!(!typer.drain(Duration::from_millis(20)).unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        *sink.unsent.borrow_mut() = vec![10, 0];
+        assert!(typer.drain(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_close_waits_share_one_timeout() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text("a").unwrap();
+        // A server that never reads uses up the whole wait, leaving none to
+        // sync in
+        *sink.unsent.borrow_mut() = vec![10];
+        assert!(!typer.settle_input(Duration::from_millis(20)).unwrap());
+        assert!(!sink.events().contains(&Recorded::Sync));
+
+        *sink.unsent.borrow_mut() = vec![0];
+        assert!(typer.settle_input(Duration::from_millis(20)).unwrap());
+        assert_eq!(sink.events().last(), Some(&Recorded::Sync));
+    }
+
+    #[test]
+    fn test_mock_server_binds_configured_capabilities() {
+        let (_server, typer) = MockServer::connect_with(EiTypeConfig {
+            capabilities: Some(vec![DeviceCapability::Keyboard, DeviceCapability::Pointer]),
+            ..Default::default()
+        });
+        // Button and scroll were not asked for, so they weren't bound
+        assert_eq!(
+            typer.capabilities(),
+            vec![DeviceCapability::Keyboard, DeviceCapability::Pointer]
+        );
+        assert!(matches!(
+            typer.scroll_discrete(0, 1).unwrap_err(),
+            EiTypeError::NoDevice(DeviceCapability::Scroll)
+        ));
+        typer.type_text("ok").unwrap();
+    }
+
+    /// Portal that answers with `answer` and records what it was asked for
+    struct MockPortal {
+        answer: fn() -> Result<Option<String>, EiTypeError>,
+        asked: RefCell<Vec<(Option<String>, Vec<DeviceCapability>)>>,
+    }
+
+    impl MockPortal {
+        fn new(answer: fn() -> Result<Option<String>, EiTypeError>) -> Self {
+            Self {
+                answer,
+                asked: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl AuthorizationPortal for MockPortal {
+        fn authorize(
+            &self,
+            restore_token: Option<&str>,
+            capabilities: &[DeviceCapability],
+        ) -> Result<Option<String>, EiTypeError> {
+            self.asked
+                .borrow_mut()
+                .push((restore_token.map(str::to_string), capabilities.to_vec()));
+            (self.answer)()
+        }
+    }
+
+    #[test]
+    fn test_request_permission_returns_token() {
+        let portal = MockPortal::new(|| Ok(Some("token-1".to_string())));
+        let config = EiTypeConfig {
+            pointer: true,
+            ..Default::default()
+        };
+        let token = request_permission_with(&portal, &config, None).unwrap();
+        assert_eq!(token.as_deref(), Some("token-1"));
+        // A fresh authorization for exactly the devices connecting would use
+        assert_eq!(
+            *portal.asked.borrow(),
+            vec![(None, config.requested_capabilities())]
+        );
+    }
+
+    #[test]
+    fn test_request_permission_saves_token_in_namespace() {
+        let portal = MockPortal::new(|| Ok(Some("token-1".to_string())));
+        let (_dir, store) = temp_token_store("setup");
+        request_permission_with(&portal, &EiTypeConfig::default(), Some(&store)).unwrap();
+        assert_eq!(store.load().as_deref(), Some("token-1"));
+
+        // Asking again renews the saved grant; granted without a token, the
+        // saved one is left as it was
+        let portal = MockPortal::new(|| Ok(None));
+        request_permission_with(&portal, &EiTypeConfig::default(), Some(&store)).unwrap();
+        assert_eq!(portal.asked.borrow()[0].0.as_deref(), Some("token-1"));
+        assert_eq!(store.load().as_deref(), Some("token-1"));
+    }
+
+    #[test]
+    fn test_request_permission_granted_without_token() {
+        let portal = MockPortal::new(|| Ok(None));
+        let token = request_permission_with(&portal, &EiTypeConfig::default(), None).unwrap();
+        assert_eq!(token, None);
+    }
+
+    #[test]
+    fn test_request_permission_denied() {
+        let portal = MockPortal::new(|| {
+            Err(portal_error(
+                "Failed to get session response",
+                ashpd::Error::Response(ashpd::desktop::ResponseError::Cancelled),
+            ))
+        });
+        let err = request_permission_with(&portal, &EiTypeConfig::default(), None).unwrap_err();
+        assert!(matches!(err, EiTypeError::AuthorizationDenied));
+
+        // Other answers are refusals; only failing to reach the portal is
+        // a connection error
+        let err = portal_error(
+            "Failed to start session",
+            ashpd::Error::Response(ashpd::desktop::ResponseError::Other),
+        );
+        assert!(matches!(err, EiTypeError::PortalRefused(_)), "{}", err);
+        let err = portal_error(
+            "Failed to select devices",
+            ashpd::Error::Portal(ashpd::PortalError::InvalidArgument("types".to_string())),
+        );
+        assert!(matches!(err, EiTypeError::PortalRefused(_)), "{}", err);
+        let err = portal_error("Failed to create session", ashpd::Error::NoResponse);
+        assert!(matches!(err, EiTypeError::Connection(_)), "{}", err);
+        assert!(
+            err.to_string().contains("Failed to create session"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_portal_retries_transient_failures() {
+        let config = EiTypeConfig {
+            connect_retries: 3,
+            connect_retry_delay_ms: 1,
+            ..Default::default()
+        };
+        let attempts = Cell::new(0);
+        let result = with_portal_retries(&config, || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(EiTypeError::Connection(
+                    "Failed to create session".to_string(),
+                ))
+            } else {
+                Ok("token")
+            }
+        });
+        assert_eq!(result.unwrap(), "token");
+        assert_eq!(attempts.get(), 3);
+
+        // Out of retries: the last error is returned
+        attempts.set(0);
+        let result: Result<(), _> = with_portal_retries(&config, || {
+            attempts.set(attempts.get() + 1);
+            Err(EiTypeError::Connection(
+                "Failed to start session".to_string(),
+            ))
+        });
+        assert!(matches!(result, Err(EiTypeError::Connection(_))));
+        assert_eq!(attempts.get(), 4);
+
+        // No retries by default
+        attempts.set(0);
+        let result: Result<(), _> = with_portal_retries(&EiTypeConfig::default(), || {
+            attempts.set(attempts.get() + 1);
+            Err(EiTypeError::Connection(
+                "Failed to start session".to_string(),
+            ))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_portal_denial_is_not_retried() {
+        let config = EiTypeConfig {
+            connect_retries: 3,
+            connect_retry_delay_ms: 1,
+            ..Default::default()
+        };
+        let portal = MockPortal::new(|| Err(EiTypeError::AuthorizationDenied));
+        let err = request_permission_with(&portal, &config, None).unwrap_err();
+        assert!(matches!(err, EiTypeError::AuthorizationDenied));
+        assert_eq!(portal.asked.borrow().len(), 1);
+
+        let portal = MockPortal::new(|| {
+            Err(EiTypeError::PortalRefused(
+                "Failed to start session".to_string(),
+            ))
+        });
+        let err = request_permission_with(&portal, &config, None).unwrap_err();
+        assert!(matches!(err, EiTypeError::PortalRefused(_)));
+        assert_eq!(portal.asked.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_portal_device_types() {
+        use ashpd::desktop::remote_desktop::DeviceType;
+
+        assert_eq!(
+            portal_device_types(&[DeviceCapability::Keyboard]),
+            DeviceType::Keyboard
+        );
+        assert_eq!(
+            portal_device_types(&[DeviceCapability::Keyboard, DeviceCapability::Scroll]),
+            DeviceType::Keyboard | DeviceType::Pointer
+        );
+        assert_eq!(
+            portal_device_types(&[DeviceCapability::Touch]),
+            DeviceType::Keyboard | DeviceType::Touchscreen
+        );
+    }
+
+    #[test]
+    fn test_requested_capabilities() {
+        let config = EiTypeConfig::default();
+        assert_eq!(
+            config.requested_capabilities(),
+            vec![DeviceCapability::Keyboard]
+        );
+        let config = EiTypeConfig {
+            pointer: true,
+            ..Default::default()
+        };
+        assert_eq!(config.requested_capabilities().len(), 4);
+        let config = EiTypeConfig {
+            pointer: true,
+            capabilities: Some(vec![DeviceCapability::Touch, DeviceCapability::Keyboard]),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.requested_capabilities(),
+            vec![DeviceCapability::Keyboard, DeviceCapability::Touch]
+        );
+    }
+
+    #[test]
+    fn test_mock_server_middle_click_paste() {
+        let (server, mut typer) = MockServer::connect_with(EiTypeConfig {
+            pointer: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            typer.capabilities(),
+            vec![
+                DeviceCapability::Keyboard,
+                DeviceCapability::Button,
+                DeviceCapability::Scroll,
+                DeviceCapability::Pointer,
+            ]
+        );
+        typer.set_clipboard(MockClipboard::default());
+        let options = PasteOptions {
+            selection: Selection::Primary,
+            trigger: PasteTrigger::MiddleClick,
+            restore_after: None,
+        };
+        typer.paste_text("x", &options).unwrap();
+        typer.close();
+
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| *e != ServerEvent::Frame)
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                // Keyboard and pointer are separate devices, both emulating
+                ServerEvent::StartEmulating,
+                ServerEvent::StartEmulating,
+                ServerEvent::Button(BTN_MIDDLE, true),
+                ServerEvent::Button(BTN_MIDDLE, false),
+                ServerEvent::StopEmulating,
+                ServerEvent::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_server_scroll() {
+        let (server, mut typer) = MockServer::connect_with(EiTypeConfig {
+            pointer: true,
+            ..Default::default()
+        });
+        typer.scroll_discrete(0, -2).unwrap();
+        typer.scroll_smooth(1.5, 0.0).unwrap();
+        typer.close();
+
+        let events: Vec<ServerEvent> = std::iter::from_fn(|| Some(server.next_event()))
+            .filter(|e| {
+                matches!(
+                    e,
+                    ServerEvent::ScrollDiscrete(..) | ServerEvent::ScrollSmooth(..)
+                )
+            })
+            .take(2)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ServerEvent::ScrollDiscrete(0, -240),
+                ServerEvent::ScrollSmooth(1.5, 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_threaded_eitype_from_multiple_threads() {
+        let (server, client) = MockServer::start();
+        let typer = Arc::new(
+            ThreadedEiType::spawn(move || {
+                let config = EiTypeConfig {
+                    layout: Some("us".to_string()),
+                    layout_index: Some(0),
+                    ignore_layout_cache: true,
+                    ..Default::default()
+                };
+                EiType::from_stream(client, config)
+            })
+            .unwrap(),
+        );
+
+        let words = ["aaaa", "bbbb", "cccc", "dddd"];
+        let handles: Vec<_> = words
+            .into_iter()
+            .map(|word| {
+                let typer = Arc::clone(&typer);
+                std::thread::spawn(move || typer.type_text(word).unwrap())
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        typer.press_key("Return").unwrap();
+        drop(Arc::into_inner(typer).unwrap());
+
+        let presses: Vec<u32> = std::iter::from_fn(|| Some(server.next_event()))
+            .take_while(|e| *e != ServerEvent::Disconnect)
+            .filter_map(|e| match e {
+                ServerEvent::Key(kc, true) => Some(kc),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(presses.len(), 17);
+        assert_eq!(presses[16], 28);
+        // Each call's keys arrive together, whatever order the threads ran in
+        let mut runs: Vec<u32> = presses[..16].chunks(4).map(|c| c[0]).collect();
+        assert!(presses[..16]
+            .chunks(4)
+            .all(|c| c.iter().all(|&kc| kc == c[0])));
+        runs.sort();
+        assert_eq!(runs, vec![30, 32, 46, 48]);
+    }
+
+    #[test]
+    fn test_threaded_eitype_cancel_and_busy() {
+        let (_server, client) = MockServer::start();
+        let typer = Arc::new(
+            ThreadedEiType::spawn(move || {
+                let config = EiTypeConfig {
+                    layout: Some("us".to_string()),
+                    layout_index: Some(0),
+                    ignore_layout_cache: true,
+                    delay_ms: 10,
+                    ..Default::default()
+                };
+                EiType::from_stream(client, config)
+            })
+            .unwrap(),
+        );
+
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let worker = {
+            let typer = Arc::clone(&typer);
+            std::thread::spawn(move || {
+                typer.with(move |eitype| {
+                    started_tx.send(()).unwrap();
+                    eitype.type_text(&"a".repeat(500))
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+        assert!(matches!(
+            typer.type_text_if_idle("b"),
+            Err(EiTypeError::Busy)
+        ));
+        // Cancelling doesn't wait behind the running call
+        typer.cancel();
+        let err = worker.join().unwrap().unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::Cancelled), "{:?}", err);
+        typer.type_text_if_idle("b").unwrap();
+        // With nothing running, the next call isn't cancelled
+        typer.cancel();
+        typer.type_text("c").unwrap();
+    }
+
+    #[test]
+    fn test_threaded_eitype_reports_connect_error() {
+        let result =
+            ThreadedEiType::spawn(|| Err(EiTypeError::NoDevice(DeviceCapability::Keyboard)));
+        assert!(matches!(
+            result,
+            Err(EiTypeError::NoDevice(DeviceCapability::Keyboard))
+        ));
+    }
+
+    #[test]
+    fn test_threaded_eitype_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ThreadedEiType>();
+    }
+
+    #[test]
+    fn test_dispatch_pending_does_not_block() {
+        let (_server, typer) = MockServer::connect();
+        let start = Instant::now();
+        for _ in 0..10 {
+            typer.dispatch_pending().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(!typer.needs_flush());
+    }
+
+    /// Dispatch server events until `status` has something, for up to 5s
+    fn next_status(
+        typer: &EiType,
+        status: &std::sync::mpsc::Receiver<StatusEvent>,
+    ) -> Result<StatusEvent, EiTypeError> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Ok(event) = status.try_recv() {
+                return Ok(event);
+            }
+            assert!(Instant::now() < deadline, "no status event");
+            typer.dispatch_pending()?;
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_status_receiver_reports_connection_and_pause() {
+        let (server, typer) = MockServer::connect();
+        let status = typer.status_receiver();
+        assert_eq!(status.try_recv(), Ok(StatusEvent::Connected));
+
+        server.pause_devices();
+        assert_eq!(
+            next_status(&typer, &status).unwrap(),
+            StatusEvent::DevicePaused {
+                device: "keyboard".to_string()
+            }
+        );
+
+        // Every receiver sees the disconnect
+        let late = typer.status_receiver();
+        assert_eq!(late.try_recv(), Ok(StatusEvent::Connected));
+        server.disconnect_client();
+        assert!(next_status(&typer, &status).is_err());
+        let removed = StatusEvent::DeviceRemoved {
+            device: "keyboard".to_string(),
+        };
+        let disconnected = StatusEvent::Disconnected {
+            reason: "Disconnected: test".to_string(),
+        };
+        let expected = vec![removed, disconnected];
+        assert_eq!(status.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(late.try_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_dispatch_pending_reports_disconnect() {
+        let (server, typer) = MockServer::connect();
+        server.disconnect_client();
+
+        // The fd becomes readable once the server's message arrives
+        let mut pollfd = [rustix::event::PollFd::new(
+            &typer,
+            rustix::event::PollFlags::IN,
+        )];
+        let timeout = rustix::time::Timespec {
+            tv_sec: 5,
+            tv_nsec: 0,
+        };
+        assert_eq!(rustix::event::poll(&mut pollfd, Some(&timeout)).unwrap(), 1);
+        let err = typer.dispatch_pending().unwrap_err();
+        assert!(matches!(err, EiTypeError::Disconnected { .. }));
+    }
+
+    #[test]
+    fn test_disconnect_reason_is_surfaced() {
+        // While connecting
+        let (_server, client) = MockServer::start_with(MockOptions {
+            disconnect_on_bind: Some((DisconnectReason::Protocol, "unexpected request")),
+            ..Default::default()
+        });
+        let Err(err) = EiType::from_stream(client, mock_config()) else {
+            panic!("connected despite the disconnect");
+        };
+        let EiTypeError::Disconnected {
+            reason,
+            explanation,
+        } = &err
+        else {
+            panic!("expected Disconnected, got {:?}", err);
+        };
+        assert_eq!(reason, "protocol");
+        assert_eq!(explanation, "unexpected request");
+        assert_eq!(
+            err.to_string(),
+            "Disconnected by the EI server (protocol): unexpected request"
+        );
+
+        // While typing, and on every call after it
+        let (server, typer) = MockServer::connect();
+        server.disconnect_client_with(DisconnectReason::Disconnected, "session closed");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let err = loop {
+            match typer.type_text("a") {
+                Ok(()) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Ok(()) => panic!("the disconnect never arrived"),
+                Err(err) => break err,
+            }
+        };
+        let expected = |err: &EiTypeError| {
+            matches!(
+                err.root(),
+                EiTypeError::Disconnected { reason, explanation }
+                    if reason == "disconnected" && explanation == "session closed"
+            )
+        };
+        assert!(expected(&err), "{:?}", err);
+        assert!(expected(&typer.sync().unwrap_err()));
+    }
+
+    /// An `EiType` on a US keymap that records into the returned sink.
+    fn recording_eitype(config: EiTypeConfig) -> (EiType, RecordingSink) {
+        let sink = RecordingSink::default();
+        let config = EiTypeConfig {
+            layout: config.layout.clone().or(Some("us".to_string())),
+            ..config
+        };
+        let keymap = load_keymap(&config, None).unwrap();
+        let eitype = EiType::with_sink(Box::new(sink.clone()), keymap, 0, &config).unwrap();
+        (eitype, sink)
+    }
+
+    /// Clipboard that records what was set, or fails if `fail` is true
+    #[derive(Clone, Default)]
+    struct MockClipboard {
+        selections: std::rc::Rc<RefCell<HashMap<Selection, String>>>,
+        sets: std::rc::Rc<RefCell<Vec<(Selection, String)>>>,
+        fail: bool,
+    }
+
+    impl MockClipboard {
+        fn with(selection: Selection, text: &str) -> Self {
+            let clipboard = Self::default();
+            clipboard
+                .selections
+                .borrow_mut()
+                .insert(selection, text.to_string());
+            clipboard
+        }
+
+        fn sets(&self) -> Vec<(Selection, String)> {
+            self.sets.borrow().clone()
+        }
+
+        fn current(&self, selection: Selection) -> Option<String> {
+            self.selections.borrow().get(&selection).cloned()
+        }
+    }
+
+    impl Clipboard for MockClipboard {
+        fn get_text(&self, selection: Selection) -> Result<Option<String>, EiTypeError> {
+            if self.fail {
+                return Err(EiTypeError::Clipboard("unavailable".to_string()));
+            }
+            Ok(self.current(selection))
+        }
+
+        fn set_text(&self, selection: Selection, text: &str) -> Result<(), EiTypeError> {
+            if self.fail {
+                return Err(EiTypeError::Clipboard("unavailable".to_string()));
+            }
+            self.selections
+                .borrow_mut()
+                .insert(selection, text.to_string());
+            self.sets.borrow_mut().push((selection, text.to_string()));
+            Ok(())
+        }
+    }
+
+    fn paste_eitype(clipboard: &MockClipboard) -> (EiType, RecordingSink) {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            paste_threshold: Some(3),
+            ..Default::default()
+        });
+        typer.set_clipboard(clipboard.clone());
+        (typer, sink)
+    }
+
+    #[test]
+    fn test_insert_text_pastes_long_text() {
+        let clipboard = MockClipboard::default();
+        let (typer, sink) = paste_eitype(&clipboard);
+        typer.insert_text("abcd").unwrap();
+        assert_eq!(
+            clipboard.sets(),
+            vec![(Selection::Clipboard, "abcd".to_string())]
+        );
+        assert_eq!(
+            key_events(&sink),
+            vec![(29, true), (47, true), (47, false), (29, false)]
+        );
+    }
+
+    #[test]
+    fn test_insert_text_types_short_text() {
+        let clipboard = MockClipboard::default();
+        let (typer, sink) = paste_eitype(&clipboard);
+        typer.insert_text("abc").unwrap();
+        assert!(clipboard.sets().is_empty());
+        assert!(!sink.presses().contains(&29));
+    }
+
+    #[test]
+    fn test_insert_text_types_when_clipboard_unavailable() {
+        let clipboard = MockClipboard {
+            fail: true,
+            ..Default::default()
+        };
+        let (mut typer, sink) = paste_eitype(&clipboard);
+        typer.insert_text("abcd").unwrap();
+        assert_eq!(sink.presses(), vec![30, 48, 46, 32]);
+
+        typer.clear_clipboard();
+        typer.insert_text("dcba").unwrap();
+        assert_eq!(sink.presses(), vec![30, 48, 46, 32, 32, 46, 48, 30]);
+    }
+
+    #[test]
+    fn test_insert_text_paste_uses_layout_v_key() {
+        let clipboard = MockClipboard::default();
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us".to_string()),
+            variant: Some("dvorak".to_string()),
+            paste_threshold: Some(0),
+            ..Default::default()
+        });
+        typer.set_clipboard(clipboard);
+        typer.insert_text("x").unwrap();
+        // On Dvorak, "v" is on the QWERTY "." key
+        assert_eq!(sink.presses(), vec![29, 52]);
+    }
+
+    /// Key and button events from pasting "x" with `options`
+    fn paste_events(options: PasteOptions) -> Vec<Recorded> {
+        let clipboard = MockClipboard::default();
+        let (typer, sink) = paste_eitype(&clipboard);
+        typer.paste_text("x", &options).unwrap();
+        assert_eq!(clipboard.current(options.selection), Some("x".to_string()));
+        sink.events()
+            .into_iter()
+            .filter(|e| matches!(e, Recorded::Key(..) | Recorded::Button(..)))
+            .collect()
+    }
+
+    #[test]
+    fn test_paste_text_triggers() {
+        use Recorded::{Button, Key};
+        let cases = [
+            (
+                PasteTrigger::CtrlV,
+                vec![Key(29, true), Key(47, true), Key(47, false), Key(29, false)],
+            ),
+            (
+                PasteTrigger::CtrlShiftV,
+                vec![
+                    Key(29, true),
+                    Key(42, true),
+                    Key(47, true),
+                    Key(47, false),
+                    Key(42, false),
+                    Key(29, false),
+                ],
+            ),
+            (
+                PasteTrigger::ShiftInsert,
+                vec![
+                    Key(42, true),
+                    Key(110, true),
+                    Key(110, false),
+                    Key(42, false),
+                ],
+            ),
+            (
+                PasteTrigger::MiddleClick,
+                vec![Button(BTN_MIDDLE, true), Button(BTN_MIDDLE, false)],
+            ),
+        ];
+        for selection in [Selection::Clipboard, Selection::Primary] {
+            for (trigger, expected) in &cases {
+                let options = PasteOptions {
+                    selection,
+                    trigger: *trigger,
+                    restore_after: None,
+                };
+                assert_eq!(paste_events(options), *expected, "{:?}", options);
+            }
+        }
+    }
+
+    #[test]
+    fn test_paste_text_restores_the_modified_selection() {
+        let clipboard = MockClipboard::with(Selection::Primary, "selected");
+        clipboard
+            .selections
+            .borrow_mut()
+            .insert(Selection::Clipboard, "copied".to_string());
+        let (typer, _sink) = paste_eitype(&clipboard);
+
+        let options = PasteOptions {
+            selection: Selection::Primary,
+            trigger: PasteTrigger::MiddleClick,
+            restore_after: Some(Duration::from_millis(1)),
+        };
+        typer.paste_text("pasted", &options).unwrap();
+        assert_eq!(
+            clipboard.sets(),
+            vec![
+                (Selection::Primary, "pasted".to_string()),
+                (Selection::Primary, "selected".to_string()),
+            ]
+        );
+        assert_eq!(
+            clipboard.current(Selection::Clipboard),
+            Some("copied".to_string())
+        );
+    }
+
+    #[test]
+    fn test_paste_text_without_restore_leaves_text() {
+        let clipboard = MockClipboard::with(Selection::Clipboard, "old");
+        let (typer, _sink) = paste_eitype(&clipboard);
+        typer.paste_text("new", &PasteOptions::default()).unwrap();
+        assert_eq!(
+            clipboard.current(Selection::Clipboard),
+            Some("new".to_string())
+        );
+    }
+
+    #[test]
+    fn test_paste_text_middle_click_needs_pointer() {
+        let clipboard = MockClipboard::with(Selection::Primary, "selected");
+        let (typer, sink) = paste_eitype(&clipboard);
+        sink.pointer.set(false);
+        let options = PasteOptions {
+            selection: Selection::Primary,
+            trigger: PasteTrigger::MiddleClick,
+            restore_after: None,
+        };
+        let err = typer.paste_text("x", &options).unwrap_err();
+        assert!(matches!(
+            err,
+            EiTypeError::NoDevice(DeviceCapability::Button)
+        ));
+        // The selection is left alone
+        assert!(clipboard.sets().is_empty());
+    }
+
+    #[test]
+    fn test_scroll_discrete_emits_notches() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.scroll_discrete(1, -3).unwrap();
+        assert_eq!(
+            sink.events(),
+            vec![
+                Recorded::StartEmulating,
+                Recorded::ScrollDiscrete(120, -360),
+                Recorded::Frame,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scroll_smooth_emits_deltas() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.scroll_smooth(0.0, 12.5).unwrap();
+        assert_eq!(
+            sink.events(),
+            vec![
+                Recorded::StartEmulating,
+                Recorded::ScrollSmooth(0.0, 12.5),
+                Recorded::Frame,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scroll_needs_pointer() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.pointer.set(false);
+        assert!(matches!(
+            typer.scroll_discrete(0, 1),
+            Err(EiTypeError::NoDevice(DeviceCapability::Scroll))
+        ));
+        assert!(matches!(
+            typer.scroll_smooth(0.0, 1.0),
+            Err(EiTypeError::NoDevice(DeviceCapability::Scroll))
+        ));
+    }
+
+    #[test]
+    fn test_touch_emits_slots_and_coordinates() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.touch.set(true);
+        sink.events.borrow_mut().clear();
+        typer.touch_down(0, 10.0, 20.5).unwrap();
+        typer.touch_down(1, 300.0, 40.0).unwrap();
+        typer.touch_motion(0, 15.0, 25.0).unwrap();
+        typer.touch_up(1).unwrap();
+        typer.touch_up(0).unwrap();
+        assert_eq!(
+            sink.events(),
+            vec![
+                Recorded::TouchDown(0, 10.0, 20.5),
+                Recorded::Frame,
+                Recorded::TouchDown(1, 300.0, 40.0),
+                Recorded::Frame,
+                Recorded::TouchMotion(0, 15.0, 25.0),
+                Recorded::Frame,
+                Recorded::TouchUp(1),
+                Recorded::Frame,
+                Recorded::TouchUp(0),
+                Recorded::Frame,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_touch_checks_slots_and_regions() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.touch.set(true);
+        sink.regions.borrow_mut().push(DeviceRegion {
+            x: 0.0,
+            y: 0.0,
+            width: 1920.0,
+            height: 1080.0,
+        });
+        sink.events.borrow_mut().clear();
+        assert!(matches!(typer.touch_up(0), Err(EiTypeError::Touch(_))));
+        assert!(matches!(
+            typer.touch_motion(0, 1.0, 1.0),
+            Err(EiTypeError::Touch(_))
+        ));
+        assert!(matches!(
+            typer.touch_down(0, 2000.0, 10.0),
+            Err(EiTypeError::Touch(_))
+        ));
+        typer.touch_down(0, 1919.0, 10.0).unwrap();
+        assert!(matches!(
+            typer.touch_down(0, 5.0, 5.0),
+            Err(EiTypeError::Touch(_))
+        ));
+        assert!(matches!(
+            typer.touch_motion(0, 10.0, 1080.0),
+            Err(EiTypeError::Touch(_))
+        ));
+        // Closing lifts the touch still down
+        typer.close();
+        assert!(sink.events().contains(&Recorded::TouchUp(0)));
+    }
+
+    #[test]
+    fn test_touch_needs_touch_device() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig::default());
+        assert!(matches!(
+            typer.touch_down(0, 1.0, 1.0),
+            Err(EiTypeError::NoDevice(DeviceCapability::Touch))
+        ));
+        let (typer, _sink) = recording_eitype(EiTypeConfig {
+            capabilities: Some(vec![DeviceCapability::Touch]),
+            ..Default::default()
+        });
+        assert!(matches!(
+            typer.touch_down(0, 1.0, 1.0),
+            Err(EiTypeError::CapabilityUnavailable(DeviceCapability::Touch))
+        ));
+        // A failed touch_down leaves the slot free
+        assert!(matches!(typer.touch_up(0), Err(EiTypeError::Touch(_))));
+    }
+
+    #[test]
+    fn test_paste_options_from_str() {
+        assert_eq!("primary".parse(), Ok(Selection::Primary));
+        assert_eq!("Clipboard".parse(), Ok(Selection::Clipboard));
+        assert_eq!("shift-insert".parse(), Ok(PasteTrigger::ShiftInsert));
+        assert_eq!("ctrl-shift-v".parse(), Ok(PasteTrigger::CtrlShiftV));
+        assert!("ctrl-q".parse::<PasteTrigger>().is_err());
+    }
+
+    #[test]
+    fn test_idle_stop_emulating_restarts_on_next_input() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            idle_stop_emulating_ms: Some(20),
+            ..Default::default()
+        });
+        typer.type_text("a").unwrap();
+        assert!(!typer.check_idle().unwrap());
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(typer.check_idle().unwrap());
+        assert!(!typer.check_idle().unwrap());
+        typer.type_text("b").unwrap();
+
+        // Idle past the window without check_idle: the next input restarts
+        std::thread::sleep(Duration::from_millis(40));
+        typer.type_text("c").unwrap();
+
+        let lifecycle: Vec<Recorded> = sink
+            .events()
+            .into_iter()
+            .filter(|e| !matches!(e, Recorded::Frame))
+            .collect();
+        assert_eq!(
+            lifecycle,
+            vec![
+                Recorded::StartEmulating,
+                Recorded::Key(30, true),
+                Recorded::Key(30, false),
+                Recorded::StopEmulating,
+                Recorded::StartEmulating,
+                Recorded::Key(48, true),
+                Recorded::Key(48, false),
+                Recorded::StopEmulating,
+                Recorded::StartEmulating,
+                Recorded::Key(46, true),
+                Recorded::Key(46, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_idle_stop_by_default() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text("a").unwrap();
+        assert!(!typer.check_idle().unwrap());
+        assert!(!sink.events().contains(&Recorded::StopEmulating));
+    }
+
+    #[test]
+    fn test_layout_auto_detection_can_be_disabled() {
+        let config = EiTypeConfig::default();
+        let cache = DetectedLayout::default();
+        assert_eq!(
+            auto_detected_layout_index(&config, &cache, || Some(2)),
+            Some(2)
+        );
+
+        let config = EiTypeConfig {
+            auto_detect_layout: false,
+            ..Default::default()
+        };
+        let cache = DetectedLayout::default();
+        let detected = Cell::new(false);
+        let index = auto_detected_layout_index(&config, &cache, || {
+            detected.set(true);
+            Some(2)
+        });
+        assert_eq!(index, None);
+        assert!(!detected.get());
+    }
+
+    #[test]
+    fn test_detected_layout_is_cached_for_ttl() {
+        let config = EiTypeConfig {
+            layout_detection_ttl_ms: 50,
+            ..Default::default()
+        };
+        let cache = DetectedLayout::default();
+        let calls = Cell::new(0);
+        let detect = || {
+            // Detecting leaves the cache free for other connections
+            assert!(cache.try_lock().is_ok());
+            calls.set(calls.get() + 1);
+            Some(calls.get())
+        };
+        assert_eq!(auto_detected_layout_index(&config, &cache, detect), Some(1));
+        assert_eq!(auto_detected_layout_index(&config, &cache, detect), Some(1));
+        assert_eq!(calls.get(), 1);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(auto_detected_layout_index(&config, &cache, detect), Some(2));
+
+        // A TTL of 0 always detects again
+        let config = EiTypeConfig {
+            layout_detection_ttl_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(auto_detected_layout_index(&config, &cache, detect), Some(3));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn test_keycode_for_char() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.events.borrow_mut().clear();
+        assert_eq!(typer.keycode_for_char('a'), Some((30, 0)));
+        assert_eq!(typer.keycode_for_char('A'), Some((30, 1)));
+        assert_eq!(typer.keycode_for_char('!'), Some((2, 1)));
+        assert_eq!(typer.keycode_for_char('ж'), None);
+        assert!(sink.events().is_empty());
+
+        typer.planner.get_mut().keymap = None;
+        assert_eq!(typer.keycode_for_char('a'), None);
+    }
+
+    #[test]
+    fn test_literal_whitespace() {
+        let plan = |config: &EiTypeConfig| {
+            let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, config);
+            planner.plan_text("\n\t").unwrap()
+        };
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            ..Default::default()
+        };
+        let presses = |events: Vec<PlannedEvent>| -> Vec<u32> {
+            events
+                .into_iter()
+                .filter_map(|e| match e {
+                    PlannedEvent::Press(keycode) => Some(keycode),
+                    _ => None,
+                })
+                .collect()
+        };
+        // Return and Tab by default; the Linefeed key's keysym and Tab
+        // when literal
+        assert_eq!(presses(plan(&config)), vec![28, 15]);
+        let literal = EiTypeConfig {
+            literal_whitespace: true,
+            ..config
+        };
+        assert_eq!(presses(plan(&literal)), vec![KEY_LINEFEED, 15]);
+
+        let (typer, sink) = recording_eitype(literal.clone());
+        typer.type_text("a\n").unwrap();
+        assert_eq!(sink.presses(), vec![30, KEY_LINEFEED]);
+        // The Return key is still a key name away
+        typer.press_key("return").unwrap();
+        assert_eq!(sink.presses(), vec![30, KEY_LINEFEED, 28]);
+
+        // Without a keymap the QWERTY table's Linefeed key is used
+        let mut planner = Planner::without_keymap(&literal);
+        assert_eq!(
+            presses(planner.plan_text("\n").unwrap()),
+            vec![KEY_LINEFEED]
+        );
+    }
+
+    #[test]
+    fn test_terminal_safe_text() {
+        assert_eq!(terminal_safe_text("ls -la\n"), "ls -la");
+        assert_eq!(terminal_safe_text("a\x1b[31mb\x07\x7f\u{9b}"), "a[31mb");
+        assert_eq!(
+            terminal_safe_text("one\r\ntwo\rthree\n\n\r"),
+            "one two three"
+        );
+        assert_eq!(terminal_safe_text("x\u{2028}y\u{85}"), "x y");
+        assert_eq!(terminal_safe_text("\x03\x04\n"), "");
+        assert!(matches!(
+            terminal_safe_text("cd\tdir é"),
+            std::borrow::Cow::Borrowed("cd\tdir é")
+        ));
+    }
+
+    #[test]
+    fn test_terminal_safe_withholds_return() {
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            ..Default::default()
+        };
+        let (typer, sink) = recording_eitype(config.clone());
+        typer.type_text("ls\n").unwrap();
+        assert_eq!(sink.presses(), vec![38, 31, 28]);
+
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            terminal_safe: true,
+            ..config
+        });
+        // The trailing newline and the escape character are left out
+        typer.type_text("l\x1bs\n").unwrap();
+        assert_eq!(sink.presses(), vec![38, 31]);
+        // An interior newline is a space
+        typer.type_text("l\ns").unwrap();
+        assert_eq!(sink.presses(), vec![38, 31, 38, 57, 31]);
+        // Return has to be asked for
+        typer.type_line("\n").unwrap();
+        assert_eq!(sink.presses(), vec![38, 31, 38, 57, 31, 28]);
+
+        // Reports count the text that was left to type
+        let outcome = typer.type_text_report("a\x1bb\n").unwrap();
+        assert_eq!(outcome.chars_requested, 2);
+        assert_eq!(outcome.chars_delivered, 2);
+        assert!(outcome.skipped.is_empty());
+
+        // Timed typing is filtered too
+        sink.events.borrow_mut().clear();
+        let items: Vec<(char, Duration)> =
+            "l\x1bs\n".chars().map(|ch| (ch, Duration::ZERO)).collect();
+        typer.type_timed(&items).unwrap();
+        assert_eq!(sink.presses(), vec![38, 31]);
+    }
+
+    #[test]
+    fn test_typeable_chars() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let chars = typer.typeable_chars();
+        for ch in ('a'..='z').chain('A'..='Z').chain('0'..='9') {
+            assert!(chars.contains(&ch), "{:?}", ch);
+        }
+        for ch in [' ', '\n', '\t', '!', '~', '|'] {
+            assert!(chars.contains(&ch), "{:?}", ch);
+        }
+        assert!(!chars.contains(&'ж'));
+        assert!(!chars.iter().any(|&ch| ch == '\u{8}' || ch == '\u{1b}'));
+        assert!(chars.windows(2).all(|pair| pair[0] < pair[1]));
+        // Every one of them types
+        let text: String = chars.iter().collect();
+        typer.type_text(&text).unwrap();
+
+        // A second layout's characters count only once it is active
+        let config = EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            ..Default::default()
+        };
+        let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+        assert!(!planner.typeable_chars().contains(&'ж'));
+        planner.layout_index = 1;
+        let russian = planner.typeable_chars();
+        assert!(russian.contains(&'ж') && russian.contains(&'1') && !russian.contains(&'q'));
+
+        typer.planner.get_mut().keymap = None;
+        let fallback = typer.typeable_chars();
+        assert!(fallback.contains(&'a') && fallback.contains(&'?'));
+        assert_eq!(fallback.len(), 26 * 2 + 10 + QWERTY_SYMBOLS.len());
+    }
+
+    #[test]
+    fn test_collect_timing_records_each_char() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig {
+            collect_timing: true,
+            ..Default::default()
+        });
+        typer.type_text("ab").unwrap();
+        typer.type_text("c").unwrap();
+        let timings = typer.timings();
+        assert_eq!(timings.iter().map(|t| t.ch).collect::<String>(), "abc");
+        assert_eq!(timings[0].gap, Duration::ZERO);
+        assert!(timings[1].gap >= timings[0].plan + timings[0].flush);
+        assert!(!typer.timings_truncated());
+
+        // Off by default, with no trace kept and nothing allocated per
+        // character for one
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text("ab").unwrap();
+        assert!(typer.timing.is_none());
+        assert!(typer.timings().is_empty());
+        sink.events.borrow_mut().reserve(1000);
+        sink.frame_timestamps.borrow_mut().reserve(1000);
+        let long = "ab".repeat(100);
+        let before = allocations();
+        typer.type_text("ab").unwrap();
+        let short = allocations() - before;
+        let before = allocations();
+        typer.type_text(&long).unwrap();
+        assert_eq!(allocations() - before, short);
+    }
+
+    #[test]
+    fn test_timing_trace_is_bounded() {
+        let mut trace = TimingTrace::default();
+        let started = Instant::now();
+        for i in 0..=TimingTrace::CAPACITY {
+            let ch = if i == 0 { 'x' } else { 'y' };
+            trace.record(ch, started, Duration::ZERO);
+        }
+        assert_eq!(trace.chars.len(), TimingTrace::CAPACITY);
+        assert!(trace.truncated);
+        assert!(trace.chars.iter().all(|t| t.ch == 'y'));
+    }
+
+    #[test]
+    fn test_stop_emulating_after_each_text() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            stop_emulating_after_each: true,
+            ..Default::default()
+        });
+        assert!(sink.events().is_empty());
+        typer.type_text("a").unwrap();
+        typer.type_text("b").unwrap();
+
+        let lifecycle: Vec<Recorded> = sink
+            .events()
+            .into_iter()
+            .filter(|e| !matches!(e, Recorded::Frame))
+            .collect();
+        assert_eq!(
+            lifecycle,
+            vec![
+                Recorded::StartEmulating,
+                Recorded::Key(30, true),
+                Recorded::Key(30, false),
+                Recorded::StopEmulating,
+                Recorded::StartEmulating,
+                Recorded::Key(48, true),
+                Recorded::Key(48, false),
+                Recorded::StopEmulating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explicit_start_stop_emulating() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.stop_emulating().unwrap();
+        typer.stop_emulating().unwrap();
+        typer.start_emulating().unwrap();
+        typer.start_emulating().unwrap();
+        typer.type_text("a").unwrap();
+        typer.hold_modifier("shift").unwrap();
+        typer.stop_emulating().unwrap();
+        // The next input starts a new session by itself
+        typer.type_text("b").unwrap();
+
+        let lifecycle: Vec<Recorded> = sink
+            .events()
+            .into_iter()
+            .filter(|e| !matches!(e, Recorded::Frame))
+            .collect();
+        assert_eq!(
+            lifecycle,
+            vec![
+                Recorded::StartEmulating,
+                Recorded::StopEmulating,
+                Recorded::StartEmulating,
+                Recorded::Key(30, true),
+                Recorded::Key(30, false),
+                Recorded::Key(42, true),
+                Recorded::Key(42, false),
+                Recorded::StopEmulating,
+                Recorded::StartEmulating,
+                Recorded::Key(48, true),
+                Recorded::Key(48, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_char_with_mods_nests_level_modifiers() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("de".to_string()),
+            ..Default::default()
+        });
+        // '@' is AltGr+Q on German; Ctrl and AltGr must be down together
+        typer.type_char_with_mods('@', &["ctrl"]).unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (29, true),
+                (100, true),
+                (16, true),
+                (16, false),
+                (100, false),
+                (29, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_char_with_mods_does_not_double_press_shift() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_char_with_mods('A', &["ctrl", "shift"]).unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (29, true),
+                (42, true),
+                (30, true),
+                (30, false),
+                (42, false),
+                (29, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_down_and_up_actions() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let actions = vec![
+            Action::KeyDown("shift".to_string()),
+            Action::Key("left".to_string()),
+            Action::KeyUp("shift".to_string()),
+            Action::KeyDown("ctrl".to_string()),
+        ];
+        // The key still down at the end of the sequence is released
+        typer.execute_actions(&actions).unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (42, true),
+                (105, true),
+                (105, false),
+                (42, false),
+                (29, true),
+                (29, false),
+            ]
+        );
+
+        sink.events.borrow_mut().clear();
+        assert!(matches!(typer.key_up("shift"), Err(EiTypeError::Typing(_))));
+        typer.key_down("alt").unwrap();
+        assert!(matches!(typer.key_down("alt"), Err(EiTypeError::Typing(_))));
+
+        // So does closing
+        typer.close();
+        assert_eq!(key_events(&sink), vec![(56, true), (56, false)]);
+    }
+
+    #[test]
+    fn test_parse_inline_login_form() {
+        let actions = parse_inline("user@example.com\\tsecret{sleep:50}{key:Return}").unwrap();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Type("user@example.com".to_string()),
+                Action::Key("tab".to_string()),
+                Action::Type("secret".to_string()),
+                Action::Sleep(Duration::from_millis(50)),
+                Action::Key("Return".to_string()),
+            ]
+        );
+
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_inline("a\\tb{combo:ctrl+a}\\n").unwrap();
+        assert_eq!(sink.presses(), vec![30, 15, 48, 29, 30, 28]);
+    }
+
+    #[test]
+    fn test_parse_inline_escapes() {
+        let actions = parse_inline("{{x} \\\\n {{{key:tab}").unwrap();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Type("{x} \\n {".to_string()),
+                Action::Key("tab".to_string()),
+            ]
+        );
+        assert_eq!(parse_inline("").unwrap(), vec![]);
+        assert_eq!(
+            parse_inline("{combo:backtab}").unwrap(),
+            vec![Action::Key("backtab".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_errors_give_byte_offsets() {
+        let offset = |input| match parse_inline(input) {
+            Err(EiTypeError::InlineSyntax { offset, message }) => (offset, message),
+            other => panic!("{:?}: {:?}", input, other),
+        };
+        let (at, message) = offset("né{key:tabb}");
+        assert_eq!(at, 3);
+        assert!(message.contains("did you mean tab"), "{}", message);
+        assert_eq!(offset("{key:shift+tab}").0, 0);
+        assert_eq!(offset("ab{key:tab").0, 2);
+        assert_eq!(offset("{enter}").0, 0);
+        assert_eq!(offset("x{sleep:soon}").0, 1);
+        assert_eq!(offset("a\\x").0, 1);
+        assert_eq!(offset("a\\").0, 1);
+
+        // Nothing is typed when the input doesn't parse
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        assert!(typer.type_inline("abc{key:nope}").is_err());
+        assert!(sink.presses().is_empty());
+    }
+
+    #[test]
+    fn test_with_modifiers_scopes_nest() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let actions = vec![
+            Action::Type("a".to_string()),
+            Action::WithModifiers {
+                mods: vec!["ctrl".to_string(), "shift".to_string()],
+                inner: vec![
+                    Action::Key("left".to_string()),
+                    Action::WithModifiers {
+                        mods: vec!["shift".to_string(), "alt".to_string()],
+                        inner: vec![Action::Key("right".to_string())],
+                    },
+                    Action::Key("left".to_string()),
+                ],
+            },
+            Action::Type("b".to_string()),
+        ];
+        typer.execute_actions(&actions).unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (30, true),
+                (30, false),
+                (29, true),
+                (42, true),
+                (105, true),
+                (105, false),
+                // The inner shift is already down, so only alt is pressed
+                (56, true),
+                (106, true),
+                (106, false),
+                (56, false),
+                (105, true),
+                (105, false),
+                (42, false),
+                (29, false),
+                (48, true),
+                (48, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_keys() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.hold_modifier("ctrl").unwrap();
+        typer.key_down("a").unwrap();
+        sink.events.borrow_mut().clear();
+
+        typer.reset_keys().unwrap();
+        let events = sink.events();
+        let released: Vec<u32> = events
+            .iter()
+            .filter_map(|event| match event {
+                Recorded::Key(keycode, false) => Some(*keycode),
+                _ => None,
+            })
+            .collect();
+        // Keys down first, latest first, then the modifiers, each once
+        assert_eq!(released[..8], [30, 29, 42, 54, 97, 56, 100, 125]);
+        assert!(released.contains(&126));
+        let mut unique = released.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), released.len());
+        // Each in a frame of its own, as `emit` sends keys
+        assert_eq!(events.len(), 2 * released.len());
+        assert!(events
+            .chunks(2)
+            .all(|pair| matches!(pair, [Recorded::Key(_, false), Recorded::Frame])));
+
+        // Nothing is held any more, so holding ctrl presses it again
+        sink.events.borrow_mut().clear();
+        typer.release_modifiers().unwrap();
+        typer.hold_modifier("ctrl").unwrap();
+        assert_eq!(key_events(&sink), vec![(29, true)]);
+    }
+
+    #[test]
+    fn test_with_modifier_closure() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let selected = typer
+            .with_modifier("ctrl", |typer| {
+                typer.press_key("a")?;
+                typer.with_modifier("shift", |typer| {
+                    typer.with_modifier("ctrl", |typer| typer.press_key("left"))?;
+                    Ok("selected")
+                })
+            })
+            .unwrap();
+        assert_eq!(selected, "selected");
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (29, true),
+                (30, true),
+                (30, false),
+                (42, true),
+                // The inner ctrl is already down
+                (105, true),
+                (105, false),
+                (42, false),
+                (29, false),
+            ]
+        );
 
-    let stream = UnixStream::connect(path).map_err(|e| {
-        EiTypeError::Connection(format!("Failed to connect to socket {:?}: {}", path, e))
-    })?;
+        // An error in the closure still releases the modifier, and is returned
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let err = typer
+            .with_modifier("ctrl", |typer| {
+                typer.press_key("c")?;
+                typer.press_key("nosuchkey")?;
+                typer.press_key("v")
+            })
+            .unwrap_err();
+        assert!(matches!(err, EiTypeError::UnknownKey(_)), "{:?}", err);
+        assert_eq!(
+            key_events(&sink),
+            vec![(29, true), (46, true), (46, false), (29, false)]
+        );
+        typer.type_text("x").unwrap();
+        assert_eq!(key_events(&sink)[4..], [(45, true), (45, false)]);
+    }
 
-    stream
-        .set_nonblocking(true)
-        .map_err(|e| EiTypeError::Connection(format!("Failed to set non-blocking: {}", e)))?;
+    #[test]
+    fn test_with_modifiers_releases_on_inner_error() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let actions = vec![Action::WithModifiers {
+            mods: vec!["ctrl".to_string()],
+            inner: vec![
+                Action::Key("a".to_string()),
+                Action::Key("nosuchkey".to_string()),
+                Action::Key("b".to_string()),
+            ],
+        }];
+        let err = typer.execute_actions(&actions).unwrap_err();
+        assert!(matches!(err, EiTypeError::UnknownKey(_)), "{:?}", err);
+        assert_eq!(
+            key_events(&sink),
+            vec![(29, true), (30, true), (30, false), (29, false)]
+        );
+    }
 
-    Ok(stream)
-}
+    #[test]
+    fn test_with_modifiers_shift_scope_types_capitals_once() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let actions = vec![Action::WithModifiers {
+            mods: vec!["shift".to_string()],
+            inner: vec![Action::Type("AB".to_string())],
+        }];
+        typer.execute_actions(&actions).unwrap();
+        // The planner leaves the held shift alone rather than pressing it again
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (42, true),
+                (30, true),
+                (30, false),
+                (48, true),
+                (48, false),
+                (42, false)
+            ]
+        );
+    }
 
-// ============================================================================
-// Main EiType Struct
-// ============================================================================
+    #[test]
+    fn test_execute_actions_reported() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let actions = vec![
+            Action::Type("hi".to_string()),
+            Action::Key("return".to_string()),
+            Action::Type("a中b".to_string()),
+            Action::Key("tab".to_string()),
+        ];
+        let (reports, result) = typer.execute_actions_reported(&actions);
+        assert!(matches!(
+            result.unwrap_err().root(),
+            EiTypeError::CharNotFound('中')
+        ));
 
-/// Main interface for typing text via EI protocol
-#[cfg_attr(feature = "python", pyclass(unsendable))]
-pub struct EiType {
-    connection: reis::event::Connection,
-    device: reis::event::Device,
-    keyboard: ei::Keyboard,
-    keymap: Option<xkb::Keymap>,
-    xkb_state: Option<xkb::State>,
-    key_to_keycode: HashMap<String, u32>,
-    /// Real-modifier name (e.g. "Mod5") -> evdev keycode that produces it,
-    /// derived from the active keymap's modmap. Populated by `install_keymap`.
-    keymap_mod_keycodes: HashMap<String, u32>,
-    delay: Duration,
-    held_modifiers: Vec<u32>,
-    sequence: u32,
-    layout_index: u32,
-    /// Track whether close() has been called to avoid double-close
-    closed: bool,
-}
+        let summary: Vec<_> = reports
+            .iter()
+            .map(|r| (r.index, r.kind, r.chars_typed))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(0, "type", 2), (1, "key", 0), (2, "type", 1), (3, "key", 0)]
+        );
+        assert_eq!(reports[0].status, ActionStatus::Ok);
+        assert_eq!(reports[1].status, ActionStatus::Ok);
+        assert!(matches!(&reports[2].status, ActionStatus::Failed(m) if m.contains("中")));
+        assert_eq!(reports[3].status, ActionStatus::Skipped);
 
-impl EiType {
-    /// Connect via the XDG RemoteDesktop portal (simple version, no token management)
-    pub fn connect_portal(config: EiTypeConfig) -> Result<Self, EiTypeError> {
-        let (eitype, _token) = Self::connect_portal_with_token(config, None)?;
-        Ok(eitype)
+        let stats = typer.stats();
+        assert_eq!(stats.chars_typed, 3);
+        assert_eq!(stats.keys_pressed, 4);
+        assert_eq!(stats.qwerty_fallback_chars, 0);
     }
 
-    /// Connect via the XDG RemoteDesktop portal with token support.
-    ///
-    /// If `restore_token` is provided and valid, the portal will skip the authorization dialog.
-    /// Returns the EiType instance and optionally a new restore token to save for future use.
-    pub fn connect_portal_with_token(
-        config: EiTypeConfig,
-        restore_token: Option<&str>,
-    ) -> Result<(Self, Option<String>), EiTypeError> {
-        let (stream, new_token) = connect_via_portal(restore_token)?;
-        let eitype = Self::from_stream(stream, config)?;
-        Ok((eitype, new_token))
+    #[test]
+    fn test_stats_count_frames_for_known_text() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text("Hello, world").unwrap();
+        let stats = typer.stats();
+        assert_eq!(stats.chars_typed, 12);
+        // A press and a release frame per key: one for each character, plus
+        // Shift for "H"
+        assert_eq!(stats.keys_pressed, 13);
+        assert_eq!(stats.frames_sent, 2 * 13);
+        assert_eq!(
+            stats.frames_sent,
+            sink.events()
+                .iter()
+                .filter(|e| matches!(e, Recorded::Frame))
+                .count()
+        );
+        assert_eq!(stats.flush_retries, 0);
     }
 
-    /// Connect via a Unix socket (for testing or direct EIS connections)
-    pub fn connect_socket(path: &Path, config: EiTypeConfig) -> Result<Self, EiTypeError> {
-        let stream = connect_via_socket(path)?;
-        Self::from_stream(stream, config)
+    #[test]
+    fn test_type_line_ends_with_return() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_line("ls").unwrap();
+        assert_eq!(sink.presses(), vec![38, 31, 28]);
+        assert_eq!(key_events(&sink).last(), Some(&(28, false)));
     }
 
-    /// Internal: create EiType from an already-connected stream
-    fn from_stream(stream: UnixStream, config: EiTypeConfig) -> Result<Self, EiTypeError> {
-        // Clone fd for non-blocking polling later (to detect layout group)
-        let poll_stream = stream
-            .try_clone()
-            .map_err(|e| EiTypeError::Connection(format!("Failed to clone stream: {}", e)))?;
+    #[test]
+    fn test_type_line_stops_on_typing_error() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let err = typer.type_line("a中").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('中')));
+        // Never submits a partial line
+        assert_eq!(sink.presses(), vec![30]);
+    }
 
-        let context = ei::Context::new(stream)
-            .map_err(|e| EiTypeError::Connection(format!("Failed to create EI context: {}", e)))?;
+    #[test]
+    fn test_max_text_len_rejects_oversized_text() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            max_text_len: Some(3),
+            ..Default::default()
+        });
+        sink.events.borrow_mut().clear();
+        let err = typer.type_text("abcd").unwrap_err();
+        assert_eq!(err.to_string(), "Typing error: text exceeds max length 3");
+        assert!(typer.type_line("abcd").is_err());
+        let mut cursor = TypeCursor::new("ab中d");
+        assert!(typer.try_type_text(&mut cursor).is_err());
+        assert_eq!(cursor.typed(), 0);
+        // Nothing was sent
+        assert_eq!(sink.events(), vec![]);
 
-        info!("Performing handshake...");
-        let (connection, mut event_iter) = context
-            .handshake_blocking("eitype", ContextType::Sender)
-            .map_err(|e| EiTypeError::Connection(format!("Handshake failed: {}", e)))?;
+        // Text at the limit is fine
+        typer.type_text("abc").unwrap();
+        assert_eq!(typer.stats().chars_typed, 3);
+    }
 
-        info!("Connected! Waiting for devices...");
+    #[test]
+    fn test_press_key_accepts_localized_names() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        for name in [
+            "Eingabe",
+            "entrée",
+            "пробел",
+            "Probel",
+            "Rücktaste",
+            "Échap",
+            "Entf",
+        ] {
+            typer.press_key(name).unwrap();
+        }
+        assert_eq!(sink.presses(), vec![28, 28, 57, 57, 14, 1, 111]);
+    }
 
-        // Process events until we get a keyboard device
-        let mut result: Option<(reis::event::Device, ei::Keyboard)> = None;
+    #[test]
+    fn test_typing_ascii_allocates_nothing_per_char() {
+        let text = "The Quick Brown Fox, 42 jumps! (over) the \"lazy\" dog?\n";
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        // Warm up, and leave room for the events about to be recorded
+        typer.type_text(text).unwrap();
+        sink.events.borrow_mut().reserve(100 * text.len());
+        sink.frame_timestamps.borrow_mut().reserve(100 * text.len());
 
-        for event_result in &mut event_iter {
-            let event = event_result
-                .map_err(|e| EiTypeError::Connection(format!("Error processing event: {}", e)))?;
-            trace!("Received event: {:?}", event);
+        let long = text.repeat(20);
+        let before = allocations();
+        typer.type_text(text).unwrap();
+        let short = allocations() - before;
+        let before = allocations();
+        typer.type_text(&long).unwrap();
+        // What a call allocates doesn't depend on its length
+        assert_eq!(allocations() - before, short);
+    }
 
-            match event {
-                EiEvent::Disconnected(disconnected) => {
-                    let reason = disconnected.reason;
-                    let explanation = &disconnected.explanation;
-                    error!("Disconnected: {:?} - {}", reason, explanation);
-                    return Err(EiTypeError::Connection(
-                        "Disconnected from EI server".to_string(),
-                    ));
-                }
+    #[test]
+    fn test_single_key_fast_path() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.press_key("escape").unwrap();
+        let primed = sink.events().len();
 
-                EiEvent::SeatAdded(seat_added) => {
-                    let seat = &seat_added.seat;
-                    debug!("Seat added: {:?}", seat.name());
-                    seat.bind_capabilities(&[DeviceCapability::Keyboard]);
-                    connection
-                        .flush()
-                        .map_err(|e| EiTypeError::Connection(e.to_string()))?;
-                }
+        // A plain tap is press, frame, release, frame, flushed twice
+        sink.flush_budget.set(Some(10));
+        typer.press_key("escape").unwrap();
+        typer.type_text("x").unwrap();
+        assert_eq!(sink.flush_budget.get(), Some(6));
+        sink.flush_budget.set(None);
+        let tap = |kc| {
+            [
+                Recorded::Key(kc, true),
+                Recorded::Frame,
+                Recorded::Key(kc, false),
+                Recorded::Frame,
+            ]
+        };
+        assert_eq!(sink.events()[primed..], [tap(1), tap(45)].concat());
+        let stats = typer.stats();
+        assert_eq!(stats.keys_pressed, 3);
+        assert_eq!(stats.chars_typed, 1);
 
-                EiEvent::DeviceAdded(device_added) => {
-                    let device = &device_added.device;
-                    debug!("Device added: {:?}", device.name());
-                }
+        // Anything more than a plain tap takes the regular path
+        typer.type_text("X").unwrap();
+        typer.press_key("shift+tab").unwrap();
+        assert_eq!(
+            key_events(&sink)[6..],
+            [
+                (42, true),
+                (45, true),
+                (45, false),
+                (42, false),
+                (42, true),
+                (15, true),
+                (15, false),
+                (42, false)
+            ]
+        );
+        assert_eq!(typer.stats().chars_typed, 2);
 
-                EiEvent::DeviceResumed(device_resumed) => {
-                    let device = device_resumed.device.clone();
-                    debug!("Device resumed: {:?}", device.name());
+        // A format character is still left out when a plain key types it
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            keymap_string: Some(remapped_us_keymap(&[("AE01", "U200B")])),
+            ..Default::default()
+        });
+        typer.type_text("\u{200B}").unwrap();
+        assert!(key_events(&sink).is_empty());
+        assert_eq!(typer.stats().format_chars_stripped, 1);
+    }
 
-                    if let Some(keyboard) = device.interface::<ei::Keyboard>() {
-                        info!("Keyboard device available: {:?}", device.name());
-                        result = Some((device, keyboard));
-                        break;
-                    }
-                }
+    #[test]
+    fn test_named_combos_expand_to_held_modifiers() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.press_key("backtab").unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![(42, true), (15, true), (15, false), (42, false)]
+        );
 
-                EiEvent::DevicePaused(paused) => {
-                    debug!("Device paused: {:?}", paused.device.name());
-                }
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.press_key("Redo").unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (29, true),
+                (42, true),
+                (44, true),
+                (44, false),
+                (42, false),
+                (29, false)
+            ]
+        );
 
-                EiEvent::DeviceRemoved(removed) => {
-                    debug!("Device removed: {:?}", removed.device.name());
-                }
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        for name in ["shifttab", "paste", "copy", "cut", "selectall"] {
+            typer.press_key(name).unwrap();
+        }
+        assert_eq!(sink.presses(), vec![42, 15, 29, 47, 29, 46, 29, 45, 29, 30]);
+    }
 
-                _ => {
-                    trace!("Other event");
-                }
-            }
+    #[test]
+    fn test_combos_respect_modifier_overrides() {
+        // Explicit combos name the modifier keys to use
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.press_key("kc:54+tab").unwrap();
+        typer.press_key("rctrl+shift+z").unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (54, true),
+                (15, true),
+                (15, false),
+                (54, false),
+                (97, true),
+                (42, true),
+                (44, true),
+                (44, false),
+                (42, false),
+                (97, false)
+            ]
+        );
+
+        // Named combos use whichever keys "shift" and "ctrl" resolve to
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        {
+            let mut planner = typer.planner.borrow_mut();
+            planner.key_to_keycode.insert("shift".to_string(), 54);
+            planner.key_to_keycode.insert("ctrl".to_string(), 97);
         }
+        typer.press_key("backtab").unwrap();
+        typer.press_key("paste").unwrap();
+        assert_eq!(sink.presses(), vec![54, 15, 97, 47]);
+    }
 
-        let (device, keyboard) = result.ok_or(EiTypeError::NoKeyboard)?;
+    #[test]
+    fn test_combo_with_unknown_part_sends_nothing() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let err = typer.press_key("ctrl+hyperspace").unwrap_err();
+        assert!(matches!(err, EiTypeError::UnknownKey(ref n) if n == "hyperspace"));
+        assert!(key_events(&sink).is_empty());
 
-        // Try to auto-detect active layout group from modifiers event
-        let detected_group = if config.layout_index.is_none() {
-            poll_for_layout_group(&poll_stream, &mut event_iter)
-        } else {
-            None
-        };
+        let message = typer.press_key("bakctab").unwrap_err().to_string();
+        assert!(message.contains("did you mean backtab"), "{}", message);
+    }
 
-        let layout_index = config
-            .layout_index
-            .or(detected_group)
-            .or_else(|| {
-                if config.layout_index.is_none() {
-                    info!("No layout group from EI protocol, trying compositor-specific detection");
-                    detect_active_layout_index()
-                } else {
-                    None
-                }
-            })
-            .unwrap_or(0);
-        info!("Using layout index: {}", layout_index);
+    #[test]
+    fn test_list_key_names_marks_combos() {
+        let names = list_key_names();
+        assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(names.contains(&("tab".to_string(), None)));
+        assert!(names.contains(&("backtab".to_string(), Some("shift+tab"))));
+        // Named combos never shadow a plain key name
+        let keys = build_key_to_keycode_map();
+        assert!(NAMED_COMBOS
+            .iter()
+            .all(|(name, _)| !keys.contains_key(*name)));
+    }
 
-        let mut eitype = Self {
-            connection,
-            device,
-            keyboard,
-            keymap: None,
-            xkb_state: None,
-            key_to_keycode: build_key_to_keycode_map(),
-            keymap_mod_keycodes: HashMap::new(),
-            delay: Duration::from_millis(config.delay_ms),
-            held_modifiers: Vec::new(),
-            sequence: 1,
-            layout_index,
-            closed: false,
-        };
+    #[test]
+    fn test_capabilities_cover_every_action_and_key() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.schema_version, CAPABILITIES_SCHEMA_VERSION);
+        let space = capabilities
+            .keys
+            .iter()
+            .find(|key| key.name == "space")
+            .unwrap();
+        assert_eq!(space.code, 57);
+        assert!(space.aliases.contains(&"leertaste"));
+        assert_eq!(capabilities.keys.len(), build_key_to_keycode_map().len());
+        assert_eq!(capabilities.combos.len(), NAMED_COMBOS.len());
 
-        // Setup keymap
-        eitype.setup_keymap(&config)?;
+        // One entry per Action variant, in declaration order
+        let actions = [
+            Action::Type(String::new()),
+            Action::Key(String::new()),
+            Action::ModifierHold(String::new()),
+            Action::ModifierPress(String::new()),
+            Action::KeyDown(String::new()),
+            Action::KeyUp(String::new()),
+            Action::Keysym(String::new()),
+            Action::Paste(String::new(), PasteOptions::default()),
+            Action::ScrollDiscrete(0, 0),
+            Action::ScrollSmooth(0.0, 0.0),
+            Action::Tap(0.0, 0.0),
+            Action::WithModifiers {
+                mods: Vec::new(),
+                inner: Vec::new(),
+            },
+            Action::Sleep(Duration::ZERO),
+            Action::Focus(FocusTarget::AppId(String::new())),
+        ];
+        let kinds: Vec<_> = capabilities.actions.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, actions.iter().map(Action::kind).collect::<Vec<_>>());
+        let paste = &capabilities.actions[7];
+        assert!(paste.params[1]
+            .choices
+            .iter()
+            .all(|choice| choice.parse::<Selection>().is_ok()));
+        assert!(paste.params[2]
+            .choices
+            .iter()
+            .all(|choice| choice.parse::<PasteTrigger>().is_ok()));
+    }
 
-        // Start emulating
-        eitype.start_emulating()?;
+    #[test]
+    fn test_type_text_counting_includes_modifier_presses() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        assert_eq!(typer.type_text_counting("hi").unwrap(), 2);
+        // Shift for each capital, plus one key per character
+        assert_eq!(typer.type_text_counting("Hi!").unwrap(), 5);
+        assert_eq!(sink.presses().len(), 7);
 
-        Ok(eitype)
+        // A held Shift is not pressed again
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        typer.hold_modifier("shift").unwrap();
+        assert_eq!(typer.type_text_counting("HI").unwrap(), 2);
+        typer.release_modifiers().unwrap();
     }
 
-    /// Install a freshly-loaded keymap and recompute the modifier-keycode lookup.
-    /// Centralizes the bookkeeping so every load path stays in sync.
-    fn install_keymap(&mut self, keymap: xkb::Keymap, state: xkb::State) {
-        self.keymap_mod_keycodes = build_keymap_modifier_map(&keymap);
-        debug!(
-            "Resolved modifier keycodes from keymap: {:?}",
-            self.keymap_mod_keycodes
-        );
-        self.keymap = Some(keymap);
-        self.xkb_state = Some(state);
+    #[test]
+    fn test_type_text_reliable_resumes_without_duplicating() {
+        let (reference, reference_sink) = recording_eitype(EiTypeConfig::default());
+        reference.type_text("abcdef").unwrap();
+        let expected = reference_sink.presses();
+
+        let (mut typer, first) = recording_eitype(EiTypeConfig::default());
+        // Press and release "a" and "b" and press "c", then the connection
+        // breaks releasing it
+        first.flush_budget.set(Some(5));
+        first.flush_errno.set(32);
+        let second = Rc::new(RefCell::new(None));
+        let reconnected = second.clone();
+        typer.reconnector = Some(Box::new(move || {
+            let (fresh, sink) = recording_eitype(EiTypeConfig::default());
+            *reconnected.borrow_mut() = Some(sink);
+            Ok(fresh)
+        }));
+
+        typer.type_text_reliable("abcdef").unwrap();
+        let second = second.borrow_mut().take().expect("reconnected");
+        // "c" got through, so typing resumes after it, releasing its key
+        assert_eq!(first.presses(), expected[..3]);
+        assert_eq!(second.presses(), expected[3..]);
+        assert_eq!(key_events(&second)[0], (expected[2], false));
+        assert_eq!(typer.stats().chars_typed, 6);
+
+        // Breaking while flushing the press of "c" resumes from it
+        let (mut typer, first) = recording_eitype(EiTypeConfig::default());
+        first.flush_budget.set(Some(4));
+        first.flush_errno.set(32);
+        let second = Rc::new(RefCell::new(None));
+        let reconnected = second.clone();
+        typer.reconnector = Some(Box::new(move || {
+            let (fresh, sink) = recording_eitype(EiTypeConfig::default());
+            *reconnected.borrow_mut() = Some(sink);
+            Ok(fresh)
+        }));
+        typer.type_text_reliable("abcdef").unwrap();
+        let second = second.borrow_mut().take().expect("reconnected");
+        assert_eq!(first.presses(), expected[..3]);
+        assert_eq!(second.presses(), expected[2..]);
+        assert_eq!(key_events(&second)[0], (expected[2], true));
+        assert_eq!(typer.stats().chars_typed, 6);
+
+        // Without a way to reconnect, the failure is returned
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.flush_budget.set(Some(0));
+        sink.flush_errno.set(32);
+        let err = typer.type_text_reliable("abc").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::Flush(_)), "{:?}", err);
     }
 
-    fn setup_keymap(&mut self, config: &EiTypeConfig) -> Result<(), EiTypeError> {
-        let xkb_context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    #[test]
+    fn test_reconnect_keeps_status_receivers() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let status = typer.status_receiver();
+        assert_eq!(status.try_recv(), Ok(StatusEvent::Connected));
+        let fresh_status = Rc::new(RefCell::new(None));
+        let reconnected = fresh_status.clone();
+        typer.reconnector = Some(Box::new(move || {
+            let (fresh, _sink) = recording_eitype(EiTypeConfig::default());
+            *reconnected.borrow_mut() = Some(fresh.status.clone());
+            Ok(fresh)
+        }));
 
-        if config.is_specified() {
-            let rules = "";
-            let model = config.model.as_deref().unwrap_or("");
-            let layout = config.layout.as_deref().unwrap_or("");
-            let variant = config.variant.as_deref().unwrap_or("");
-            let options = config.options.clone();
+        typer.reconnect().unwrap();
+        assert_eq!(status.try_recv(), Ok(StatusEvent::Connected));
+        // Events from the new connection's sink still arrive
+        let device = || "keyboard".to_string();
+        let fresh_status = fresh_status.borrow_mut().take().expect("reconnected");
+        fresh_status.send(StatusEvent::DevicePaused { device: device() });
+        assert_eq!(
+            status.try_recv(),
+            Ok(StatusEvent::DevicePaused { device: device() })
+        );
+        typer.close();
+        assert!(matches!(
+            status.try_recv(),
+            Ok(StatusEvent::Disconnected { .. })
+        ));
+    }
 
-            info!(
-                "Loading keymap from configuration: layout={}, variant={}, model={}",
-                if layout.is_empty() {
-                    "(default)"
-                } else {
-                    layout
-                },
-                if variant.is_empty() {
-                    "(none)"
-                } else {
-                    variant
-                },
-                if model.is_empty() { "(default)" } else { model }
-            );
+    #[test]
+    fn test_type_text_reliable_only_retries_connection_failures() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig {
+            strict_key_state: true,
+            ..Default::default()
+        });
+        let reconnects = Rc::new(Cell::new(0));
+        let counted = reconnects.clone();
+        typer.reconnector = Some(Box::new(move || {
+            counted.set(counted.get() + 1);
+            Ok(recording_eitype(EiTypeConfig::default()).0)
+        }));
+        // Typing Shift while it's held is a strict key state error
+        typer.hold_modifier("shift").unwrap();
+        let err = typer.type_text_reliable("A").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::Typing(_)), "{:?}", err);
+        assert_eq!(reconnects.get(), 0);
+    }
 
-            let keymap = xkb::Keymap::new_from_names(
-                &xkb_context,
-                rules,
-                model,
-                layout,
-                variant,
-                options,
-                xkb::KEYMAP_COMPILE_NO_FLAGS,
-            )
-            .ok_or_else(|| {
-                EiTypeError::Keymap("Failed to load keymap from configuration".to_string())
-            })?;
+    #[test]
+    fn test_validate_input() {
+        assert_eq!(validate_input("héllo".as_bytes()).unwrap(), "héllo");
+        assert_eq!(validate_input(b"").unwrap(), "");
 
-            let state = xkb::State::new(&keymap);
-            self.install_keymap(keymap, state);
-            return Ok(());
-        }
+        // Latin-1 "é" in the middle
+        let err = validate_input(b"caf\xe9 au lait").unwrap_err();
+        assert!(matches!(
+            err,
+            EiTypeError::InvalidUtf8 {
+                offset: 3,
+                truncated: false
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Input is not valid UTF-8 at byte 3 (is it in another encoding, e.g. \
+             Latin-1? iconv can convert it)"
+        );
 
-        // Try to use the keymap provided by the EI server
-        if let Some(keymap_info) = self.device.keymap() {
-            use std::os::fd::FromRawFd;
-            use std::os::fd::IntoRawFd;
-            let fd_dup = rustix::io::dup(&keymap_info.fd).map_err(|e| {
-                EiTypeError::Keymap(format!("Failed to duplicate keymap fd: {}", e))
-            })?;
-            let owned_fd = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd_dup.into_raw_fd()) };
-
-            let keymap = unsafe {
-                xkb::Keymap::new_from_fd(
-                    &xkb_context,
-                    owned_fd,
-                    keymap_info.size as usize,
-                    xkb::KEYMAP_FORMAT_TEXT_V1,
-                    xkb::KEYMAP_COMPILE_NO_FLAGS,
-                )
+        // The first two bytes of a three-byte character at the end
+        let err = validate_input(b"ok \xe2\x82").unwrap_err();
+        assert!(matches!(
+            err,
+            EiTypeError::InvalidUtf8 {
+                offset: 3,
+                truncated: true
             }
-            .map_err(|e| EiTypeError::Keymap(format!("Failed to read keymap from fd: {}", e)))?
-            .ok_or_else(|| EiTypeError::Keymap("Failed to compile keymap".to_string()))?;
+        ));
+        assert!(err
+            .to_string()
+            .ends_with("(it ends partway through a character)"));
+    }
 
-            let state = xkb::State::new(&keymap);
+    #[test]
+    fn test_unknown_key_suggests_near_misses() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let message = typer.press_key("eingabr").unwrap_err().to_string();
+        assert!(message.contains("did you mean eingabe"), "{}", message);
 
-            let num_layouts = keymap.num_layouts();
-            if num_layouts > 0 {
-                let layout_name = keymap.layout_get_name(0);
-                info!(
-                    "Keymap loaded from EI server: layout=\"{}\" ({} layout(s) available)",
-                    layout_name, num_layouts
-                );
-                for i in 0..num_layouts {
-                    debug!("  Layout {}: \"{}\"", i, keymap.layout_get_name(i));
-                }
-            } else {
-                info!("Keymap loaded from EI server (no layout name available)");
-            }
+        let message = typer.press_key("retrun").unwrap_err().to_string();
+        assert!(message.contains("return"), "{}", message);
 
-            self.install_keymap(keymap, state);
-            return Ok(());
-        }
+        let message = typer.press_key("xyzzy").unwrap_err().to_string();
+        assert_eq!(message, "Unknown key: xyzzy");
+    }
 
-        // Fallback: use system default keymap
-        info!("Loading system default keymap");
+    #[test]
+    fn test_hold_modifier_raw_keycode() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.hold_modifier("kc:100").unwrap();
+        typer.type_text("a").unwrap();
+        typer.release_modifiers().unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![(100, true), (30, true), (30, false), (100, false)]
+        );
 
-        let keymap = xkb::Keymap::new_from_names(
-            &xkb_context,
-            "",
-            "",
-            "",
-            "",
-            None,
-            xkb::KEYMAP_COMPILE_NO_FLAGS,
-        )
-        .ok_or_else(|| EiTypeError::Keymap("Failed to load system default keymap".to_string()))?;
+        typer.press_modifier("kc:125").unwrap();
+        assert_eq!(sink.presses().last(), Some(&125));
+    }
 
-        let state = xkb::State::new(&keymap);
+    #[test]
+    fn test_max_modifier_hold_presses_modifier_again() {
+        let items: Vec<(char, Duration)> = "ABCDEF"
+            .chars()
+            .map(|ch| (ch, Duration::from_millis(40)))
+            .collect();
 
-        self.install_keymap(keymap, state);
-        Ok(())
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            max_modifier_hold_ms: Some(60),
+            ..Default::default()
+        });
+        typer.clock = Clock::Fake(Cell::new(0));
+        typer.hold_modifier("shift").unwrap();
+        typer.type_timed(&items).unwrap();
+        typer.release_modifiers().unwrap();
+        assert_eq!(sink.presses(), vec![42, 30, 48, 42, 46, 32, 42, 18, 33]);
+        let events = key_events(&sink);
+        let refresh = events.iter().position(|&e| e == (42, false)).unwrap();
+        assert_eq!(events[refresh - 1], (48, false));
+        assert_eq!(events[refresh + 1], (42, true));
+        assert_eq!(events[refresh + 2], (46, true));
+
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.clock = Clock::Fake(Cell::new(0));
+        typer.hold_modifier("shift").unwrap();
+        typer.type_timed(&items).unwrap();
+        typer.release_modifiers().unwrap();
+        assert_eq!(sink.presses(), vec![42, 30, 48, 46, 32, 18, 33]);
+        assert_eq!(key_events(&sink).last(), Some(&(42, false)));
     }
 
-    fn start_emulating(&mut self) -> Result<(), EiTypeError> {
-        let serial = self.connection.serial();
-        self.device.device().start_emulating(serial, self.sequence);
-        self.sequence += 1;
-        self.flush_with_retry()
+    #[test]
+    fn test_raw_keycode_out_of_range() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        for name in ["kc:768", "kc:-1", "kc:ctrl", "kc:"] {
+            let err = typer.hold_modifier(name).unwrap_err();
+            assert!(matches!(err, EiTypeError::UnknownKey(_)), "{}", name);
+        }
+        assert!(key_events(&sink).is_empty());
+        assert_eq!(parse_raw_keycode("767").unwrap(), 767);
     }
 
-    fn stop_emulating(&mut self) -> Result<(), EiTypeError> {
-        let serial = self.connection.serial();
-        self.device.device().stop_emulating(serial);
-        self.flush_with_retry()
+    #[test]
+    fn test_type_char_with_mods_unknown_modifier_types_nothing() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let err = typer.type_char_with_mods('a', &["hyperspace"]).unwrap_err();
+        assert!(matches!(err, EiTypeError::UnknownKey(_)));
+        assert!(key_events(&sink).is_empty());
     }
 
-    fn send_frame(&self) -> Result<(), EiTypeError> {
-        let serial = self.connection.serial();
-        let timestamp = get_timestamp();
-        self.device.device().frame(serial, timestamp);
-        self.flush_with_retry()
+    #[test]
+    fn test_try_type_text_completes_on_free_socket() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            delay_ms: 1000,
+            ..Default::default()
+        });
+        let mut cursor = TypeCursor::new("abcd");
+        let start = Instant::now();
+        assert_eq!(
+            typer.try_type_text(&mut cursor).unwrap(),
+            TryTypeStatus::Completed
+        );
+        // The key delay is ignored: try_type_text never sleeps
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(cursor.is_finished());
+        assert_eq!(cursor.typed(), 4);
+        assert_eq!(sink.presses(), vec![30, 48, 46, 32]);
     }
 
-    /// Flush the connection with retry logic for EAGAIN (WouldBlock) errors.
-    ///
-    /// When the socket buffer is full (common with long text input), flush()
-    /// returns EAGAIN. Instead of failing immediately, we wait for the buffer
-    /// to drain and retry.
-    fn flush_with_retry(&self) -> Result<(), EiTypeError> {
-        const MAX_RETRIES: u32 = 50;
-        const INITIAL_DELAY_MS: u64 = 1;
-        const MAX_DELAY_MS: u64 = 100;
+    #[test]
+    fn test_try_type_text_makes_progress_on_stalled_socket() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let mut cursor = TypeCursor::new("abcdef");
 
-        let mut retries = 0;
-        let mut delay_ms = INITIAL_DELAY_MS;
+        // Completely stalled: nothing is typed
+        sink.flush_budget.set(Some(0));
+        assert_eq!(
+            typer.try_type_text(&mut cursor).unwrap(),
+            TryTypeStatus::WouldBlock
+        );
+        assert_eq!(cursor.typed(), 0);
+        assert!(sink.presses().is_empty());
 
+        // The socket drains a little before each call and then stalls again:
+        // every call still moves forward
+        let mut calls = 0;
+        let mut typed = Vec::new();
         loop {
-            match self.connection.flush() {
-                Ok(()) => return Ok(()),
-                Err(e) => {
-                    // Check if this is EAGAIN/EWOULDBLOCK (errno 11 on Linux)
-                    // Use raw_os_error() to avoid rustix version mismatch issues
-                    // (reis uses a different rustix version than our direct dependency)
-                    let raw_errno = e.raw_os_error();
-                    let is_would_block = raw_errno == 11; // EAGAIN == EWOULDBLOCK on Linux
+            calls += 1;
+            assert!(calls < 20, "no forward progress");
+            sink.flush_budget.set(Some(6));
+            let status = typer.try_type_text(&mut cursor).unwrap();
+            typed.push(cursor.typed());
+            if status == TryTypeStatus::Completed {
+                break;
+            }
+        }
+        assert!(calls > 1);
+        // The last call may only flush what was already typed
+        assert!(typed.windows(2).all(|w| w[0] < w[1] || w[1] == 6));
+        assert_eq!(cursor.remaining(), "");
+        assert_eq!(sink.presses(), vec![30, 48, 46, 32, 18, 33]);
+    }
 
-                    if !is_would_block {
-                        // Not a recoverable error, fail immediately
-                        return Err(EiTypeError::Typing(e.to_string()));
-                    }
+    #[test]
+    fn test_try_type_text_error_keeps_cursor() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let mut cursor = TypeCursor::new("ab中c");
+        let err = typer.try_type_text(&mut cursor).unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('中')));
+        assert!(matches!(
+            err,
+            EiTypeError::InText {
+                index: 2,
+                byte_offset: 2,
+                ..
+            }
+        ));
+        assert_eq!(cursor.remaining(), "中c");
+        assert_eq!(sink.presses(), vec![30, 48]);
+    }
 
-                    retries += 1;
-                    if retries > MAX_RETRIES {
-                        return Err(EiTypeError::Typing(format!(
-                            "Socket buffer full after {} retries: {}",
-                            MAX_RETRIES, e
-                        )));
-                    }
+    #[test]
+    fn test_type_text_unchunked_by_default() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text("abcd").unwrap();
+        assert_eq!(sink.presses(), vec![30, 48, 46, 32]);
+        assert!(!sink.events().contains(&Recorded::Dispatch));
+    }
 
-                    trace!(
-                        "Socket buffer full (EAGAIN), waiting {}ms before retry {}/{}",
-                        delay_ms,
-                        retries,
-                        MAX_RETRIES
-                    );
+    #[test]
+    fn test_type_text_chunk_boundaries_and_pauses() {
+        let pause = Duration::from_millis(30);
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            chunk: Some(ChunkPolicy {
+                chars_per_chunk: 2,
+                pause_between: pause,
+            }),
+            ..Default::default()
+        });
+        let progress = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let progress_clone = progress.clone();
+        typer.set_progress_callback(move |p| progress_clone.borrow_mut().push(*p));
+        typer.clock = Clock::Fake(Cell::new(0));
+
+        typer.type_text("abcde").unwrap();
 
-                    std::thread::sleep(Duration::from_millis(delay_ms));
+        assert_eq!(sink.presses(), vec![30, 48, 46, 32, 18]);
+        // Boundaries after 'b' and 'd'; none after the final partial chunk
+        let boundaries: Vec<usize> = progress
+            .borrow()
+            .iter()
+            .filter(|p| p.chunk_boundary)
+            .map(|p| p.typed)
+            .collect();
+        assert_eq!(boundaries, vec![2, 4]);
+        assert_eq!(progress.borrow().last().unwrap().total, 5);
 
-                    // Exponential backoff with cap
-                    delay_ms = (delay_ms * 2).min(MAX_DELAY_MS);
-                }
-            }
+        // Events are drained once per boundary, right before the next chunk
+        let events = sink.events();
+        let dispatches: Vec<usize> = events
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| **e == Recorded::Dispatch)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(dispatches.len(), 2);
+        for i in dispatches {
+            assert!(matches!(events[i + 1], Recorded::Key(_, true)));
         }
+        // Each chunk's frames are stamped exactly one pause after the last
+        // chunk's (the clock only moves while pausing)
+        let p = pause.as_micros() as u64;
+        assert_eq!(
+            *sink.frame_timestamps.borrow(),
+            vec![0, 0, 0, 0, p, p, p, p, 2 * p, 2 * p]
+        );
     }
 
-    fn press_key_internal(&self, keycode: u32) -> Result<(), EiTypeError> {
-        trace!("Pressing key: {}", keycode);
-        self.keyboard.key(keycode, KeyState::Press);
-        self.send_frame()?;
-        Ok(())
+    #[test]
+    fn test_type_timed() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.clock = Clock::Fake(Cell::new(0));
+        let items = [
+            ('h', Duration::from_millis(30)),
+            ('I', Duration::ZERO),
+            ('!', Duration::from_millis(60)),
+        ];
+        typer.type_timed(&items).unwrap();
+        // The last delay is waited out too
+        assert_eq!(typer.clock.now(), 90_000);
+        assert_eq!(sink.presses(), vec![35, 42, 23, 42, 2]);
+
+        // Each pause comes between a character's last release and the next
+        // character's first press: 'h' is stamped before the first pause,
+        // 'I' and '!' after it
+        let timestamps = sink.frame_timestamps.borrow().clone();
+        assert_eq!(timestamps.len(), key_events(&sink).len());
+        let h_frames = key_events(&sink)
+            .iter()
+            .take_while(|&&e| e != (42, true))
+            .count();
+        assert_eq!(h_frames, 2);
+        assert!(timestamps[..h_frames].iter().all(|&t| t == 0));
+        assert!(timestamps[h_frames..].iter().all(|&t| t == 30_000));
+
+        // Errors point at the failing character
+        let err = typer
+            .type_timed(&[('a', Duration::ZERO), ('☃', Duration::ZERO)])
+            .unwrap_err();
+        assert!(
+            matches!(err, EiTypeError::InText { index: 1, .. }),
+            "{:?}",
+            err
+        );
     }
 
-    fn release_key_internal(&self, keycode: u32) -> Result<(), EiTypeError> {
-        trace!("Releasing key: {}", keycode);
-        self.keyboard.key(keycode, KeyState::Released);
-        self.send_frame()?;
-        Ok(())
+    #[test]
+    fn test_cancel_before_typing() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.cancel_handle().cancel();
+        let err = typer.type_text("abc").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::Cancelled));
+        assert!(sink.presses().is_empty());
+        // The cancellation is consumed; the next call types normally
+        typer.type_text("a").unwrap();
+        assert_eq!(sink.presses(), vec![30]);
     }
 
-    fn tap_key_internal(&self, keycode: u32) -> Result<(), EiTypeError> {
-        self.press_key_internal(keycode)?;
-        if !self.delay.is_zero() {
-            std::thread::sleep(self.delay);
-        }
-        self.release_key_internal(keycode)?;
-        if !self.delay.is_zero() {
-            std::thread::sleep(self.delay);
+    #[test]
+    fn test_nested_modifier_holds() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.hold_modifier("shift").unwrap();
+        typer.hold_modifier("shift").unwrap();
+        // Typing an uppercase letter neither presses Shift again nor lets it go
+        typer.type_text("A").unwrap();
+        typer.key_up("shift").unwrap();
+        assert_eq!(key_events(&sink), vec![(42, true), (30, true), (30, false)]);
+
+        // The last hold releases it
+        typer.key_up("shift").unwrap();
+        assert_eq!(key_events(&sink).last(), Some(&(42, false)));
+        assert!(typer.held_modifiers.is_empty());
+        assert!(matches!(typer.key_up("shift"), Err(EiTypeError::Typing(_))));
+    }
+
+    #[test]
+    fn test_duplicate_press_and_stray_release_skipped() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.events.borrow_mut().clear();
+        typer.press_key_internal(30).unwrap();
+        typer.press_key_internal(30).unwrap();
+        typer.release_key_internal(30).unwrap();
+        typer.release_key_internal(30).unwrap();
+        typer.release_key_internal(48).unwrap();
+        assert_eq!(key_events(&sink), vec![(30, true), (30, false)]);
+        // No empty frames for the skipped events
+        let frames = sink
+            .events()
+            .iter()
+            .filter(|e| **e == Recorded::Frame)
+            .count();
+        assert_eq!(frames, 2);
+        assert_eq!(typer.stats().keys_pressed, 1);
+    }
+
+    #[test]
+    fn test_strict_key_state_errors() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            strict_key_state: true,
+            ..Default::default()
+        });
+        typer.press_key_internal(30).unwrap();
+        assert!(matches!(
+            typer.press_key_internal(30),
+            Err(EiTypeError::Typing(_))
+        ));
+        typer.release_key_internal(30).unwrap();
+        assert!(matches!(
+            typer.release_key_internal(30),
+            Err(EiTypeError::Typing(_))
+        ));
+
+        // Nested holds are still fine, but typing Shift while it's held isn't
+        typer.hold_modifier("shift").unwrap();
+        typer.hold_modifier("shift").unwrap();
+        assert!(typer.type_text("A").is_err());
+        typer.release_modifiers().unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![(30, true), (30, false), (42, true), (42, false)]
+        );
+    }
+
+    #[test]
+    fn test_execute_actions_repeated() {
+        let delay = Duration::from_millis(30);
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        sink.events.borrow_mut().clear();
+        let actions = vec![
+            Action::ModifierHold("shift".to_string()),
+            Action::Type("a".to_string()),
+            Action::Key("tab".to_string()),
+        ];
+
+        let (completed, reports, result) = typer.execute_actions_repeated(&actions, 3, delay);
+        result.unwrap();
+        assert_eq!(completed, 3);
+        assert_eq!(reports.len(), 3);
+        assert!(reports.iter().all(|r| r.status == ActionStatus::Ok));
+
+        let iteration = [
+            (42, true),
+            (30, true),
+            (30, false),
+            (15, true),
+            (15, false),
+            (42, false),
+        ];
+        assert_eq!(key_events(&sink), iteration.repeat(3));
+
+        // Each iteration starts only after the delay
+        let events = sink.events.borrow();
+        let shift_presses: Vec<Instant> = events
+            .iter()
+            .filter(|(_, e)| *e == Recorded::Key(42, true))
+            .map(|(at, _)| *at)
+            .collect();
+        let shift_releases: Vec<Instant> = events
+            .iter()
+            .filter(|(_, e)| *e == Recorded::Key(42, false))
+            .map(|(at, _)| *at)
+            .collect();
+        for i in 1..3 {
+            assert!(shift_presses[i].duration_since(shift_releases[i - 1]) >= delay);
         }
-        Ok(())
     }
 
-    fn type_char(&self, ch: char) -> Result<(), EiTypeError> {
-        trace!("Typing character: {:?}", ch);
+    #[test]
+    fn test_execute_actions_repeated_stops_when_cancelled() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let handle = typer.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
 
-        if let Some(keymap) = &self.keymap {
-            let key_match = find_keycode_for_char(ch, keymap, self.layout_index)?;
-            let mod_keycodes =
-                modifier_keycodes_for_match(keymap, &key_match, &self.keymap_mod_keycodes);
+        let actions = vec![Action::Type("a".to_string())];
+        let started = Instant::now();
+        let (completed, _, result) =
+            typer.execute_actions_repeated(&actions, 5, Duration::from_secs(10));
+        canceller.join().unwrap();
 
-            for &mkc in &mod_keycodes {
-                self.press_key_internal(mkc)?;
-            }
+        assert!(matches!(result, Err(EiTypeError::Cancelled)));
+        assert_eq!(completed, 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(sink.presses(), vec![30]);
+    }
 
-            self.tap_key_internal(key_match.evdev_keycode)?;
+    #[test]
+    fn test_cancel_interrupts_chunk_pause() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            chunk: Some(ChunkPolicy {
+                chars_per_chunk: 1,
+                pause_between: Duration::from_secs(10),
+            }),
+            ..Default::default()
+        });
+        let handle = typer.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
 
-            for &mkc in mod_keycodes.iter().rev() {
-                self.release_key_internal(mkc)?;
-            }
-        } else {
-            // Fallback when no keymap: use hardcoded QWERTY map
-            let ch_lower = ch.to_ascii_lowercase();
-            if let Some(&keycode) = self.key_to_keycode.get(&ch_lower.to_string()) {
-                let need_shift = ch.is_ascii_uppercase();
+        let started = Instant::now();
+        let result = typer.type_text("ab");
+        canceller.join().unwrap();
 
-                if need_shift {
-                    let shift_keycode = self.key_to_keycode.get("shift").copied().unwrap_or(42);
-                    self.press_key_internal(shift_keycode)?;
-                }
+        assert!(matches!(result.unwrap_err().root(), EiTypeError::Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(sink.presses(), vec![30]);
+    }
 
-                self.tap_key_internal(keycode)?;
+    #[test]
+    fn test_type_text_error_position_and_context() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let text = "The quick brown fox jumps over the lazy ж dog and keeps running far away";
+        let err = typer.type_text(text).unwrap_err();
+        match &err {
+            EiTypeError::InText {
+                index,
+                byte_offset,
+                context,
+                source,
+            } => {
+                assert_eq!(*index, 40);
+                assert_eq!(*byte_offset, 40);
+                assert_eq!(context, " over the lazy ж dog and keeps ");
+                assert!(matches!(**source, EiTypeError::CharNotFound('ж')));
+            }
+            other => panic!("expected InText, got {:?}", other),
+        }
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('ж')));
+    }
 
-                if need_shift {
-                    let shift_keycode = self.key_to_keycode.get("shift").copied().unwrap_or(42);
-                    self.release_key_internal(shift_keycode)?;
-                }
-            } else {
-                warn!("Could not find keycode for character: {:?}", ch);
-                return Err(EiTypeError::CharNotFound(ch));
+    #[test]
+    fn test_error_in_text_multibyte_context() {
+        // Multi-byte characters around the failure must not be split
+        let text = "ééééééééééééééééééé€ñññññññññññññññññ";
+        let index = 19;
+        let byte_offset = text.char_indices().nth(index).unwrap().0;
+        let err = error_in_text(EiTypeError::CharNotFound('€'), text, index, byte_offset);
+        match err {
+            EiTypeError::InText {
+                byte_offset,
+                context,
+                ..
+            } => {
+                assert_eq!(byte_offset, 38);
+                assert_eq!(context, format!("{}€{}", "é".repeat(15), "ñ".repeat(15)));
             }
+            other => panic!("expected InText, got {:?}", other),
         }
+    }
 
-        Ok(())
+    #[test]
+    fn test_error_in_text_context_at_start() {
+        let err = error_in_text(EiTypeError::CharNotFound('x'), "xyz", 0, 0);
+        assert!(matches!(err, EiTypeError::InText { ref context, .. } if context == "xyz"));
     }
 
-    /// Type a string of text
-    pub fn type_text(&self, text: &str) -> Result<(), EiTypeError> {
-        debug!("Typing text: {:?}", text);
-        for ch in text.chars() {
-            self.type_char(ch)?;
-        }
-        Ok(())
+    #[test]
+    fn test_type_keysym_names_eacute() {
+        // é is AltGr+e (level 2) on us-intl
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            variant: Some("intl".to_string()),
+            ..Default::default()
+        });
+        typer.type_keysym_names(&["eacute"]).unwrap();
+        assert_eq!(
+            sink.events()
+                .into_iter()
+                .filter(|e| matches!(e, Recorded::Key(..)))
+                .collect::<Vec<_>>(),
+            vec![
+                Recorded::Key(100, true),
+                Recorded::Key(18, true),
+                Recorded::Key(18, false),
+                Recorded::Key(100, false),
+            ]
+        );
     }
 
-    /// Press and release a special key (e.g., "Return", "Tab", "Escape")
-    pub fn press_key(&self, key_name: &str) -> Result<(), EiTypeError> {
-        let keycode = self
-            .key_to_keycode
-            .get(&key_name.to_lowercase())
-            .copied()
-            .ok_or_else(|| EiTypeError::UnknownKey(key_name.to_string()))?;
+    #[test]
+    fn test_type_keysym_names_cyrillic_de() {
+        // д is on the L key on the Russian layout
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("ru".to_string()),
+            ..Default::default()
+        });
+        typer.type_keysym_names(&["Cyrillic_de"]).unwrap();
+        assert_eq!(sink.presses(), vec![38]);
+    }
 
-        debug!("Pressing special key: {} (keycode {})", key_name, keycode);
-        self.tap_key_internal(keycode)
+    #[test]
+    fn test_type_keysym_names_unknown_name_types_nothing() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let err = typer.type_keysym_names(&["a", "not_a_keysym"]).unwrap_err();
+        assert!(matches!(err, EiTypeError::UnknownKey(ref n) if n == "not_a_keysym"));
+        assert!(sink.presses().is_empty());
     }
 
-    /// Hold a modifier key (will be released when release_modifiers is called)
-    pub fn hold_modifier(&mut self, mod_name: &str) -> Result<(), EiTypeError> {
-        let keycode = self
-            .key_to_keycode
-            .get(&mod_name.to_lowercase())
-            .copied()
-            .ok_or_else(|| EiTypeError::UnknownKey(mod_name.to_string()))?;
+    #[test]
+    fn test_keysym_from_name() {
+        assert_eq!(keysym_from_name("eacute").map(|k| k.raw()), Some(0x00e9));
+        assert_eq!(
+            keysym_from_name("Cyrillic_de").map(|k| k.raw()),
+            Some(0x06c4)
+        );
+        assert_eq!(keysym_from_name("return").map(|k| k.raw()), Some(0xff0d));
+        assert!(keysym_from_name("not_a_keysym").is_none());
+    }
 
-        debug!("Holding modifier: {} (keycode {})", mod_name, keycode);
-        self.press_key_internal(keycode)?;
-        self.held_modifiers.push(keycode);
-        Ok(())
+    fn keymap_with_options(layout: &str, options: &str) -> xkb::Keymap {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            layout,
+            "",
+            Some(options.to_string()),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .expect("system xkb data required (install xkeyboard-config)")
     }
 
-    /// Press and release a modifier key (like a regular key press)
-    pub fn press_modifier(&self, mod_name: &str) -> Result<(), EiTypeError> {
-        let keycode = self
-            .key_to_keycode
-            .get(&mod_name.to_lowercase())
-            .copied()
-            .ok_or_else(|| EiTypeError::UnknownKey(mod_name.to_string()))?;
+    #[test]
+    fn test_convert_layout_us_ru() {
+        let keymap = keymap_with_options("us,ru", "");
+        assert_eq!(convert_layout("ghbdtn", 0, 1, &keymap), "привет");
+        assert_eq!(
+            convert_layout("Ghbdtn? rfr ltkf", 0, 1, &keymap),
+            "Привет, как дела"
+        );
+        assert_eq!(convert_layout("руддщ цщкдв", 1, 0, &keymap), "hello world");
+        // Round trip, and characters missing from the source layout stay put
+        assert_eq!(convert_layout("привет", 0, 1, &keymap), "привет");
+        assert_eq!(
+            convert_layout(&convert_layout("ntcn 123", 0, 1, &keymap), 1, 0, &keymap),
+            "ntcn 123"
+        );
+        // Out-of-range target layouts leave the text alone
+        assert_eq!(convert_layout("ghbdtn", 0, 5, &keymap), "ghbdtn");
+    }
 
-        debug!("Pressing modifier: {} (keycode {})", mod_name, keycode);
-        self.tap_key_internal(keycode)
+    #[test]
+    fn test_retype_converted_deletes_then_types() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            ..Default::default()
+        });
+        typer.retype_converted("руддщ", 1, 0).unwrap();
+        let mut expected = vec![14; 5];
+        expected.extend([35, 18, 38, 38, 24]);
+        assert_eq!(sink.presses(), expected);
     }
 
-    /// Release all held modifiers
-    pub fn release_modifiers(&mut self) -> Result<(), EiTypeError> {
-        for keycode in self.held_modifiers.drain(..).rev().collect::<Vec<_>>() {
-            debug!("Releasing held modifier keycode {}", keycode);
-            self.release_key_internal(keycode)?;
-        }
-        Ok(())
+    #[test]
+    fn test_delete_chars() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.delete_chars(0).unwrap();
+        assert!(sink.presses().is_empty());
+        typer.delete_chars(3).unwrap();
+        assert_eq!(sink.presses(), vec![14; 3]);
+
+        typer.cancel_handle().cancel();
+        assert!(matches!(typer.delete_chars(2), Err(EiTypeError::Cancelled)));
+        assert_eq!(sink.presses().len(), 3);
     }
 
-    /// Execute a sequence of actions
-    pub fn execute_actions(&mut self, actions: &[Action]) -> Result<(), EiTypeError> {
-        info!("Executing {} actions", actions.len());
+    #[test]
+    fn test_common_prefix_edit() {
+        assert_eq!(common_prefix_edit("", "hello"), (0, "hello"));
+        assert_eq!(common_prefix_edit("hello", "help"), (3, "p"));
+        assert_eq!(common_prefix_edit("hello", "hello world"), (5, " world"));
+        assert_eq!(common_prefix_edit("hello world", "hello"), (5, ""));
+        assert_eq!(common_prefix_edit("abc", "xyz"), (0, "xyz"));
+        assert_eq!(common_prefix_edit("naïve", "naïf"), (3, "f"));
+    }
 
-        for action in actions {
-            match action {
-                Action::Type(text) => {
-                    self.type_text(text)?;
-                }
-                Action::Key(key_name) => {
-                    self.press_key(key_name)?;
-                }
-                Action::ModifierHold(mod_name) => {
-                    self.hold_modifier(mod_name)?;
-                }
-                Action::ModifierPress(mod_name) => {
-                    self.press_modifier(mod_name)?;
+    #[test]
+    fn test_char_offset() {
+        assert_eq!(char_offset("naïve", 0), Some(0));
+        assert_eq!(char_offset("naïve", 3), Some(4));
+        assert_eq!(char_offset("naïve", 5), Some(6));
+        assert_eq!(char_offset("naïve", 6), None);
+        assert_eq!(char_offset("", 0), Some(0));
+    }
+
+    /// The text an editor shows after the recorded key events: xkb's text
+    /// for each press, with Backspace deleting the last character
+    fn visible_text(typer: &EiType, sink: &RecordingSink) -> String {
+        let planner = typer.planner.borrow();
+        let mut state = xkb::State::new(planner.keymap().unwrap());
+        let mut text = String::new();
+        for (keycode, pressed) in key_events(sink) {
+            let kc = xkb::Keycode::new(keycode + 8);
+            if pressed {
+                if keycode == 14 {
+                    text.pop();
+                } else {
+                    text.push_str(&state.key_get_utf8(kc));
                 }
+                state.update_key(kc, xkb::KeyDirection::Down);
+            } else {
+                state.update_key(kc, xkb::KeyDirection::Up);
             }
         }
+        text
+    }
 
-        // Release any held modifiers
-        self.release_modifiers()?;
+    #[test]
+    fn test_type_session_revise_sequence() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let mut session = TypeSession::new(&typer);
+        // Partial speech recognition results, each revising the last
+        session.feed(&typer, "I scream").unwrap();
+        session.revise(&typer, 0, "Ice cream").unwrap();
+        session.feed(&typer, " is").unwrap();
+        session.revise(&typer, 10, "isn't bad").unwrap();
+        session.revise(&typer, 10, "is great!").unwrap();
+        assert_eq!(session.text(), "Ice cream is great!");
+        assert_eq!(visible_text(&typer, &sink), "Ice cream is great!");
 
-        Ok(())
-    }
+        // Only the differing tail was deleted: "scream" -> "ce cream" keeps
+        // "I", and "isn't bad" -> "is great!" keeps "is"
+        let backspaces = sink.presses().iter().filter(|&&kc| kc == 14).count();
+        assert_eq!(backspaces, 7 + 7);
 
-    /// Explicitly close the connection and release all resources.
-    ///
-    /// This method should be called when you're done with the EiType instance,
-    /// especially before attempting to create a new connection. While the Drop
-    /// implementation will clean up automatically, calling close() explicitly
-    /// ensures proper cleanup and avoids potential issues with reconnection.
-    ///
-    /// After calling close(), this instance should not be used anymore.
-    pub fn close(&mut self) {
-        if self.closed {
-            return;
-        }
-        self.closed = true;
+        let outcome = session.finish(&typer);
+        assert_eq!(outcome.chars_requested, 19);
+        assert_eq!(outcome.chars_delivered, 19);
+        assert!(outcome.skipped.is_empty());
+        assert_eq!(
+            outcome.stats.chars_typed,
+            "I scream".len() + "ce cream".len() + " is".len() + "n't bad".len() + " great!".len()
+        );
+    }
 
-        debug!("Closing EiType connection");
+    #[test]
+    fn test_type_session_skipped_chars() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let mut session = TypeSession::new(&typer);
+        session.feed(&typer, "a😀b").unwrap();
+        // The emoji was never typed, so only "b" needs deleting
+        session.revise(&typer, 1, "😀c").unwrap();
+        session.feed(&typer, "∞").unwrap();
+        assert_eq!(visible_text(&typer, &sink), "ac");
+        assert_eq!(sink.presses().iter().filter(|&&kc| kc == 14).count(), 1);
 
-        // Release any held modifiers
-        let _ = self.release_modifiers();
+        let outcome = session.finish(&typer);
+        assert_eq!(outcome.chars_requested, 4);
+        assert_eq!(outcome.chars_delivered, 2);
+        assert_eq!(
+            outcome
+                .skipped
+                .iter()
+                .map(|s| (s.index, s.ch))
+                .collect::<Vec<_>>(),
+            vec![(1, '😀'), (3, '∞')]
+        );
+    }
 
-        // Stop emulating
-        let _ = self.stop_emulating();
+    #[test]
+    fn test_type_session_rejects_index_past_end() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let mut session = TypeSession::new(&typer);
+        session.feed(&typer, "ab").unwrap();
+        sink.events.borrow_mut().clear();
+        assert!(matches!(
+            session.revise(&typer, 3, "c"),
+            Err(EiTypeError::Typing(_))
+        ));
+        assert!(sink.presses().is_empty());
+        session.revise(&typer, 2, "c").unwrap();
+        assert_eq!(session.text(), "abc");
+    }
 
-        // Send disconnect request to the EI server
-        // This tells the server we're intentionally disconnecting
-        self.connection.connection().disconnect();
+    #[test]
+    fn test_find_group_switch_keys_lock_toggle() {
+        let keymap = keymap_with_options("us,ru", "grp:sclk_toggle");
+        let keys = find_group_switch_keys(&keymap);
+        assert_eq!(
+            keys.first(),
+            Some(&GroupSwitchKey {
+                evdev_keycode: 70,
+                kind: GroupSwitchKind::Lock
+            }),
+            "Scroll Lock should be the first (real) switch key, got {:?}",
+            keys
+        );
+    }
 
-        // Flush to ensure the disconnect message is sent
-        let _ = self.connection.flush();
+    #[test]
+    fn test_find_group_switch_keys_momentary() {
+        let keymap = keymap_with_options("us,ru", "grp:switch");
+        let keys = find_group_switch_keys(&keymap);
+        assert_eq!(
+            keys.first(),
+            Some(&GroupSwitchKey {
+                evdev_keycode: 100,
+                kind: GroupSwitchKind::Momentary
+            }),
+            "Right Alt should switch groups while held, got {:?}",
+            keys
+        );
+    }
 
-        debug!("EiType connection closed");
+    #[test]
+    fn test_find_group_switch_keys_single_layout() {
+        assert!(find_group_switch_keys(&system_keymap("us", "")).is_empty());
     }
-}
 
-impl Drop for EiType {
-    fn drop(&mut self) {
-        // Use close() which handles all cleanup including EI disconnect
-        self.close();
+    #[test]
+    fn test_plan_group_switch_lock_returns_to_original() {
+        let keymap = keymap_with_options("us,ru", "grp:sclk_toggle");
+        let keys = find_group_switch_keys(&keymap);
+        let plan = plan_group_switch(&keymap, &keys, 0, 1, 38).unwrap();
+        assert_eq!(plan.kind, GroupSwitchKind::Lock);
+        assert_eq!(plan.enter, vec![(70, true), (70, false)]);
+        assert_eq!(plan.exit, vec![(70, true), (70, false)]);
     }
-}
 
-// ============================================================================
-// Python Bindings
-// ============================================================================
+    #[test]
+    fn test_plan_group_switch_lock_cycles_three_groups() {
+        // ISO_Next_Group cycles 0 -> 1 -> 2 -> 0: one tap in, two taps back
+        let keymap = keymap_with_options("us,ru,de", "grp:sclk_toggle");
+        let keys = find_group_switch_keys(&keymap);
+        let plan = plan_group_switch(&keymap, &keys, 0, 1, 38).unwrap();
+        assert_eq!(plan.enter.len(), 2);
+        assert_eq!(plan.exit.len(), 4);
+        let mut sequence = plan.enter.clone();
+        sequence.extend(&plan.exit);
+        assert_eq!(simulate_group(&keymap, 0, &sequence), (0, 0));
+    }
 
-#[cfg(feature = "python")]
-#[pymethods]
-impl EiType {
-    /// Connect via the XDG RemoteDesktop portal (simple version)
-    #[staticmethod]
-    #[pyo3(signature = (config=None))]
-    fn py_connect_portal(config: Option<EiTypeConfig>) -> PyResult<Self> {
-        let config = config.unwrap_or_default();
-        Self::connect_portal(config)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    #[test]
+    fn test_plan_group_switch_momentary() {
+        let keymap = keymap_with_options("us,ru", "grp:switch");
+        let keys = find_group_switch_keys(&keymap);
+        let plan = plan_group_switch(&keymap, &keys, 0, 1, 38).unwrap();
+        assert_eq!(plan.kind, GroupSwitchKind::Momentary);
+        assert_eq!(plan.enter, vec![(100, true)]);
+        assert_eq!(plan.exit, vec![(100, false)]);
     }
 
-    /// Connect via the XDG RemoteDesktop portal with token support
-    #[staticmethod]
-    #[pyo3(signature = (restore_token=None, config=None))]
-    fn py_connect_portal_with_token(
-        restore_token: Option<&str>,
-        config: Option<EiTypeConfig>,
-    ) -> PyResult<(Self, Option<String>)> {
-        let config = config.unwrap_or_default();
-        Self::connect_portal_with_token(config, restore_token)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    #[test]
+    fn test_keystroke_hook_runs_once_per_key_frame() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let seen = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let seen_clone = seen.clone();
+        typer.set_keystroke_hook(move |info| seen_clone.borrow_mut().push(info));
+
+        typer.type_text("Hi").unwrap();
+        typer.press_key("return").unwrap();
+
+        let events = sink.events();
+        let key_frames = events
+            .windows(2)
+            .filter(|w| matches!(w, [Recorded::Key(..), Recorded::Frame]))
+            .count();
+        let seen = seen.borrow();
+        assert_eq!(seen.len(), key_frames);
+        let keys: Vec<(u32, bool)> = seen.iter().map(|i| (i.keycode, i.pressed)).collect();
+        assert_eq!(keys, key_events(&sink));
+
+        // Shift is reported as part of 'H'; named keys have no character
+        let chars: Vec<Option<char>> = seen.iter().map(|i| i.ch).collect();
+        let h = Some('H');
+        let i = Some('i');
+        assert_eq!(chars, vec![h, h, h, h, i, i, None, None]);
+        assert!(seen.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
     }
 
-    /// Connect via a Unix socket
-    #[staticmethod]
-    #[pyo3(signature = (path, config=None))]
-    fn py_connect_socket(path: &str, config: Option<EiTypeConfig>) -> PyResult<Self> {
-        let config = config.unwrap_or_default();
-        Self::connect_socket(Path::new(path), config)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    #[test]
+    fn test_keystroke_hook_redaction_and_panics() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let calls = std::rc::Rc::new(Cell::new(0));
+        let calls_clone = calls.clone();
+        typer.set_redact_keystrokes(true);
+        typer.set_keystroke_hook(move |info| {
+            assert_eq!(info.ch, None);
+            calls_clone.set(calls_clone.get() + 1);
+            if calls_clone.get() == 3 {
+                panic!("hook failure");
+            }
+        });
+
+        // The panicking hook is dropped, but typing finishes
+        typer.type_text("abc").unwrap();
+        assert_eq!(calls.get(), 3);
+        assert_eq!(sink.presses(), vec![30, 48, 46]);
     }
 
-    /// Type a string of text
-    #[pyo3(name = "type_text")]
-    fn py_type_text(&self, text: &str) -> PyResult<()> {
-        self.type_text(text)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    fn key_events(sink: &RecordingSink) -> Vec<(u32, bool)> {
+        sink.events()
+            .into_iter()
+            .filter_map(|e| match e {
+                Recorded::Key(kc, pressed) => Some((kc, pressed)),
+                _ => None,
+            })
+            .collect()
     }
 
-    /// Press and release a special key
-    #[pyo3(name = "press_key")]
-    fn py_press_key(&self, key_name: &str) -> PyResult<()> {
-        self.press_key(key_name)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    /// Gaps between the frames of successive key events, in fake clock
+    /// microseconds (each key event gets its own frame); clears the sink
+    fn key_event_gaps(sink: &RecordingSink) -> Vec<u64> {
+        let timestamps: Vec<u64> = sink.frame_timestamps.borrow_mut().drain(..).collect();
+        assert_eq!(timestamps.len(), key_events(sink).len());
+        sink.events.borrow_mut().clear();
+        timestamps
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .collect()
     }
 
-    /// Hold a modifier key
-    #[pyo3(name = "hold_modifier")]
-    fn py_hold_modifier(&mut self, mod_name: &str) -> PyResult<()> {
-        self.hold_modifier(mod_name)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    #[test]
+    fn test_no_keymap_fallback_types_shifted_symbols() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.planner.get_mut().keymap = None;
+        typer.type_text("!@?").unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (42, true),
+                (2, true),
+                (2, false),
+                (42, false),
+                (42, true),
+                (3, true),
+                (3, false),
+                (42, false),
+                (42, true),
+                (53, true),
+                (53, false),
+                (42, false),
+            ]
+        );
     }
 
-    /// Press and release a modifier key
-    #[pyo3(name = "press_modifier")]
-    fn py_press_modifier(&self, mod_name: &str) -> PyResult<()> {
-        self.press_modifier(mod_name)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    #[test]
+    fn test_no_keymap_fallback_unshifted_and_unmappable() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.planner.get_mut().keymap = None;
+        typer.type_text("a1/").unwrap();
+        assert_eq!(sink.presses(), vec![30, 2, 53]);
+        assert_eq!(typer.stats().qwerty_fallback_chars, 3);
+
+        let err = typer.type_text("é").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('é')));
     }
 
-    /// Release all held modifiers
-    #[pyo3(name = "release_modifiers")]
-    fn py_release_modifiers(&mut self) -> PyResult<()> {
-        self.release_modifiers()
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    #[test]
+    fn test_type_text_report_skips_missing_chars() {
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let outcome = typer.type_text_report("a😀b∞c").unwrap();
+        assert_eq!(sink.presses(), vec![30, 48, 46]);
+        assert_eq!(outcome.chars_requested, 5);
+        assert_eq!(outcome.chars_delivered, 3);
+        assert_eq!(
+            outcome
+                .skipped
+                .iter()
+                .map(|s| (s.index, s.ch))
+                .collect::<Vec<_>>(),
+            vec![(1, '😀'), (3, '∞')]
+        );
+        assert_eq!(
+            outcome.skipped[0].reason,
+            EiTypeError::CharNotFound('😀').to_string()
+        );
+        assert!(outcome.fallbacks.is_empty());
+        assert_eq!(outcome.stats.chars_typed, 3);
+        assert_eq!(outcome.stats.keys_pressed, 3);
+
+        // type_text still fails on the first missing character
+        assert!(typer.type_text("a😀").is_err());
     }
 
-    /// Close the connection and release all resources.
-    ///
-    /// This method should be called when you're done with the EiType instance,
-    /// especially before attempting to create a new connection. While the
-    /// destructor will clean up automatically, calling close() explicitly
-    /// ensures proper cleanup and avoids potential issues with reconnection.
-    ///
-    /// After calling close(), this instance should not be used anymore.
-    #[pyo3(name = "close")]
-    fn py_close(&mut self) {
-        self.close();
+    #[test]
+    fn test_format_chars_stripped() {
+        let salted = "\u{FEFF}a\u{200B}b\u{00AD}c\u{2060}\u{200E} d";
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.type_text(salted).unwrap();
+        assert_eq!(sink.presses(), vec![30, 48, 46, 57, 32]);
+        assert_eq!(typer.stats().format_chars_stripped, 5);
+        let outcome = typer.type_text_report(salted).unwrap();
+        assert_eq!(outcome.chars_requested, 10);
+        assert_eq!(outcome.chars_delivered, 5);
+        assert_eq!(outcome.format_chars_stripped, 5);
+        assert!(outcome.skipped.is_empty());
+
+        // Visible characters are left alone, ZWJ sequences included
+        let (typer, sink) = recording_eitype(EiTypeConfig::default());
+        let outcome = typer.type_text_report("x\u{00A0}👩\u{200D}💻").unwrap();
+        assert_eq!(sink.presses(), vec![45]);
+        assert_eq!(outcome.format_chars_stripped, 0);
+        assert_eq!(outcome.skipped.len(), 4);
+
+        // Without stripping, the error names the invisible character
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            strip_format_chars: false,
+            ..Default::default()
+        });
+        let err = typer.type_text(salted).unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('\u{FEFF}')));
+        assert!(
+            err.to_string().starts_with(
+                "Character not found in keymap: U+FEFF ZERO WIDTH NO-BREAK SPACE at character 0"
+            ),
+            "{}",
+            err
+        );
+        assert!(sink.presses().is_empty());
+        let outcome = typer.type_text_report(salted).unwrap();
+        assert_eq!(outcome.skipped.len(), 5);
+        assert_eq!(
+            outcome.skipped[1].reason,
+            "Character not found in keymap: U+200B ZERO WIDTH SPACE"
+        );
+        assert_eq!(outcome.format_chars_stripped, 0);
     }
 
-    /// Context manager entry - returns self for use with `with` statement.
-    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
-        slf
-    }
+    #[test]
+    fn test_press_key_by_keycap_char() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("ru".to_string()),
+            ..Default::default()
+        });
+        // ж is on the semicolon key; Ж is its shifted level
+        typer.press_key("ж").unwrap();
+        typer.press_key("Ж").unwrap();
+        typer.press_key("ctrl+ж").unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (39, true),
+                (39, false),
+                (42, true),
+                (39, true),
+                (39, false),
+                (42, false),
+                (29, true),
+                (39, true),
+                (39, false),
+                (29, false),
+            ]
+        );
+        let err = typer.press_key("ctrl+ĳ").unwrap_err();
+        assert!(matches!(err, EiTypeError::CharNotFound('ĳ')), "{:?}", err);
+        let err = typer.press_key("жж").unwrap_err();
+        assert!(matches!(err, EiTypeError::UnknownKey(ref n) if n == "жж"));
 
-    /// Context manager exit - closes the connection.
-    ///
-    /// This ensures proper cleanup when used with `with` statements:
-    /// ```python
-    /// with EiType.connect_portal() as typer:
-    ///     typer.type_text("Hello!")
-    /// # Connection is automatically closed here
-    /// ```
-    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
-    fn __exit__(
-        &mut self,
-        _exc_type: Option<&Bound<'_, PyAny>>,
-        _exc_value: Option<&Bound<'_, PyAny>>,
-        _traceback: Option<&Bound<'_, PyAny>>,
-    ) -> bool {
-        self.close();
-        false // Don't suppress exceptions
+        // On a layout that isn't active, through the group-switch key
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            options: Some("grp:sclk_toggle".to_string()),
+            ..Default::default()
+        });
+        typer.press_key("ж").unwrap();
+        assert_eq!(sink.presses(), vec![70, 39, 70]);
     }
-}
 
-#[cfg(feature = "python")]
-#[pymodule]
-fn eitype(m: &Bound<'_, PyModule>) -> PyResult<()> {
-    m.add_class::<EiType>()?;
-    m.add_class::<EiTypeConfig>()?;
-    Ok(())
-}
+    #[test]
+    fn test_type_text_report_fallbacks() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            options: Some("grp:sclk_toggle".to_string()),
+            ..Default::default()
+        });
+        let outcome = typer.type_text_report("aд😀").unwrap();
+        assert_eq!(outcome.chars_delivered, 2);
+        assert_eq!(outcome.skipped.len(), 1);
+        assert_eq!(outcome.fallbacks, vec!["group_switch"]);
+        assert_eq!(outcome.stats.group_switches, 1);
 
-// ============================================================================
-// Tests
-// ============================================================================
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        typer.planner.get_mut().keymap = None;
+        let outcome = typer.type_text_report("é1").unwrap();
+        assert_eq!(outcome.chars_delivered, 1);
+        assert_eq!(outcome.skipped[0].index, 0);
+        assert_eq!(outcome.fallbacks, vec!["qwerty_fallback"]);
+        assert_eq!(outcome.stats.qwerty_fallback_chars, 1);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// A US keymap that can type some, but not all, scalars of the
+    /// clusters in `test_grapheme_clusters_*`: 👩 and 🇩 on 1, a combining
+    /// acute on 2, the zero width joiner on 3
+    #[cfg(feature = "graphemes")]
+    fn partial_cluster_config() -> EiTypeConfig {
+        EiTypeConfig {
+            keymap_string: Some(remapped_us_keymap(&[
+                ("AE01", "U1F469, U1F1E9"),
+                ("AE02", "U0301"),
+                ("AE03", "U200D"),
+            ])),
+            ..Default::default()
+        }
+    }
 
+    #[cfg(feature = "graphemes")]
     #[test]
-    fn test_build_key_to_keycode_map_modifiers() {
-        let map = build_key_to_keycode_map();
-        assert_eq!(map.get("shift"), Some(&42));
-        assert_eq!(map.get("ctrl"), Some(&29));
-        assert_eq!(map.get("alt"), Some(&56));
-        assert_eq!(map.get("super"), Some(&125));
+    fn test_grapheme_clusters_are_skipped_whole() {
+        let (typer, sink) = recording_eitype(partial_cluster_config());
+        // 👩 ZWJ 💻, 🇩🇪, and o with a circumflex no key produces
+        let outcome = typer
+            .type_text_report("x\u{1F469}\u{200D}\u{1F4BB}y\u{1F1E9}\u{1F1EA}zo\u{302}")
+            .unwrap();
+        assert_eq!(sink.presses(), vec![45, 21, 44]);
+        assert_eq!(
+            outcome
+                .skipped
+                .iter()
+                .map(|s| (s.index, s.ch))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, '\u{1F469}'),
+                (2, '\u{200D}'),
+                (3, '\u{1F4BB}'),
+                (5, '\u{1F1E9}'),
+                (6, '\u{1F1EA}'),
+                (8, 'o'),
+                (9, '\u{302}')
+            ]
+        );
+        // Every scalar of a cluster gives the reason the cluster was skipped
+        assert_eq!(
+            outcome.skipped[0].reason,
+            EiTypeError::CharNotFound('\u{1F4BB}').to_string()
+        );
+        assert_eq!(outcome.chars_delivered, 3);
     }
 
+    #[cfg(feature = "graphemes")]
     #[test]
-    fn test_build_key_to_keycode_map_special_keys() {
-        let map = build_key_to_keycode_map();
-        assert_eq!(map.get("escape"), Some(&1));
-        assert_eq!(map.get("return"), Some(&28));
-        assert_eq!(map.get("tab"), Some(&15));
-        assert_eq!(map.get("space"), Some(&57));
+    fn test_grapheme_clusters_fail_before_typing_any_part() {
+        for (text, index, ch) in [
+            ("x\u{1F469}\u{200D}\u{1F4BB}", 3, '\u{1F4BB}'),
+            ("x\u{1F1E9}\u{1F1EA}", 2, '\u{1F1EA}'),
+            ("xo\u{302}", 2, '\u{302}'),
+        ] {
+            let (typer, sink) = recording_eitype(partial_cluster_config());
+            let err = typer.type_text(text).unwrap_err();
+            let EiTypeError::InText {
+                index: err_index,
+                source,
+                ..
+            } = err
+            else {
+                panic!("expected InText, got {:?}", err);
+            };
+            assert_eq!(err_index, index, "{:?}", text);
+            assert!(matches!(*source, EiTypeError::CharNotFound(c) if c == ch));
+            assert_eq!(sink.presses(), vec![45], "{:?}", text);
+        }
     }
 
+    #[cfg(feature = "graphemes")]
     #[test]
-    fn test_build_key_to_keycode_map_letters() {
-        let map = build_key_to_keycode_map();
-        assert_eq!(map.get("a"), Some(&30));
-        assert_eq!(map.get("z"), Some(&44));
+    fn test_typable_grapheme_clusters_are_typed() {
+        let (typer, sink) = recording_eitype(partial_cluster_config());
+        // e with a combining acute, and 👩 ZWJ 👩
+        typer
+            .type_text("e\u{301}\u{1F469}\u{200D}\u{1F469}")
+            .unwrap();
+        assert_eq!(sink.presses(), vec![18, 3, 2, 4, 2]);
+        assert_eq!(typer.stats().chars_typed, 5);
     }
 
     #[test]
-    fn test_keysym_to_char_ascii() {
-        assert_eq!(keysym_to_char(0x20), Some(' '));
-        assert_eq!(keysym_to_char(0x41), Some('A'));
-        assert_eq!(keysym_to_char(0x61), Some('a'));
+    fn test_type_text_switches_group_for_foreign_run() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            options: Some("grp:sclk_toggle".to_string()),
+            ..Default::default()
+        });
+        typer.type_text("aдд").unwrap();
+        // д is on the L key (38) in the Russian group; Scroll Lock toggles in
+        // once before the run and once after it.
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (30, true),
+                (30, false),
+                (70, true),
+                (70, false),
+                (38, true),
+                (38, false),
+                (38, true),
+                (38, false),
+                (70, true),
+                (70, false),
+            ]
+        );
+        assert_eq!(typer.stats().group_switches, 1);
     }
 
     #[test]
-    fn test_keysym_to_char_unicode() {
-        assert_eq!(keysym_to_char(0x1000000 + 0x00e9), Some('é'));
+    fn test_type_text_holds_momentary_group_switch() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            options: Some("grp:switch".to_string()),
+            ..Default::default()
+        });
+        typer.type_text("дa").unwrap();
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (100, true),
+                (38, true),
+                (38, false),
+                (100, false),
+                (30, true),
+                (30, false),
+            ]
+        );
     }
 
     #[test]
-    fn test_keysym_to_char_cyrillic() {
-        // Cyrillic_PE / Cyrillic_pe — regression test for issue #8
-        assert_eq!(keysym_to_char(0x06f0), Some('П'));
-        assert_eq!(keysym_to_char(0x06d0), Some('п'));
+    fn test_type_text_restores_group_after_error() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            options: Some("grp:sclk_toggle".to_string()),
+            ..Default::default()
+        });
+        let err = typer.type_text("дξ").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('ξ')));
+        let events = key_events(&sink);
+        assert_eq!(events.last(), Some(&(70, false)));
+        assert_eq!(events.iter().filter(|e| **e == (70, true)).count(), 2);
     }
 
     #[test]
-    fn test_keysym_to_char_greek() {
-        // Greek_alpha → α, Greek_ALPHA → Α
-        assert_eq!(keysym_to_char(0x07e1), Some('α'));
-        assert_eq!(keysym_to_char(0x07c1), Some('Α'));
+    fn test_type_text_releases_momentary_switch_after_error() {
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us,ru,gr".to_string()),
+            options: Some("grp:switch".to_string()),
+            ..Default::default()
+        });
+        // Leave only the switch key, which reaches the Russian group but not
+        // the Greek one
+        typer
+            .planner
+            .borrow_mut()
+            .group_switch_keys
+            .retain(|key| key.kind == GroupSwitchKind::Momentary);
+        let err = typer.type_text("дα").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('α')));
+        let events = key_events(&sink);
+        assert_eq!(events.last(), Some(&(100, false)));
+        for &(keycode, _) in &events {
+            let presses = events.iter().filter(|e| **e == (keycode, true)).count();
+            let releases = events.iter().filter(|e| **e == (keycode, false)).count();
+            assert_eq!(presses, releases, "key {} in {:?}", keycode, events);
+        }
+    }
+
+    /// What `EiType` should record for planned events (delays leave no trace)
+    fn planned_as_recorded(events: &[PlannedEvent]) -> Vec<Recorded> {
+        events
+            .iter()
+            .filter_map(|event| match *event {
+                PlannedEvent::Press(kc) => Some(Recorded::Key(kc, true)),
+                PlannedEvent::Release(kc) => Some(Recorded::Key(kc, false)),
+                PlannedEvent::Frame => Some(Recorded::Frame),
+                PlannedEvent::Delay(_) => None,
+            })
+            .collect()
     }
 
     #[test]
-    fn test_keysym_to_char_special() {
-        assert_eq!(keysym_to_char(0xff0d), Some('\n')); // XK_Return → '\n'
-        assert_eq!(keysym_to_char(0xff09), Some('\t')); // XK_Tab
+    fn test_planner_matches_recorded_typing() {
+        let config = EiTypeConfig {
+            layout: Some("de,ru".to_string()),
+            options: Some("grp:sclk_toggle".to_string()),
+            ..Default::default()
+        };
+        // Shift, AltGr, a run from the other group, and back
+        let text = "A@ддz!";
+        let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+        let planned = planner.plan_text(text).unwrap();
+
+        let (typer, sink) = recording_eitype(config);
+        sink.events.borrow_mut().clear();
+        typer.type_text(text).unwrap();
+        assert_eq!(sink.events(), planned_as_recorded(&planned));
+        assert!(planned.contains(&PlannedEvent::Press(70)));
+        assert_eq!(planned.last(), Some(&PlannedEvent::Frame));
     }
 
     #[test]
-    fn test_keysym_to_char_unmapped() {
-        assert_eq!(keysym_to_char(0xffe1), None); // XK_Shift_L has no Unicode mapping
+    fn test_settle_after_word_pauses_at_whitespace() {
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            settle_after_word_ms: Some(40),
+            ..Default::default()
+        };
+        let settle = PlannedEvent::Delay(Duration::from_millis(40));
+        let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+
+        // The pause follows each whitespace character's key events, and
+        // nothing else
+        let text = "ab cd\nef";
+        for ch in text.chars() {
+            let plan = planner.plan_char(ch).unwrap();
+            let pauses = plan.events.iter().filter(|e| **e == settle).count();
+            if ch.is_whitespace() {
+                assert_eq!(pauses, 1, "{:?}", ch);
+                assert_eq!(plan.events.last(), Some(&settle));
+            } else {
+                assert_eq!(pauses, 0, "{:?}", ch);
+            }
+        }
+        let planned = planner.plan_text(text).unwrap();
+        assert_eq!(planned.iter().filter(|e| **e == settle).count(), 2);
+
+        // Typing executes the same plan
+        let (typer, sink) = recording_eitype(config);
+        sink.events.borrow_mut().clear();
+        typer.type_text(text).unwrap();
+        assert_eq!(sink.events(), planned_as_recorded(&planned));
+
+        let mut planner = Planner::without_keymap(&EiTypeConfig::default());
+        let planned = planner.plan_text("a b").unwrap();
+        assert!(!planned.iter().any(|e| matches!(e, PlannedEvent::Delay(_))));
     }
 
+    #[cfg(feature = "transliterate")]
     #[test]
-    fn test_config_default() {
-        let config = EiTypeConfig::default();
-        assert!(config.layout.is_none());
-        assert!(!config.is_specified());
+    fn test_ascii_transliterate_types_approximations() {
+        let config = EiTypeConfig {
+            ascii_transliterate: true,
+            ..Default::default()
+        };
+        let typed_as = |text: &str| {
+            let (typer, sink) = recording_eitype(config.clone());
+            typer.type_text(text).unwrap();
+            key_events(&sink)
+        };
+        let plain = |text: &str| {
+            let (typer, sink) = recording_eitype(EiTypeConfig::default());
+            typer.type_text(text).unwrap();
+            key_events(&sink)
+        };
+        assert_eq!(typed_as("café"), plain("cafe"));
+        assert_eq!(typed_as("“ok” – fine…"), plain("\"ok\" - fine..."));
+        assert_eq!(typed_as("Æ"), plain("AE"));
+
+        // Off by default
+        let (typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let err = typer.type_text("é").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('é')));
+
+        // A character with no approximation still fails
+        let (typer, _sink) = recording_eitype(config);
+        let err = typer.type_text("a\u{E000}").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('\u{E000}')));
     }
 
+    #[cfg(feature = "transliterate")]
     #[test]
-    fn test_config_is_specified() {
-        let mut config = EiTypeConfig::default();
-        assert!(!config.is_specified());
+    fn test_type_text_report_lists_substitutions() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig {
+            ascii_transliterate: true,
+            ..Default::default()
+        });
+        let outcome = typer.type_text_report("café\u{E000}…").unwrap();
+        let substitution = |index, original: &str, replacement: &str| Substitution {
+            index,
+            original: original.to_string(),
+            replacement: replacement.to_string(),
+        };
+        assert_eq!(
+            outcome.substitutions,
+            vec![substitution(3, "é", "e"), substitution(5, "…", "...")]
+        );
+        assert_eq!(outcome.skipped.len(), 1);
+        assert_eq!(outcome.chars_requested, 6);
+        assert_eq!(outcome.chars_delivered, 5);
 
-        config.layout = Some("us".to_string());
-        assert!(config.is_specified());
+        // A session keeps them at their place in the whole text, and drops
+        // the ones revised away
+        let mut session = TypeSession::new(&typer);
+        session.feed(&typer, "né").unwrap();
+        session.feed(&typer, " ok…").unwrap();
+        session.revise(&typer, 3, "fine").unwrap();
+        let outcome = session.finish(&typer);
+        assert_eq!(outcome.substitutions, vec![substitution(1, "é", "e")]);
+
+        // Without transliteration nothing is substituted
+        let (typer, _sink) = recording_eitype(EiTypeConfig::default());
+        assert!(typer
+            .type_text_report("é")
+            .unwrap()
+            .substitutions
+            .is_empty());
     }
 
     #[test]
-    fn test_get_timestamp_monotonic() {
-        let t1 = get_timestamp();
-        std::thread::sleep(std::time::Duration::from_millis(1));
-        let t2 = get_timestamp();
-        assert!(t2 >= t1);
+    fn test_modifier_settle_pauses_around_level_modifiers_only() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            layout: Some("us".to_string()),
+            variant: Some("intl".to_string()),
+            modifier_settle_ms: 30,
+            ..Default::default()
+        });
+        typer.clock = Clock::Fake(Cell::new(0));
+        sink.events.borrow_mut().clear();
+        sink.frame_timestamps.borrow_mut().clear();
+        // Unmodified, Shift, AltGr, unmodified
+        typer.type_text("aBäc").unwrap();
+
+        assert_eq!(
+            key_events(&sink),
+            vec![
+                (30, true),
+                (30, false),
+                (42, true),
+                (48, true),
+                (48, false),
+                (42, false),
+                (100, true),
+                (16, true),
+                (16, false),
+                (100, false),
+                (46, true),
+                (46, false)
+            ]
+        );
+        // Only the gaps from a modifier press to its key's press, and from
+        // that key's release to the modifier's release, are settled
+        assert_eq!(
+            key_event_gaps(&sink),
+            vec![0, 0, 30_000, 0, 30_000, 0, 30_000, 0, 30_000, 0, 0]
+        );
     }
 
     #[test]
-    fn test_exponential_backoff_calculation() {
-        // Verify the backoff formula used in flush_with_retry():
-        // delay_ms = (delay_ms * 2).min(MAX_DELAY_MS)
-        let max_delay_ms: u64 = 100;
-        let mut delay_ms: u64 = 1;
+    fn test_set_delay_paces_later_typing() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        typer.clock = Clock::Fake(Cell::new(0));
+        assert_eq!(typer.delay(), Duration::ZERO);
+        sink.frame_timestamps.borrow_mut().clear();
+        typer.type_text("ab").unwrap();
+        assert_eq!(key_event_gaps(&sink), vec![0, 0, 0]);
 
-        // Should double each time until capped at 100ms
-        let expected_delays = [2, 4, 8, 16, 32, 64, 100, 100];
-        for expected in expected_delays {
-            delay_ms = (delay_ms * 2).min(max_delay_ms);
-            assert_eq!(delay_ms, expected);
+        typer.set_delay(Duration::from_millis(30));
+        assert_eq!(typer.delay(), Duration::from_millis(30));
+        typer.type_text("ab").unwrap();
+        assert_eq!(key_event_gaps(&sink), vec![30_000; 3]);
+
+        typer.set_delay(Duration::ZERO);
+        typer.type_text("ab").unwrap();
+        assert_eq!(key_event_gaps(&sink), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_profiles_parse() {
+        let profiles = Profiles::parse(
+            "# Typing profiles
+            [paths]
+            delay_ms = \"ignored\"
+
+            [profile.terminal]
+            delay_ms = 3  # fast
+            settle_after_word_ms = 0
+
+            [profile.\"libre office\"]
+            delay_ms = 20
+            strip_format_chars = false
+
+            [profile.editor]
+            paste_threshold = false
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            profiles.names().collect::<Vec<_>>(),
+            ["editor", "libre office", "terminal"]
+        );
+        assert_eq!(
+            profiles.get("terminal").unwrap(),
+            &TypingProfile {
+                delay_ms: Some(3),
+                settle_after_word_ms: Some(0),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            profiles.get("libre office").unwrap().strip_format_chars,
+            Some(false)
+        );
+        // Pasting turned off, rather than left as configured
+        assert_eq!(profiles.get("editor").unwrap().paste_threshold, Some(None));
+
+        let err = profiles.get("browser").unwrap_err().to_string();
+        assert!(
+            err.contains("\"browser\" (available: editor, libre office, terminal)"),
+            "{}",
+            err
+        );
+        for (contents, message) in [
+            ("[profile.a]\ngap_ms = 3", "unknown field `gap_ms`"),
+            ("[profile.a]\ndelay_ms = \"fast\"", "invalid type"),
+            ("[profile.a]\ndelay_ms = fast", "delay_ms = fast"),
+            (
+                "[profile.a]\npaste_threshold = true",
+                "or false to turn pasting off",
+            ),
+            ("[profile.\"\"]\ndelay_ms = 1", "empty profile name"),
+        ] {
+            let err = Profiles::parse(contents).unwrap_err().to_string();
+            assert!(err.contains(message), "{}", err);
+            if !contents.contains("\"\"") {
+                assert!(err.contains("line 2"), "{}", err);
+            }
         }
     }
 
     #[test]
-    fn test_eagain_errno_value() {
-        // Document the expected errno for EAGAIN on Linux.
-        // flush_with_retry() checks raw_os_error() == 11 to detect
-        // when the socket buffer is full and we should retry.
-        assert_eq!(libc::EAGAIN, 11);
-        // On Linux, EWOULDBLOCK is the same as EAGAIN
-        assert_eq!(libc::EWOULDBLOCK, libc::EAGAIN);
+    fn test_profiles_with_delay() {
+        let contents = "# Typing profiles\n\n[profile.terminal]\ndelay_ms = 3  # fast\n\n[profile.editor]\npaste_threshold = false\n";
+        let updated = Profiles::with_delay(contents, "terminal", 1).unwrap();
+        assert_eq!(updated, contents.replace("delay_ms = 3", "delay_ms = 1"));
+
+        let updated = Profiles::with_delay(contents, "editor", 8).unwrap();
+        let profiles = Profiles::parse(&updated).unwrap();
+        assert!(updated.starts_with("# Typing profiles\n"));
+        assert_eq!(
+            profiles.get("editor").unwrap(),
+            &TypingProfile {
+                delay_ms: Some(8),
+                paste_threshold: Some(None),
+                ..Default::default()
+            }
+        );
+        assert_eq!(profiles.get("terminal").unwrap().delay_ms, Some(3));
+
+        // A new file gets just the profile
+        assert_eq!(
+            Profiles::with_delay("", "terminal", 2).unwrap(),
+            "[profile.terminal]\ndelay_ms = 2\n"
+        );
+
+        for (contents, name, message) in [
+            ("", "", "empty profile name"),
+            ("profile = 3", "terminal", "profile is not a table"),
+            (
+                "[profile]\nterminal = 3",
+                "terminal",
+                "profile.terminal is not a table",
+            ),
+            ("[profile.a]\ngap_ms = 3", "a", "unknown field `gap_ms`"),
+            ("[profile.a\n", "a", "line 1"),
+        ] {
+            let err = Profiles::with_delay(contents, name, 1)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(message), "{}", err);
+        }
     }
 
-    /// Create a minimal two-layout XKB keymap where SPCE only defines one group.
-    /// This reproduces the real-world scenario where layout-independent keys
-    /// like space have fewer groups than the global keymap layout count.
-    fn make_two_layout_keymap_with_single_group_space() -> xkb::Keymap {
-        let context = xkb::Context::new(xkb::CONTEXT_NO_DEFAULT_INCLUDES);
-        let keymap_str = r#"
-xkb_keymap {
-    xkb_keycodes "test" {
-        minimum = 8;
-        maximum = 255;
-        <SPCE> = 65;
-        <AC01> = 38;
-        <AD01> = 24;
-    };
-    xkb_types "test" {
-        virtual_modifiers NumLock;
-        type "ONE_LEVEL" {
-            modifiers = none;
-            level_name[Level1] = "Any";
+    #[test]
+    fn test_profile_merge() {
+        let base = TypingProfile::of_config(&EiTypeConfig {
+            delay_ms: 10,
+            paste_threshold: Some(100),
+            ..Default::default()
+        });
+        let terminal = TypingProfile {
+            delay_ms: Some(3),
+            ascii_transliterate: Some(true),
+            ..Default::default()
         };
-        type "TWO_LEVEL" {
-            modifiers = Shift;
-            map[Shift] = Level2;
-            level_name[Level1] = "Base";
-            level_name[Level2] = "Shift";
+        let merged = base.merged(&terminal);
+        // The profile's settings win, and the rest are inherited
+        assert_eq!(merged.delay_ms, Some(3));
+        assert_eq!(merged.ascii_transliterate, Some(true));
+        assert_eq!(merged.paste_threshold, Some(Some(100)));
+        assert_eq!(merged.strip_format_chars, Some(true));
+        assert_eq!(merged.settle_after_word_ms, Some(0));
+
+        let editor = TypingProfile {
+            paste_threshold: Some(None),
+            ..Default::default()
         };
-    };
-    xkb_compatibility "test" {
-        interpret Any+AnyOf(all) { action = SetMods(modifiers=modMapMods,clearLocks); };
-    };
-    xkb_symbols "test" {
-        name[group1] = "English (US)";
-        name[group2] = "English (Programmer Dvorak)";
+        assert_eq!(base.merged(&editor).paste_threshold, Some(None));
+    }
 
-        // Space: only ONE group (layout-independent) — triggers the bug
-        key <SPCE> { [ space ] };
+    #[test]
+    fn test_apply_profile_switches_settings() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig {
+            delay_ms: 5,
+            paste_threshold: Some(100),
+            ..Default::default()
+        });
+        let mut profiles = Profiles::default();
+        profiles.insert(
+            "terminal",
+            TypingProfile {
+                delay_ms: Some(1),
+                settle_after_word_ms: Some(40),
+                ..Default::default()
+            },
+        );
+        profiles.insert(
+            "editor",
+            TypingProfile {
+                strip_format_chars: Some(false),
+                paste_threshold: Some(Some(10)),
+                ..Default::default()
+            },
+        );
+        profiles.insert(
+            "no paste",
+            TypingProfile {
+                paste_threshold: Some(None),
+                ..Default::default()
+            },
+        );
+        typer.set_profiles(profiles);
 
-        // Letter keys: two groups (different in each layout)
-        key <AC01> {
-            symbols[Group1] = [ a, A ],
-            symbols[Group2] = [ a, A ]
-        };
-        key <AD01> {
-            symbols[Group1] = [ q, Q ],
-            symbols[Group2] = [ semicolon, colon ]
-        };
-    };
-};
-"#;
-        xkb::Keymap::new_from_string(
-            &context,
-            keymap_str.to_string(),
-            xkb::KEYMAP_FORMAT_TEXT_V1,
-            xkb::KEYMAP_COMPILE_NO_FLAGS,
-        )
-        .expect("Failed to create test keymap")
+        typer.apply_profile("terminal").unwrap();
+        assert_eq!(typer.active_profile(), Some("terminal"));
+        assert_eq!(typer.delay(), Duration::from_millis(1));
+        assert_eq!(
+            typer.planner.borrow().settle_after_word,
+            Some(Duration::from_millis(40))
+        );
+        assert_eq!(typer.paste_threshold, Some(100));
+
+        // Switching drops the terminal's settings rather than stacking
+        typer.apply_profile("editor").unwrap();
+        assert_eq!(typer.delay(), Duration::from_millis(5));
+        assert_eq!(typer.planner.borrow().settle_after_word, None);
+        assert_eq!(typer.paste_threshold, Some(10));
+        let err = typer.type_text("a\u{200B}").unwrap_err();
+        assert!(matches!(err.root(), EiTypeError::CharNotFound('\u{200B}')));
+
+        let err = typer.apply_profile("browser").unwrap_err();
+        assert!(matches!(err, EiTypeError::InvalidConfig(_)), "{:?}", err);
+        assert_eq!(typer.active_profile(), Some("editor"));
+
+        typer.apply_profile("no paste").unwrap();
+        assert_eq!(typer.paste_threshold, None);
+
+        typer.clear_profile();
+        assert_eq!(typer.active_profile(), None);
+        assert_eq!(typer.paste_threshold, Some(100));
+        typer.type_text("a\u{200B}").unwrap();
+    }
+
+    #[test]
+    fn test_set_delay_outlasts_profiles() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig {
+            delay_ms: 5,
+            ..Default::default()
+        });
+        let mut profiles = Profiles::default();
+        profiles.insert(
+            "terminal",
+            TypingProfile {
+                delay_ms: Some(1),
+                ..Default::default()
+            },
+        );
+        typer.set_profiles(profiles);
+
+        typer.set_delay(Duration::from_millis(30));
+        typer.apply_profile("terminal").unwrap();
+        assert_eq!(typer.delay(), Duration::from_millis(30));
+        typer.clear_profile();
+        assert_eq!(typer.delay(), Duration::from_millis(30));
+
+        // Reset, the profile's delay applies again, then the configured one
+        typer.apply_profile("terminal").unwrap();
+        typer.reset_delay();
+        assert_eq!(typer.delay(), Duration::from_millis(1));
+        typer.clear_profile();
+        assert_eq!(typer.delay(), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_key_delay_follows_every_key_event() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig {
+            delay_ms: 25,
+            ..Default::default()
+        });
+        typer.clock = Clock::Fake(Cell::new(0));
+        sink.events.borrow_mut().clear();
+        sink.frame_timestamps.borrow_mut().clear();
+
+        // Shifted text, a special key, a held modifier, a tapped modifier
+        // and a key held with key_down, each pacing its own way before
+        typer.type_text("aB").unwrap();
+        typer.press_key("Return").unwrap();
+        typer.hold_modifier("ctrl").unwrap();
+        typer.type_text("c").unwrap();
+        typer.release_modifiers().unwrap();
+        typer.press_modifier("alt").unwrap();
+        typer.key_down("shift").unwrap();
+        typer.key_up("shift").unwrap();
+
+        // Exactly one key delay between successive key events
+        assert_eq!(key_event_gaps(&sink), vec![25_000; 15]);
     }
 
     #[test]
-    fn test_find_keycode_for_char_space_at_layout_1() {
-        let keymap = make_two_layout_keymap_with_single_group_space();
-        // Space should be findable regardless of layout index.
-        // This reproduces the bug: with layout_index=1, keys that only
-        // define 1 layout (like space) are skipped, causing CharNotFound.
-        let result = find_keycode_for_char(' ', &keymap, 1);
-        assert!(
-            result.is_ok(),
-            "Space should be found at layout_index=1, got: {:?}",
-            result
+    fn test_planner_char_plans_and_errors() {
+        // The key delay is EiType's to add, not planned
+        let config = EiTypeConfig {
+            delay_ms: 5,
+            ..Default::default()
+        };
+        let mut planner = Planner::without_keymap(&config);
+        let plan = planner.plan_char('A').unwrap();
+        assert!(plan.qwerty_fallback);
+        assert!(!plan.group_switch);
+        assert_eq!(
+            plan.events,
+            vec![
+                PlannedEvent::Press(42),
+                PlannedEvent::Frame,
+                PlannedEvent::Press(30),
+                PlannedEvent::Frame,
+                PlannedEvent::Release(30),
+                PlannedEvent::Frame,
+                PlannedEvent::Release(42),
+                PlannedEvent::Frame,
+            ]
         );
-        let m = result.unwrap();
-        // evdev keycode for space is 57 (XKB 65 - 8)
-        assert_eq!(m.evdev_keycode, 57, "Space should map to evdev keycode 57");
+        assert_eq!(
+            planner.plan_text("ab\u{e9}").unwrap_err(),
+            PlanError {
+                ch: '\u{e9}',
+                index: 2
+            }
+        );
+
+        let config = EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            options: Some("grp:switch".to_string()),
+            ..Default::default()
+        };
+        let mut planner = Planner::new(config.compile_keymap().unwrap(), 0, &config);
+        let plan = planner.plan_char('д').unwrap();
+        assert!(plan.group_switch);
+        assert_eq!(
+            plan.events[..2],
+            [PlannedEvent::Press(100), PlannedEvent::Frame]
+        );
+        assert_eq!(
+            planner.finish(),
+            vec![PlannedEvent::Release(100), PlannedEvent::Frame]
+        );
+        assert!(planner.finish().is_empty());
     }
 
     #[test]
-    fn test_find_keycode_for_char_space_at_layout_0() {
-        let keymap = make_two_layout_keymap_with_single_group_space();
-        // Baseline: space at layout 0 should always work
-        let result = find_keycode_for_char(' ', &keymap, 0);
-        assert!(result.is_ok(), "Space should be found at layout_index=0");
-        let m = result.unwrap();
-        assert_eq!(m.evdev_keycode, 57);
+    fn test_execute_actions_releases_modifiers_on_error() {
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        let result = typer.execute_actions(&[
+            Action::ModifierHold("ctrl".to_string()),
+            Action::ModifierHold("shift".to_string()),
+            Action::Type("aж".to_string()),
+            Action::Key("return".to_string()),
+        ]);
+        assert!(matches!(
+            result.unwrap_err().root(),
+            EiTypeError::CharNotFound('ж')
+        ));
+        // Both modifiers are released (in reverse order) and Return never ran
+        let events = key_events(&sink);
+        assert_eq!(&events[events.len() - 2..], &[(42, false), (29, false)]);
+        assert!(!events.contains(&(28, true)));
     }
 
     #[test]
-    fn test_find_keycode_for_char_letter_at_layout_1() {
-        let keymap = make_two_layout_keymap_with_single_group_space();
-        // Letters should be findable at layout_index=1 (dvp layout)
-        let result = find_keycode_for_char('a', &keymap, 1);
+    fn test_key_table_matches_standard_keymap() {
+        let (typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let report = typer.key_table_report();
+        assert_eq!(report.len(), SPECIAL_KEY_KEYSYMS.len());
         assert!(
-            result.is_ok(),
-            "'a' should be found at layout_index=1, got: {:?}",
-            result
+            report.iter().all(KeyTableEntry::matches_keymap),
+            "{:?}",
+            report
         );
     }
 
-    /// Build a keymap from system xkb data. Used by AltGr tests to exercise the
-    /// real key types (FOUR_LEVEL_ALPHABETIC, ISO_Level3_Shift -> Mod5, etc.)
-    /// rather than approximating them by hand.
-    fn system_keymap(layout: &str, variant: &str) -> xkb::Keymap {
-        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
-        xkb::Keymap::new_from_names(
-            &context,
-            "",
-            "",
-            layout,
-            variant,
-            None,
-            xkb::KEYMAP_COMPILE_NO_FLAGS,
-        )
-        .expect("system xkb data required for this test (install xkeyboard-config)")
+    #[test]
+    fn test_key_table_follows_swapped_escape() {
+        let config = EiTypeConfig {
+            options: Some("caps:swapescape".to_string()),
+            ..Default::default()
+        };
+        let (typer, sink) = recording_eitype(config.clone());
+        let escape = typer
+            .key_table_report()
+            .into_iter()
+            .find(|entry| entry.name == "escape")
+            .unwrap();
+        assert!(!escape.matches_keymap());
+        assert_eq!(escape.keymap_keycode, Some(58));
+        assert_eq!(
+            escape.to_string(),
+            "escape: keycode 1 doesn't produce Escape in this keymap, keycode 58 does (using 58)"
+        );
+
+        typer.press_key("escape").unwrap();
+        typer.press_key("capslock").unwrap();
+        assert_eq!(sink.presses(), vec![58, 1]);
+
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            prefer_keymap_keys: false,
+            ..config
+        });
+        typer.press_key("esc").unwrap();
+        assert_eq!(sink.presses(), vec![1]);
     }
 
-    #[test]
-    fn test_find_keycode_for_char_altgr_us_intl() {
-        // Regression for issue #15: ä lives on the Q key at level 2 (AltGr) on
-        // us-intl. The lookup must report level 2, not flatten it to "no modifier".
-        let keymap = system_keymap("us", "intl");
-        let m = find_keycode_for_char('ä', &keymap, 0).expect("ä should be found");
-        assert_eq!(m.evdev_keycode, 16, "ä is on the Q key");
-        assert_eq!(m.level, 2, "ä is at level 2 (AltGr) on us-intl");
+    /// The US keymap as text, with the symbols of each `(key, symbols)` in
+    /// `remaps` replaced, e.g. `("RTRN", "Control_R")`
+    fn remapped_us_keymap(remaps: &[(&str, &str)]) -> String {
+        let text = system_keymap("us", "").get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        for (key, symbols) in remaps {
+            let prefix = format!("key <{}>", key);
+            let line = lines
+                .iter_mut()
+                .find(|line| line.trim_start().starts_with(&prefix))
+                .unwrap_or_else(|| panic!("no <{}> in the keymap", key));
+            *line = format!("{} {{ [ {} ] }};", prefix, symbols);
+        }
+        lines.join("\n")
     }
 
     #[test]
-    fn test_modifier_keycodes_for_match_altgr_level2() {
-        // Level 2 -> AltGr. Default us-intl modmap binds Mod5 to RAlt (evdev 100).
-        let keymap = system_keymap("us", "intl");
-        let m = find_keycode_for_char('ä', &keymap, 0).unwrap();
-        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
-        assert_eq!(mods, vec![100], "ä on us-intl needs AltGr (evdev 100)");
+    fn test_key_table_substitutes_stand_ins_for_remapped_keys() {
+        // Return and Tab turned into modifiers; Caps Lock gives ISO_Left_Tab
+        let config = EiTypeConfig {
+            keymap_string: Some(remapped_us_keymap(&[
+                ("RTRN", "Control_R"),
+                ("TAB", "Hyper_L"),
+                ("CAPS", "ISO_Left_Tab"),
+            ])),
+            ..Default::default()
+        };
+        let (typer, sink) = recording_eitype(config.clone());
+        let report = typer.key_table_report();
+        let entry = |name: &str| report.iter().find(|e| e.name == name).unwrap().clone();
+
+        let enter = entry("return");
+        assert_eq!(enter.keymap_keycode, Some(96));
+        assert_eq!(enter.substitute.as_deref(), Some("KP_Enter"));
+        assert_eq!(enter.keycode, 96);
+        assert_eq!(
+            enter.to_string(),
+            "return: no key produces Return in this keymap, keycode 96 produces KP_Enter (using 96)"
+        );
+        let tab = entry("tab");
+        assert_eq!(tab.keymap_keycode, Some(58));
+        assert_eq!(tab.substitute.as_deref(), Some("ISO_Left_Tab"));
+        // Keys that are where the table expects need no stand-in
+        assert_eq!(entry("space").substitute, None);
+        assert!(entry("backspace").matches_keymap());
+
+        typer.press_key("return").unwrap();
+        typer.press_key("enter").unwrap();
+        typer.press_key("tab").unwrap();
+        typer.press_key("space").unwrap();
+        assert_eq!(sink.presses(), vec![96, 96, 58, 57]);
+
+        // Without prefer_keymap_keys the table keycodes are used regardless
+        let (typer, sink) = recording_eitype(EiTypeConfig {
+            prefer_keymap_keys: false,
+            ..config
+        });
+        typer.press_key("return").unwrap();
+        typer.press_key("tab").unwrap();
+        assert_eq!(sink.presses(), vec![28, 15]);
     }
 
     #[test]
-    fn test_modifier_keycodes_for_match_altgr_shift_level3() {
-        // Ä on us-intl is at level 3: AltGr + Shift.
-        let keymap = system_keymap("us", "intl");
-        let m = find_keycode_for_char('Ä', &keymap, 0).unwrap();
-        assert_eq!(m.level, 3, "Ä is at level 3 (AltGr+Shift) on us-intl");
-        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
-        // Order isn't fixed; just assert set membership and count.
-        assert_eq!(mods.len(), 2);
-        assert!(mods.contains(&42), "Ä needs Shift (evdev 42)");
-        assert!(mods.contains(&100), "Ä needs AltGr (evdev 100)");
+    fn test_keymap_fingerprint_stable_across_identical_loads() {
+        let a = keymap_fingerprint(&system_keymap("us", ""));
+        let b = keymap_fingerprint(&system_keymap("us", ""));
+        assert_eq!(a, b);
+        assert_ne!(a, keymap_fingerprint(&system_keymap("de", "")));
     }
 
     #[test]
-    fn test_modifier_keycodes_for_match_level0_no_mods() {
+    fn test_keymap_changed_callback_and_connection_info() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        let us = typer.keymap_fingerprint();
+        assert_ne!(us, 0);
+
+        let seen = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let seen_clone = seen.clone();
+        typer.on_keymap_changed(move |fp| seen_clone.borrow_mut().push(fp));
+
+        // Reinstalling an identical keymap is not a change
         let keymap = system_keymap("us", "");
-        let m = find_keycode_for_char('a', &keymap, 0).unwrap();
-        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
-        assert!(mods.is_empty(), "level 0 needs no modifiers");
+        typer.install_keymap(keymap);
+        assert!(seen.borrow().is_empty());
+
+        let keymap = keymap_with_options("de,us", "");
+        typer.install_keymap(keymap);
+        let de = typer.keymap_fingerprint();
+        assert_ne!(de, us);
+        assert_eq!(*seen.borrow(), vec![de]);
+
+        let info = typer.connection_info();
+        assert_eq!(info.keymap_fingerprint, de);
+        assert_eq!(info.keymap_source, KeymapSource::ConfigNames);
+        assert_eq!(info.num_layouts, 2);
     }
 
     #[test]
-    fn test_modifier_keycodes_for_match_level1_shift() {
-        let keymap = system_keymap("us", "");
-        let m = find_keycode_for_char('A', &keymap, 0).unwrap();
-        let mods = modifier_keycodes_for_match(&keymap, &m, &build_keymap_modifier_map(&keymap));
-        assert_eq!(mods, vec![42], "level 1 needs Shift only");
+    fn test_out_of_range_layout_index_falls_back_to_zero() {
+        let config = EiTypeConfig {
+            layout: Some("us".to_string()),
+            ..Default::default()
+        };
+        let keymap = load_keymap(&config, None).unwrap();
+        let typer =
+            EiType::with_sink(Box::new(RecordingSink::default()), keymap, 3, &config).unwrap();
+        let info = typer.connection_info();
+        assert_eq!(info.layout_index, 0);
+        assert_eq!(info.clamped_layout_index, Some(3));
+        assert_eq!(info.layout_name, "English (US)");
+        assert_eq!(
+            typer.resolved_layout(),
+            (KeymapSource::ConfigNames, 0, "English (US)".to_string())
+        );
     }
 
     #[test]
-    fn test_keymap_modifier_map_default_us_intl() {
-        // Sanity: on the default us-intl modmap, the canonical bindings hold.
-        let keymap = system_keymap("us", "intl");
-        let map = build_keymap_modifier_map(&keymap);
-        assert_eq!(map.get("Shift").copied(), Some(42), "Shift -> LShift");
-        assert_eq!(map.get("Mod5").copied(), Some(100), "Mod5 -> RAlt (AltGr)");
+    fn test_resolved_layout_after_reload() {
+        let (mut typer, _sink) = recording_eitype(EiTypeConfig::default());
+        typer
+            .reload_keymap(&EiTypeConfig {
+                layout: Some("de,us".to_string()),
+                layout_index: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        let info = typer.connection_info();
+        assert_eq!(info.clamped_layout_index, None);
+        assert_eq!(
+            typer.resolved_layout(),
+            (KeymapSource::ConfigNames, 1, "English (US)".to_string())
+        );
+
+        // An explicit keymap wins over layout names; its single layout
+        // can't satisfy the configured index
+        let keymap_string = system_keymap("fr", "").get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        typer
+            .reload_keymap(&EiTypeConfig {
+                keymap_string: Some(keymap_string),
+                layout: Some("de,us".to_string()),
+                layout_index: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        let info = typer.connection_info();
+        assert_eq!(info.keymap_source, KeymapSource::CustomString);
+        assert_eq!(info.layout_index, 0);
+        assert_eq!(info.clamped_layout_index, Some(1));
+        assert_eq!(info.layout_name, "French");
     }
 
     #[test]
-    fn test_keymap_modifier_map_honors_lv3_ralt_alt() {
-        // Regression: with `lv3:ralt_alt`, RAlt is Alt (Mod1), not AltGr (Mod5).
-        // The Menu key (evdev 127) should pick up Mod5 via `lv3:menu_switch`.
-        // This is the configuration that broke PR #16's hardcoded AltGr=100.
-        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
-        let keymap = xkb::Keymap::new_from_names(
-            &context,
-            "",
-            "",
-            "us",
-            "intl",
-            Some("lv3:ralt_alt,lv3:menu_switch".to_string()),
-            xkb::KEYMAP_COMPILE_NO_FLAGS,
-        )
-        .expect("system xkb data required (install xkeyboard-config)");
-        let map = build_keymap_modifier_map(&keymap);
+    fn test_reload_keymap_switches_lookups() {
+        let fixture = |layout| system_keymap(layout, "").get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let keymap_config = |layout| EiTypeConfig {
+            keymap_string: Some(fixture(layout)),
+            layout_index: Some(0),
+            ignore_layout_cache: true,
+            ..Default::default()
+        };
+        let (mut typer, sink) = recording_eitype(EiTypeConfig::default());
+        assert_eq!(typer.keycode_for_char('y'), Some((21, 0)));
+        typer.hold_modifier("shift").unwrap();
+        sink.events.borrow_mut().clear();
+
+        // Y and Z swap places on a German keyboard
+        typer.reload_keymap(&keymap_config("de")).unwrap();
+        assert_eq!(typer.keycode_for_char('y'), Some((44, 0)));
+        assert_eq!(typer.keycode_for_char('ß'), Some((12, 0)));
+        assert_eq!(typer.keymap_source(), KeymapSource::CustomString);
+        // The held modifier was released, and emulation went on as before
+        assert_eq!(key_events(&sink), vec![(42, false)]);
+        assert!(!sink.events().contains(&Recorded::StopEmulating));
+        typer.type_text("y").unwrap();
+        assert_eq!(sink.presses(), vec![44]);
+
+        // A keymap that doesn't compile leaves the old one in place
+        let broken = EiTypeConfig {
+            keymap_string: Some("not a keymap".to_string()),
+            ..Default::default()
+        };
+        typer.hold_modifier("shift").unwrap();
+        assert!(matches!(
+            typer.reload_keymap(&broken),
+            Err(EiTypeError::Keymap(_))
+        ));
+        assert_eq!(typer.keycode_for_char('y'), Some((44, 0)));
         assert_eq!(
-            map.get("Mod5").copied(),
-            Some(127),
-            "with lv3:menu_switch, Mod5 should resolve to the Menu key (evdev 127), \
-             not the hardcoded RAlt=100"
+            typer.held_modifiers.keycodes().collect::<Vec<_>>(),
+            vec![42]
         );
-        // RAlt is now Mod1 under lv3:ralt_alt — Mod1 should resolve to it (or LAlt;
-        // either is fine, both produce Mod1).
-        let mod1 = map.get("Mod1").copied();
-        assert!(
-            mod1 == Some(56) || mod1 == Some(100),
-            "Mod1 should be LAlt (56) or RAlt (100); got {:?}",
-            mod1
+
+        typer.reload_keymap(&keymap_config("us")).unwrap();
+        assert_eq!(typer.keycode_for_char('y'), Some((21, 0)));
+    }
+
+    #[test]
+    fn test_load_keymap_source_file() {
+        let keymap_str = system_keymap("us", "").get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &keymap_str).unwrap();
+        let config = EiTypeConfig {
+            layout: Some("de".to_string()),
+            keymap_file: Some(file.path().to_path_buf()),
+            ..Default::default()
+        };
+        let (keymap, source) = load_keymap(&config, None).unwrap();
+        assert_eq!(source, KeymapSource::File);
+        assert_eq!(
+            keymap_fingerprint(&keymap),
+            keymap_fingerprint(&system_keymap("us", ""))
         );
     }
 
@@ -1944,12 +16742,196 @@ xkb_keymap {
         );
     }
 
+    #[test]
+    fn test_gnome_layout_index() {
+        // Captured `gsettings get org.gnome.desktop.input-sources ...` output
+        let sources = "[('xkb', 'us'), ('ibus', 'anthy'), ('xkb', 'de')]";
+        let mru = "[('xkb', 'de'), ('xkb', 'us'), ('ibus', 'anthy')]";
+        let keymap = system_keymap("us,de", "");
+        assert_eq!(gnome_layout_index(mru, sources, &keymap), Some(1));
+
+        let mru = "[('ibus', 'anthy'), ('xkb', 'us')]";
+        assert_eq!(gnome_layout_index(mru, sources, &keymap), Some(0));
+        assert_eq!(gnome_layout_index("@a(ss) []", sources, &keymap), Some(0));
+
+        // GNOME has two layouts but the keymap only one: index 1 is invalid
+        let mru = "[('xkb', 'de'), ('xkb', 'us')]";
+        let keymap = system_keymap("us", "");
+        assert_eq!(gnome_layout_index(mru, sources, &keymap), None);
+    }
+
+    #[test]
+    fn test_check_gnome_layout() {
+        let keymap = system_keymap("us,fr", "");
+        assert_eq!(check_gnome_layout(&keymap, "fr", 1, 2), Some(1));
+        // A different layout at the index is only warned about
+        assert_eq!(check_gnome_layout(&keymap, "us+dvp", 1, 2), Some(1));
+        assert_eq!(check_gnome_layout(&keymap, "de", 2, 3), None);
+    }
+
     #[test]
     fn test_parse_gnome_input_sources_empty() {
         assert!(parse_gnome_input_sources("[]").is_empty());
         assert!(parse_gnome_input_sources("").is_empty());
     }
 
+    #[test]
+    fn test_exclusive_lock_would_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("eitype.lock");
+        let holder = Rc::new(ExclusiveLock::open(&path, None).unwrap());
+        let other = Rc::new(ExclusiveLock::open(&path, Some(Duration::ZERO)).unwrap());
+
+        let outer = holder.acquire().unwrap();
+        let inner = holder.acquire().unwrap();
+        assert!(matches!(other.acquire(), Err(EiTypeError::WouldConflict)));
+
+        // Still held by the outer acquisition
+        drop(inner);
+        assert!(matches!(other.acquire(), Err(EiTypeError::WouldConflict)));
+
+        let patient = Rc::new(ExclusiveLock::open(&path, Some(Duration::from_millis(30))).unwrap());
+        let start = Instant::now();
+        assert!(matches!(patient.acquire(), Err(EiTypeError::WouldConflict)));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        drop(outer);
+        let _acquired = other.acquire().unwrap();
+    }
+
+    #[test]
+    fn test_exclusive_serializes_two_typers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("eitype.lock");
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let threads: Vec<_> = ["abcdef", "ghijkl"]
+            .into_iter()
+            .map(|text| {
+                let path = path.clone();
+                let barrier = Arc::clone(&barrier);
+                std::thread::spawn(move || {
+                    let (mut typer, sink) = recording_eitype(EiTypeConfig {
+                        delay_ms: 2,
+                        ..Default::default()
+                    });
+                    typer.exclusive = Some(Rc::new(ExclusiveLock::open(&path, None).unwrap()));
+                    barrier.wait();
+                    typer.type_text(text).unwrap();
+                    let keys: Vec<Instant> = sink
+                        .events
+                        .borrow()
+                        .iter()
+                        .filter(|(_, e)| matches!(e, Recorded::Key(..)))
+                        .map(|(at, _)| *at)
+                        .collect();
+                    (keys[0], keys[keys.len() - 1])
+                })
+            })
+            .collect();
+        let mut spans: Vec<(Instant, Instant)> =
+            threads.into_iter().map(|t| t.join().unwrap()).collect();
+        spans.sort();
+
+        // One typer's keystrokes all come before the other's
+        assert!(
+            spans[0].1 < spans[1].0,
+            "keystrokes interleaved: {:?}",
+            spans
+        );
+    }
+
+    /// A `LayoutCache` under a fresh directory (removed when it is dropped)
+    fn temp_layout_cache() -> (tempfile::TempDir, LayoutCache) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = LayoutCache::at(dir.path().join("eitype").join("layout-cache.toml"));
+        (dir, cache)
+    }
+
+    #[test]
+    fn test_layout_cache_write_read() {
+        let (_dir, cache) = temp_layout_cache();
+        assert_eq!(cache.get(0xabc, 2), None);
+
+        cache.set(0xabc, 1);
+        cache.set(0xdef, 0);
+        assert_eq!(cache.get(0xabc, 2), Some(1));
+        assert_eq!(cache.get(0xdef, 2), Some(0));
+
+        let contents = std::fs::read_to_string(&cache.path).unwrap();
+        assert!(
+            contents.contains("\"0000000000000abc\" = 1"),
+            "{}",
+            contents
+        );
+
+        cache.set(0xabc, 0);
+        assert_eq!(cache.get(0xabc, 2), Some(0));
+    }
+
+    #[test]
+    fn test_write_file_atomically() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("nested").join("file");
+        let files = || {
+            let mut files: Vec<String> = std::fs::read_dir(dir.join("nested"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            files
+        };
+
+        write_file_atomically(&path, b"first", 0o644).unwrap();
+        write_file_atomically(&path, b"second", 0o600).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(files(), ["file"]);
+
+        // A failed rename leaves what was there, and no temporary file
+        let occupied = dir.join("nested").join("occupied");
+        std::fs::create_dir_all(occupied.join("child")).unwrap();
+        assert!(write_file_atomically(&occupied, b"third", 0o644).is_err());
+        assert!(occupied.join("child").is_dir());
+        assert_eq!(files(), ["file", "occupied"]);
+    }
+
+    #[test]
+    fn test_layout_cache_invalidates_out_of_range() {
+        let (_dir, cache) = temp_layout_cache();
+        cache.set(0xabc, 2);
+        cache.set(0xdef, 1);
+
+        // Only two layouts now: the stale entry is dropped for good
+        assert_eq!(cache.get(0xabc, 2), None);
+        assert_eq!(cache.get(0xabc, 3), None);
+        assert_eq!(cache.get(0xdef, 2), Some(1));
+    }
+
+    #[test]
+    fn test_set_layout_index_updates_cache() {
+        let config = EiTypeConfig {
+            layout: Some("us,ru".to_string()),
+            ..Default::default()
+        };
+        let (mut typer, _sink) = recording_eitype(config);
+        let (_dir, cache) = temp_layout_cache();
+        typer.layout_cache = Some(LayoutCache::at(cache.path.clone()));
+
+        typer.set_layout_index(1).unwrap();
+        assert_eq!(typer.connection_info().layout_index, 1);
+        assert_eq!(cache.get(typer.keymap_fingerprint(), 2), Some(1));
+
+        assert!(matches!(
+            typer.set_layout_index(2),
+            Err(EiTypeError::Keymap(_))
+        ));
+        assert_eq!(typer.connection_info().layout_index, 1);
+    }
+
     #[test]
     fn test_parse_sway_layout_index_typical() {
         let json = r#"[
@@ -1991,4 +16973,184 @@ xkb_keymap {
 ]"#;
         assert_eq!(parse_sway_layout_index(json), None);
     }
+
+    #[test]
+    fn test_parse_sway_focused_app() {
+        // Trimmed `swaymsg -t get_tree --raw`: the focused workspace's
+        // container isn't focused itself, and a title mentions "focused"
+        let json = r#"{"id": 1, "type": "root", "focused": false, "name": "root", "nodes": [
+  {"id": 3, "type": "workspace", "focused": false, "name": "1", "nodes": [
+    {"id": 4, "type": "con", "focused": false, "name": "\"focused\": true {", "nodes": [],
+     "app_id": "org.gnome.Terminal", "pid": 100},
+    {"id": 5, "type": "con", "focused": true, "name": "Dictation", "nodes": [],
+     "floating_nodes": [], "app_id": "com.example.Dictate", "pid": 101}
+  ]}
+]}"#;
+        assert_eq!(
+            parse_sway_focused_app(json),
+            Some("com.example.Dictate".to_string())
+        );
+
+        // Xwayland windows have no app_id, only an X11 class
+        let json = r#"{"nodes": [{"type": "con", "focused": true, "nodes": [], "app_id": null,
+  "window_properties": {"title": "x", "class": "Firefox", "instance": "Navigator"}}]}"#;
+        assert_eq!(parse_sway_focused_app(json), Some("Firefox".to_string()));
+
+        // An empty workspace has focus: the next window's app_id isn't used
+        let json = r#"{"nodes": [{"type": "workspace", "focused": true, "nodes": []},
+  {"type": "workspace", "focused": false, "nodes": [{"app_id": "foot"}]}]}"#;
+        assert_eq!(parse_sway_focused_app(json), None);
+    }
+
+    #[test]
+    fn test_parse_hyprland_focused_app() {
+        let json = r#"{
+    "address": "0x55d1e3b0c2a0",
+    "mapped": true,
+    "workspace": {
+        "id": 1,
+        "name": "1"
+    },
+    "class": "com.example.Dictate",
+    "title": "Dictate \u2014 \"listening\"",
+    "initialClass": "com.example.Dictate"
+}"#;
+        assert_eq!(
+            parse_hyprland_focused_app(json),
+            Some("com.example.Dictate".to_string())
+        );
+        assert_eq!(parse_hyprland_focused_app("{}"), None);
+        assert_eq!(parse_hyprland_focused_app(r#"{"class": ""}"#), None);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(
+            json_string(r#""a\"b\\c\u00e9""#),
+            Some("a\"b\\cé".to_string())
+        );
+        assert_eq!(json_string("null"), None);
+    }
+
+    #[test]
+    fn test_check_focus_guard() {
+        let err = check_focus_guard("com.example.dictate", || {
+            Some("com.example.Dictate".to_string())
+        })
+        .unwrap_err();
+        assert!(
+            matches!(&err, EiTypeError::FocusGuardTriggered { focused } if focused == "com.example.Dictate")
+        );
+        check_focus_guard("com.example.Dictate", || Some("foot".to_string())).unwrap();
+        check_focus_guard("com.example.Dictate", || None).unwrap();
+    }
+
+    /// Trimmed `swaymsg -t get_tree --raw` with a native and an Xwayland
+    /// window, on a workspace whose name mentions an app id
+    const SWAY_TREE: &str = r#"{"id": 1, "type": "root", "name": "root", "nodes": [
+  {"id": 3, "type": "workspace", "name": "firefox", "nodes": [
+    {"id": 4, "type": "con", "name": "~/src \u2014 foot", "nodes": [],
+     "app_id": "foot", "pid": 100},
+    {"id": 7, "type": "con", "name": "Inbox - Mozilla Firefox", "nodes": [],
+     "app_id": null, "pid": 101,
+     "window_properties": {"title": "Inbox", "class": "Firefox", "instance": "Navigator"}}
+  ], "floating_nodes": []}
+]}"#;
+
+    /// Trimmed `hyprctl clients -j`, with an unmapped window first
+    const HYPRLAND_CLIENTS: &str = r#"[{
+    "address": "0x55d1e3b0a000",
+    "mapped": false,
+    "workspace": {"id": -99, "name": "special"},
+    "class": "foot",
+    "title": "scratch"
+}, {
+    "address": "0x55d1e3b0c2a0",
+    "mapped": true,
+    "workspace": {"id": 1, "name": "1"},
+    "class": "foot",
+    "title": "~/src \u2014 foot",
+    "initialClass": "foot"
+}, {
+    "address": "0x55d1e3b0d100",
+    "mapped": true,
+    "workspace": {"id": 2, "name": "2"},
+    "class": "firefox",
+    "title": "Inbox - Mozilla Firefox",
+    "initialClass": "firefox"
+}]"#;
+
+    #[test]
+    fn test_parse_sway_window_id() {
+        let find = |target| parse_sway_window_id(SWAY_TREE, &target);
+        assert_eq!(find(FocusTarget::AppId("FOOT".into())), Some("4".into()));
+        // By X11 class, not the workspace named after it
+        assert_eq!(find(FocusTarget::AppId("firefox".into())), Some("7".into()));
+        assert_eq!(
+            find(FocusTarget::title("^Inbox").unwrap()),
+            Some("7".into())
+        );
+        assert_eq!(
+            find(FocusTarget::title("— foot$").unwrap()),
+            Some("4".into())
+        );
+        assert_eq!(find(FocusTarget::AppId("root".into())), None);
+        assert_eq!(find(FocusTarget::title("^firefox$").unwrap()), None);
+    }
+
+    #[test]
+    fn test_parse_hyprland_window_address() {
+        let find = |target| parse_hyprland_window_address(HYPRLAND_CLIENTS, &target);
+        assert_eq!(
+            find(FocusTarget::AppId("foot".into())),
+            Some("0x55d1e3b0c2a0".into())
+        );
+        assert_eq!(
+            find(FocusTarget::title("Mozilla").unwrap()),
+            Some("0x55d1e3b0d100".into())
+        );
+        assert_eq!(find(FocusTarget::title("scratch").unwrap()), None);
+        assert_eq!(find(FocusTarget::AppId("special".into())), None);
+    }
+
+    #[test]
+    fn test_focus_window_with() {
+        let target = FocusTarget::AppId("firefox".into());
+        let mut commands = Vec::new();
+        let focused = focus_window_with(&target, Some(CompositorIpc::Sway), |program, args| {
+            commands.push(format!("{} {}", program, args.join(" ")));
+            Some(SWAY_TREE.to_string())
+        })
+        .unwrap();
+        assert!(focused);
+        assert_eq!(
+            commands,
+            ["swaymsg -t get_tree --raw", "swaymsg [con_id=7] focus"]
+        );
+
+        commands.clear();
+        let focused = focus_window_with(&target, Some(CompositorIpc::Hyprland), |program, args| {
+            commands.push(format!("{} {}", program, args.join(" ")));
+            Some(HYPRLAND_CLIENTS.to_string())
+        })
+        .unwrap();
+        assert!(focused);
+        assert_eq!(
+            commands[1],
+            "hyprctl dispatch focuswindow address:0x55d1e3b0d100"
+        );
+
+        let missing = FocusTarget::AppId("thunderbird".into());
+        let err = focus_window_with(&missing, Some(CompositorIpc::Sway), |_, _| {
+            Some(SWAY_TREE.to_string())
+        })
+        .unwrap_err();
+        assert!(
+            matches!(&err, EiTypeError::FocusTargetNotFound(target) if target == "app id thunderbird")
+        );
+
+        // Without compositor IPC, focus is left alone
+        let focused = focus_window_with(&missing, None, |_, _| unreachable!()).unwrap();
+        assert!(!focused);
+    }
 }
@@ -3,63 +3,21 @@
 //! This is the command-line interface for the eitype library.
 
 use anyhow::{bail, Context, Result};
-use clap::Parser;
-use eitype::{Action, EiType, EiTypeConfig};
-use log::{error, info, warn};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use eitype::{
+    capabilities, convert_layout, default_token_namespace, diff_keymaps, list_key_names,
+    parse_inline, request_portal_permission, validate_input, Action, ActionReport, ActionStatus,
+    AltGrMode, BenchOptions, BenchReport, CalibrateOptions, Calibration, Capabilities, CharTiming,
+    DeviceCapability, EiType, EiTypeConfig, EiTypeError, FocusTarget, KeyPosition, KeymapDiff,
+    KeymapSource, KeysymDiff, LatencyPercentiles, ModifierOrder, PasteOptions, PasteTrigger,
+    Profiles, Selection, TimestampSource, TokenStore, TypingStats, RESTORE_TOKEN_ENV,
+};
+use log::{debug, error, info, warn};
 use std::fs;
-use std::path::PathBuf;
-
-// ============================================================================
-// Token Storage (CLI-only, not in library)
-// ============================================================================
-
-fn get_token_path() -> PathBuf {
-    let cache_dir = std::env::var("XDG_CACHE_HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-            PathBuf::from(home).join(".cache")
-        });
-    cache_dir.join("eitype").join("restore_token")
-}
-
-fn load_restore_token() -> Option<String> {
-    let path = get_token_path();
-    match fs::read_to_string(&path) {
-        Ok(token) => {
-            let token = token.trim().to_string();
-            if !token.is_empty() {
-                info!("Loaded restore token from {:?}", path);
-                Some(token)
-            } else {
-                None
-            }
-        }
-        Err(_) => None,
-    }
-}
-
-fn save_restore_token(token: &str) -> Result<()> {
-    let path = get_token_path();
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("Failed to create token directory: {:?}", parent))?;
-    }
-    fs::write(&path, token)
-        .with_context(|| format!("Failed to save restore token to {:?}", path))?;
-    info!("Saved restore token to {:?}", path);
-    Ok(())
-}
-
-fn clear_restore_token() -> Result<()> {
-    let path = get_token_path();
-    if path.exists() {
-        fs::remove_file(&path)
-            .with_context(|| format!("Failed to remove token file: {:?}", path))?;
-        info!("Cleared restore token from {:?}", path);
-    }
-    Ok(())
-}
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use xkbcommon::xkb;
 
 // ============================================================================
 // CLI Arguments
@@ -69,19 +27,107 @@ fn clear_restore_token() -> Result<()> {
 #[derive(Parser, Debug)]
 #[command(name = "eitype", version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Text to type (can be specified multiple times)
     #[arg(value_name = "TEXT")]
     text: Vec<String>,
 
+    /// Type the contents of a file, as UTF-8, newlines included (`-` reads
+    /// stdin; can be specified multiple times)
+    #[arg(long = "file", value_name = "PATH")]
+    files: Vec<PathBuf>,
+
     /// Delay between key events in milliseconds
     #[arg(short = 'd', long, default_value = "0", value_name = "MS")]
     delay: u64,
 
-    /// Press a special key (e.g., return, tab, escape, backspace)
+    /// Press a special key (e.g., return, tab, escape, backspace). Common
+    /// localized names work too (e.g., eingabe, entrée, пробел).
     #[arg(short = 'k', long = "key", value_name = "KEY")]
     keys: Vec<String>,
 
-    /// Hold a modifier key (e.g., shift, ctrl, alt, super)
+    /// Type a key by XKB keysym name (e.g., eacute, Cyrillic_de)
+    #[arg(long = "keysym", value_name = "KEYSYM")]
+    keysyms: Vec<String>,
+
+    /// Press a key and keep it down until --keyup KEY (any key still down is
+    /// released when eitype exits), e.g. `--keydown shift -k left --keyup shift`
+    #[arg(long = "keydown", value_name = "KEY")]
+    key_downs: Vec<String>,
+
+    /// Release a key pressed with --keydown
+    #[arg(long = "keyup", value_name = "KEY")]
+    key_ups: Vec<String>,
+
+    /// Instead of typing TEXT, print it converted from layout index FROM to
+    /// TO, as if it had been typed on the wrong layout (e.g. 0,1)
+    #[arg(long, value_name = "FROM,TO", value_parser = parse_layout_pair)]
+    convert_layout: Option<(u32, u32)>,
+
+    /// With --convert-layout, delete TEXT with Backspace and type the
+    /// conversion instead of printing it
+    #[arg(long, requires = "convert_layout")]
+    retype: bool,
+
+    /// Scroll by DX,DY (e.g. 0,3 scrolls down three notches)
+    #[arg(long, value_name = "DX,DY", value_parser = parse_scroll, allow_hyphen_values = true)]
+    scroll: Vec<(f64, f64)>,
+
+    /// Treat --scroll amounts as mouse wheel notches (the default)
+    #[arg(long, conflicts_with = "smooth")]
+    discrete: bool,
+
+    /// Treat --scroll amounts as pixel deltas, like a touchpad
+    #[arg(long)]
+    smooth: bool,
+
+    /// Tap the touchscreen at X,Y (in logical pixels)
+    #[arg(long, value_name = "X,Y", value_parser = parse_position)]
+    tap: Vec<(f64, f64)>,
+
+    /// Press Return after each TEXT
+    #[arg(long)]
+    line: bool,
+
+    /// Run all the actions N times over one connection
+    #[arg(long, default_value = "1", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: u32,
+
+    /// Pause between --repeat iterations in milliseconds
+    #[arg(long, default_value = "0", value_name = "MS")]
+    repeat_delay: u64,
+
+    /// Paste TEXT via the clipboard instead of typing it (needs wl-clipboard)
+    #[arg(long)]
+    paste: bool,
+
+    /// Read keys inside TEXT (and --file): {key:NAME}, {combo:SPEC},
+    /// {sleep:MS}, \t and \n for Tab and Return; {{ and \\ for a literal {
+    /// and \
+    #[arg(long, conflicts_with = "paste")]
+    inline: bool,
+
+    /// Selection to paste from: clipboard or primary
+    #[arg(long, default_value = "clipboard", value_name = "SELECTION")]
+    paste_selection: Selection,
+
+    /// How to trigger the paste: ctrl-v, ctrl-shift-v, shift-insert or middle-click
+    #[arg(long, default_value = "ctrl-v", value_name = "TRIGGER")]
+    paste_trigger: PasteTrigger,
+
+    /// Restore the selection's previous contents this many milliseconds after pasting
+    #[arg(long, value_name = "MS")]
+    paste_restore_ms: Option<u64>,
+
+    /// Keep one connection open and type each line written to this FIFO
+    /// (created if missing) until Ctrl+C or SIGTERM; writers may come and go
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["text", "files"])]
+    fifo: Option<PathBuf>,
+
+    /// Hold a modifier key (e.g., shift, ctrl, alt, super, or kc:N for
+    /// evdev keycode N)
     #[arg(short = 'M', long = "mod", value_name = "MOD")]
     modifiers: Vec<String>,
 
@@ -94,6 +140,15 @@ struct Args {
     #[arg(short = 's', long, value_name = "PATH")]
     socket: Option<String>,
 
+    /// Run the portal connection again up to N times when it fails in a way
+    /// that may be transient (e.g. xdg-desktop-portal restarting at boot)
+    #[arg(long, default_value_t = 0, value_name = "N", global = true)]
+    connect_retries: u32,
+
+    /// Milliseconds to wait before each --connect-retries retry
+    #[arg(long, default_value_t = 1000, value_name = "MS", global = true)]
+    connect_retry_delay_ms: u64,
+
     /// XKB keyboard layout (e.g., "us", "de", "fr"). Overrides XKB_DEFAULT_LAYOUT env var.
     #[arg(short = 'l', long, value_name = "LAYOUT")]
     layout: Option<String>,
@@ -111,10 +166,121 @@ struct Args {
     options: Option<String>,
 
     /// XKB layout index to use when multiple layouts are available.
-    /// If not specified, auto-detects from the compositor (GNOME, KDE, Sway).
+    /// If not specified, uses the index last given for the same keymap, or
+    /// auto-detects from the compositor (GNOME, KDE, Sway).
     #[arg(long, value_name = "INDEX")]
     layout_index: Option<u32>,
 
+    /// Don't ask the desktop (gsettings, qdbus, swaymsg) for the active
+    /// layout; use index 0 unless --layout-index or the cache says otherwise
+    #[arg(long)]
+    no_auto_detect_layout: bool,
+
+    /// Refuse to type if the focused window belongs to this app id, e.g. the
+    /// dictation app running eitype (only enforceable on Sway and Hyprland)
+    #[arg(long, value_name = "APP_ID")]
+    refuse_if_focused: Option<String>,
+
+    /// Focus the window of this app id (or X11 class) before typing (Sway
+    /// and Hyprland; elsewhere the focused window is typed into, with a
+    /// warning)
+    #[arg(long, value_name = "APP_ID", conflicts_with = "focus_title")]
+    focus_app: Option<String>,
+
+    /// Focus the window whose title matches this regex before typing, like
+    /// --focus-app
+    #[arg(long, value_name = "REGEX")]
+    focus_title: Option<String>,
+
+    /// Milliseconds to wait after --focus-app or --focus-title focuses a
+    /// window, before typing
+    #[arg(long, default_value_t = 200, value_name = "MS")]
+    focus_settle_ms: u64,
+
+    /// Clock for event timestamps: process-relative or monotonic
+    /// (CLOCK_MONOTONIC, for compositors that check timestamps)
+    #[arg(long, default_value = "process-relative", value_name = "SOURCE")]
+    timestamp_source: TimestampSource,
+
+    /// Key to press for AltGr: right-alt (the keymap's level 3 key) or
+    /// level3-keysym (the key bound to ISO_Level3_Shift, for sessions where
+    /// right Alt doesn't act as AltGr)
+    #[arg(long, default_value = "right-alt", value_name = "MODE", global = true)]
+    altgr_mode: AltGrMode,
+
+    /// Modifier to press first for characters that need both Shift and
+    /// AltGr: shift-first or level3-first
+    #[arg(
+        long,
+        default_value = "shift-first",
+        value_name = "ORDER",
+        global = true
+    )]
+    modifier_order: ModifierOrder,
+
+    /// Type characters no key produces as ASCII approximations (é as e,
+    /// … as ...) instead of failing, for fields that only take ASCII
+    #[arg(long, global = true)]
+    ascii_transliterate: bool,
+
+    /// Type zero width spaces, soft hyphens, byte order marks and other
+    /// invisible format characters rather than leaving them out (they fail
+    /// unless the keymap has them)
+    #[arg(long, global = true)]
+    keep_format_chars: bool,
+
+    /// Type with the settings of this `[profile.NAME]` table of
+    /// ~/.config/eitype/config.toml, over the other options
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Type newlines in TEXT as linefeed characters instead of pressing
+    /// Return (tabs are the Tab key either way)
+    #[arg(long, global = true)]
+    literal: bool,
+
+    /// Drop control characters and line breaks so pasted-in text can't run
+    /// a command in a terminal; only --line presses Return
+    #[arg(long, global = true)]
+    terminal_safe: bool,
+
+    /// Don't read or update the remembered layout index
+    #[arg(long)]
+    ignore_layout_cache: bool,
+
+    /// Wait for other eitype instances to finish instead of typing over them
+    #[arg(long)]
+    exclusive: bool,
+
+    /// Give up if another instance is still typing after this many milliseconds
+    /// (0 = fail immediately)
+    #[arg(long, value_name = "MS", requires = "exclusive")]
+    exclusive_timeout: Option<u64>,
+
+    /// Refuse to type any TEXT longer than this many characters
+    #[arg(long, value_name = "CHARS")]
+    max_text_len: Option<usize>,
+
+    /// Pause briefly between keys while the socket keeps filling up, for
+    /// compositors that drop input when they fall behind
+    #[arg(long)]
+    adaptive_throttle: bool,
+
+    /// Record how long each typed character took and write it to FILE as
+    /// CSV (planning, flush and gap times in microseconds)
+    #[arg(long, value_name = "FILE")]
+    timing_out: Option<PathBuf>,
+
+    /// When done, print characters typed, frames sent, flush retries and
+    /// elapsed time to stderr
+    #[arg(long)]
+    stats: bool,
+
+    /// Always send the built-in keycodes for key names like "escape", even
+    /// where the keymap puts that key elsewhere (e.g. `caps:swapescape`)
+    #[arg(long, global = true)]
+    no_prefer_keymap_keys: bool,
+
     /// Verbose output
     #[arg(short = 'v', long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -122,11 +288,281 @@ struct Args {
     /// Clear saved portal session token and force new authorization dialog
     #[arg(long)]
     reset_token: bool,
+
+    /// Save and load the portal restore token under NAME, so programs that
+    /// run eitype keep separate tokens (default: the name eitype was run as)
+    #[arg(long, value_name = "NAME", global = true)]
+    namespace: Option<String>,
+
+    /// Print any new portal restore token to stderr (it is still saved too),
+    /// e.g. to keep it in EITYPE_RESTORE_TOKEN
+    #[arg(long)]
+    print_token: bool,
+
+    /// List the key names -k accepts, marking the ones that press a combo
+    /// (e.g. backtab for shift+tab), and exit
+    #[arg(long)]
+    list_keys: bool,
+
+    /// Result format: human (log messages only) or json (one JSON object on
+    /// stdout when done; logs still go to stderr)
+    #[arg(long, value_enum, default_value = "human", value_name = "FORMAT")]
+    output: OutputFormat,
+
+    /// The action flags' values in command-line order, as (flag, index into
+    /// its values); filled in by `try_parse_ordered`. Empty = grouped by flag.
+    #[arg(skip)]
+    order: Vec<(ActionArg, usize)>,
+
+    /// Contents of each --file, filled in by `read_files`
+    #[arg(skip)]
+    file_texts: Vec<String>,
+}
+
+/// A flag (or TEXT) that adds actions, which run in command-line order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ActionArg {
+    Text,
+    File,
+    Keysym,
+    Key,
+    KeyDown,
+    KeyUp,
+    PressMod,
+    Scroll,
+    Tap,
+}
+
+impl ActionArg {
+    /// Every action flag, in the order they run when the command-line order
+    /// isn't known
+    const ALL: [ActionArg; 9] = [
+        ActionArg::Text,
+        ActionArg::File,
+        ActionArg::Keysym,
+        ActionArg::Key,
+        ActionArg::KeyDown,
+        ActionArg::KeyUp,
+        ActionArg::PressMod,
+        ActionArg::Scroll,
+        ActionArg::Tap,
+    ];
+
+    /// The flag's clap id (its field in `Args`)
+    fn id(self) -> &'static str {
+        match self {
+            ActionArg::Text => "text",
+            ActionArg::File => "files",
+            ActionArg::Keysym => "keysyms",
+            ActionArg::Key => "keys",
+            ActionArg::KeyDown => "key_downs",
+            ActionArg::KeyUp => "key_ups",
+            ActionArg::PressMod => "press_modifiers",
+            ActionArg::Scroll => "scroll",
+            ActionArg::Tap => "tap",
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Connect and show the keymap in use, and which keycode each special
+    /// key name (-k) sends with it
+    Info,
+    /// Manage the saved portal restore token
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Find the fastest --delay the compositor keeps up with, by typing
+    /// (and erasing) a probe into the focused window at increasing rates
+    Calibrate(CalibrateArgs),
+    /// Measure how fast typing goes, by typing (and erasing) pangrams into
+    /// the focused window, or into nothing with `--against null`
+    Bench(BenchArgs),
+    /// Show what two keymaps type differently, e.g. the server's keymap
+    /// against the one on another machine
+    KeymapDiff(KeymapDiffArgs),
+    /// List the key names, combos, actions, fallbacks and backends this
+    /// build supports
+    Capabilities(CapabilitiesArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct KeymapDiffArgs {
+    /// First keymap: `live` (the EI server's), `file:PATH`, or
+    /// `LAYOUT[(VARIANT)][:OPTIONS]`, e.g. `us(intl)` or `de:lv3:ralt_alt`
+    #[arg(value_name = "KEYMAP")]
+    a: KeymapSpec,
+
+    /// Second keymap, in the same forms
+    #[arg(value_name = "KEYMAP")]
+    b: KeymapSpec,
+
+    /// Print the differences as one JSON object
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CapabilitiesArgs {
+    /// Print the catalogue as one versioned JSON object, for tools
+    #[arg(long)]
+    json: bool,
+}
+
+/// A keymap named on the `keymap-diff` command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeymapSpec {
+    /// The keymap the EI server sends
+    Live,
+    /// A keymap file
+    File(PathBuf),
+    /// Compiled from layout names
+    Names {
+        layout: String,
+        variant: Option<String>,
+        options: Option<String>,
+    },
+}
+
+impl std::str::FromStr for KeymapSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "live" {
+            return Ok(KeymapSpec::Live);
+        }
+        if let Some(path) = s.strip_prefix("file:") {
+            return Ok(KeymapSpec::File(PathBuf::from(path)));
+        }
+        let (names, options) = match s.split_once(':') {
+            Some((names, options)) => (names, Some(options.to_string())),
+            None => (s, None),
+        };
+        let (layout, variant) = match names.strip_suffix(')').and_then(|n| n.split_once('(')) {
+            Some((layout, variant)) => (layout, Some(variant.to_string())),
+            None => (names, None),
+        };
+        if layout.is_empty() || layout.contains(['(', ')']) {
+            return Err(format!(
+                "invalid keymap {:?} (expected live, file:PATH or LAYOUT[(VARIANT)][:OPTIONS])",
+                s
+            ));
+        }
+        Ok(KeymapSpec::Names {
+            layout: layout.to_string(),
+            variant,
+            options,
+        })
+    }
+}
+
+impl KeymapSpec {
+    /// Compile the keymap, connecting for `live`
+    fn load(&self, args: &Args) -> Result<xkb::Keymap> {
+        let config = match self {
+            KeymapSpec::Live => {
+                let eitype = connect(args, EiTypeConfig::default())?;
+                if eitype.keymap_source() != KeymapSource::Server {
+                    bail!("The EI server sent no keymap");
+                }
+                return eitype.keymap().context("The EI server sent no keymap");
+            }
+            KeymapSpec::File(path) => EiTypeConfig {
+                keymap_file: Some(path.clone()),
+                ..Default::default()
+            },
+            KeymapSpec::Names {
+                layout,
+                variant,
+                options,
+            } => EiTypeConfig {
+                layout: Some(layout.clone()),
+                variant: variant.clone(),
+                options: options.clone(),
+                ..Default::default()
+            },
+        };
+        Ok(config.compile_keymap()?)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct CalibrateArgs {
+    /// Text typed at each rate
+    #[arg(long, value_name = "TEXT")]
+    probe: Option<String>,
+
+    /// Leave the probe text in place instead of erasing it with Backspace
+    #[arg(long)]
+    no_erase: bool,
+
+    /// Don't ask for confirmation before typing
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Save the recommended delay as delay_ms of profile NAME in the config
+    /// file, for --profile NAME
+    #[arg(long, value_name = "NAME")]
+    save: Option<String>,
+}
+
+impl CalibrateArgs {
+    fn to_options(&self) -> CalibrateOptions {
+        let defaults = CalibrateOptions::default();
+        CalibrateOptions {
+            probe: self.probe.clone().unwrap_or(defaults.probe),
+            erase: !self.no_erase,
+            ..defaults
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchArgs {
+    /// Characters of pangram text to type
+    #[arg(long, default_value_t = 2000, value_name = "N")]
+    chars: usize,
+
+    /// Where to type: editor (the focused window) or null (nowhere, without
+    /// connecting, to tell eitype's own overhead from the compositor's)
+    #[arg(long, value_enum, default_value = "editor", value_name = "TARGET")]
+    against: BenchTarget,
+
+    /// Don't ask for confirmation before typing
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Print the results as one JSON object
+    #[arg(long)]
+    json: bool,
+}
+
+/// Where `eitype bench` types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum BenchTarget {
+    Editor,
+    Null,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum TokenCommand {
+    /// Ask for remote control permission now (showing the portal dialog)
+    /// and save the restore token, without typing anything
+    Request,
+}
+
+/// How the CLI reports its result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Human,
+    Json,
 }
 
 impl Args {
     /// Convert CLI args to EiTypeConfig
     fn to_config(&self) -> EiTypeConfig {
+        let pointer = !self.scroll.is_empty()
+            || (self.paste && self.paste_trigger == PasteTrigger::MiddleClick);
         EiTypeConfig {
             layout: self
                 .layout
@@ -146,37 +582,252 @@ impl Args {
                 .or_else(|| std::env::var("XKB_DEFAULT_OPTIONS").ok()),
             layout_index: self.layout_index,
             delay_ms: self.delay,
+            keymap_string: None,
+            keymap_file: None,
+            chunk: None,
+            paste_threshold: None,
+            idle_stop_emulating_ms: None,
+            stop_emulating_after_each: false,
+            pointer,
+            ignore_layout_cache: self.ignore_layout_cache,
+            exclusive: self.exclusive,
+            exclusive_timeout_ms: self.exclusive_timeout,
+            timestamp_source: self.timestamp_source,
+            adaptive_throttle: self.adaptive_throttle,
+            max_text_len: self.max_text_len,
+            prefer_keymap_keys: !self.no_prefer_keymap_keys,
+            capabilities: (!self.tap.is_empty()).then(|| {
+                let mut capabilities = vec![DeviceCapability::Touch];
+                if pointer {
+                    capabilities.extend([
+                        DeviceCapability::Pointer,
+                        DeviceCapability::Button,
+                        DeviceCapability::Scroll,
+                    ]);
+                }
+                capabilities
+            }),
+            collect_timing: self.timing_out.is_some(),
+            auto_detect_layout: !self.no_auto_detect_layout,
+            layout_detection_ttl_ms: EiTypeConfig::default().layout_detection_ttl_ms,
+            refuse_if_focused_app: self.refuse_if_focused.clone(),
+            focus_settle_ms: self.focus_settle_ms,
+            strict_key_state: false,
+            settle_after_word_ms: None,
+            altgr_mode: self.altgr_mode,
+            modifier_order: self.modifier_order,
+            modifier_settle_ms: 0,
+            ascii_transliterate: self.ascii_transliterate,
+            strip_format_chars: !self.keep_format_chars,
+            literal_whitespace: self.literal,
+            device_failover_ms: 2000,
+            connect_retries: self.connect_retries,
+            connect_retry_delay_ms: self.connect_retry_delay_ms,
+            max_modifier_hold_ms: None,
+            terminal_safe: self.terminal_safe,
+            token_namespace: Some(self.token_namespace()),
+        }
+    }
+
+    /// The --namespace for the restore token, or the default one
+    fn token_namespace(&self) -> String {
+        self.namespace
+            .clone()
+            .unwrap_or_else(default_token_namespace)
+    }
+
+    /// Parse arguments like `try_parse_from`, also recording the order the
+    /// action flags were given in, so they run in that order
+    fn try_parse_ordered<I, T>(itr: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = Self::command().try_get_matches_from(itr)?;
+        let mut args = Self::from_arg_matches(&matches)?;
+        let mut positions = Vec::new();
+        for arg in ActionArg::ALL {
+            if let Some(indices) = matches.indices_of(arg.id()) {
+                positions.extend(indices.enumerate().map(|(i, position)| (position, arg, i)));
+            }
         }
+        positions.sort();
+        args.order = positions.into_iter().map(|(_, arg, i)| (arg, i)).collect();
+        Ok(args)
     }
 
     /// Build list of actions from CLI args
-    fn to_actions(&self) -> Vec<Action> {
+    fn to_actions(&self) -> Result<Vec<Action>, EiTypeError> {
         let mut actions = Vec::new();
 
+        // Focus the target window before anything is typed into it
+        if let Some(app_id) = &self.focus_app {
+            actions.push(Action::Focus(FocusTarget::AppId(app_id.clone())));
+        } else if let Some(pattern) = &self.focus_title {
+            actions.push(Action::Focus(FocusTarget::title(pattern)?));
+        }
+
         // Add held modifiers first
         for m in &self.modifiers {
             actions.push(Action::ModifierHold(m.clone()));
         }
 
-        // Add text
-        for text in &self.text {
-            actions.push(Action::Type(text.clone()));
+        // Then everything else, in command-line order if known
+        let grouped;
+        let order = if self.order.is_empty() {
+            grouped = self.grouped_order();
+            &grouped
+        } else {
+            &self.order
+        };
+        for &(arg, i) in order {
+            match arg {
+                ActionArg::Text => self.push_text(&mut actions, &self.text[i])?,
+                ActionArg::File => self.push_text(&mut actions, &self.file_texts[i])?,
+                ActionArg::Keysym => actions.push(Action::Keysym(self.keysyms[i].clone())),
+                ActionArg::Key => actions.push(Action::Key(self.keys[i].clone())),
+                ActionArg::KeyDown => actions.push(Action::KeyDown(self.key_downs[i].clone())),
+                ActionArg::KeyUp => actions.push(Action::KeyUp(self.key_ups[i].clone())),
+                ActionArg::PressMod => {
+                    actions.push(Action::ModifierPress(self.press_modifiers[i].clone()))
+                }
+                ActionArg::Scroll => {
+                    let (dx, dy) = self.scroll[i];
+                    if self.smooth {
+                        actions.push(Action::ScrollSmooth(dx, dy));
+                    } else {
+                        actions.push(Action::ScrollDiscrete(dx as i32, dy as i32));
+                    }
+                }
+                ActionArg::Tap => {
+                    let (x, y) = self.tap[i];
+                    actions.push(Action::Tap(x, y));
+                }
+            }
         }
 
-        // Add keys
-        for key in &self.keys {
-            actions.push(Action::Key(key.clone()));
+        Ok(actions)
+    }
+
+    /// Add the actions for one TEXT (or --file): type or paste it, or with
+    /// --inline, its text and keys; and press Return after it with --line
+    fn push_text(&self, actions: &mut Vec<Action>, text: &str) -> Result<(), EiTypeError> {
+        if self.paste {
+            actions.push(Action::Paste(text.to_string(), self.paste_options()));
+        } else if self.inline {
+            actions.extend(parse_inline(text)?);
+        } else {
+            actions.push(Action::Type(text.to_string()));
+        }
+        if self.line {
+            actions.push(Action::Key("return".to_string()));
         }
+        Ok(())
+    }
+
+    /// Read every --file (stdin for `-`), so `to_actions` can type them
+    fn read_files(&mut self) -> Result<()> {
+        self.file_texts = self
+            .files
+            .iter()
+            .map(|path| read_text_file(path))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Every action flag value, grouped by flag in `ActionArg::ALL` order
+    fn grouped_order(&self) -> Vec<(ActionArg, usize)> {
+        ActionArg::ALL
+            .into_iter()
+            .flat_map(|arg| {
+                let count = match arg {
+                    ActionArg::Text => self.text.len(),
+                    ActionArg::File => self.files.len(),
+                    ActionArg::Keysym => self.keysyms.len(),
+                    ActionArg::Key => self.keys.len(),
+                    ActionArg::KeyDown => self.key_downs.len(),
+                    ActionArg::KeyUp => self.key_ups.len(),
+                    ActionArg::PressMod => self.press_modifiers.len(),
+                    ActionArg::Scroll => self.scroll.len(),
+                    ActionArg::Tap => self.tap.len(),
+                };
+                (0..count).map(move |i| (arg, i))
+            })
+            .collect()
+    }
 
-        // Add pressed modifiers
-        for m in &self.press_modifiers {
-            actions.push(Action::ModifierPress(m.clone()));
+    /// Discrete scrolling only moves by whole notches
+    fn check_scroll(&self) -> Result<()> {
+        if self.smooth {
+            return Ok(());
+        }
+        if let Some((dx, dy)) = self
+            .scroll
+            .iter()
+            .find(|(dx, dy)| dx.fract() != 0.0 || dy.fract() != 0.0)
+        {
+            bail!(
+                "Discrete scrolling needs whole notches, got {},{} (use --smooth for pixel deltas)",
+                dx,
+                dy
+            );
         }
+        Ok(())
+    }
 
-        actions
+    /// Paste options from the --paste-* flags
+    fn paste_options(&self) -> PasteOptions {
+        PasteOptions {
+            selection: self.paste_selection,
+            trigger: self.paste_trigger,
+            restore_after: self.paste_restore_ms.map(std::time::Duration::from_millis),
+        }
     }
 }
 
+/// Parse a `--scroll` amount of the form "DX,DY"
+fn parse_scroll(s: &str) -> Result<(f64, f64), String> {
+    let (dx, dy) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected DX,DY, got '{}'", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("invalid scroll amount '{}'", v))
+    };
+    Ok((parse(dx)?, parse(dy)?))
+}
+
+/// Parse a `--convert-layout` pair of layout indices of the form "FROM,TO"
+fn parse_layout_pair(s: &str) -> Result<(u32, u32), String> {
+    let (from, to) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected FROM,TO, got '{}'", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("invalid layout index '{}'", v))
+    };
+    Ok((parse(from)?, parse(to)?))
+}
+
+/// Parse a `--tap` position of the form "X,Y"
+fn parse_position(s: &str) -> Result<(f64, f64), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected X,Y, got '{}'", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("invalid position '{}'", v))
+    };
+    Ok((parse(x)?, parse(y)?))
+}
+
 /// Get socket path from CLI arg or LIBEI_SOCKET environment variable.
 fn get_socket_path(socket_arg: Option<&str>) -> Option<PathBuf> {
     if let Some(path) = socket_arg {
@@ -191,112 +842,1557 @@ fn get_socket_path(socket_arg: Option<&str>) -> Option<PathBuf> {
 }
 
 // ============================================================================
-// Main
+// JSON Output
 // ============================================================================
 
-fn run(args: Args) -> Result<()> {
-    let actions = args.to_actions();
+/// Minimal JSON value, enough for `--output json` without a JSON dependency.
+/// Objects keep their keys in insertion order so the output is stable.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
 
-    if actions.is_empty() {
-        bail!("No text or keys to type. Use --help for usage.");
+impl Json {
+    fn object<const N: usize>(fields: [(&str, Json); N]) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
     }
 
-    let config = args.to_config();
+    fn string(s: impl Into<String>) -> Self {
+        Json::String(s.into())
+    }
 
-    // Handle --reset-token flag
-    if args.reset_token {
-        clear_restore_token()?;
+    fn count(n: usize) -> Self {
+        Json::Number(n as i64)
     }
+}
 
-    // Connect to EI
-    let mut eitype = if let Some(socket_path) = get_socket_path(args.socket.as_deref()) {
-        // Socket path specified via -s or LIBEI_SOCKET
-        EiType::connect_socket(&socket_path, config)?
-    } else {
-        // Default to portal (with session persistence)
-        let saved_token = if args.reset_token {
-            None
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                f.write_str("\"")?;
+                for ch in s.chars() {
+                    match ch {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{}", Json::string(key.as_str()), value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// What a run did, filled in as far as it got
+#[derive(Debug, Default)]
+struct RunSummary {
+    /// Reports of the last (or only) iteration's actions
+    actions: Vec<ActionReport>,
+    /// Iterations of the actions completed (see `--repeat`)
+    iterations: u32,
+    stats: TypingStats,
+    keymap_source: Option<KeymapSource>,
+    /// Time spent running the actions
+    elapsed: Duration,
+}
+
+/// Version of the `--output json` schema; bump on incompatible changes
+const JSON_SCHEMA_VERSION: i64 = 1;
+
+impl RunSummary {
+    /// The `--stats` report
+    fn stats_report(&self) -> String {
+        let stats = &self.stats;
+        let seconds = self.elapsed.as_secs_f64();
+        let rate = if seconds > 0.0 {
+            stats.chars_typed as f64 / seconds
         } else {
-            load_restore_token()
+            0.0
         };
+        format!(
+            "chars typed:   {}\n\
+             keys pressed:  {}\n\
+             frames sent:   {}\n\
+             flush retries: {}\n\
+             elapsed:       {} ms ({:.0} chars/s)",
+            stats.chars_typed,
+            stats.keys_pressed,
+            stats.frames_sent,
+            stats.flush_retries,
+            self.elapsed.as_millis(),
+            rate
+        )
+    }
 
-        let (eitype, new_token) =
-            EiType::connect_portal_with_token(config, saved_token.as_deref())?;
-
-        // Save new token for future runs
-        if let Some(token) = new_token {
-            if let Err(e) = save_restore_token(&token) {
-                warn!("Failed to save restore token: {}", e);
-            }
+    /// The `--output json` result object
+    fn to_json(&self, result: &Result<()>) -> Json {
+        let stats = &self.stats;
+        let mut fallbacks = Vec::new();
+        if self.keymap_source == Some(KeymapSource::SystemDefault) {
+            fallbacks.push(Json::string("system_default_keymap"));
+        }
+        if stats.group_switches > 0 {
+            fallbacks.push(Json::string("group_switch"));
+        }
+        if stats.qwerty_fallback_chars > 0 {
+            fallbacks.push(Json::string("qwerty_fallback"));
         }
 
-        eitype
-    };
-
-    // Execute actions
-    if let Err(e) = eitype.execute_actions(&actions) {
-        error!("Error executing actions: {}", e);
-        return Err(e.into());
+        Json::object([
+            ("schema_version", Json::Number(JSON_SCHEMA_VERSION)),
+            ("success", Json::Bool(result.is_ok())),
+            ("chars_typed", Json::count(stats.chars_typed)),
+            (
+                "stats",
+                Json::object([
+                    ("chars_typed", Json::count(stats.chars_typed)),
+                    ("keys_pressed", Json::count(stats.keys_pressed)),
+                    ("group_switches", Json::count(stats.group_switches)),
+                    (
+                        "qwerty_fallback_chars",
+                        Json::count(stats.qwerty_fallback_chars),
+                    ),
+                    ("throttle_events", Json::count(stats.throttle_events)),
+                    ("held_key_replays", Json::count(stats.held_key_replays)),
+                    ("frames_sent", Json::count(stats.frames_sent)),
+                    ("flush_retries", Json::count(stats.flush_retries)),
+                    (
+                        "format_chars_stripped",
+                        Json::count(stats.format_chars_stripped),
+                    ),
+                    (
+                        "throttle_time_ms",
+                        Json::Number(stats.throttle_time.as_millis() as i64),
+                    ),
+                ]),
+            ),
+            (
+                "keymap_source",
+                self.keymap_source
+                    .map_or(Json::Null, |source| Json::string(format!("{:?}", source))),
+            ),
+            ("fallbacks", Json::Array(fallbacks)),
+            ("iterations", Json::Number(self.iterations.into())),
+            (
+                "actions",
+                Json::Array(self.actions.iter().map(action_json).collect()),
+            ),
+            (
+                "error",
+                result.as_ref().err().map_or(Json::Null, error_json),
+            ),
+        ])
     }
+}
 
-    info!("Done");
-    Ok(())
+fn action_json(report: &ActionReport) -> Json {
+    let (status, error) = match &report.status {
+        ActionStatus::Ok => ("ok", Json::Null),
+        ActionStatus::Failed(message) => ("failed", Json::string(message.as_str())),
+        ActionStatus::Skipped => ("skipped", Json::Null),
+    };
+    Json::object([
+        ("index", Json::count(report.index)),
+        ("kind", Json::string(report.kind)),
+        ("status", Json::string(status)),
+        ("chars_typed", Json::count(report.chars_typed)),
+        ("error", error),
+    ])
 }
 
-fn main() {
-    let args = Args::parse();
+/// `{"variant", "message", "fields"}` for an error, with the `EiTypeError`
+/// variant's fields when it is one (variant "Other" otherwise)
+fn error_json(error: &anyhow::Error) -> Json {
+    match error.downcast_ref::<EiTypeError>() {
+        Some(e) => eitype_error_json(e),
+        None => Json::object([
+            ("variant", Json::string("Other")),
+            ("message", Json::string(format!("{:#}", error))),
+            ("fields", Json::object([])),
+        ]),
+    }
+}
 
-    // Setup logging
-    let log_level = match args.verbose {
-        0 => log::LevelFilter::Warn,
-        1 => log::LevelFilter::Info,
-        2 => log::LevelFilter::Debug,
-        _ => log::LevelFilter::Trace,
+fn eitype_error_json(error: &EiTypeError) -> Json {
+    let message = |m: &str| [("message", Json::string(m))];
+    let (variant, fields) = match error {
+        EiTypeError::Connection(m) => ("Connection", Json::object(message(m))),
+        EiTypeError::Disconnected {
+            reason,
+            explanation,
+        } => (
+            "Disconnected",
+            Json::object([
+                ("reason", Json::string(reason.as_str())),
+                ("explanation", Json::string(explanation.as_str())),
+            ]),
+        ),
+        EiTypeError::InvalidConfig(m) => ("InvalidConfig", Json::object(message(m))),
+        EiTypeError::AuthorizationDenied => ("AuthorizationDenied", Json::object([])),
+        EiTypeError::PortalRefused(m) => ("PortalRefused", Json::object(message(m))),
+        EiTypeError::Keymap(m) => ("Keymap", Json::object(message(m))),
+        EiTypeError::UnknownKey(name) => (
+            "UnknownKey",
+            Json::object([("name", Json::string(name.as_str()))]),
+        ),
+        EiTypeError::Typing(m) => ("Typing", Json::object(message(m))),
+        EiTypeError::Flush(m) => ("Flush", Json::object(message(m))),
+        EiTypeError::NoDevice(capability) => (
+            "NoDevice",
+            Json::object([("capability", Json::string(format!("{:?}", capability)))]),
+        ),
+        EiTypeError::Touch(m) => ("Touch", Json::object(message(m))),
+        EiTypeError::HeldKeysLost(m) => ("HeldKeysLost", Json::object(message(m))),
+        EiTypeError::DeviceLost { chars_typed } => (
+            "DeviceLost",
+            Json::object([("chars_typed", Json::count(*chars_typed))]),
+        ),
+        EiTypeError::FocusTargetNotFound(target) => (
+            "FocusTargetNotFound",
+            Json::object([("target", Json::string(target.as_str()))]),
+        ),
+        EiTypeError::FocusGuardTriggered { focused } => (
+            "FocusGuardTriggered",
+            Json::object([("focused", Json::string(focused.as_str()))]),
+        ),
+        EiTypeError::CapabilityUnavailable(capability) => (
+            "CapabilityUnavailable",
+            Json::object([("capability", Json::string(format!("{:?}", capability)))]),
+        ),
+        EiTypeError::CharNotFound(ch) => (
+            "CharNotFound",
+            Json::object([("char", Json::string(ch.to_string()))]),
+        ),
+        EiTypeError::KeysymNotFound(name) => (
+            "KeysymNotFound",
+            Json::object([("name", Json::string(name.as_str()))]),
+        ),
+        EiTypeError::Clipboard(m) => ("Clipboard", Json::object(message(m))),
+        EiTypeError::Cancelled => ("Cancelled", Json::object([])),
+        EiTypeError::ConfirmationTimeout { key, timeout } => (
+            "ConfirmationTimeout",
+            Json::object([
+                ("key", Json::string(key.as_str())),
+                ("timeout_ms", Json::count(timeout.as_millis() as usize)),
+            ]),
+        ),
+        EiTypeError::WouldConflict => ("WouldConflict", Json::object([])),
+        EiTypeError::Busy => ("Busy", Json::object([])),
+        EiTypeError::InlineSyntax { offset, message } => (
+            "InlineSyntax",
+            Json::object([
+                ("offset", Json::count(*offset)),
+                ("message", Json::string(message.as_str())),
+            ]),
+        ),
+        EiTypeError::InvalidUtf8 { offset, truncated } => (
+            "InvalidUtf8",
+            Json::object([
+                ("offset", Json::count(*offset)),
+                ("truncated", Json::Bool(*truncated)),
+            ]),
+        ),
+        EiTypeError::InText {
+            index,
+            byte_offset,
+            context,
+            source,
+        } => (
+            "InText",
+            Json::object([
+                ("index", Json::count(*index)),
+                ("byte_offset", Json::count(*byte_offset)),
+                ("context", Json::string(context.as_str())),
+                ("source", eitype_error_json(source)),
+            ]),
+        ),
     };
+    Json::object([
+        ("variant", Json::string(variant)),
+        ("message", Json::string(error.to_string())),
+        ("fields", fields),
+    ])
+}
 
-    env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Warn)
-        .filter_module("eitype", log_level)
-        .format_timestamp(None)
-        .init();
+// ============================================================================
+// Timing Output
+// ============================================================================
 
-    if let Err(e) = run(args) {
-        error!("{:#}", e);
-        std::process::exit(1);
+/// `--timing-out` CSV: one row per typed character, times in microseconds
+fn timing_csv(timings: &[CharTiming]) -> String {
+    let mut csv = String::from("index,char,plan_us,flush_us,gap_us\n");
+    for (index, timing) in timings.iter().enumerate() {
+        let ch = match timing.ch {
+            '"' => "\"\"\"\"".to_string(),
+            ',' | '\n' | '\r' => format!("\"{}\"", timing.ch),
+            ch => ch.to_string(),
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            index,
+            ch,
+            timing.plan.as_micros(),
+            timing.flush.as_micros(),
+            timing.gap.as_micros()
+        ));
+    }
+    csv
+}
+
+/// Write the characters' timings to `path` for `--timing-out`
+fn write_timings(eitype: &EiType, path: &Path) -> Result<()> {
+    if eitype.timings_truncated() {
+        warn!("Timing trace is truncated to the most recent characters");
     }
+    fs::write(path, timing_csv(&eitype.timings()))
+        .with_context(|| format!("Failed to write timings to {:?}", path))
 }
 
 // ============================================================================
-// Tests
+// FIFO Input
 // ============================================================================
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// How often `read_fifo_lines` checks `stop` while nothing is written
+const FIFO_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
-    #[test]
-    fn test_cli_parsing_basic() {
-        let args = Args::try_parse_from(["eitype", "hello"]).unwrap();
-        assert_eq!(args.text, vec!["hello"]);
-        assert_eq!(args.delay, 0);
-    }
+/// Open `path` as a FIFO for reading, creating it (mode 0600) if missing
+fn open_fifo(path: &Path) -> Result<fs::File> {
+    use rustix::fs::{FileType, Mode, OFlags};
+    use std::os::unix::fs::FileTypeExt;
 
-    #[test]
-    fn test_cli_parsing_multiple_text() {
-        let args = Args::try_parse_from(["eitype", "hello", "world"]).unwrap();
-        assert_eq!(args.text, vec!["hello", "world"]);
+    match fs::metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_fifo() => {
+            bail!("{:?} exists and is not a FIFO", path)
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            rustix::fs::mknodat(
+                rustix::fs::CWD,
+                path,
+                FileType::Fifo,
+                Mode::from_raw_mode(0o600),
+                0,
+            )
+            .with_context(|| format!("Failed to create FIFO {:?}", path))?;
+            info!("Created FIFO {:?}", path);
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
     }
+    // Non-blocking, so opening doesn't wait for a writer and `stop` is
+    // still checked while there is none
+    let fd = rustix::fs::open(
+        path,
+        OFlags::RDONLY | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .with_context(|| format!("Failed to open FIFO {:?}", path))?;
+    Ok(fs::File::from(fd))
+}
 
-    #[test]
-    fn test_cli_parsing_delay() {
-        let args = Args::try_parse_from(["eitype", "-d", "100", "hello"]).unwrap();
-        assert_eq!(args.delay, 100);
-    }
+/// Pass each line written to the FIFO at `path` to `handle`, without its
+/// line ending, until `stop` is set
+///
+/// Writers may come and go: when the last one closes the FIFO, a final line
+/// without a newline is handled too, and the FIFO is opened again for the
+/// next writer. Lines that aren't UTF-8 are skipped with a warning.
+fn read_fifo_lines(path: &Path, stop: &AtomicBool, mut handle: impl FnMut(&str)) -> Result<()> {
+    use rustix::event::{poll, PollFd, PollFlags};
+    use std::io::Read;
 
-    #[test]
-    fn test_cli_parsing_keys() {
-        let args = Args::try_parse_from(["eitype", "-k", "return", "-k", "tab"]).unwrap();
-        assert_eq!(args.keys, vec!["return", "tab"]);
+    let timeout = rustix::time::Timespec {
+        tv_sec: 0,
+        tv_nsec: FIFO_POLL_INTERVAL.as_nanos() as i64,
+    };
+    let mut handle_line = |line: &[u8]| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match validate_input(line) {
+            Ok(text) => handle(text),
+            Err(e) => warn!("Skipping a line from the FIFO: {}", e),
+        }
+    };
+
+    let mut fifo = open_fifo(path)?;
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !stop.load(Ordering::SeqCst) {
+        let mut pollfd = [PollFd::new(&fifo, PollFlags::IN)];
+        match poll(&mut pollfd, Some(&timeout)) {
+            Ok(0) | Err(rustix::io::Errno::INTR) => continue,
+            Ok(_) => {}
+            Err(e) => return Err(e).context("Failed to wait for the FIFO"),
+        }
+        let n = match fifo.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to read the FIFO"),
+        };
+        if n == 0 {
+            // Every writer has closed it. Open it again before closing this
+            // end: with no reader left, anything a new writer had already
+            // written would be thrown away.
+            if !pending.is_empty() {
+                handle_line(&std::mem::take(&mut pending));
+            }
+            debug!("FIFO writer closed {:?}, reopening", path);
+            fifo = open_fifo(path)?;
+            continue;
+        }
+        pending.extend_from_slice(&buf[..n]);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            handle_line(&line[..end]);
+            if stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Main
+// ============================================================================
+
+/// Connect over the socket from -s/LIBEI_SOCKET, or else via the portal
+/// (with session persistence), and apply --profile
+fn connect(args: &Args, config: EiTypeConfig) -> Result<EiType> {
+    // Handle --reset-token flag
+    if args.reset_token {
+        let store = token_store(args)?;
+        store
+            .clear()
+            .with_context(|| format!("Failed to remove token file: {:?}", store.path()))?;
+    }
+
+    // Check the profile before connecting, which may show a dialog
+    let profiles = args.profile.as_deref().map(load_profiles).transpose()?;
+    let mut eitype = open_connection(args, config)?;
+    if let (Some(name), Some(profiles)) = (&args.profile, profiles) {
+        eitype.set_profiles(profiles);
+        eitype.apply_profile(name)?;
+    }
+    Ok(eitype)
+}
+
+/// The restore token file for --namespace
+fn token_store(args: &Args) -> Result<TokenStore> {
+    Ok(TokenStore::open(&args.token_namespace())?)
+}
+
+/// The profiles in the default config file, checking that `name` is one
+fn load_profiles(name: &str) -> Result<Profiles> {
+    let path = Profiles::default_path().context("No config directory for profiles")?;
+    let profiles = Profiles::load(&path)?;
+    profiles.get(name)?;
+    Ok(profiles)
+}
+
+/// Connect over the socket or the portal, as `connect` describes
+fn open_connection(args: &Args, config: EiTypeConfig) -> Result<EiType> {
+    if let Some(socket_path) = get_socket_path(args.socket.as_deref()) {
+        // Socket path specified via -s or LIBEI_SOCKET
+        return Ok(EiType::connect_socket(&socket_path, config)?);
+    }
+
+    if args.reset_token {
+        // Otherwise the library falls back to EITYPE_RESTORE_TOKEN
+        std::env::remove_var(RESTORE_TOKEN_ENV);
+    }
+
+    // The library loads the saved token and saves new ones for future runs,
+    // in the --namespace token file
+    let (eitype, new_token) = EiType::connect_portal_with_token(config, None)?;
+    if let (Some(token), true) = (new_token, args.print_token) {
+        eprintln!("{}", token);
+    }
+
+    Ok(eitype)
+}
+
+/// `--convert-layout FROM,TO`: print each TEXT converted between layouts,
+/// or retype it with `--retype`
+fn run_convert(args: &Args, from: u32, to: u32) -> Result<()> {
+    if args.text.is_empty() {
+        bail!("--convert-layout needs TEXT to convert");
+    }
+    let config = args.to_config();
+    if args.retype {
+        let eitype = connect(args, config)?;
+        for text in &args.text {
+            eitype.retype_converted(text, from, to)?;
+        }
+        return Ok(());
+    }
+
+    let keymap = config.compile_keymap()?;
+    for text in &args.text {
+        println!("{}", convert_layout(text, from, to, &keymap));
+    }
+    Ok(())
+}
+
+/// `eitype token request`: authorize through the portal and save the token
+fn run_token_request(args: &Args) -> Result<()> {
+    let store = token_store(args)?;
+    // Saved here rather than by the library, so failing to save is an error
+    let config = EiTypeConfig {
+        token_namespace: None,
+        ..args.to_config()
+    };
+    let token = request_portal_permission(&config)?;
+    match token {
+        Some(token) => {
+            if args.print_token {
+                eprintln!("{}", token);
+            }
+            store
+                .save(&token)
+                .with_context(|| format!("Failed to save restore token to {:?}", store.path()))?;
+            println!("Authorized; saved restore token to {:?}", store.path());
+        }
+        None => println!("Authorized, but the portal issued no restore token to save"),
+    }
+    Ok(())
+}
+
+/// What `eitype calibrate` asks before typing anything
+fn calibration_warning(options: &CalibrateOptions) -> String {
+    let mut warning = format!(
+        "WARNING: calibration TYPES INTO THE FOCUSED WINDOW.\n\
+         It types {:?} {} times, as fast as it can.\n",
+        options.probe,
+        options.delays_ms.len()
+    );
+    if options.erase {
+        warning.push_str(
+            "Each time it then presses Backspace once per character to erase it,\n\
+             which deletes text if the window doesn't take the probe as typed.\n",
+        );
+    } else {
+        warning.push_str("The probe is NOT erased afterwards.\n");
+    }
+    warning.push_str(
+        "Focus an empty text field (or one whose contents you can lose) first.\n\
+         Continue? [y/N] ",
+    );
+    warning
+}
+
+/// What `eitype bench` asks before typing anything
+fn bench_warning(options: &BenchOptions) -> String {
+    format!(
+        "WARNING: bench TYPES INTO THE FOCUSED WINDOW.\n\
+         It types {} characters of pangrams as fast as it can, then presses\n\
+         Backspace once per character to erase them, which deletes text if the\n\
+         window doesn't take them as typed.\n\
+         Focus an empty text field (or one whose contents you can lose) first.\n\
+         Continue? [y/N] ",
+        options.chars
+    )
+}
+
+/// Print `warning` and read the answer from stdin; whether it was yes
+fn confirm(warning: &str) -> Result<bool> {
+    eprint!("{}", warning);
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read the answer")?;
+    Ok(is_yes(&answer))
+}
+
+/// Whether an answer to a [y/N] prompt is yes
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// `eitype keymap-diff`: print what two keymaps type differently
+fn run_keymap_diff(args: &Args, diff_args: &KeymapDiffArgs) -> Result<()> {
+    let a = diff_args
+        .a
+        .load(args)
+        .context("Failed to load the first keymap")?;
+    let b = diff_args
+        .b
+        .load(args)
+        .context("Failed to load the second keymap")?;
+    let diff = diff_keymaps(&a, &b);
+    if diff_args.json {
+        println!("{}", keymap_diff_json(&diff));
+    } else {
+        print!("{}", format_keymap_diff(&diff));
+    }
+    Ok(())
+}
+
+/// A keysym as `keymap-diff` prints it: its character, or its name
+fn keysym_label(entry: &KeysymDiff) -> String {
+    match entry.ch {
+        Some(ch) if !ch.is_whitespace() => ch.to_string(),
+        _ => entry.keysym.clone(),
+    }
+}
+
+/// The differences as `eitype keymap-diff` prints them, by category
+fn format_keymap_diff(diff: &KeymapDiff) -> String {
+    if diff.is_empty() {
+        return "No differences\n".to_string();
+    }
+    let mut report = String::new();
+    for (title, entries) in [
+        ("Only in the first keymap", &diff.only_in_a),
+        ("Only in the second keymap", &diff.only_in_b),
+    ] {
+        if !entries.is_empty() {
+            let labels: Vec<String> = entries.iter().map(keysym_label).collect();
+            report.push_str(&format!(
+                "{} ({}): {}\n",
+                title,
+                entries.len(),
+                labels.join(" ")
+            ));
+        }
+    }
+    if !diff.moved.is_empty() {
+        report.push_str(&format!(
+            "On a different key or level ({}):\n",
+            diff.moved.len()
+        ));
+        for entry in &diff.moved {
+            if let (Some(a), Some(b)) = (&entry.a, &entry.b) {
+                report.push_str(&format!(
+                    "  {} ({}): {} -> {}\n",
+                    keysym_label(entry),
+                    entry.keysym,
+                    a,
+                    b
+                ));
+            }
+        }
+    }
+    report
+}
+
+fn keymap_diff_json(diff: &KeymapDiff) -> Json {
+    let position = |position: &Option<KeyPosition>| match position {
+        Some(position) => Json::object([
+            ("keycode", Json::count(position.keycode as usize)),
+            ("level", Json::count(position.level as usize)),
+            (
+                "modifiers",
+                Json::Array(position.modifiers.iter().map(Json::string).collect()),
+            ),
+        ]),
+        None => Json::Null,
+    };
+    let entries = |entries: &[KeysymDiff]| {
+        Json::Array(
+            entries
+                .iter()
+                .map(|entry| {
+                    Json::object([
+                        ("keysym", Json::string(&entry.keysym)),
+                        (
+                            "char",
+                            entry
+                                .ch
+                                .map_or(Json::Null, |ch| Json::string(ch.to_string())),
+                        ),
+                        ("a", position(&entry.a)),
+                        ("b", position(&entry.b)),
+                    ])
+                })
+                .collect(),
+        )
+    };
+    Json::object([
+        ("only_in_a", entries(&diff.only_in_a)),
+        ("only_in_b", entries(&diff.only_in_b)),
+        ("moved", entries(&diff.moved)),
+    ])
+}
+
+/// `eitype capabilities`: print what this build supports
+fn run_capabilities(capabilities_args: &CapabilitiesArgs) -> Result<()> {
+    let capabilities = capabilities();
+    if capabilities_args.json {
+        println!("{}", capabilities_json(&capabilities));
+    } else {
+        print!("{}", format_capabilities(&capabilities));
+    }
+    Ok(())
+}
+
+/// The catalogue as `eitype capabilities` prints it
+fn format_capabilities(capabilities: &Capabilities) -> String {
+    let names = |names: &[&str]| names.join(", ");
+    let mut out = format!("eitype {}\n\nActions:\n", capabilities.version);
+    for action in &capabilities.actions {
+        let params: Vec<String> = action
+            .params
+            .iter()
+            .map(|param| {
+                let mut param_name = param.name.to_string();
+                if !param.choices.is_empty() {
+                    param_name = format!("{}={}", param_name, param.choices.join("|"));
+                }
+                if param.optional {
+                    format!("[{}]", param_name)
+                } else {
+                    param_name
+                }
+            })
+            .collect();
+        out.push_str(&format!("  {} {}\n", action.kind, params.join(" ")));
+    }
+    out.push_str(&format!(
+        "\nKeys: {} names, {} combos (see --list-keys)\n",
+        capabilities.keys.len(),
+        capabilities.combos.len()
+    ));
+    out.push_str(&format!("Fallbacks: {}\n", names(&capabilities.fallbacks)));
+    out.push_str(&format!("Backends: {}\n", names(&capabilities.backends)));
+    out.push_str(&format!("Features: {}\n", names(&capabilities.features)));
+    out
+}
+
+/// The catalogue as one JSON object, for `eitype capabilities --json`
+fn capabilities_json(capabilities: &Capabilities) -> Json {
+    let strings = |items: &[&str]| Json::Array(items.iter().copied().map(Json::string).collect());
+    let keys = capabilities
+        .keys
+        .iter()
+        .map(|key| {
+            Json::object([
+                ("name", Json::string(&key.name)),
+                ("code", Json::count(key.code as usize)),
+                ("aliases", strings(&key.aliases)),
+            ])
+        })
+        .collect();
+    let combos = capabilities
+        .combos
+        .iter()
+        .map(|combo| {
+            Json::object([
+                ("name", Json::string(combo.name)),
+                ("keys", Json::string(combo.keys)),
+            ])
+        })
+        .collect();
+    let actions = capabilities
+        .actions
+        .iter()
+        .map(|action| {
+            let params = action
+                .params
+                .iter()
+                .map(|param| {
+                    Json::object([
+                        ("name", Json::string(param.name)),
+                        ("type", Json::string(param.kind)),
+                        ("choices", strings(param.choices)),
+                        ("optional", Json::Bool(param.optional)),
+                    ])
+                })
+                .collect();
+            Json::object([
+                ("kind", Json::string(action.kind)),
+                ("params", Json::Array(params)),
+            ])
+        })
+        .collect();
+    Json::object([
+        (
+            "schema_version",
+            Json::count(capabilities.schema_version as usize),
+        ),
+        ("version", Json::string(capabilities.version)),
+        ("keys", Json::Array(keys)),
+        ("combos", Json::Array(combos)),
+        ("actions", Json::Array(actions)),
+        ("fallbacks", strings(&capabilities.fallbacks)),
+        ("backends", strings(&capabilities.backends)),
+        ("features", strings(&capabilities.features)),
+    ])
+}
+
+/// The key names as `--list-keys` prints them, one per line
+fn format_key_list() -> String {
+    list_key_names()
+        .into_iter()
+        .map(|(name, combo)| match combo {
+            Some(combo) => format!("{} (combo: {})\n", name, combo),
+            None => format!("{}\n", name),
+        })
+        .collect()
+}
+
+/// The calibration results as `eitype calibrate` prints them
+fn format_calibration(calibration: &Calibration) -> String {
+    let mut report = String::new();
+    for step in &calibration.steps {
+        report.push_str(&format!(
+            "{:>4} ms: {:>6.0} chars/s, {} flush retries, {} throttle pauses{}\n",
+            step.delay_ms,
+            step.chars_per_sec,
+            step.flush_retries,
+            step.throttle_events,
+            if step.kept_up { "" } else { " (fell behind)" }
+        ));
+    }
+    report.push_str(&format!(
+        "Ceiling: {:.0} chars/s\n",
+        calibration.ceiling_chars_per_sec
+    ));
+    match (
+        calibration.recommended_delay_ms,
+        calibration.recommended_chars_per_sec,
+    ) {
+        (Some(delay_ms), Some(chars_per_sec)) => report.push_str(&format!(
+            "Recommended: --delay {} (about {:.0} chars/s)\n",
+            delay_ms, chars_per_sec
+        )),
+        _ => report.push_str("Recommended: none; the compositor fell behind at every rate tried\n"),
+    }
+    report
+}
+
+/// `eitype calibrate`: measure how fast the compositor keeps up
+fn run_calibrate(args: &Args, calibrate: &CalibrateArgs) -> Result<()> {
+    let options = calibrate.to_options();
+    if !calibrate.yes && !confirm(&calibration_warning(&options))? {
+        bail!("Calibration cancelled");
+    }
+    let mut eitype = connect(args, args.to_config())?;
+    let calibration = eitype.calibrate(&options)?;
+    eitype.close();
+    print!("{}", format_calibration(&calibration));
+    if let Some(name) = &calibrate.save {
+        let delay_ms = calibration
+            .recommended_delay_ms
+            .context("Nothing to save: the compositor fell behind at every rate tried")?;
+        let path = Profiles::default_path().context("No config directory for profiles")?;
+        Profiles::save_delay(&path, name, delay_ms)?;
+        println!("Saved to profile {:?} in {}", name, path.display());
+    }
+    Ok(())
+}
+
+/// `eitype bench`: measure typing throughput and per-character times
+fn run_bench(args: &Args, bench: &BenchArgs) -> Result<()> {
+    let report = bench_report(args, bench)?;
+    if bench.json {
+        println!("{}", bench_json(bench.against, &report));
+    } else {
+        print!("{}", format_bench(&report));
+    }
+    Ok(())
+}
+
+/// Connect (or with `--against null`, don't) and run the bench
+fn bench_report(args: &Args, bench: &BenchArgs) -> Result<BenchReport> {
+    let options = BenchOptions {
+        chars: bench.chars,
+        ..Default::default()
+    };
+    let mut eitype = match bench.against {
+        BenchTarget::Null => EiType::null(args.to_config())?,
+        BenchTarget::Editor => {
+            if !bench.yes && !confirm(&bench_warning(&options))? {
+                bail!("Bench cancelled");
+            }
+            connect(args, args.to_config())?
+        }
+    };
+    let report = eitype.bench(&options)?;
+    eitype.close();
+    Ok(report)
+}
+
+/// The bench results as `eitype bench` prints them
+fn format_bench(report: &BenchReport) -> String {
+    let mut out = format!(
+        "{} chars in {:.3} s: {:.0} chars/s\n\
+         {} flush retries, {} throttle pauses\n",
+        report.chars,
+        report.elapsed.as_secs_f64(),
+        report.chars_per_sec,
+        report.flush_retries,
+        report.throttle_events
+    );
+    if report.sampled < report.chars {
+        out.push_str(&format!(
+            "Per-char times are for the last {} chars only\n",
+            report.sampled
+        ));
+    }
+    out.push_str("Per-char times (us):  p50      p90      p99      max\n");
+    for (name, latency) in [
+        ("plan", &report.plan),
+        ("flush", &report.flush),
+        ("gap", &report.gap),
+    ] {
+        out.push_str(&format!(
+            "  {:<18}{:>5}{:>9}{:>9}{:>9}\n",
+            name,
+            latency.p50.as_micros(),
+            latency.p90.as_micros(),
+            latency.p99.as_micros(),
+            latency.max.as_micros()
+        ));
+    }
+    out
+}
+
+/// `eitype bench --json`: times in microseconds
+fn bench_json(against: BenchTarget, report: &BenchReport) -> Json {
+    let micros = |d: Duration| Json::Number(d.as_micros() as i64);
+    let percentiles = |latency: &LatencyPercentiles| {
+        Json::object([
+            ("p50", micros(latency.p50)),
+            ("p90", micros(latency.p90)),
+            ("p99", micros(latency.p99)),
+            ("max", micros(latency.max)),
+        ])
+    };
+    let against = match against {
+        BenchTarget::Editor => "editor",
+        BenchTarget::Null => "null",
+    };
+    Json::object([
+        ("against", Json::string(against)),
+        ("chars", Json::count(report.chars)),
+        ("elapsed_us", micros(report.elapsed)),
+        (
+            "chars_per_sec",
+            Json::Number(report.chars_per_sec.round() as i64),
+        ),
+        ("flush_retries", Json::count(report.flush_retries)),
+        ("throttle_events", Json::count(report.throttle_events)),
+        ("sampled", Json::count(report.sampled)),
+        (
+            "latency_us",
+            Json::object([
+                ("plan", percentiles(&report.plan)),
+                ("flush", percentiles(&report.flush)),
+                ("gap", percentiles(&report.gap)),
+            ]),
+        ),
+    ])
+}
+
+/// `eitype info`: print the connection's keymap details and key table audit
+fn run_info(args: &Args) -> Result<()> {
+    let eitype = connect(args, args.to_config())?;
+    let info = eitype.connection_info();
+    println!("Keymap source: {:?}", info.keymap_source);
+    println!("Keymap fingerprint: {:016x}", info.keymap_fingerprint);
+    println!(
+        "Layouts: {} (typing in {}: {})",
+        info.num_layouts, info.layout_index, info.layout_name
+    );
+    if let Some(index) = info.clamped_layout_index {
+        println!(
+            "Layout index {} is out of range; fell back to layout 0",
+            index
+        );
+    }
+    println!("Special keys:");
+    for entry in eitype.key_table_report() {
+        println!("  {}", entry);
+    }
+    Ok(())
+}
+
+/// Text from `path` (stdin for `-`), which must be UTF-8
+fn read_text_file(path: &Path) -> Result<String> {
+    let bytes = if path == Path::new("-") {
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+            .context("Failed to read stdin")?;
+        bytes
+    } else {
+        fs::read(path).with_context(|| format!("Failed to read {:?}", path))?
+    };
+    let text = validate_input(&bytes).with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(text.to_string())
+}
+
+/// --fifo: type each line written to the FIFO over one connection until
+/// Ctrl+C or SIGTERM
+fn run_fifo(args: &Args, path: &Path, summary: &mut RunSummary) -> Result<()> {
+    if !args.to_actions()?.is_empty() {
+        bail!("--fifo types the lines written to the FIFO and takes no other actions");
+    }
+    let mut eitype = connect(args, args.to_config())?;
+
+    // Stop between characters, then release held keys and close
+    let stop = std::sync::Arc::new(AtomicBool::new(false));
+    let cancel = eitype.cancel_handle();
+    let stopping = stop.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        if stopping.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        cancel.cancel();
+    }) {
+        warn!("Failed to install Ctrl+C handler: {}", e);
+    }
+
+    info!("Typing lines written to {:?}", path);
+    let started = Instant::now();
+    let result = read_fifo_lines(path, &stop, |line| {
+        let mut actions = Vec::new();
+        let typed = args
+            .push_text(&mut actions, line)
+            .and_then(|()| eitype.execute_actions(&actions));
+        if let Err(e) = typed {
+            if !stop.load(Ordering::SeqCst) {
+                error!("Failed to type a line from the FIFO: {}", e);
+            }
+        }
+    });
+    summary.elapsed = started.elapsed();
+    summary.stats = eitype.stats();
+    summary.keymap_source = Some(eitype.keymap_source());
+    eitype.close();
+    result
+}
+
+fn run(mut args: Args, summary: &mut RunSummary) -> Result<()> {
+    match &args.command {
+        Some(Command::Info) => return run_info(&args),
+        Some(Command::Token(TokenCommand::Request)) => return run_token_request(&args),
+        Some(Command::Calibrate(calibrate)) => return run_calibrate(&args, calibrate),
+        Some(Command::Bench(bench)) => return run_bench(&args, bench),
+        Some(Command::KeymapDiff(diff_args)) => return run_keymap_diff(&args, diff_args),
+        Some(Command::Capabilities(capabilities_args)) => {
+            return run_capabilities(capabilities_args)
+        }
+        None => {}
+    }
+    if let Some((from, to)) = args.convert_layout {
+        return run_convert(&args, from, to);
+    }
+    if args.list_keys {
+        print!("{}", format_key_list());
+        return Ok(());
+    }
+    if let Some(path) = &args.fifo {
+        return run_fifo(&args, path, summary);
+    }
+    args.check_scroll()?;
+    args.read_files()?;
+    let actions = args.to_actions()?;
+    summary.actions = actions
+        .iter()
+        .enumerate()
+        .map(|(index, action)| ActionReport {
+            index,
+            kind: action.kind(),
+            status: ActionStatus::Skipped,
+            chars_typed: 0,
+        })
+        .collect();
+
+    if actions.is_empty() {
+        bail!("No text, keys, scrolling or taps to send. Use --help for usage.");
+    }
+
+    let config = args.to_config();
+    let mut eitype = connect(&args, config)?;
+
+    // Ctrl+C stops typing between characters, releasing held keys; a second
+    // one exits at once
+    let cancel = eitype.cancel_handle();
+    let interrupted = AtomicBool::new(false);
+    if let Err(e) = ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        cancel.cancel();
+    }) {
+        warn!("Failed to install Ctrl+C handler: {}", e);
+    }
+
+    // Execute actions
+    let started = Instant::now();
+    let (iterations, reports, result) = eitype.execute_actions_repeated(
+        &actions,
+        args.repeat,
+        Duration::from_millis(args.repeat_delay),
+    );
+    summary.elapsed = started.elapsed();
+    summary.actions = reports;
+    summary.iterations = iterations;
+    summary.stats = eitype.stats();
+    summary.keymap_source = Some(eitype.keymap_source());
+    if let Some(path) = &args.timing_out {
+        write_timings(&eitype, path)?;
+    }
+    if let Err(e) = result {
+        if args.output == OutputFormat::Human {
+            error!("Error executing actions: {}", e);
+        }
+        return Err(e.into());
+    }
+
+    if args.repeat > 1 {
+        let stats = &summary.stats;
+        info!(
+            "Done: {} iterations, {} characters, {} keys",
+            iterations, stats.chars_typed, stats.keys_pressed
+        );
+    } else {
+        info!("Done");
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::try_parse_ordered(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    // Setup logging
+    let log_level = match args.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module("eitype", log_level)
+        .format_timestamp(None)
+        .init();
+
+    let output = args.output;
+    let print_stats = args.stats && args.command.is_none();
+    let mut summary = RunSummary::default();
+    let result = run(args, &mut summary);
+    if print_stats {
+        eprintln!("{}", summary.stats_report());
+    }
+    match output {
+        OutputFormat::Human => {
+            if let Err(e) = &result {
+                error!("{:#}", e);
+            }
+        }
+        OutputFormat::Json => println!("{}", summary.to_json(&result)),
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `json` the way a consumer of the output would
+    fn parse_json(json: &Json) -> serde_json::Value {
+        let text = json.to_string();
+        serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", e, text))
+    }
+
+    /// Keys of a JSON object, in order
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        value
+            .as_object()
+            .unwrap_or_else(|| panic!("not an object: {}", value))
+            .keys()
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn report(
+        index: usize,
+        kind: &'static str,
+        status: ActionStatus,
+        chars: usize,
+    ) -> ActionReport {
+        ActionReport {
+            index,
+            kind,
+            status,
+            chars_typed: chars,
+        }
+    }
+
+    #[test]
+    fn test_json_output_success() {
+        let summary = RunSummary {
+            actions: vec![
+                report(0, "type", ActionStatus::Ok, 5),
+                report(1, "key", ActionStatus::Ok, 0),
+            ],
+            stats: TypingStats {
+                chars_typed: 5,
+                keys_pressed: 7,
+                group_switches: 0,
+                qwerty_fallback_chars: 0,
+                throttle_events: 3,
+                throttle_time: std::time::Duration::from_micros(1500),
+                held_key_replays: 0,
+                frames_sent: 14,
+                flush_retries: 2,
+                format_chars_stripped: 0,
+            },
+            keymap_source: Some(KeymapSource::Server),
+            iterations: 1,
+            elapsed: Duration::from_millis(40),
+        };
+        let json = parse_json(&summary.to_json(&Ok(())));
+
+        assert_eq!(
+            keys(&json),
+            vec![
+                "schema_version",
+                "success",
+                "chars_typed",
+                "stats",
+                "keymap_source",
+                "fallbacks",
+                "iterations",
+                "actions",
+                "error"
+            ]
+        );
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["success"], true);
+        assert_eq!(json["chars_typed"], 5);
+        assert_eq!(json["stats"]["keys_pressed"], 7);
+        assert_eq!(json["stats"]["throttle_events"], 3);
+        assert_eq!(json["stats"]["throttle_time_ms"], 1);
+        assert_eq!(json["stats"]["frames_sent"], 14);
+        assert_eq!(json["stats"]["flush_retries"], 2);
+        assert_eq!(json["keymap_source"], "Server");
+        assert_eq!(json["fallbacks"], serde_json::json!([]));
+        assert_eq!(json["iterations"], 1);
+        let action = &json["actions"][1];
+        assert_eq!(action["kind"], "key");
+        assert_eq!(action["status"], "ok");
+        assert_eq!(action["error"], serde_json::Value::Null);
+        assert_eq!(json["error"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_json_output_char_not_found() {
+        let error = EiTypeError::InText {
+            index: 1,
+            byte_offset: 1,
+            context: "a\"中".to_string(),
+            source: Box::new(EiTypeError::CharNotFound('中')),
+        };
+        let summary = RunSummary {
+            actions: vec![
+                report(0, "type", ActionStatus::Failed(error.to_string()), 1),
+                report(1, "key", ActionStatus::Skipped, 0),
+            ],
+            stats: TypingStats {
+                chars_typed: 1,
+                qwerty_fallback_chars: 1,
+                ..Default::default()
+            },
+            keymap_source: Some(KeymapSource::SystemDefault),
+            iterations: 0,
+            elapsed: Duration::ZERO,
+        };
+        let json = parse_json(&summary.to_json(&Err(error.into())));
+
+        assert_eq!(json["success"], false);
+        assert_eq!(
+            json["fallbacks"],
+            serde_json::json!(["system_default_keymap", "qwerty_fallback"])
+        );
+        assert_eq!(json["actions"][0]["status"], "failed");
+        assert_eq!(json["actions"][1]["status"], "skipped");
+
+        let error = &json["error"];
+        assert_eq!(error["variant"], "InText");
+        let fields = &error["fields"];
+        assert_eq!(fields["index"], 1);
+        assert_eq!(fields["context"], "a\"中");
+        let source = &fields["source"];
+        assert_eq!(source["variant"], "CharNotFound");
+        assert_eq!(source["fields"]["char"], "中");
+    }
+
+    #[test]
+    fn test_json_output_connection_and_other_errors() {
+        let summary = RunSummary {
+            actions: vec![report(0, "type", ActionStatus::Skipped, 0)],
+            ..Default::default()
+        };
+        let error = EiTypeError::Connection("Handshake failed:\n\tbroken pipe".to_string());
+        let json = parse_json(&summary.to_json(&Err(error.into())));
+        assert_eq!(json["keymap_source"], serde_json::Value::Null);
+        let error = &json["error"];
+        assert_eq!(error["variant"], "Connection");
+        assert_eq!(
+            error["fields"]["message"],
+            "Handshake failed:\n\tbroken pipe"
+        );
+
+        let json = parse_json(&summary.to_json(&Err(anyhow::anyhow!("No text\u{1}"))));
+        assert_eq!(json["error"]["variant"], "Other");
+        assert_eq!(json["error"]["message"], "No text\u{1}");
+    }
+
+    /// Write `contents` to a file called `name` in a fresh directory
+    /// (removed when it is dropped)
+    fn temp_file(name: &str, contents: &[u8]) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_file_text_runs_in_order() {
+        let (_dir, path) = temp_file("order.txt", "from a file\n".as_bytes());
+        let mut args = Args::try_parse_ordered([
+            "eitype".as_ref(),
+            "before".as_ref(),
+            "--file".as_ref(),
+            path.as_os_str(),
+            "-k".as_ref(),
+            "tab".as_ref(),
+        ])
+        .unwrap();
+        args.read_files().unwrap();
+        let actions = args.to_actions().unwrap();
+        assert_eq!(actions.len(), 3);
+        assert!(matches!(&actions[0], Action::Type(t) if t == "before"));
+        assert!(matches!(&actions[1], Action::Type(t) if t == "from a file\n"));
+        assert!(matches!(&actions[2], Action::Key(k) if k == "tab"));
+    }
+
+    #[test]
+    fn test_file_with_invalid_utf8_is_rejected() {
+        let (_dir, path) = temp_file("latin1.txt", b"na\xefve");
+        let mut args =
+            Args::try_parse_from(["eitype".as_ref(), "--file".as_ref(), path.as_os_str()]).unwrap();
+        let error = args.read_files().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EiTypeError>(),
+            Some(EiTypeError::InvalidUtf8 {
+                offset: 2,
+                truncated: false
+            })
+        ));
+        let message = format!("{:#}", error);
+        assert!(message.contains("latin1.txt"), "{}", message);
+        assert!(message.contains("not valid UTF-8 at byte 2"), "{}", message);
+
+        let json = parse_json(&RunSummary::default().to_json(&Err(error)));
+        let error = &json["error"];
+        assert_eq!(error["variant"], "InvalidUtf8");
+        assert_eq!(error["fields"]["offset"], 2);
+        assert_eq!(error["fields"]["truncated"], false);
+
+        let (_dir, path) = temp_file("truncated.txt", b"\xf0\x9f\x98");
+        let error = read_text_file(&path).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EiTypeError>(),
+            Some(EiTypeError::InvalidUtf8 {
+                offset: 0,
+                truncated: true
+            })
+        ));
+        assert!(read_text_file(Path::new("/nonexistent/eitype.txt")).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_output() {
+        let args = Args::try_parse_from(["eitype", "x"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Human);
+        let args = Args::try_parse_from(["eitype", "--output", "json", "x"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Json);
+        assert!(Args::try_parse_from(["eitype", "--output", "yaml", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_basic() {
+        let args = Args::try_parse_from(["eitype", "hello"]).unwrap();
+        assert_eq!(args.text, vec!["hello"]);
+        assert_eq!(args.delay, 0);
+    }
+
+    #[test]
+    fn test_cli_parsing_convert() {
+        let args =
+            Args::try_parse_from(["eitype", "-l", "us,ru", "--convert-layout", "0,1", "ghbdtn"])
+                .unwrap();
+        assert_eq!(args.convert_layout, Some((0, 1)));
+        assert_eq!(args.text, vec!["ghbdtn"]);
+        assert!(!args.retype);
+        assert_eq!(args.layout.as_deref(), Some("us,ru"));
+
+        assert!(Args::try_parse_from(["eitype", "--convert-layout", "0", "x"]).is_err());
+        assert!(Args::try_parse_from(["eitype", "--retype", "x"]).is_err());
+        // The word "convert" is typed like any other text
+        let args = Args::try_parse_from(["eitype", "convert"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.convert_layout, None);
+        assert_eq!(args.text, vec!["convert"]);
+    }
+
+    #[test]
+    fn test_cli_parsing_info() {
+        let args =
+            Args::try_parse_from(["eitype", "--options", "caps:swapescape", "info"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Info)));
+        assert!(args.to_config().prefer_keymap_keys);
+
+        let args =
+            Args::try_parse_from(["eitype", "--no-prefer-keymap-keys", "-k", "esc"]).unwrap();
+        assert!(!args.to_config().prefer_keymap_keys);
+    }
+
+    #[test]
+    fn test_cli_parsing_token_request() {
+        let args = Args::try_parse_from(["eitype", "token", "request"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Token(TokenCommand::Request))
+        ));
+        assert!(Args::try_parse_from(["eitype", "token"]).is_err());
+
+        let args = Args::try_parse_from(["eitype", "--print-token", "token", "request"]).unwrap();
+        assert!(args.print_token);
+    }
+
+    #[test]
+    fn test_cli_parsing_token_namespace() {
+        let args = Args::try_parse_from(["eitype", "x"]).unwrap();
+        assert_eq!(
+            args.to_config().token_namespace,
+            Some(default_token_namespace())
+        );
+
+        let args = Args::try_parse_from(["eitype", "token", "request", "--namespace", "dictation"])
+            .unwrap();
+        assert_eq!(args.namespace.as_deref(), Some("dictation"));
+        let args = Args::try_parse_from(["eitype", "--namespace", "dictation", "x"]).unwrap();
+        assert_eq!(
+            args.to_config().token_namespace.as_deref(),
+            Some("dictation")
+        );
+
+        // Checked like any other config value
+        let args = Args::try_parse_from(["eitype", "--namespace", "../x", "x"]).unwrap();
+        assert!(args.to_config().validate().is_err());
+        assert!(token_store(&args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_calibrate() {
+        let args = Args::try_parse_from(["eitype", "calibrate"]).unwrap();
+        let Some(Command::Calibrate(calibrate)) = &args.command else {
+            panic!("expected calibrate, got {:?}", args.command);
+        };
+        assert!(!calibrate.yes);
+        assert_eq!(calibrate.to_options(), CalibrateOptions::default());
+
+        let args =
+            Args::try_parse_from(["eitype", "calibrate", "--probe", "xyz", "--no-erase", "-y"])
+                .unwrap();
+        let Some(Command::Calibrate(calibrate)) = &args.command else {
+            panic!("expected calibrate, got {:?}", args.command);
+        };
+        assert!(calibrate.yes);
+        assert_eq!(calibrate.save, None);
+        let options = calibrate.to_options();
+        assert_eq!(options.probe, "xyz");
+        assert!(!options.erase);
+
+        let args = Args::try_parse_from(["eitype", "calibrate", "--save", "terminal"]).unwrap();
+        let Some(Command::Calibrate(calibrate)) = &args.command else {
+            panic!("expected calibrate, got {:?}", args.command);
+        };
+        assert_eq!(calibrate.save.as_deref(), Some("terminal"));
+    }
+
+    #[test]
+    fn test_calibration_warning_and_answer() {
+        let warning = calibration_warning(&CalibrateOptions::default());
+        assert!(warning.starts_with("WARNING: calibration TYPES INTO THE FOCUSED WINDOW"));
+        assert!(warning.contains("Backspace"));
+        let warning = calibration_warning(&CalibrateOptions {
+            erase: false,
+            ..Default::default()
+        });
+        assert!(warning.contains("NOT erased"));
+
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes(""));
+        assert!(!is_yes("no"));
+    }
+
+    #[test]
+    fn test_cli_parsing_multiple_text() {
+        let args = Args::try_parse_from(["eitype", "hello", "world"]).unwrap();
+        assert_eq!(args.text, vec!["hello", "world"]);
+    }
+
+    #[test]
+    fn test_cli_parsing_delay() {
+        let args = Args::try_parse_from(["eitype", "-d", "100", "hello"]).unwrap();
+        assert_eq!(args.delay, 100);
+    }
+
+    #[test]
+    fn test_cli_parsing_keys() {
+        let args = Args::try_parse_from(["eitype", "-k", "return", "-k", "tab"]).unwrap();
+        assert_eq!(args.keys, vec!["return", "tab"]);
     }
 
     #[test]
@@ -366,6 +2462,721 @@ mod tests {
         assert_eq!(config.layout, Some("de".to_string()));
         assert_eq!(config.variant, Some("nodeadkeys".to_string()));
         assert_eq!(config.delay_ms, 50);
+        assert_eq!(config.timestamp_source, TimestampSource::ProcessRelative);
+
+        assert_eq!(config.altgr_mode, AltGrMode::RightAlt);
+
+        let args = Args::try_parse_from(["eitype", "--altgr-mode", "level3-keysym", "x"]).unwrap();
+        assert_eq!(args.to_config().altgr_mode, AltGrMode::Level3Keysym);
+        assert_eq!(config.modifier_order, ModifierOrder::ShiftFirst);
+        let args =
+            Args::try_parse_from(["eitype", "x", "--modifier-order", "level3-first"]).unwrap();
+        assert_eq!(args.to_config().modifier_order, ModifierOrder::Level3First);
+        assert!(!config.ascii_transliterate);
+        let args = Args::try_parse_from(["eitype", "x", "--ascii-transliterate"]).unwrap();
+        assert!(args.to_config().ascii_transliterate);
+        assert!(config.strip_format_chars);
+        let args = Args::try_parse_from(["eitype", "x", "--keep-format-chars"]).unwrap();
+        assert!(!args.to_config().strip_format_chars);
+        assert!(!config.literal_whitespace);
+        let args = Args::try_parse_from(["eitype", "--literal", "a\nb"]).unwrap();
+        assert!(args.to_config().literal_whitespace);
+        assert!(!config.terminal_safe);
+        let args = Args::try_parse_from(["eitype", "--terminal-safe", "x"]).unwrap();
+        assert!(args.to_config().terminal_safe);
+
+        let args =
+            Args::try_parse_from(["eitype", "--timestamp-source", "monotonic", "x"]).unwrap();
+        assert_eq!(
+            args.to_config().timestamp_source,
+            TimestampSource::ClockMonotonic
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_repeat() {
+        let args = Args::try_parse_from(["eitype", "hello"]).unwrap();
+        assert_eq!((args.repeat, args.repeat_delay), (1, 0));
+        let args =
+            Args::try_parse_from(["eitype", "--repeat", "5", "--repeat-delay", "200", "hello"])
+                .unwrap();
+        assert_eq!((args.repeat, args.repeat_delay), (5, 200));
+        assert!(Args::try_parse_from(["eitype", "--repeat", "0", "hello"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_keydown_keyup() {
+        let args = Args::try_parse_ordered([
+            "eitype",
+            "--keydown",
+            "shift",
+            "-k",
+            "left",
+            "--keyup",
+            "shift",
+        ])
+        .unwrap();
+        assert_eq!(args.key_downs, vec!["shift"]);
+        assert_eq!(args.key_ups, vec!["shift"]);
+        let actions = args.to_actions().unwrap();
+        assert_eq!(actions.len(), 3);
+        assert!(matches!(&actions[0], Action::KeyDown(k) if k == "shift"));
+        assert!(matches!(&actions[1], Action::Key(k) if k == "left"));
+        assert!(matches!(&actions[2], Action::KeyUp(k) if k == "shift"));
+    }
+
+    #[test]
+    fn test_actions_follow_command_line_order() {
+        let args = Args::try_parse_ordered([
+            "eitype", "-k", "tab", "hello", "--keysym", "eacute", "-k", "return", "world",
+        ])
+        .unwrap();
+        let kinds: Vec<&str> = args
+            .to_actions()
+            .unwrap()
+            .iter()
+            .map(Action::kind)
+            .collect();
+        assert_eq!(kinds, vec!["key", "type", "keysym", "key", "type"]);
+
+        // Without the order, actions are grouped by flag
+        let args = Args::try_parse_from(["eitype", "-k", "tab", "hello"]).unwrap();
+        let kinds: Vec<&str> = args
+            .to_actions()
+            .unwrap()
+            .iter()
+            .map(Action::kind)
+            .collect();
+        assert_eq!(kinds, vec!["type", "key"]);
+    }
+
+    #[test]
+    fn test_cli_parsing_keysym() {
+        let args =
+            Args::try_parse_from(["eitype", "--keysym", "eacute", "--keysym", "Cyrillic_de"])
+                .unwrap();
+        assert_eq!(args.keysyms, vec!["eacute", "Cyrillic_de"]);
+
+        let actions = args.to_actions().unwrap();
+        assert!(matches!(&actions[0], Action::Keysym(k) if k == "eacute"));
+        assert!(matches!(&actions[1], Action::Keysym(k) if k == "Cyrillic_de"));
+    }
+
+    #[test]
+    fn test_cli_parsing_paste() {
+        let args = Args::try_parse_from([
+            "eitype",
+            "--paste",
+            "--paste-selection",
+            "primary",
+            "--paste-trigger",
+            "middle-click",
+            "--paste-restore-ms",
+            "200",
+            "hello",
+        ])
+        .unwrap();
+        assert!(args.to_config().pointer);
+
+        let actions = args.to_actions().unwrap();
+        let expected = PasteOptions {
+            selection: Selection::Primary,
+            trigger: PasteTrigger::MiddleClick,
+            restore_after: Some(std::time::Duration::from_millis(200)),
+        };
+        assert!(matches!(&actions[0], Action::Paste(t, o) if t == "hello" && *o == expected));
+    }
+
+    #[test]
+    fn test_cli_parsing_paste_defaults() {
+        let args = Args::try_parse_from(["eitype", "--paste", "hello"]).unwrap();
+        assert!(!args.to_config().pointer);
+        let actions = args.to_actions().unwrap();
+        assert!(matches!(&actions[0], Action::Paste(_, o) if *o == PasteOptions::default()));
+
+        assert!(Args::try_parse_from(["eitype", "--paste-trigger", "ctrl-q", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_scroll() {
+        let args = Args::try_parse_from(["eitype", "--scroll", "0,-3", "--scroll", "1,0"]).unwrap();
+        assert!(args.to_config().pointer);
+        args.check_scroll().unwrap();
+        let actions = args.to_actions().unwrap();
+        assert!(matches!(actions[0], Action::ScrollDiscrete(0, -3)));
+        assert!(matches!(actions[1], Action::ScrollDiscrete(1, 0)));
+
+        let args = Args::try_parse_from(["eitype", "--smooth", "--scroll", "0,2.5"]).unwrap();
+        let actions = args.to_actions().unwrap();
+        assert!(matches!(actions[0], Action::ScrollSmooth(dx, dy) if dx == 0.0 && dy == 2.5));
+
+        // Fractional notches are rejected unless scrolling smoothly
+        let args = Args::try_parse_from(["eitype", "--discrete", "--scroll", "0,2.5"]).unwrap();
+        assert!(args.check_scroll().is_err());
+
+        assert!(Args::try_parse_from(["eitype", "--scroll", "3"]).is_err());
+        assert!(
+            Args::try_parse_from(["eitype", "--smooth", "--discrete", "--scroll", "0,1"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_tap() {
+        let args = Args::try_parse_from(["eitype", "--tap", "100,200.5"]).unwrap();
+        let config = args.to_config();
+        assert!(!config.pointer);
+        assert_eq!(config.capabilities, Some(vec![DeviceCapability::Touch]));
+        let actions = args.to_actions().unwrap();
+        assert!(matches!(actions[0], Action::Tap(x, y) if x == 100.0 && y == 200.5));
+
+        // Taps after scrolling keep the pointer capabilities
+        let args = Args::try_parse_from(["eitype", "--scroll", "0,1", "--tap", "1,2"]).unwrap();
+        let config = args.to_config();
+        assert!(config.pointer);
+        assert!(config
+            .requested_capabilities()
+            .contains(&DeviceCapability::Scroll));
+        assert!(config
+            .requested_capabilities()
+            .contains(&DeviceCapability::Touch));
+        let actions = args.to_actions().unwrap();
+        assert!(matches!(actions[1], Action::Tap(..)));
+
+        assert!(Args::try_parse_from(["eitype", "--tap", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_line() {
+        let args = Args::try_parse_from(["eitype", "--line", "ls", "pwd"]).unwrap();
+        let actions = args.to_actions().unwrap();
+        assert_eq!(actions.len(), 4);
+        assert!(matches!(&actions[0], Action::Type(t) if t == "ls"));
+        assert!(matches!(&actions[1], Action::Key(k) if k == "return"));
+        assert!(matches!(&actions[2], Action::Type(t) if t == "pwd"));
+        assert!(matches!(&actions[3], Action::Key(k) if k == "return"));
+    }
+
+    #[test]
+    fn test_cli_parsing_inline() {
+        let args = Args::try_parse_from(["eitype", "--inline", "--line", "me\\tpw"]).unwrap();
+        let actions = args.to_actions().unwrap();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Type("me".to_string()),
+                Action::Key("tab".to_string()),
+                Action::Type("pw".to_string()),
+                Action::Key("return".to_string()),
+            ][..]
+        );
+        let args = Args::try_parse_from(["eitype", "--inline", "ok", "{x}"]).unwrap();
+        assert!(matches!(
+            args.to_actions(),
+            Err(EiTypeError::InlineSyntax { offset: 0, .. })
+        ));
+        // Without --inline, braces and backslashes are typed as is
+        let args = Args::try_parse_from(["eitype", "{x}\\t"]).unwrap();
+        assert_eq!(
+            args.to_actions().unwrap(),
+            vec![Action::Type("{x}\\t".to_string())]
+        );
+        assert!(Args::try_parse_from(["eitype", "--inline", "--paste", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_focus() {
+        let args = Args::try_parse_from(["eitype", "--focus-app", "firefox", "hi"]).unwrap();
+        assert_eq!(args.to_config().focus_settle_ms, 200);
+        assert_eq!(
+            args.to_actions().unwrap(),
+            vec![
+                Action::Focus(FocusTarget::AppId("firefox".to_string())),
+                Action::Type("hi".to_string()),
+            ]
+        );
+        let args = Args::try_parse_from([
+            "eitype",
+            "--focus-title",
+            "^Inbox",
+            "--focus-settle-ms",
+            "50",
+            "-k",
+            "return",
+        ])
+        .unwrap();
+        assert_eq!(args.to_config().focus_settle_ms, 50);
+        assert_eq!(
+            args.to_actions().unwrap()[0],
+            Action::Focus(FocusTarget::title("^Inbox").unwrap())
+        );
+        let args = Args::try_parse_from(["eitype", "--focus-title", "(", "x"]).unwrap();
+        assert!(matches!(
+            args.to_actions(),
+            Err(EiTypeError::InvalidConfig(_))
+        ));
+        assert!(
+            Args::try_parse_from(["eitype", "--focus-app", "a", "--focus-title", "b", "x"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_profile() {
+        let args = Args::try_parse_from(["eitype", "x"]).unwrap();
+        assert_eq!(args.profile, None);
+        let args = Args::try_parse_from(["eitype", "--profile", "terminal", "x"]).unwrap();
+        assert_eq!(args.profile.as_deref(), Some("terminal"));
+        let args = Args::try_parse_from(["eitype", "info", "--profile", "terminal"]).unwrap();
+        assert_eq!(args.profile.as_deref(), Some("terminal"));
+    }
+
+    #[test]
+    fn test_cli_parsing_fifo() {
+        let args = Args::try_parse_from(["eitype", "--fifo", "/tmp/eitype.fifo"]).unwrap();
+        assert_eq!(args.fifo, Some(PathBuf::from("/tmp/eitype.fifo")));
+        assert!(Args::try_parse_from(["eitype", "--fifo", "/tmp/eitype.fifo", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_read_fifo_lines_across_writers() {
+        use std::io::Write;
+        use std::sync::{mpsc, Arc};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input");
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, lines) = mpsc::channel();
+        let reader = {
+            let (path, stop) = (path.clone(), stop.clone());
+            std::thread::spawn(move || {
+                read_fifo_lines(&path, &stop, |line| sender.send(line.to_string()).unwrap())
+            })
+        };
+        let write = |text: &str| {
+            // Opening for writing waits for the reader to open the FIFO
+            let mut fifo = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            fifo.write_all(text.as_bytes()).unwrap();
+        };
+        let next = || lines.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The FIFO is created on first use
+        while !path.exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        write("hello\r\nworld\n\nno newline");
+        assert_eq!(next(), "hello");
+        assert_eq!(next(), "world");
+        assert_eq!(next(), "");
+        // Handled when the writer closes
+        assert_eq!(next(), "no newline");
+
+        write("second writer\n");
+        assert_eq!(next(), "second writer");
+
+        stop.store(true, Ordering::SeqCst);
+        reader.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_read_fifo_lines_rejects_regular_file() {
+        let (_dir, path) = temp_file("not-a-fifo", b"x");
+        let err = read_fifo_lines(&path, &AtomicBool::new(false), |_| {}).unwrap_err();
+        assert!(err.to_string().contains("not a FIFO"), "{}", err);
+    }
+
+    #[test]
+    fn test_cli_parsing_connect_retries() {
+        let config = Args::try_parse_from(["eitype", "x"]).unwrap().to_config();
+        assert_eq!(config.connect_retries, 0);
+        assert_eq!(config.connect_retry_delay_ms, 1000);
+        let args = Args::try_parse_from([
+            "eitype",
+            "--connect-retries",
+            "5",
+            "--connect-retry-delay-ms",
+            "200",
+            "x",
+        ])
+        .unwrap();
+        assert_eq!(args.to_config().connect_retries, 5);
+        assert_eq!(args.to_config().connect_retry_delay_ms, 200);
+    }
+
+    #[test]
+    fn test_cli_parsing_no_auto_detect_layout() {
+        let args = Args::try_parse_from(["eitype", "x"]).unwrap();
+        assert!(args.to_config().auto_detect_layout);
+        let args = Args::try_parse_from(["eitype", "--no-auto-detect-layout", "x"]).unwrap();
+        assert!(!args.to_config().auto_detect_layout);
+    }
+
+    #[test]
+    fn test_cli_parsing_timing_out() {
+        let args = Args::try_parse_from(["eitype", "--timing-out", "t.csv", "x"]).unwrap();
+        assert_eq!(args.timing_out, Some(PathBuf::from("t.csv")));
+        assert!(args.to_config().collect_timing);
+        assert!(
+            !Args::try_parse_from(["eitype", "x"])
+                .unwrap()
+                .to_config()
+                .collect_timing
+        );
+    }
+
+    #[test]
+    fn test_format_key_list() {
+        assert!(
+            Args::try_parse_from(["eitype", "--list-keys"])
+                .unwrap()
+                .list_keys
+        );
+        let list = format_key_list();
+        let lines: Vec<&str> = list.lines().collect();
+        assert!(lines.contains(&"tab"));
+        assert!(lines.contains(&"backtab (combo: shift+tab)"));
+        assert!(lines.contains(&"redo (combo: ctrl+shift+z)"));
+    }
+
+    #[test]
+    fn test_cli_parsing_stats() {
+        assert!(
+            Args::try_parse_from(["eitype", "--stats", "x"])
+                .unwrap()
+                .stats
+        );
+        assert!(!Args::try_parse_from(["eitype", "x"]).unwrap().stats);
+    }
+
+    #[test]
+    fn test_stats_report() {
+        let summary = RunSummary {
+            stats: TypingStats {
+                chars_typed: 50,
+                keys_pressed: 54,
+                frames_sent: 108,
+                flush_retries: 3,
+                ..Default::default()
+            },
+            elapsed: Duration::from_millis(250),
+            ..Default::default()
+        };
+        let report = summary.stats_report();
+        assert_eq!(
+            report.lines().collect::<Vec<_>>(),
+            vec![
+                "chars typed:   50",
+                "keys pressed:  54",
+                "frames sent:   108",
+                "flush retries: 3",
+                "elapsed:       250 ms (200 chars/s)",
+            ]
+        );
+        // Nothing ran: no division by zero
+        assert!(RunSummary::default()
+            .stats_report()
+            .ends_with("0 ms (0 chars/s)"));
+    }
+
+    #[test]
+    fn test_cli_parsing_bench() {
+        let args = Args::try_parse_from(["eitype", "bench"]).unwrap();
+        let Some(Command::Bench(bench)) = &args.command else {
+            panic!("expected bench, got {:?}", args.command);
+        };
+        assert_eq!(bench.chars, 2000);
+        assert_eq!(bench.against, BenchTarget::Editor);
+        assert!(!bench.yes && !bench.json);
+
+        let args = Args::try_parse_from([
+            "eitype",
+            "bench",
+            "--against",
+            "null",
+            "--chars",
+            "50",
+            "--json",
+        ])
+        .unwrap();
+        let Some(Command::Bench(bench)) = &args.command else {
+            panic!("expected bench, got {:?}", args.command);
+        };
+        assert_eq!((bench.chars, bench.against), (50, BenchTarget::Null));
+        assert!(bench.json);
+        assert!(Args::try_parse_from(["eitype", "bench", "--against", "vim"]).is_err());
+    }
+
+    #[test]
+    fn test_bench_against_null() {
+        let args = Args::try_parse_from([
+            "eitype",
+            "-l",
+            "us",
+            "--layout-index",
+            "0",
+            "--ignore-layout-cache",
+            "bench",
+            "--against",
+            "null",
+            "--chars",
+            "300",
+        ])
+        .unwrap();
+        let Some(Command::Bench(bench)) = &args.command else {
+            panic!("expected bench, got {:?}", args.command);
+        };
+        let report = bench_report(&args, bench).unwrap();
+        assert_eq!(report.chars, 300);
+
+        let json = parse_json(&bench_json(bench.against, &report));
+        assert_eq!(json["against"], "null");
+        assert_eq!(json["chars"], 300);
+        assert_eq!(json["flush_retries"], 0);
+        assert_eq!(json["sampled"], 300);
+        for key in ["elapsed_us", "chars_per_sec", "throttle_events"] {
+            assert!(json[key].is_u64(), "{}", key);
+        }
+        let latency = &json["latency_us"];
+        for part in ["plan", "flush", "gap"] {
+            let values: Vec<u64> = ["p50", "p90", "p99", "max"]
+                .iter()
+                .map(|key| {
+                    let value = &latency[part][key];
+                    value
+                        .as_u64()
+                        .unwrap_or_else(|| panic!("{}.{} is {}", part, key, value))
+                })
+                .collect();
+            assert!(values.windows(2).all(|w| w[0] <= w[1]), "{:?}", values);
+        }
+
+        let text = format_bench(&report);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("300 chars in "), "{}", lines[0]);
+        assert!(lines[0].ends_with(" chars/s"), "{}", lines[0]);
+        assert_eq!(lines[1], "0 flush retries, 0 throttle pauses");
+        assert!(lines[3].starts_with("  plan "), "{}", lines[3]);
+        assert!(lines[5].starts_with("  gap "), "{}", lines[5]);
+    }
+
+    #[test]
+    fn test_format_bench() {
+        let micros = Duration::from_micros;
+        let latency = |base| LatencyPercentiles {
+            p50: micros(base),
+            p90: micros(base * 2),
+            p99: micros(base * 4),
+            max: micros(base * 10),
+        };
+        let mut report = BenchReport {
+            chars: 2000,
+            elapsed: Duration::from_millis(1250),
+            chars_per_sec: 1600.0,
+            flush_retries: 3,
+            throttle_events: 1,
+            sampled: 2000,
+            plan: latency(2),
+            flush: latency(15),
+            gap: latency(600),
+        };
+        assert_eq!(
+            format_bench(&report).lines().collect::<Vec<_>>(),
+            vec![
+                "2000 chars in 1.250 s: 1600 chars/s",
+                "3 flush retries, 1 throttle pauses",
+                "Per-char times (us):  p50      p90      p99      max",
+                "  plan                  2        4        8       20",
+                "  flush                15       30       60      150",
+                "  gap                 600     1200     2400     6000",
+            ]
+        );
+
+        // More chars than the timing trace keeps
+        report.chars = 150_000;
+        report.sampled = 100_000;
+        assert_eq!(
+            format_bench(&report).lines().nth(2),
+            Some("Per-char times are for the last 100000 chars only")
+        );
+    }
+
+    #[test]
+    fn test_format_calibration() {
+        let step = |delay_ms, chars_per_sec, kept_up: bool| eitype::CalibrationStep {
+            delay_ms,
+            chars_per_sec,
+            flush_retries: usize::from(!kept_up),
+            throttle_events: 2 * usize::from(!kept_up),
+            kept_up,
+        };
+        let calibration = Calibration {
+            steps: vec![
+                step(4, 100.0, true),
+                step(2, 180.4, true),
+                step(1, 250.0, false),
+            ],
+            recommended_delay_ms: Some(2),
+            recommended_chars_per_sec: Some(180.4),
+            ceiling_chars_per_sec: 250.0,
+        };
+        assert_eq!(
+            format_calibration(&calibration).lines().collect::<Vec<_>>(),
+            vec![
+                "   4 ms:    100 chars/s, 0 flush retries, 0 throttle pauses",
+                "   2 ms:    180 chars/s, 0 flush retries, 0 throttle pauses",
+                "   1 ms:    250 chars/s, 1 flush retries, 2 throttle pauses (fell behind)",
+                "Ceiling: 250 chars/s",
+                "Recommended: --delay 2 (about 180 chars/s)",
+            ]
+        );
+
+        let calibration = Calibration {
+            steps: vec![step(4, 90.0, false)],
+            recommended_delay_ms: None,
+            recommended_chars_per_sec: None,
+            ceiling_chars_per_sec: 90.0,
+        };
+        assert!(format_calibration(&calibration).ends_with("fell behind at every rate tried\n"));
+    }
+
+    #[test]
+    fn test_keymap_spec_parsing() {
+        let names =
+            |layout: &str, variant: Option<&str>, options: Option<&str>| KeymapSpec::Names {
+                layout: layout.to_string(),
+                variant: variant.map(str::to_string),
+                options: options.map(str::to_string),
+            };
+        assert_eq!("live".parse(), Ok(KeymapSpec::Live));
+        assert_eq!(
+            "file:/tmp/a.xkb".parse(),
+            Ok(KeymapSpec::File(PathBuf::from("/tmp/a.xkb")))
+        );
+        assert_eq!("us".parse(), Ok(names("us", None, None)));
+        assert_eq!("us(intl)".parse(), Ok(names("us", Some("intl"), None)));
+        assert_eq!(
+            "de:lv3:ralt_alt".parse(),
+            Ok(names("de", None, Some("lv3:ralt_alt")))
+        );
+        assert!("(intl)".parse::<KeymapSpec>().is_err());
+        assert!("us(intl".parse::<KeymapSpec>().is_err());
+
+        let args =
+            Args::try_parse_from(["eitype", "keymap-diff", "live", "us(intl)", "--json"]).unwrap();
+        let Some(Command::KeymapDiff(diff_args)) = args.command else {
+            panic!("expected keymap-diff");
+        };
+        assert_eq!(diff_args.a, KeymapSpec::Live);
+        assert!(diff_args.json);
+    }
+
+    #[test]
+    fn test_capabilities_json_schema() {
+        let args = Args::try_parse_from(["eitype", "capabilities", "--json"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Capabilities(CapabilitiesArgs { json: true }))
+        ));
+
+        // A snapshot of the layout: changing it means bumping the schema version
+        let json = parse_json(&capabilities_json(&capabilities()));
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(
+            keys(&json),
+            vec![
+                "schema_version",
+                "version",
+                "keys",
+                "combos",
+                "actions",
+                "fallbacks",
+                "backends",
+                "features"
+            ]
+        );
+        assert_eq!(keys(&json["keys"][0]), vec!["name", "code", "aliases"]);
+        assert_eq!(keys(&json["combos"][0]), vec!["name", "keys"]);
+        let paste = json["actions"]
+            .as_array()
+            .expect("actions is not an array")
+            .iter()
+            .find(|action| action["kind"] == "paste")
+            .expect("paste action");
+        assert_eq!(keys(paste), vec!["kind", "params"]);
+        assert_eq!(
+            keys(&paste["params"][1]),
+            vec!["name", "type", "choices", "optional"]
+        );
+        assert_eq!(paste["params"][1]["optional"], true);
+        assert!(format_capabilities(&capabilities()).contains("\n  tap x y\n"));
+    }
+
+    #[test]
+    fn test_keymap_diff_output() {
+        let args = Args::try_parse_from(["eitype", "x"]).unwrap();
+        let load = |spec: &str| spec.parse::<KeymapSpec>().unwrap().load(&args).unwrap();
+        let diff = diff_keymaps(&load("us"), &load("us(intl)"));
+
+        let report = format_keymap_diff(&diff);
+        let only_in_b = report
+            .lines()
+            .find(|line| line.starts_with("Only in the second keymap"))
+            .expect(&report);
+        assert!(only_in_b.contains(" ä "), "{}", only_in_b);
+        assert!(report.contains("\n  ' (apostrophe): keycode 40 level 0 -> keycode 40 level"));
+
+        let json = parse_json(&keymap_diff_json(&diff));
+        assert_eq!(keys(&json), vec!["only_in_a", "only_in_b", "moved"]);
+        let apostrophe = json["moved"]
+            .as_array()
+            .expect("moved is not an array")
+            .iter()
+            .find(|entry| entry["keysym"] == "apostrophe")
+            .expect("apostrophe moved");
+        assert_eq!(apostrophe["char"], "'");
+        assert_eq!(apostrophe["a"]["level"], 0);
+        assert_eq!(apostrophe["b"]["modifiers"][0], "AltGr");
+
+        assert_eq!(
+            format_keymap_diff(&diff_keymaps(&load("us"), &load("us"))),
+            "No differences\n"
+        );
+    }
+
+    #[test]
+    fn test_timing_csv() {
+        let timing = |ch, plan, flush, gap| CharTiming {
+            ch,
+            plan: std::time::Duration::from_micros(plan),
+            flush: std::time::Duration::from_micros(flush),
+            gap: std::time::Duration::from_micros(gap),
+        };
+        let csv = timing_csv(&[timing('a', 3, 40, 0), timing(',', 2, 35, 120)]);
+        assert_eq!(
+            csv,
+            "index,char,plan_us,flush_us,gap_us\n0,a,3,40,0\n1,\",\",2,35,120\n"
+        );
+        assert_eq!(
+            timing_csv(&[timing('"', 0, 0, 0)]).lines().nth(1),
+            Some("0,\"\"\"\",0,0,0")
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_exclusive() {
+        let args = Args::try_parse_from(["eitype", "--exclusive", "--exclusive-timeout", "0", "x"])
+            .unwrap();
+        let config = args.to_config();
+        assert!(config.exclusive);
+        assert_eq!(config.exclusive_timeout_ms, Some(0));
+
+        assert!(
+            !Args::try_parse_from(["eitype", "x"])
+                .unwrap()
+                .to_config()
+                .exclusive
+        );
+        assert!(Args::try_parse_from(["eitype", "--exclusive-timeout", "5", "x"]).is_err());
     }
 
     #[test]
@@ -375,7 +3186,7 @@ mod tests {
         ])
         .unwrap();
 
-        let actions = args.to_actions();
+        let actions = args.to_actions().unwrap();
         assert_eq!(actions.len(), 4);
 
         // ModifierHold comes first
@@ -0,0 +1,55 @@
+//! Integration test for closing right after typing.
+//!
+//! This test requires a running Wayland desktop with EI support (e.g., GNOME on Wayland)
+//! and `wl-paste` from wl-clipboard. It is behind a feature flag because it:
+//! - Requires user authorization via the portal dialog (first run only)
+//! - Actually types text into the focused window, and replaces the clipboard
+//! - Will fail in CI environments without a display
+//!
+//! To run it:
+//! ```sh
+//! cargo test --features wayland-integration-tests --test close
+//! ```
+//!
+//! Before running, open an empty text editor and ensure it has focus: the
+//! test selects and copies everything in it to check what arrived.
+
+#![cfg(feature = "wayland-integration-tests")]
+
+use eitype::{EiType, EiTypeConfig};
+use std::process::Command;
+
+/// Type a short string and close at once, as a short-lived CLI invocation
+/// does; every character must still land.
+#[test]
+fn test_close_right_after_typing_keeps_last_chars() {
+    let test_text = "last characters intact 0123456789";
+
+    println!("Connecting to portal...");
+    let mut typer =
+        EiType::connect_portal(EiTypeConfig::default()).expect("Failed to connect to portal");
+    typer.type_text(test_text).expect("Failed to type text");
+    typer.close();
+
+    // Copy what the editor received with a fresh connection
+    let mut copier =
+        EiType::connect_portal(EiTypeConfig::default()).expect("Failed to connect to portal");
+    copier.hold_modifier("ctrl").unwrap();
+    copier.type_text("a").unwrap();
+    copier.type_text("c").unwrap();
+    copier.release_modifiers().unwrap();
+    copier.close();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    let output = Command::new("wl-paste")
+        .arg("--no-newline")
+        .output()
+        .expect("Failed to run wl-paste");
+    let pasted = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        pasted.ends_with(test_text),
+        "Expected the editor to end with {:?}, got {:?}",
+        test_text,
+        pasted
+    );
+}
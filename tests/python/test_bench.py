@@ -0,0 +1,38 @@
+"""Tests for EiType.null and bench (no compositor needed).
+
+Run after `maturin develop` with:
+
+    pytest tests/python
+"""
+
+from datetime import timedelta
+
+import pytest
+
+from eitype import BenchReport, EiType, EiTypeConfig
+
+
+def null_typer():
+    return EiType.null(EiTypeConfig(layout="us", layout_index=0, ignore_layout_cache=True))
+
+
+def test_bench_null_report():
+    typer = null_typer()
+    report = typer.bench(chars=100)
+    typer.close()
+    assert isinstance(report, BenchReport)
+    assert report.chars == 100
+    assert report.chars_per_sec > 0
+    assert report.flush_retries == 0
+    for latency in (report.plan, report.flush, report.gap):
+        assert isinstance(latency.p50, timedelta)
+        assert latency.p50 <= latency.p90 <= latency.p99 <= latency.max
+
+
+def test_null_has_only_a_keyboard():
+    typer = null_typer()
+    typer.type_text("hello")
+    assert typer.timings() == []
+    with pytest.raises(RuntimeError):
+        typer.scroll_discrete(0, 1)
+    typer.close()
@@ -0,0 +1,142 @@
+"""Tests for EiTypeConfig's Python interface (no connection needed).
+
+Run after `maturin develop` with:
+
+    pytest tests/python
+"""
+
+from datetime import timedelta
+
+import pytest
+
+from eitype import (
+    AltGrMode,
+    ChunkPolicy,
+    DeviceCapability,
+    EiTypeConfig,
+    ModifierOrder,
+    TimestampSource,
+    clear_detected_layout_cache,
+)
+
+
+def full_config() -> EiTypeConfig:
+    """A config with every field away from its default"""
+    return EiTypeConfig(
+        layout="us,ru",
+        variant=",phonetic",
+        model="pc105",
+        options="grp:alt_shift_toggle",
+        layout_index=1,
+        delay_ms=5,
+        keymap_string="xkb_keymap {};",
+        keymap_file="/tmp/keymap.xkb",
+        paste_threshold=200,
+        idle_stop_emulating_ms=3000,
+        stop_emulating_after_each=True,
+        pointer=True,
+        ignore_layout_cache=True,
+        exclusive=True,
+        exclusive_timeout_ms=0,
+        timestamp_source=TimestampSource.ClockMonotonic,
+        adaptive_throttle=True,
+        max_text_len=1000,
+        prefer_keymap_keys=False,
+        capabilities=[DeviceCapability.Scroll],
+        collect_timing=True,
+        auto_detect_layout=False,
+        layout_detection_ttl_ms=0,
+        refuse_if_focused_app="com.example.Dictate",
+        focus_settle_ms=50,
+        strip_format_chars=False,
+        strict_key_state=True,
+        chunk=ChunkPolicy(50, timedelta(milliseconds=20)),
+        settle_after_word_ms=30,
+        altgr_mode=AltGrMode.Level3Keysym,
+        modifier_order=ModifierOrder.Level3First,
+        modifier_settle_ms=5,
+        ascii_transliterate=True,
+        literal_whitespace=True,
+        device_failover_ms=500,
+        connect_retries=3,
+        connect_retry_delay_ms=250,
+        max_modifier_hold_ms=40,
+        terminal_safe=True,
+        token_namespace="my-app",
+    )
+
+
+@pytest.mark.parametrize("config", [EiTypeConfig(), full_config()])
+def test_dict_round_trip(config):
+    fields = config.to_dict()
+    assert EiTypeConfig.from_dict(fields) == config
+    assert EiTypeConfig.from_dict(fields).to_dict() == fields
+
+
+def test_to_dict_has_every_constructor_field():
+    fields = EiTypeConfig().to_dict()
+    assert fields["layout"] is None
+    assert fields["delay_ms"] == 0
+    assert fields["adaptive_throttle"] is False
+    assert len(fields) == 40
+
+
+def test_from_dict_defaults_missing_fields():
+    assert EiTypeConfig.from_dict({"delay_ms": 3}) == EiTypeConfig(delay_ms=3)
+    with pytest.raises(AttributeError):
+        EiTypeConfig.from_dict({"no_such_field": 1})
+
+
+def test_positional_arguments_keep_their_places():
+    # Fields added later go at the end, so positional calls mean the same
+    config = EiTypeConfig("us", None, None, None, 0, 5, None, None, 100, 3000, True)
+    assert config.delay_ms == 5
+    assert config.paste_threshold == 100
+    assert config.idle_stop_emulating_ms == 3000
+    assert config.pointer is True
+    assert config.stop_emulating_after_each is False
+
+
+def test_layout_detection_can_be_refreshed():
+    assert EiTypeConfig().layout_detection_ttl_ms == 5000
+    assert EiTypeConfig(layout_detection_ttl_ms=0).layout_detection_ttl_ms == 0
+    assert clear_detected_layout_cache() is None
+
+
+def test_repr_is_stable():
+    assert repr(EiTypeConfig()) == repr(EiTypeConfig())
+    assert repr(full_config()) == repr(full_config())
+    text = repr(EiTypeConfig(layout="de", delay_ms=2))
+    assert text.startswith("EiTypeConfig(layout='de', variant=None, ")
+    assert "delay_ms=2" in text
+    assert "chunk=None" in text
+    assert repr(ChunkPolicy(10, timedelta(seconds=1))) == (
+        "ChunkPolicy(chars_per_chunk=10, pause_between=datetime.timedelta(seconds=1))"
+    )
+
+
+def test_equality_and_copy_modify():
+    config = full_config()
+    copy = EiTypeConfig.from_dict(config.to_dict())
+    assert copy == config
+    copy.delay_ms = 10
+    assert copy.delay_ms == 10
+    assert copy != config
+
+
+def test_set_validates():
+    config = EiTypeConfig(layout="us,ru")
+    config.layout_index = 1
+    with pytest.raises(ValueError):
+        config.layout_index = 2
+    with pytest.raises(ValueError):
+        config.layout = ""
+    # A rejected value leaves the config unchanged
+    assert config.layout == "us,ru"
+    assert config.layout_index == 1
+    with pytest.raises(ValueError):
+        EiTypeConfig(refuse_if_focused_app=" ")
+    with pytest.raises(ValueError):
+        EiTypeConfig.from_dict({"layout": "us", "layout_index": 1})
+    with pytest.raises(ValueError):
+        config.token_namespace = "../other-app"
@@ -0,0 +1,81 @@
+"""Tests for using EiType from several Python threads (no compositor needed).
+
+Run after `maturin develop` with:
+
+    pytest tests/python
+"""
+
+import threading
+
+import pytest
+
+from eitype import EiType, EiTypeConfig, TypingError
+
+
+def null_typer(**fields):
+    config = EiTypeConfig(layout="us", layout_index=0, ignore_layout_cache=True, **fields)
+    return EiType.null(config)
+
+
+def run_threads(targets):
+    threads = [threading.Thread(target=target) for target in targets]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+
+
+def test_connect_error_from_several_threads():
+    errors = []
+
+    def connect():
+        try:
+            EiType.connect_socket("/nonexistent/eitype-test.sock")
+        except RuntimeError as e:
+            errors.append(str(e))
+
+    run_threads([connect] * 4)
+    assert len(errors) == 4
+    assert all("nonexistent" in error for error in errors)
+
+
+def test_connect_error_is_runtime_error():
+    with pytest.raises(RuntimeError):
+        EiType.connect_socket("/nonexistent/eitype-test.sock")
+
+
+def test_two_threads_never_interleave():
+    # A key delay gives the other thread every chance to cut in
+    typer = null_typer(delay_ms=1)
+    typed = []
+    typer.set_keystroke_hook(lambda info: typed.append(info.ch) if info.pressed else None)
+    first, second = "abcdefghij" * 3, "0123456789" * 3
+
+    run_threads([lambda: typer.type_text(first), lambda: typer.type_text(second)])
+    typer.close()
+    assert "".join(typed) in (first + second, second + first)
+
+
+def test_type_text_if_idle_raises_while_busy():
+    typer = null_typer(delay_ms=5)
+    started = threading.Event()
+    typer.set_keystroke_hook(lambda info: started.set())
+    errors = []
+
+    def type_long_text():
+        try:
+            typer.type_text("a" * 200)
+        except TypingError as e:
+            errors.append(str(e))
+
+    worker = threading.Thread(target=type_long_text)
+    worker.start()
+    started.wait()
+    with pytest.raises(TypingError):
+        typer.type_text_if_idle("b")
+    typer.cancel()
+    worker.join()
+    assert len(errors) == 1 and "cancelled" in errors[0]
+    # The cancel was for the call that was running, not this one
+    typer.type_text_if_idle("b")
+    typer.close()
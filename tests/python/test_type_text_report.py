@@ -0,0 +1,52 @@
+"""Tests for EiType.type_text_report (no compositor needed).
+
+Run after `maturin develop` with:
+
+    pytest tests/python
+"""
+
+from eitype import EiType, EiTypeConfig, SkippedChar, Substitution, TypeOutcome
+
+
+def null_typer(**fields):
+    config = EiTypeConfig(layout="us", layout_index=0, ignore_layout_cache=True, **fields)
+    return EiType.null(config)
+
+
+def test_report_lists_skipped_chars():
+    typer = null_typer()
+    outcome = typer.type_text_report("a😀b∞")
+    typer.close()
+    assert isinstance(outcome, TypeOutcome)
+    assert outcome.chars_requested == 4
+    assert outcome.chars_delivered == 2
+    assert [(s.index, s.ch) for s in outcome.skipped] == [(1, "😀"), (3, "∞")]
+    assert all(isinstance(s, SkippedChar) for s in outcome.skipped)
+    assert outcome.substitutions == []
+    assert outcome.fallbacks == []
+    assert outcome.stats.chars_typed == 2
+
+
+def test_report_lists_substitutions():
+    typer = null_typer(ascii_transliterate=True)
+    outcome = typer.type_text_report("café")
+    typer.close()
+    assert outcome.chars_delivered == 4
+    (substitution,) = outcome.substitutions
+    assert isinstance(substitution, Substitution)
+    assert (substitution.index, substitution.original, substitution.replacement) == (3, "é", "e")
+
+
+def test_report_as_dict():
+    typer = null_typer(ascii_transliterate=True)
+    report = typer.type_text_report("é\ue000").to_dict()
+    typer.close()
+    assert report["chars_requested"] == 2
+    assert report["chars_delivered"] == 1
+    (skipped,) = report["skipped"]
+    assert sorted(skipped) == ["ch", "index", "reason"]
+    assert (skipped["index"], skipped["ch"]) == (1, "\ue000")
+    assert report["substitutions"] == [{"index": 0, "original": "é", "replacement": "e"}]
+    assert report["format_chars_stripped"] == 0
+    assert report["fallbacks"] == []
+    assert report["stats"]["chars_typed"] == 1